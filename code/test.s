@@ -5,6 +5,7 @@
 	nop
 .endr
 
+.globl _start
 _start:
 	mov rax, 0
 	mov rbx, 0
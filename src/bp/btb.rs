@@ -0,0 +1,255 @@
+//! Branch target buffer.
+
+use crate::bp::*;
+
+/// A branch tracked by the BTB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BTBBranch {
+    pub info: BranchInfo,
+    /// The last-known target address
+    pub tgt: usize,
+}
+
+/// An entry in the BTB, describing the branches which end in some 64-byte
+/// cache line.
+///
+/// NOTE: On Zen 2, each entry can hold up to two branches if they reside
+/// in the same 64-byte line and the first branch is conditional.
+#[derive(Clone, Copy, Debug)]
+pub struct BTBEntry {
+    /// Address of the 64-byte line
+    pub line: usize,
+    /// Branches in this line (ordered by address)
+    pub brn: [Option<BTBBranch>; 2],
+    /// Timestamp of the last access (for LRU replacement)
+    pub last_use: usize,
+}
+impl BTBEntry {
+    pub fn new(line: usize) -> Self {
+        Self { line, brn: [None; 2], last_use: 0 }
+    }
+
+    /// Track a branch in this entry.
+    ///
+    /// If the resulting entry would be invalid, the new branch replaces
+    /// all other branches in the entry.
+    pub fn insert(&mut self, new: BTBBranch) {
+        let mut brns: Vec<BTBBranch> = self.brn.iter().flatten()
+            .filter(|b| b.info.addr != new.info.addr)
+            .copied().collect();
+        brns.push(new);
+        brns.sort_by_key(|b| b.info.addr);
+
+        let ok = match brns.len() {
+            1 => true,
+            2 => brns[0].info.kind == BranchKind::ConditionalDirect,
+            _ => false,
+        };
+        self.brn = [None; 2];
+        if ok {
+            for (slot, b) in self.brn.iter_mut().zip(brns) {
+                *slot = Some(b);
+            }
+        } else {
+            self.brn[0] = Some(new);
+        }
+    }
+}
+
+/// A single level in the BTB hierarchy.
+pub struct BTBLevel {
+    pub name: &'static str,
+    pub sets: usize,
+    pub ways: usize,
+    /// Number of bubbles incurred when a taken branch is predicted from
+    /// an entry in this level
+    pub bubbles: usize,
+    data: Vec<Option<BTBEntry>>,
+}
+impl BTBLevel {
+    pub fn new(name: &'static str, sets: usize, ways: usize, bubbles: usize)
+        -> Self
+    {
+        Self { name, sets, ways, bubbles, data: vec![None; sets * ways] }
+    }
+
+    pub fn num_entries(&self) -> usize { self.sets * self.ways }
+
+    fn set_range(&self, line: usize) -> std::ops::Range<usize> {
+        let set = (line >> 6) % self.sets;
+        (set * self.ways)..((set + 1) * self.ways)
+    }
+
+    /// Get a mutable reference to the entry for some line.
+    pub fn get_mut(&mut self, line: usize) -> Option<&mut BTBEntry> {
+        let range = self.set_range(line);
+        self.data[range].iter_mut().flatten().find(|e| e.line == line)
+    }
+
+    /// Get a mutable reference to the entry for some line, evicting the
+    /// least-recently-used entry in the set if necessary.
+    pub fn get_or_alloc(&mut self, line: usize) -> &mut BTBEntry {
+        let range = self.set_range(line);
+        let set = &mut self.data[range];
+        let idx = if let Some(i) = set.iter()
+            .position(|e| e.is_some_and(|e| e.line == line))
+        {
+            i
+        }
+        else if let Some(i) = set.iter().position(|e| e.is_none()) {
+            i
+        }
+        else {
+            set.iter().enumerate()
+                .min_by_key(|(_, e)| e.unwrap().last_use)
+                .map(|(i, _)| i).unwrap()
+        };
+        if set[idx].is_none_or(|e| e.line != line) {
+            set[idx] = Some(BTBEntry::new(line));
+        }
+        set[idx].as_mut().unwrap()
+    }
+
+    pub fn invalidate(&mut self, line: usize) {
+        let range = self.set_range(line);
+        for slot in self.data[range].iter_mut() {
+            if slot.is_some_and(|e| e.line == line) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// The branch target buffer.
+///
+/// Zen 2 has three levels of BTB:
+///
+/// - A 16-entry L0 BTB (predicting taken branches with no bubbles)
+/// - A 512-entry L1 BTB (predicting taken branches with one bubble)
+/// - A 7168-entry L2 BTB (predicting taken branches with four bubbles)
+///
+pub struct BranchTargetBuffer {
+    pub levels: [BTBLevel; 3],
+    /// Counter used to timestamp accesses
+    stamp: usize,
+}
+impl BranchTargetBuffer {
+    pub fn new() -> Self {
+        Self {
+            levels: [
+                BTBLevel::new("L0BTB",    1, 16, 0),
+                BTBLevel::new("L1BTB",   64,  8, 1),
+                BTBLevel::new("L2BTB", 1024,  7, 4),
+            ],
+            stamp: 0,
+        }
+    }
+
+    /// Returns true if an entry exists for the line containing 'pc'.
+    pub fn hit(&mut self, pc: usize) -> bool {
+        let line = pc & !0x3f;
+        self.levels.iter_mut().any(|l| l.get_mut(line).is_some())
+    }
+
+    pub fn invalidate(&mut self, pc: usize) {
+        let line = pc & !0x3f;
+        for l in self.levels.iter_mut() {
+            l.invalidate(line);
+        }
+    }
+
+    /// Look up the entry for the line containing 'pc', returning a copy of
+    /// the entry along with the index of the level where it was found.
+    ///
+    /// Entries found in the L1/L2 BTB are copied into the lower levels.
+    pub fn lookup(&mut self, pc: usize) -> Option<(BTBEntry, usize)> {
+        let line = pc & !0x3f;
+        self.stamp += 1;
+        let stamp = self.stamp;
+        for lvl in 0..self.levels.len() {
+            if let Some(e) = self.levels[lvl].get_mut(line) {
+                e.last_use = stamp;
+                let res = *e;
+                for lower in self.levels[..lvl].iter_mut() {
+                    *lower.get_or_alloc(line) = res;
+                }
+                return Some((res, lvl));
+            }
+        }
+        None
+    }
+
+    /// Track the target of some branch in all levels of the BTB.
+    pub fn update(&mut self, info: BranchInfo, tgt: usize) {
+        // Branches are tracked in the line containing their last byte
+        let line = (info.addr + info.len - 1) & !0x3f;
+        self.stamp += 1;
+        let stamp = self.stamp;
+        trace!("[BTB] Update {:08x} {:?} => {:08x}", info.addr, info.kind, tgt);
+        for l in self.levels.iter_mut() {
+            let e = l.get_or_alloc(line);
+            e.insert(BTBBranch { info, tgt });
+            e.last_use = stamp;
+        }
+    }
+}
+impl Default for BranchTargetBuffer {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn branch(addr: usize, kind: BranchKind) -> BTBBranch {
+        BTBBranch { info: BranchInfo { kind, len: 2, addr }, tgt: 0x1000 }
+    }
+
+    #[test]
+    fn entry_pairs_after_conditional() {
+        let mut e = BTBEntry::new(0);
+        e.insert(branch(0x20, BranchKind::UnconditionalDirect));
+        e.insert(branch(0x10, BranchKind::ConditionalDirect));
+        assert_eq!(e.brn.map(|b| b.map(|b| b.info.addr)),
+            [Some(0x10), Some(0x20)]);
+
+        // The first branch must be conditional
+        let mut e = BTBEntry::new(0);
+        e.insert(branch(0x20, BranchKind::ConditionalDirect));
+        e.insert(branch(0x10, BranchKind::UnconditionalDirect));
+        assert_eq!(e.brn.map(|b| b.map(|b| b.info.addr)), [Some(0x10), None]);
+    }
+
+    #[test]
+    fn lookup_after_update() {
+        let mut btb = BranchTargetBuffer::new();
+        assert!(btb.lookup(0x100).is_none());
+        btb.update(branch(0x13e, BranchKind::Call).info, 0x2000);
+        let (e, lvl) = btb.lookup(0x100).unwrap();
+        assert_eq!(lvl, 0);
+        assert_eq!(e.brn[0].unwrap().tgt, 0x2000);
+
+        // Tracked in the line containing the last byte
+        btb.update(branch(0x17f, BranchKind::Return).info, 0);
+        assert!(btb.lookup(0x180).is_some());
+        btb.invalidate(0x180);
+        assert!(!btb.hit(0x180));
+    }
+
+    #[test]
+    fn evict_to_lower_levels() {
+        // Lines which map to the same L1 set
+        let mut btb = BranchTargetBuffer::new();
+        let lines: Vec<usize> = (0..17).map(|i| i * 0x1000).collect();
+        for l in lines.iter() {
+            btb.update(branch(*l, BranchKind::ConditionalDirect).info, 0);
+        }
+        assert_eq!(btb.lookup(lines[16]).unwrap().1, 0);
+        assert_eq!(btb.lookup(lines[9]).unwrap().1, 0);
+
+        // Evicted from the L0 and the L1, but still in the L2 (and then 
+        // copied back into the L0)
+        assert_eq!(btb.lookup(lines[0]).unwrap().1, 2);
+        assert_eq!(btb.lookup(lines[0]).unwrap().1, 0);
+    }
+}
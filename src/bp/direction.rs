@@ -0,0 +1,217 @@
+//! Conditional branch direction prediction.
+
+use std::collections::VecDeque;
+
+use crate::bp::*;
+use serde::{ Serialize, Deserialize };
+
+/// Interface to a conditional branch direction predictor.
+///
+/// Predictors are stateless with respect to branch history: the history
+/// registers are owned by the [BranchPredictionUnit], which passes them
+/// in when making a prediction, and passes the same (checkpointed) values
+/// back when the branch is retired.
+pub trait DirectionPredictor: Send {
+    /// A short name for this predictor
+    fn name(&self) -> &'static str;
+
+    /// Predict whether the branch at 'pc' is taken.
+    fn predict(&self, pc: usize, ghr: u128, lhist: u16) -> bool;
+
+    /// Train the predictor with the outcome of a branch.
+    fn update(&mut self, pc: usize, ghr: u128, lhist: u16, taken: bool);
+}
+
+/// Different kinds of direction predictor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirectionPredictorKind {
+    /// Hashed perceptron (see [HashedPerceptron])
+    Perceptron,
+    /// TAgged GEometric history length predictor (see [Tage])
+    Tage,
+}
+impl DirectionPredictorKind {
+    pub fn build(self) -> Box<dyn DirectionPredictor> {
+        match self {
+            Self::Perceptron => Box::new(HashedPerceptron::new()),
+            Self::Tage => Box::new(Tage::new()),
+        }
+    }
+}
+impl std::str::FromStr for DirectionPredictorKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perceptron" => Ok(Self::Perceptron),
+            "tage" => Ok(Self::Tage),
+            _ => Err(format!("unknown direction predictor '{}'", s)),
+        }
+    }
+}
+
+/// A checkpoint of the speculative branch history.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HistoryCheckpoint {
+    /// Global history register
+    pub ghr: u128,
+    /// Path history register
+    pub path: u64,
+    /// Position in the speculative update log
+    pub seq: usize,
+}
+
+/// Number of entries in the local history table.
+pub const LHT_SIZE: usize = 1024;
+
+/// Speculative global/local/path branch history.
+///
+/// The global history register is updated speculatively for every
+/// conditional branch seen by the predictor, and the path history register
+/// is updated with the address of every taken branch. Since the local
+/// history table is too large to checkpoint, each speculative update is
+/// recorded in a log which is used to undo updates on the wrong path.
+pub struct BranchHistory {
+    /// Global history register
+    pub ghr: u128,
+    /// Path history register
+    pub path: u64,
+    /// Local history table
+    pub lht: Vec<u16>,
+    /// Log of speculative updates: (sequence number, index, old value)
+    log: VecDeque<(usize, usize, u16)>,
+    /// Sequence number for the next update
+    seq: usize,
+}
+impl BranchHistory {
+    pub fn new() -> Self {
+        Self {
+            ghr: 0,
+            path: 0,
+            lht: vec![0; LHT_SIZE],
+            log: VecDeque::new(),
+            seq: 0
+        }
+    }
+
+    fn lht_idx(pc: usize) -> usize { (pc ^ (pc >> 10)) % LHT_SIZE }
+
+    /// Return the local history for the branch at 'pc'.
+    pub fn local(&self, pc: usize) -> u16 {
+        self.lht[Self::lht_idx(pc)]
+    }
+
+    pub fn checkpoint(&self) -> HistoryCheckpoint {
+        HistoryCheckpoint { ghr: self.ghr, path: self.path, seq: self.seq }
+    }
+
+    /// Speculatively update history with the outcome of a branch.
+    pub fn push(&mut self, pc: usize, taken: bool) {
+        let idx = Self::lht_idx(pc);
+        self.log.push_back((self.seq, idx, self.lht[idx]));
+        self.lht[idx] = (self.lht[idx] << 1) | taken as u16;
+        self.ghr = (self.ghr << 1) | taken as u128;
+        self.seq += 1;
+    }
+
+    /// Speculatively update path history with the address of a taken 
+    /// branch.
+    pub fn push_path(&mut self, pc: usize) {
+        self.path = (self.path << 4) ^ ((pc >> 1) & 0xff) as u64;
+    }
+
+    /// Undo all speculative updates made after some checkpoint.
+    pub fn restore(&mut self, cp: HistoryCheckpoint) {
+        while let Some(&(seq, idx, old)) = self.log.back() {
+            if seq < cp.seq { break; }
+            self.lht[idx] = old;
+            self.log.pop_back();
+        }
+        self.ghr = cp.ghr;
+        self.path = cp.path;
+        self.seq = cp.seq;
+    }
+
+    /// Release log entries older than some checkpoint, which can no longer 
+    /// be undone (ie. because the associated branches have retired).
+    pub fn commit(&mut self, cp: HistoryCheckpoint) {
+        while let Some(&(seq, _, _)) = self.log.front() {
+            if seq >= cp.seq { break; }
+            self.log.pop_front();
+        }
+    }
+}
+impl Default for BranchHistory {
+    fn default() -> Self { Self::new() }
+}
+
+/// Fold the low 'len' bits of some history into 'bits' bits.
+pub fn fold_history(hist: u128, len: usize, bits: usize) -> usize {
+    let mask = if len >= 128 { u128::MAX } else { (1u128 << len) - 1 };
+    let mut h = hist & mask;
+    let mut res = 0usize;
+    while h != 0 {
+        res ^= (h as usize) & ((1 << bits) - 1);
+        h >>= bits;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Train a predictor on a single branch whose outcome follows some
+    /// pattern, returning the number of mispredictions after warming up.
+    fn mispredictions(kind: DirectionPredictorKind, 
+        pattern: impl Fn(usize) -> bool
+    ) -> usize {
+        let mut p = kind.build();
+        let mut hist = BranchHistory::new();
+        let pc = 0x4010;
+        let mut misses = 0;
+        for i in 0..2000 {
+            let taken = pattern(i);
+            let (ghr, lhist) = (hist.ghr, hist.local(pc));
+            if i >= 1500 && p.predict(pc, ghr, lhist) != taken {
+                misses += 1;
+            }
+            p.update(pc, ghr, lhist, taken);
+            hist.push(pc, taken);
+        }
+        misses
+    }
+
+    #[test]
+    fn learn_patterns() {
+        use DirectionPredictorKind::*;
+        for kind in [Perceptron, Tage] {
+            assert_eq!(mispredictions(kind, |_| true), 0, "{:?}", kind);
+            assert_eq!(mispredictions(kind, |_| false), 0, "{:?}", kind);
+            assert_eq!(mispredictions(kind, |i| i % 2 == 0), 0, "{:?}", kind);
+            assert_eq!(mispredictions(kind, |i| i % 4 != 3), 0, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn restore_history() {
+        let mut hist = BranchHistory::new();
+        hist.push(0x10, true);
+        let cp = hist.checkpoint();
+        hist.push(0x10, false);
+        hist.push(0x20, true);
+        hist.push_path(0x20);
+        hist.restore(cp);
+        assert_eq!(hist.checkpoint(), cp);
+        assert_eq!(hist.ghr, 0b1);
+        assert_eq!(hist.local(0x10), 0b1);
+        assert_eq!(hist.local(0x20), 0);
+    }
+
+    #[test]
+    fn fold() {
+        assert_eq!(fold_history(0b1011_0110, 8, 4), 0b1011 ^ 0b0110);
+        assert_eq!(fold_history(0b1011_0110, 4, 4), 0b0110);
+        assert_eq!(fold_history(u128::MAX, 128, 8), 0);
+    }
+}
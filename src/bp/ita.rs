@@ -0,0 +1,67 @@
+//! Indirect target array.
+
+use crate::bp::*;
+
+/// Number of entries in the indirect target array.
+pub const ITA_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+struct ITAEntry {
+    tag: u16,
+    tgt: usize,
+}
+
+/// Predicts the targets of indirect branches.
+///
+/// The BTB only tracks the last-known target for a branch. Indirect
+/// branches with more than one target (ie. for switch tables or virtual
+/// calls) are predicted with an array indexed by the branch address and 
+/// the path history leading up to the branch.
+pub struct IndirectTargetArray {
+    data: Vec<Option<ITAEntry>>,
+}
+impl IndirectTargetArray {
+    pub fn new() -> Self {
+        Self { data: vec![None; ITA_SIZE] }
+    }
+
+    fn index(pc: usize, path: u64) -> (usize, u16) {
+        let h = fold_history(path as u128, 64, 10);
+        let idx = (pc ^ (pc >> 10) ^ h) % ITA_SIZE;
+        let tag = ((pc >> 2) ^ (h << 3)) as u16;
+        (idx, tag)
+    }
+
+    /// Predict the target of the indirect branch at 'pc'.
+    pub fn predict(&self, pc: usize, path: u64) -> Option<usize> {
+        let (idx, tag) = Self::index(pc, path);
+        self.data[idx].filter(|e| e.tag == tag).map(|e| e.tgt)
+    }
+
+    /// Train with the target of a retired indirect branch.
+    pub fn update(&mut self, pc: usize, path: u64, tgt: usize) {
+        let (idx, tag) = Self::index(pc, path);
+        self.data[idx] = Some(ITAEntry { tag, tgt });
+    }
+}
+impl Default for IndirectTargetArray {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_by_path() {
+        let mut ita = IndirectTargetArray::new();
+        let pc = 0x4000;
+        assert_eq!(ita.predict(pc, 0x12), None);
+        ita.update(pc, 0x12, 0x5000);
+        ita.update(pc, 0x34, 0x6000);
+        assert_eq!(ita.predict(pc, 0x12), Some(0x5000));
+        assert_eq!(ita.predict(pc, 0x34), Some(0x6000));
+        ita.update(pc, 0x12, 0x7000);
+        assert_eq!(ita.predict(pc, 0x12), Some(0x7000));
+    }
+}
@@ -0,0 +1,280 @@
+//! Branch prediction.
+
+pub mod btb;
+pub mod direction;
+pub mod ita;
+pub mod perceptron;
+pub mod ras;
+pub mod tage;
+
+pub use btb::*;
+pub use direction::*;
+pub use ita::*;
+pub use perceptron::*;
+pub use ras::*;
+pub use tage::*;
+
+use crate::front::*;
+use crate::op::*;
+
+/// Abstract representation of the branch prediction unit.
+///
+/// The branch predictor runs ahead of instruction fetch: for each fetch
+/// block, the next-PC logic asks the BPU for the address of the next
+/// fetch block.
+pub struct BranchPredictionUnit {
+    /// Conditional branch direction predictor
+    pub dir: Box<dyn DirectionPredictor>,
+    /// Speculative branch history
+    pub hist: BranchHistory,
+    /// Return address stack
+    pub ras: ReturnAddressStack,
+    /// Indirect target predictor
+    pub ita: IndirectTargetArray,
+}
+impl BranchPredictionUnit {
+    pub fn new(kind: DirectionPredictorKind) -> Self {
+        Self { 
+            dir: kind.build(),
+            hist: BranchHistory::new(),
+            ras: ReturnAddressStack::new(),
+            ita: IndirectTargetArray::new(),
+        }
+    }
+
+    pub fn checkpoint(&self) -> SpecCheckpoint {
+        SpecCheckpoint { 
+            hist: self.hist.checkpoint(), 
+            ras: self.ras.checkpoint(),
+        }
+    }
+
+    pub fn restore(&mut self, cp: SpecCheckpoint) {
+        self.hist.restore(cp.hist);
+        self.ras.restore(cp.ras);
+    }
+
+    /// Predict the extent of the fetch block starting at `pc`.
+    ///
+    /// Returns the FTQ entry for this block, the next program counter, and
+    /// the number of bubbles incurred by this prediction.
+    //
+    // NOTE: How many branches can be predicted per-cycle?
+    pub fn predict(&mut self, btb: &mut BranchTargetBuffer, pc: usize)
+        -> (FTQEntry, usize, usize)
+    {
+        let blk     = pc & !0x1f;
+        let blk_end = blk.wrapping_add(0x20);
+        let mut ent = FTQEntry {
+            addr: blk, start: pc & 0x1f, end: 0x20, 
+            preds: [None; 2], cp: self.checkpoint(), fault: false,
+        };
+
+        // Predict each branch that ends within this fetch block, stopping
+        // at the first predicted-taken branch. Branches which start before 
+        // 'pc' are only on the path if we reached this block sequentially.
+        if let Some((e, lvl)) = btb.lookup(pc) {
+            let mut num_preds = 0;
+            for b in e.brn.iter().flatten() {
+                let brn_end = b.info.addr + b.info.len;
+                let on_path = b.info.addr >= pc || pc == blk;
+                if !on_path || brn_end <= pc || brn_end - blk > 0x20 {
+                    continue;
+                }
+
+                let cp = self.checkpoint();
+                let mut pred = Prediction {
+                    tgt: None, dir: None, cp, next: cp,
+                    lhist: self.hist.local(b.info.addr),
+                };
+                let mut tgt = b.tgt;
+                let taken = match b.info.kind {
+                    BranchKind::ConditionalDirect => {
+                        let taken = self.dir.predict(
+                            b.info.addr, cp.hist.ghr, pred.lhist
+                        );
+                        self.hist.push(b.info.addr, taken);
+                        pred.dir = Some(taken);
+                        taken
+                    },
+                    BranchKind::Call => {
+                        self.ras.push(brn_end);
+                        true
+                    },
+                    // Fall back to the last-known target when the ITA 
+                    // misses
+                    BranchKind::UnconditionalIndirect | 
+                    BranchKind::CallIndirect => {
+                        if let Some(t) = self.ita.predict(
+                            b.info.addr, cp.hist.path
+                        ) {
+                            tgt = t;
+                        }
+                        if b.info.kind == BranchKind::CallIndirect {
+                            self.ras.push(brn_end);
+                        }
+                        true
+                    },
+                    // Fall back to the last-known target when the RAS 
+                    // is empty
+                    BranchKind::Return => {
+                        if let Some(ret) = self.ras.pop() {
+                            tgt = ret;
+                        }
+                        true
+                    },
+                    _ => true,
+                };
+                if taken {
+                    pred.tgt = Some(tgt);
+                    self.hist.push_path(b.info.addr);
+                }
+                pred.next = self.checkpoint();
+                ent.preds[num_preds] = Some((b.info.addr, pred));
+                num_preds += 1;
+
+                if !taken {
+                    trace!("[BPU] {}: predicted not-taken for {:08x} {:?}",
+                        btb.levels[lvl].name, b.info.addr, b.info.kind);
+                    continue;
+                }
+                trace!("[BPU] {}: predicted {:08x} for {:08x} {:?}",
+                    btb.levels[lvl].name, tgt, b.info.addr, b.info.kind);
+                ent.end = brn_end - blk;
+                return (ent, tgt, btb.levels[lvl].bubbles);
+            }
+        }
+        (ent, blk_end, 0)
+    }
+
+    /// Update the predictor with the outcome of a retired branch.
+    pub fn update(&mut self, btb: &mut BranchTargetBuffer,
+                  info: BranchInfo, pred: Prediction, npc: usize)
+    {
+        let taken = npc != info.addr + info.len;
+        if info.kind == BranchKind::ConditionalDirect {
+            self.dir.update(info.addr, pred.cp.hist.ghr, pred.lhist, taken);
+        }
+        if info.kind.is_indirect() {
+            self.ita.update(info.addr, pred.cp.hist.path, npc);
+        }
+        self.hist.commit(pred.next.hist);
+
+        // NOTE: Branches are only allocated in the BTB after they've been 
+        // taken at least once.
+        if taken {
+            btb.update(info, npc);
+        }
+    }
+
+    /// Repair the speculative state of the predictor after some branch 
+    /// was mispredicted.
+    pub fn repair(&mut self, info: BranchInfo, pred: Prediction, npc: usize) {
+        let taken = npc != info.addr + info.len;
+        self.restore(pred.cp);
+        match info.kind {
+            BranchKind::ConditionalDirect => self.hist.push(info.addr, taken),
+            BranchKind::Call | BranchKind::CallIndirect => {
+                self.ras.push(info.addr + info.len)
+            },
+            BranchKind::Return => { self.ras.pop(); },
+            _ => {},
+        }
+        if taken {
+            self.hist.push_path(info.addr);
+        }
+    }
+}
+
+/// A prediction made by the front-end, carried along with a macro-op until
+/// it is resolved in the back-end.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Prediction {
+    /// The predicted target address (or `None` if the front-end continued
+    /// with the next-sequential instruction)
+    pub tgt: Option<usize>,
+    /// The predicted direction (for conditional branches which were seen 
+    /// by the direction predictor)
+    pub dir: Option<bool>,
+    /// Checkpoint of the speculative state before this instruction
+    pub cp: SpecCheckpoint,
+    /// Checkpoint of the speculative state after this instruction
+    pub next: SpecCheckpoint,
+    /// The local history used to predict this branch
+    pub lhist: u16,
+}
+impl Prediction {
+    /// Find the prediction for the instruction at 'addr' within a fetch 
+    /// block, given the predictions made for the block and the checkpoint
+    /// taken at the start of the block.
+    pub fn for_addr(addr: usize, preds: &[Option<(usize, Prediction)>],
+                    cp: SpecCheckpoint) -> Self
+    {
+        let mut res = Self { cp, next: cp, ..Default::default() };
+        for (brn_addr, pred) in preds.iter().flatten() {
+            if *brn_addr == addr {
+                return *pred;
+            }
+            if *brn_addr < addr {
+                res.cp = pred.next;
+                res.next = pred.next;
+            }
+        }
+        res
+    }
+}
+
+/// A checkpoint of all speculative state in the branch predictor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpecCheckpoint {
+    pub hist: HistoryCheckpoint,
+    pub ras: RASCheckpoint,
+}
+
+/// Static information about a branch instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BranchInfo {
+    pub kind: BranchKind,
+    pub len: usize,
+    pub addr: usize,
+}
+impl Default for BranchInfo {
+    fn default() -> Self {
+        Self {
+            kind: BranchKind::None,
+            len: 0,
+            addr: 0,
+        }
+    }
+}
+
+/// Different kinds of x86 branch instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    None,
+    UnconditionalDirect,
+    UnconditionalIndirect,
+    ConditionalDirect,
+    Call,
+    CallIndirect,
+    Return,
+}
+impl BranchKind {
+    /// Returns true for branches predicted with the indirect target array.
+    pub fn is_indirect(&self) -> bool {
+        matches!(self, Self::UnconditionalIndirect | Self::CallIndirect)
+    }
+}
+impl From<MacroOp> for BranchKind {
+    fn from(x: MacroOp) -> Self {
+        match x {
+            MacroOp::JmpI(_) => Self::UnconditionalDirect,
+            MacroOp::Jcc(..) => Self::ConditionalDirect,
+            MacroOp::JmpR(_) | MacroOp::JmpM(_) => Self::UnconditionalIndirect,
+            MacroOp::CallI(_) => Self::Call,
+            MacroOp::CallR(_) | MacroOp::CallM(_) => Self::CallIndirect,
+            MacroOp::Ret => Self::Return,
+            _ => Self::None,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Hashed perceptron direction predictor.
+
+use crate::bp::*;
+
+/// A hashed perceptron predictor.
+///
+/// AMD documents Zen 2 as using a hashed perceptron for conditional branch
+/// direction prediction. Each table of weights is indexed by a hash of the
+/// program counter and a segment of global history. The local history of
+/// the branch is used as an additional feature.
+pub struct HashedPerceptron {
+    /// Tables of weights (indexed by a hash of PC and history segment)
+    tables: Vec<Vec<i8>>,
+    /// Global history segment lengths (in bits) for each table
+    seg: Vec<(usize, usize)>,
+    /// Weights for the local history feature
+    local: Vec<i8>,
+    /// Training threshold
+    theta: i32,
+}
+impl HashedPerceptron {
+    pub const TABLE_BITS: usize = 10;
+
+    pub fn new() -> Self {
+        // Bias table, then tables for increasingly-older history segments
+        let seg = vec![
+            (0, 0), (0, 4), (4, 8), (8, 16), (16, 24),
+            (24, 32), (32, 48), (48, 64), (64, 96),
+        ];
+        let tables = vec![vec![0; 1 << Self::TABLE_BITS]; seg.len()];
+        let theta = (1.93 * (seg.len() + 1) as f64 + 14.0) as i32;
+        Self { tables, seg, local: vec![0; 1 << Self::TABLE_BITS], theta }
+    }
+
+    fn indexes(&self, pc: usize, ghr: u128, lhist: u16) -> Vec<usize> {
+        let mask = (1 << Self::TABLE_BITS) - 1;
+        let mut res: Vec<usize> = self.seg.iter().enumerate()
+            .map(|(i, &(lo, hi))| {
+                let h = fold_history(ghr >> lo, hi - lo, Self::TABLE_BITS);
+                (pc ^ (pc >> Self::TABLE_BITS) ^ h ^ (i << 3)) & mask
+            }).collect();
+        res.push((pc ^ ((lhist as usize) << 2)) & mask);
+        res
+    }
+
+    fn output(&self, idx: &[usize]) -> i32 {
+        let (local_idx, global_idx) = idx.split_last().unwrap();
+        let global: i32 = global_idx.iter().zip(self.tables.iter())
+            .map(|(i, t)| t[*i] as i32).sum();
+        global + self.local[*local_idx] as i32
+    }
+}
+impl Default for HashedPerceptron {
+    fn default() -> Self { Self::new() }
+}
+impl DirectionPredictor for HashedPerceptron {
+    fn name(&self) -> &'static str { "perceptron" }
+
+    fn predict(&self, pc: usize, ghr: u128, lhist: u16) -> bool {
+        self.output(&self.indexes(pc, ghr, lhist)) >= 0
+    }
+
+    fn update(&mut self, pc: usize, ghr: u128, lhist: u16, taken: bool) {
+        let idx = self.indexes(pc, ghr, lhist);
+        let out = self.output(&idx);
+        if (out >= 0) == taken && out.abs() > self.theta {
+            return;
+        }
+        let train = |w: &mut i8| {
+            *w = if taken { w.saturating_add(1) } else { w.saturating_sub(1) };
+        };
+        let (local_idx, global_idx) = idx.split_last().unwrap();
+        for (i, t) in global_idx.iter().zip(self.tables.iter_mut()) {
+            train(&mut t[*i]);
+        }
+        train(&mut self.local[*local_idx]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Return address stack.
+
+/// Number of entries in the return address stack.
+pub const RAS_SIZE: usize = 32;
+
+/// A checkpoint of the return address stack.
+///
+/// NOTE: Only the pointer and the top-of-stack entry are saved. Entries
+/// below the top may still be clobbered by wrong-path calls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RASCheckpoint {
+    /// Index of the top-of-stack entry
+    pub tos: usize,
+    /// Number of valid entries
+    pub len: usize,
+    /// Value of the top-of-stack entry
+    pub top: usize,
+}
+
+/// A circular stack of predicted return addresses.
+///
+/// Return addresses are pushed when a call is predicted, and popped when a
+/// return is predicted. When the stack overflows, the oldest entries are
+/// overwritten.
+pub struct ReturnAddressStack {
+    data: [usize; RAS_SIZE],
+    /// Index of the top-of-stack entry
+    tos: usize,
+    /// Number of valid entries
+    len: usize,
+}
+impl ReturnAddressStack {
+    pub fn new() -> Self {
+        Self { data: [0; RAS_SIZE], tos: 0, len: 0 }
+    }
+
+    pub fn push(&mut self, addr: usize) {
+        self.tos = (self.tos + 1) % RAS_SIZE;
+        self.data[self.tos] = addr;
+        self.len = (self.len + 1).min(RAS_SIZE);
+    }
+
+    /// Pop the predicted return address (or `None` if the stack is empty).
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let res = self.data[self.tos];
+        self.tos = (self.tos + RAS_SIZE - 1) % RAS_SIZE;
+        self.len -= 1;
+        Some(res)
+    }
+
+    pub fn checkpoint(&self) -> RASCheckpoint {
+        RASCheckpoint { tos: self.tos, len: self.len, top: self.data[self.tos] }
+    }
+
+    pub fn restore(&mut self, cp: RASCheckpoint) {
+        self.tos = cp.tos;
+        self.len = cp.len;
+        self.data[self.tos] = cp.top;
+    }
+}
+impl Default for ReturnAddressStack {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let mut ras = ReturnAddressStack::new();
+        assert_eq!(ras.pop(), None);
+        ras.push(0x10);
+        ras.push(0x20);
+        assert_eq!(ras.pop(), Some(0x20));
+        assert_eq!(ras.pop(), Some(0x10));
+        assert_eq!(ras.pop(), None);
+    }
+
+    #[test]
+    fn overflow() {
+        // The oldest entries are overwritten
+        let mut ras = ReturnAddressStack::new();
+        for i in 0..RAS_SIZE + 2 {
+            ras.push(i);
+        }
+        for i in (2..RAS_SIZE + 2).rev() {
+            assert_eq!(ras.pop(), Some(i));
+        }
+        assert_eq!(ras.pop(), None);
+    }
+
+    #[test]
+    fn restore() {
+        let mut ras = ReturnAddressStack::new();
+        ras.push(0x10);
+        ras.push(0x20);
+        let cp = ras.checkpoint();
+
+        // A wrong-path return and call clobber the top entry
+        assert_eq!(ras.pop(), Some(0x20));
+        ras.push(0x30);
+        ras.restore(cp);
+        assert_eq!(ras.pop(), Some(0x20));
+        assert_eq!(ras.pop(), Some(0x10));
+    }
+}
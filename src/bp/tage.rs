@@ -0,0 +1,171 @@
+//! TAGE direction predictor.
+
+use crate::bp::*;
+
+/// An entry in one of the tagged TAGE tables.
+#[derive(Clone, Copy, Debug, Default)]
+struct TageEntry {
+    /// Set when the entry is allocated (so that an empty entry never
+    /// matches a tag of zero)
+    valid: bool,
+    tag: u16,
+    /// Signed 3-bit prediction counter
+    ctr: i8,
+    /// 2-bit "usefulness" counter
+    u: u8,
+}
+
+/// A TAGE (TAgged GEometric history length) predictor.
+pub struct Tage {
+    /// Base predictor (2-bit counters indexed by PC)
+    base: Vec<u8>,
+    /// Tagged tables with increasingly-long history lengths
+    tables: Vec<Vec<TageEntry>>,
+    /// History length for each tagged table
+    hist_len: Vec<usize>,
+    /// Number of updates (used to periodically age the usefulness counters)
+    ticks: usize,
+}
+impl Tage {
+    pub const BASE_BITS: usize = 12;
+    pub const TABLE_BITS: usize = 10;
+    pub const TAG_BITS: usize = 9;
+
+    pub fn new() -> Self {
+        let hist_len = vec![5, 9, 15, 25, 44, 76, 128];
+        let tables = vec![
+            vec![TageEntry::default(); 1 << Self::TABLE_BITS]; hist_len.len()
+        ];
+        Self {
+            base: vec![2; 1 << Self::BASE_BITS],
+            tables, hist_len, ticks: 0
+        }
+    }
+
+    fn base_idx(&self, pc: usize) -> usize {
+        pc & ((1 << Self::BASE_BITS) - 1)
+    }
+    fn index(&self, t: usize, pc: usize, ghr: u128) -> usize {
+        let h = fold_history(ghr, self.hist_len[t], Self::TABLE_BITS);
+        (pc ^ (pc >> Self::TABLE_BITS) ^ h) & ((1 << Self::TABLE_BITS) - 1)
+    }
+    fn tag(&self, t: usize, pc: usize, ghr: u128) -> u16 {
+        let h1 = fold_history(ghr, self.hist_len[t], Self::TAG_BITS);
+        let h2 = fold_history(ghr, self.hist_len[t], Self::TAG_BITS - 1);
+        ((pc ^ h1 ^ (h2 << 1)) & ((1 << Self::TAG_BITS) - 1)) as u16
+    }
+
+    /// Find the provider (longest matching table) and the alternate
+    /// provider for some branch.
+    fn lookup(&self, pc: usize, ghr: u128) -> (Option<usize>, Option<usize>) {
+        let mut hits = (0..self.tables.len()).rev().filter(|&t| {
+            let e = &self.tables[t][self.index(t, pc, ghr)];
+            e.valid && e.tag == self.tag(t, pc, ghr)
+        });
+        (hits.next(), hits.next())
+    }
+
+    fn table_pred(&self, t: Option<usize>, pc: usize, ghr: u128) -> bool {
+        match t {
+            Some(t) => self.tables[t][self.index(t, pc, ghr)].ctr >= 0,
+            None => self.base[self.base_idx(pc)] >= 2,
+        }
+    }
+}
+impl Default for Tage {
+    fn default() -> Self { Self::new() }
+}
+impl DirectionPredictor for Tage {
+    fn name(&self) -> &'static str { "tage" }
+
+    fn predict(&self, pc: usize, ghr: u128, _lhist: u16) -> bool {
+        let (provider, _) = self.lookup(pc, ghr);
+        self.table_pred(provider, pc, ghr)
+    }
+
+    fn update(&mut self, pc: usize, ghr: u128, _lhist: u16, taken: bool) {
+        let (provider, alt) = self.lookup(pc, ghr);
+        let pred = self.table_pred(provider, pc, ghr);
+        let alt_pred = self.table_pred(alt, pc, ghr);
+
+        // Update the provider
+        match provider {
+            Some(t) => {
+                let idx = self.index(t, pc, ghr);
+                let e = &mut self.tables[t][idx];
+                e.ctr = if taken { 
+                    (e.ctr + 1).min(3) 
+                } else { 
+                    (e.ctr - 1).max(-4) 
+                };
+                if pred != alt_pred {
+                    e.u = if pred == taken { 
+                        (e.u + 1).min(3) 
+                    } else { 
+                        e.u.saturating_sub(1) 
+                    };
+                }
+            },
+            None => {
+                let idx = self.base_idx(pc);
+                let c = &mut self.base[idx];
+                *c = if taken { (*c + 1).min(3) } else { c.saturating_sub(1) };
+            },
+        }
+
+        // Allocate an entry in a table with longer history on mispredictions
+        if pred != taken {
+            let start = provider.map_or(0, |t| t + 1);
+            let mut allocated = false;
+            for t in start..self.tables.len() {
+                let idx = self.index(t, pc, ghr);
+                let tag = self.tag(t, pc, ghr);
+                let e = &mut self.tables[t][idx];
+                if e.u == 0 {
+                    let ctr = if taken { 0 } else { -1 };
+                    *e = TageEntry { valid: true, tag, ctr, u: 0 };
+                    allocated = true;
+                    break;
+                }
+            }
+            if !allocated {
+                for t in start..self.tables.len() {
+                    let idx = self.index(t, pc, ghr);
+                    let e = &mut self.tables[t][idx];
+                    e.u = e.u.saturating_sub(1);
+                }
+            }
+        }
+
+        // Periodically age all of the usefulness counters
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(1 << 18) {
+            for e in self.tables.iter_mut().flatten() {
+                e.u >>= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_entries_never_match() {
+        // The tag for every table is zero here
+        let t = Tage::new();
+        assert!((0..t.tables.len()).all(|i| t.tag(i, 0, 0) == 0));
+        assert_eq!(t.lookup(0, 0), (None, None));
+    }
+
+    #[test]
+    fn allocate_on_misprediction() {
+        let mut t = Tage::new();
+        let (pc, ghr) = (0x1234, 0x5a5a);
+        assert!(t.predict(pc, ghr, 0));
+        t.update(pc, ghr, 0, false);
+        assert_eq!(t.lookup(pc, ghr).0, Some(0));
+        assert!(!t.predict(pc, ghr, 0));
+    }
+}
@@ -0,0 +1,622 @@
+//! Set-associative cache models.
+//!
+//! NOTE: Caches only track tags for timing. Data is always read from and
+//! written to [crate::mem] directly.
+
+use std::io::{ self, Write };
+use serde::{ Serialize, Deserialize };
+
+/// Replacement policy used to select a victim within a set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplacementPolicy {
+    /// Least-recently used
+    Lru,
+    /// Tree-based pseudo-LRU (the number of ways must be a power of two)
+    TreePlru,
+}
+
+/// Parameters for a cache.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Capacity in bytes
+    pub size: usize,
+    /// Number of ways in each set
+    pub ways: usize,
+    /// Size of a line in bytes
+    pub line_size: usize,
+    /// Number of cycles for a hit (in addition to the latency of the
+    /// levels above this one)
+    pub hit_latency: usize,
+    /// Number of banks (interleaved at 'bank_width' bytes within a line)
+    pub num_banks: usize,
+    /// Width of a bank in bytes
+    pub bank_width: usize,
+    /// Number of miss status holding registers
+    pub num_mshrs: usize,
+    pub policy: ReplacementPolicy,
+}
+
+/// Zen 2 L1 data cache (32 KiB, 8-way, 64-byte lines).
+pub const L1D_CONFIG: CacheConfig = CacheConfig {
+    size: 32 * 1024,
+    ways: 8,
+    line_size: 64,
+    hit_latency: 4,
+    num_banks: 8,
+    bank_width: 8,
+    num_mshrs: 22,
+    policy: ReplacementPolicy::TreePlru,
+};
+
+/// Zen 2 L1 instruction cache (32 KiB, 8-way, 64-byte lines).
+pub const L1I_CONFIG: CacheConfig = CacheConfig {
+    size: 32 * 1024,
+    ways: 8,
+    line_size: 64,
+    hit_latency: 1,
+    num_banks: 1,
+    bank_width: 64,
+    num_mshrs: 8,
+    policy: ReplacementPolicy::TreePlru,
+};
+
+/// Zen 2 L2 cache (512 KiB, 8-way, 64-byte lines, private to each core).
+pub const L2_CONFIG: CacheConfig = CacheConfig {
+    size: 512 * 1024,
+    ways: 8,
+    line_size: 64,
+    hit_latency: 8,
+    num_banks: 1,
+    bank_width: 64,
+    num_mshrs: 32,
+    policy: ReplacementPolicy::TreePlru,
+};
+
+/// Zen 2 L3 cache (16 MiB, 16-way, 64-byte lines, shared by a CCX).
+pub const L3_CONFIG: CacheConfig = CacheConfig {
+    size: 16 * 1024 * 1024,
+    ways: 16,
+    line_size: 64,
+    hit_latency: 27,
+    num_banks: 1,
+    bank_width: 64,
+    num_mshrs: 64,
+    policy: ReplacementPolicy::Lru,
+};
+
+/// Result of looking up an address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// The data is available on some cycle (either the line is present,
+    /// or it's already being filled)
+    Ready(usize),
+    /// The line must be requested from the next level (see
+    /// [Cache::start_fill])
+    Miss,
+}
+
+/// Reasons why an access cannot be performed on this cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheErr {
+    /// Another access on this cycle is using the same bank
+    BankConflict,
+    /// All miss status holding registers are in use
+    MSHRFull,
+}
+
+/// A miss status holding register, tracking an outstanding line fill.
+#[derive(Clone, Copy, Debug)]
+pub struct MSHR {
+    /// Address of the line being filled
+    pub line: usize,
+    /// The cycle when the line is filled
+    pub done: usize,
+    /// This fill was started by a prefetch (and no demand access has 
+    /// merged with it yet)
+    pub prefetch: bool,
+    /// The line is dirty once it's filled
+    pub dirty: bool,
+}
+
+/// A line which was evicted from a cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Victim {
+    /// Address of the line
+    pub line: usize,
+    /// The line must be written back to the level below
+    pub dirty: bool,
+}
+
+/// Counters for a cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// Demand accesses which hit
+    pub hits: usize,
+    /// Demand accesses which missed
+    pub misses: usize,
+    /// Prefetch requests from the level above which hit
+    pub pf_hits: usize,
+    /// Prefetch requests from the level above which missed
+    pub pf_misses: usize,
+    /// Misses to a line which already has an outstanding fill
+    pub mshr_hits: usize,
+    pub evictions: usize,
+    /// Dirty lines written back to the level below after being evicted
+    pub writebacks: usize,
+    pub bank_conflicts: usize,
+    pub mshr_full: usize,
+    /// Lines installed
+    pub fills: usize,
+    /// Lines removed by the level below (to maintain inclusion), or moved
+    /// to another level
+    pub invalidations: usize,
+    /// Fills started by a prefetch
+    pub prefetches: usize,
+    /// Prefetched lines which were used by a demand access
+    pub pf_useful: usize,
+    /// Demand accesses which merged with an outstanding prefetch
+    pub pf_late: usize,
+    /// Prefetched lines which were removed without being used
+    pub pf_useless: usize,
+}
+
+/// A single set of lines.
+#[derive(Clone, Debug)]
+struct CacheSet {
+    /// The tag for each way (or `None` if the way is invalid)
+    tags: Vec<Option<usize>>,
+    /// The cycle when each way was last used (for LRU)
+    used: Vec<usize>,
+    /// Tree bits (for pseudo-LRU)
+    plru: usize,
+    /// Each way was filled by a prefetch and hasn't been used yet
+    pf: Vec<bool>,
+    /// Each way was written since it was filled
+    dirty: Vec<bool>,
+}
+impl CacheSet {
+    fn new(ways: usize) -> Self {
+        Self { 
+            tags: vec![None; ways], 
+            used: vec![0; ways], 
+            plru: 0, 
+            pf: vec![false; ways],
+            dirty: vec![false; ways],
+        }
+    }
+
+    fn find(&self, tag: usize) -> Option<usize> {
+        self.tags.iter().position(|t| *t == Some(tag))
+    }
+
+    /// Update the replacement state after an access to some way.
+    fn touch(&mut self, policy: ReplacementPolicy, way: usize, clk: usize) {
+        match policy {
+            ReplacementPolicy::Lru => self.used[way] = clk,
+            // Point each node on the path away from this way
+            ReplacementPolicy::TreePlru => {
+                let ways = self.tags.len();
+                let mut node = 1;
+                let mut span = ways / 2;
+                while span > 0 {
+                    let right = way & span != 0;
+                    if right {
+                        self.plru &= !(1 << node);
+                    } else {
+                        self.plru |= 1 << node;
+                    }
+                    node = node * 2 + right as usize;
+                    span /= 2;
+                }
+            },
+        }
+    }
+
+    /// Select a victim, preferring invalid ways.
+    fn victim(&self, policy: ReplacementPolicy) -> usize {
+        if let Some(way) = self.tags.iter().position(|t| t.is_none()) {
+            return way;
+        }
+        match policy {
+            ReplacementPolicy::Lru => {
+                (0..self.tags.len()).min_by_key(|w| self.used[*w]).unwrap()
+            },
+            // Follow the tree bits towards the pseudo-LRU way
+            ReplacementPolicy::TreePlru => {
+                let ways = self.tags.len();
+                let mut node = 1;
+                let mut way = 0;
+                let mut span = ways / 2;
+                while span > 0 {
+                    let right = self.plru & (1 << node) != 0;
+                    if right { way |= span; }
+                    node = node * 2 + right as usize;
+                    span /= 2;
+                }
+                way
+            },
+        }
+    }
+}
+
+/// A non-blocking set-associative cache.
+pub struct Cache {
+    pub name: &'static str,
+    pub cfg: CacheConfig,
+    sets: Vec<CacheSet>,
+    /// Outstanding misses
+    pub mshrs: Vec<MSHR>,
+    /// The line accessed in each bank (and the cycle of the access)
+    banks: Vec<Option<(usize, usize)>>,
+    /// The current cycle (updated by [Cache::cycle])
+    clk: usize,
+    pub stats: CacheStats,
+}
+impl Cache {
+    pub fn new(name: &'static str, cfg: CacheConfig) -> Self {
+        let num_sets = cfg.size / (cfg.ways * cfg.line_size);
+        assert!(num_sets.is_power_of_two());
+        if cfg.policy == ReplacementPolicy::TreePlru {
+            assert!(cfg.ways.is_power_of_two());
+        }
+        Self {
+            name,
+            cfg,
+            sets: vec![CacheSet::new(cfg.ways); num_sets],
+            mshrs: Vec::new(),
+            banks: vec![None; cfg.num_banks],
+            clk: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn num_sets(&self) -> usize { self.sets.len() }
+
+    /// Return the address of the line containing some address.
+    pub fn line_addr(&self, addr: usize) -> usize {
+        addr & !(self.cfg.line_size - 1)
+    }
+
+    /// Return the set index and tag for some address.
+    fn index(&self, addr: usize) -> (usize, usize) {
+        let line = addr / self.cfg.line_size;
+        (line % self.sets.len(), line / self.sets.len())
+    }
+
+    fn bank(&self, addr: usize) -> usize {
+        (addr / self.cfg.bank_width) % self.cfg.num_banks
+    }
+
+    /// Returns true if the line containing some address is present.
+    pub fn probe(&self, addr: usize) -> bool {
+        let (set, tag) = self.index(addr);
+        self.sets[set].find(tag).is_some()
+    }
+
+    /// Return the cycle when an outstanding fill for the line containing
+    /// some address completes (if any).
+    pub fn pending(&self, addr: usize) -> Option<usize> {
+        let line = self.line_addr(addr);
+        self.mshrs.iter().find(|m| m.line == line).map(|m| m.done)
+    }
+
+    pub fn mshr_free(&self) -> bool { self.mshrs.len() < self.cfg.num_mshrs }
+
+    /// Complete any line fills which have finished on this cycle, returning
+    /// any lines that were evicted.
+    pub fn cycle(&mut self, clk: usize) -> Vec<Victim> {
+        self.clk = clk;
+        let (done, pending): (Vec<MSHR>, Vec<MSHR>) = self.mshrs.iter()
+            .partition(|m| m.done <= clk);
+        self.mshrs = pending;
+        let mut victims = Vec::new();
+        for m in done.iter() {
+            trace!("[{}] Filled line {:08x}", self.name, m.line);
+            victims.extend(self.install(m.line, m.prefetch, m.dirty));
+        }
+        victims
+    }
+
+    /// Install the line containing some address (or mark it dirty if it's
+    /// already present), returning the line that was evicted (if any).
+    pub fn fill(&mut self, addr: usize, dirty: bool) -> Option<Victim> {
+        self.install(addr, false, dirty)
+    }
+
+    fn install(&mut self, addr: usize, prefetch: bool, dirty: bool) 
+        -> Option<Victim> 
+    {
+        let (set, tag) = self.index(addr);
+        let num_sets = self.sets.len();
+        let line_size = self.cfg.line_size;
+        let policy = self.cfg.policy;
+        let clk = self.clk;
+        let s = &mut self.sets[set];
+        if let Some(way) = s.find(tag) {
+            s.dirty[way] |= dirty;
+            s.touch(policy, way, clk);
+            return None;
+        }
+        let way = s.victim(policy);
+        let victim = s.tags[way].map(|t| Victim {
+            line: (t * num_sets + set) * line_size,
+            dirty: s.dirty[way],
+        });
+        if let Some(v) = victim {
+            self.stats.evictions += 1;
+            if v.dirty {
+                trace!("[{}] Write back line {:08x}", self.name, v.line);
+                self.stats.writebacks += 1;
+            }
+            if s.pf[way] {
+                self.stats.pf_useless += 1;
+            }
+        }
+        s.tags[way] = Some(tag);
+        s.pf[way] = prefetch;
+        s.dirty[way] = dirty;
+        s.touch(policy, way, clk);
+        self.stats.fills += 1;
+        victim
+    }
+
+    /// Update the replacement state and prefetch counters after a demand
+    /// access hits on some way.
+    fn use_way(&mut self, set: usize, way: usize) {
+        let policy = self.cfg.policy;
+        let clk = self.clk;
+        let s = &mut self.sets[set];
+        s.touch(policy, way, clk);
+        if s.pf[way] {
+            s.pf[way] = false;
+            self.stats.pf_useful += 1;
+        }
+    }
+
+    /// Merge a demand access with an outstanding fill to the line 
+    /// containing some address, returning the cycle when it completes.
+    pub fn merge(&mut self, addr: usize) -> Option<usize> {
+        let line = self.line_addr(addr);
+        let m = self.mshrs.iter_mut().find(|m| m.line == line)?;
+        if m.prefetch {
+            m.prefetch = false;
+            self.stats.pf_late += 1;
+        }
+        self.stats.mshr_hits += 1;
+        Some(m.done)
+    }
+
+    /// Remove the line containing some address, returning whether it was
+    /// dirty (if it was present). 
+    ///
+    /// NOTE: The caller is responsible for moving a dirty line elsewhere.
+    pub fn invalidate(&mut self, addr: usize) -> Option<bool> {
+        let (set, tag) = self.index(addr);
+        let s = &mut self.sets[set];
+        let way = s.find(tag)?;
+        s.tags[way] = None;
+        if s.pf[way] {
+            s.pf[way] = false;
+            self.stats.pf_useless += 1;
+        }
+        self.stats.invalidations += 1;
+        Some(std::mem::take(&mut s.dirty[way]))
+    }
+
+    /// Mark the line containing some address as dirty (or mark an 
+    /// outstanding fill, so that the line is dirty when it's installed).
+    pub fn set_dirty(&mut self, addr: usize) {
+        let (set, tag) = self.index(addr);
+        let line = self.line_addr(addr);
+        let s = &mut self.sets[set];
+        if let Some(way) = s.find(tag) {
+            s.dirty[way] = true;
+            return;
+        }
+        if let Some(m) = self.mshrs.iter_mut().find(|m| m.line == line) {
+            m.dirty = true;
+        }
+    }
+
+    /// Look up the line containing some address without any bank or MSHR
+    /// constraints (ie. for requests from the level above). Hits update 
+    /// the replacement state.
+    pub fn lookup(&mut self, addr: usize, demand: bool) -> bool {
+        let (set, tag) = self.index(addr);
+        let hit = self.sets[set].find(tag);
+        if let Some(way) = hit {
+            self.use_way(set, way);
+        }
+        match (demand, hit.is_some()) {
+            (true, true) => self.stats.hits += 1,
+            (true, false) => self.stats.misses += 1,
+            (false, true) => self.stats.pf_hits += 1,
+            (false, false) => self.stats.pf_misses += 1,
+        }
+        hit.is_some()
+    }
+
+    /// Start filling the line containing some address, completing on 
+    /// some cycle. The caller must check for a free MSHR.
+    pub fn start_fill(&mut self, addr: usize, done: usize, prefetch: bool) {
+        let line = self.line_addr(addr);
+        assert!(self.mshr_free());
+        trace!("[{}] {} line {:08x} on cycle {}", self.name, 
+            if prefetch { "Prefetch" } else { "Fill" }, line, done);
+        self.mshrs.push(MSHR { line, done, prefetch, dirty: false });
+        if prefetch {
+            self.stats.prefetches += 1;
+        }
+    }
+
+    /// Access some address.
+    ///
+    /// Misses merge with an outstanding fill to the same line. Otherwise,
+    /// the caller is responsible for requesting the line from the next 
+    /// level and calling [Cache::start_fill] (an MSHR is guaranteed to be
+    /// available).
+    pub fn access(&mut self, addr: usize) -> Result<Lookup, CacheErr> {
+        let line = self.line_addr(addr);
+
+        // Only one line can be accessed in each bank per cycle
+        let bank = self.bank(addr);
+        if let Some((l, cyc)) = self.banks[bank] {
+            if cyc == self.clk && l != line {
+                self.stats.bank_conflicts += 1;
+                return Err(CacheErr::BankConflict);
+            }
+        }
+
+        let (set, tag) = self.index(addr);
+        if let Some(way) = self.sets[set].find(tag) {
+            self.use_way(set, way);
+            self.banks[bank] = Some((line, self.clk));
+            self.stats.hits += 1;
+            return Ok(Lookup::Ready(self.clk + self.cfg.hit_latency));
+        }
+
+        if let Some(done) = self.merge(line) {
+            return Ok(Lookup::Ready(done));
+        }
+        if !self.mshr_free() {
+            self.stats.mshr_full += 1;
+            return Err(CacheErr::MSHRFull);
+        }
+        trace!("[{}] Miss on line {:08x}", self.name, line);
+        self.banks[bank] = Some((line, self.clk));
+        self.stats.misses += 1;
+        Ok(Lookup::Miss)
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        let s = &self.stats;
+        writeln!(out, "[{}] hits={} misses={} mshr_hits={} fills={} \
+            evictions={} writebacks={} invalidations={} bank_conflicts={} \
+            mshr_full={}", 
+            self.name, s.hits, s.misses, s.mshr_hits, s.fills, 
+            s.evictions, s.writebacks, s.invalidations, s.bank_conflicts, 
+            s.mshr_full)?;
+        writeln!(out, "[{}] pf_hits={} pf_misses={} prefetches={} useful={} \
+            late={} useless={}", self.name, s.pf_hits, s.pf_misses, 
+            s.prefetches, s.pf_useful, s.pf_late, s.pf_useless)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache with a single set of four 64-byte lines.
+    fn cache(policy: ReplacementPolicy) -> Cache {
+        Cache::new("TEST", CacheConfig {
+            size: 256, ways: 4, num_banks: 2, num_mshrs: 2, policy,
+            ..L1D_CONFIG
+        })
+    }
+
+    /// Fill some lines on consecutive cycles.
+    fn fill(c: &mut Cache, lines: &[usize]) {
+        for l in lines {
+            c.cycle(c.clk + 1);
+            c.fill(*l, false);
+        }
+    }
+
+    #[test]
+    fn lru() {
+        let mut c = cache(ReplacementPolicy::Lru);
+        fill(&mut c, &[0x000, 0x040, 0x080, 0x0c0]);
+        assert_eq!(c.stats.evictions, 0);
+        c.cycle(c.clk + 1);
+        assert!(c.access(0x000).is_ok());
+        fill(&mut c, &[0x100]);
+        assert!(!c.probe(0x040) && c.probe(0x000));
+        fill(&mut c, &[0x140]);
+        assert!(!c.probe(0x080));
+        assert_eq!(c.stats.evictions, 2);
+    }
+
+    #[test]
+    fn tree_plru() {
+        let mut c = cache(ReplacementPolicy::TreePlru);
+        fill(&mut c, &[0x000, 0x040, 0x080, 0x0c0]);
+        assert!(c.access(0x000).is_ok());
+
+        // The tree points away from the most recent accesses (to line 0 
+        // and line 3)
+        fill(&mut c, &[0x100]);
+        assert!(!c.probe(0x080));
+        fill(&mut c, &[0x140]);
+        assert!(!c.probe(0x040));
+        assert!(c.probe(0x000) && c.probe(0x0c0));
+    }
+
+    #[test]
+    fn mshr_merge() {
+        let mut c = cache(ReplacementPolicy::Lru);
+        assert_eq!(c.access(0x1000), Ok(Lookup::Miss));
+        let done = c.clk + 10;
+        c.start_fill(0x1000, done, false);
+        assert_eq!(c.access(0x1020), Ok(Lookup::Ready(done)));
+        assert_eq!(c.stats.mshr_hits, 1);
+        assert_eq!(c.pending(0x1030), Some(done));
+
+        while c.clk < done { c.cycle(c.clk + 1); }
+        assert!(c.probe(0x1000));
+        assert!(c.mshrs.is_empty());
+        c.cycle(c.clk + 1);
+        assert!(matches!(c.access(0x1000), Ok(Lookup::Ready(_))));
+        assert_eq!(c.stats.hits, 1);
+    }
+
+    #[test]
+    fn mshr_full() {
+        let mut c = cache(ReplacementPolicy::Lru);
+        for addr in [0x1000, 0x2000] {
+            c.cycle(c.clk + 1);
+            assert_eq!(c.access(addr), Ok(Lookup::Miss));
+            c.start_fill(addr, usize::MAX, false);
+        }
+        c.cycle(c.clk + 1);
+        assert_eq!(c.access(0x3000), Err(CacheErr::MSHRFull));
+        assert_eq!(c.stats.mshr_full, 1);
+    }
+
+    #[test]
+    fn bank_conflict() {
+        let mut c = cache(ReplacementPolicy::Lru);
+        fill(&mut c, &[0x000, 0x040]);
+        assert!(c.access(0x000).is_ok());
+
+        // Same bank, different line
+        assert_eq!(c.access(0x040), Err(CacheErr::BankConflict));
+        // Same line, or a different bank
+        assert!(c.access(0x010).is_ok());
+        assert!(c.access(0x048).is_ok());
+        assert_eq!(c.stats.bank_conflicts, 1);
+
+        c.cycle(c.clk + 1);
+        assert!(c.access(0x040).is_ok());
+    }
+
+    #[test]
+    fn dirty_victims() {
+        let mut c = cache(ReplacementPolicy::Lru);
+        fill(&mut c, &[0x000, 0x040, 0x080, 0x0c0]);
+        c.set_dirty(0x000);
+
+        // Fills which are marked dirty before they complete
+        c.start_fill(0x100, c.clk + 1, false);
+        c.set_dirty(0x100);
+        assert_eq!(c.cycle(c.clk + 1), vec![Victim { line: 0, dirty: true }]);
+        c.cycle(c.clk + 1);
+        assert_eq!(c.fill(0x140, false), 
+            Some(Victim { line: 0x040, dirty: false }));
+        assert_eq!(c.stats.writebacks, 1);
+        assert_eq!(c.invalidate(0x100), Some(true));
+        assert_eq!(c.invalidate(0x100), None);
+    }
+}
@@ -0,0 +1,457 @@
+//! Microarchitecture parameters.
+//!
+//! A [Config] can be loaded from a TOML or JSON file. Missing fields take
+//! their values from one of the presets (Zen 2, unless the file selects
+//! another one), so a file only needs to list the parameters being changed.
+//! For example:
+//!
+//! ```toml
+//! preset = "zen3"
+//!
+//! [backend]
+//! rob_size = 320
+//!
+//! [caches.l3]
+//! size = 67108864
+//! ```
+
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+
+use crate::bp::*;
+use crate::cache::*;
+use crate::dram::*;
+use crate::hier::*;
+
+/// Parameters for the front-end.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrontendConfig {
+    pub direction_predictor: DirectionPredictorKind,
+    /// Number of entries in the fetch target queue
+    pub ftq_size: usize,
+    /// Number of entries in the instruction byte queue
+    pub ibq_size: usize,
+    /// Number of entries in the macro-op queue
+    pub opq_size: usize,
+    /// Number of cycles that the next-PC logic is stalled after being
+    /// redirected by the back-end
+    pub redirect_penalty: usize,
+}
+impl Default for FrontendConfig {
+    fn default() -> Self {
+        Self {
+            direction_predictor: DirectionPredictorKind::Perceptron,
+            ftq_size: 8,
+            ibq_size: 20,
+            opq_size: 32,
+            redirect_penalty: 8,
+        }
+    }
+}
+
+/// Parameters for the op cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpCacheConfig {
+    pub sets: usize,
+    pub ways: usize,
+    /// Maximum number of macro-ops in a single entry
+    pub entry_ops: usize,
+    /// Maximum number of branches in a single entry
+    pub entry_branches: usize,
+    /// Maximum number of macro-ops delivered to the OPQ per cycle
+    pub width: usize,
+    /// Number of cycles lost when switching between the decode pipeline
+    /// and the op cache pipeline
+    pub switch_penalty: usize,
+}
+impl Default for OpCacheConfig {
+    fn default() -> Self {
+        Self {
+            sets: 64,
+            ways: 8,
+            entry_ops: 8,
+            entry_branches: 2,
+            width: 8,
+            switch_penalty: 2,
+        }
+    }
+}
+
+/// Parameters for dispatch, scheduling, execution and retirement.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendConfig {
+    /// Number of macro-ops dispatched per cycle
+    pub dispatch_width: usize,
+    /// Number of micro-ops retired per cycle
+    pub retire_width: usize,
+    /// Number of entries in the reorder buffer
+    pub rob_size: usize,
+    /// Number of physical registers
+    pub prf_size: usize,
+    pub num_alus: usize,
+    pub num_agus: usize,
+    /// Number of ALU schedulers
+    pub num_alu_scheds: usize,
+    /// Number of entries in each ALU scheduler
+    pub alu_sched_size: usize,
+    /// Number of entries in the AGU scheduler
+    pub agu_sched_size: usize,
+}
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            dispatch_width: 6,
+            retire_width: 8,
+            rob_size: 224,
+            prf_size: 180,
+            num_alus: 4,
+            num_agus: 3,
+            num_alu_scheds: 4,
+            alu_sched_size: 16,
+            agu_sched_size: 28,
+        }
+    }
+}
+
+/// Parameters for the load/store queue.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LsqConfig {
+    /// Number of entries in the load queue
+    pub lq_size: usize,
+    /// Number of entries in the store queue
+    pub sq_size: usize,
+    /// Number of loads which can be executed per cycle
+    pub loads_per_cycle: usize,
+    /// Number of store addresses which can be translated per cycle
+    pub stores_per_cycle: usize,
+    /// Number of cycles between a load executing and its result being
+    /// available when data is forwarded from a store
+    pub fwd_latency: usize,
+}
+impl Default for LsqConfig {
+    fn default() -> Self {
+        Self {
+            lq_size: 44,
+            sq_size: 48,
+            loads_per_cycle: 2,
+            stores_per_cycle: 1,
+            fwd_latency: 4,
+        }
+    }
+}
+
+/// Parameters for the cache hierarchy.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachesConfig {
+    pub inclusion: InclusionPolicy,
+    pub l1i: CacheConfig,
+    pub l1d: CacheConfig,
+    pub l2: CacheConfig,
+    pub l3: CacheConfig,
+    pub dram: DramConfig,
+}
+impl Default for CachesConfig {
+    fn default() -> Self {
+        Self {
+            inclusion: InclusionPolicy::Inclusive,
+            l1i: L1I_CONFIG,
+            l1d: L1D_CONFIG,
+            l2: L2_CONFIG,
+            l3: L3_CONFIG,
+            dram: DRAM_CONFIG,
+        }
+    }
+}
+
+/// Parameters for the TLBs and the page walker.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlbConfig {
+    pub l1_itlb_size: usize,
+    pub l2_itlb_size: usize,
+    pub l1_dtlb_size: usize,
+    pub l2_dtlb_size: usize,
+    /// Number of cycles for a hit in the L2 TLBs
+    pub l2_latency: usize,
+    /// Number of page table walks which may be in-flight at once
+    pub max_walks: usize,
+    /// Number of cycles for each memory access made by the page walker
+    pub walk_step_latency: usize,
+    /// Number of entries in the page walk cache
+    pub pwc_size: usize,
+}
+impl Default for TlbConfig {
+    fn default() -> Self {
+        Self {
+            l1_itlb_size: 64,
+            l2_itlb_size: 512,
+            l1_dtlb_size: 64,
+            l2_dtlb_size: 2048,
+            l2_latency: 7,
+            max_walks: 2,
+            walk_step_latency: 12,
+            pwc_size: 32,
+        }
+    }
+}
+
+/// Parameters for a whole core. The default is Zen 2.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub frontend: FrontendConfig,
+    pub opcache: OpCacheConfig,
+    pub backend: BackendConfig,
+    pub lsq: LsqConfig,
+    pub caches: CachesConfig,
+    pub tlb: TlbConfig,
+}
+impl Config {
+    /// Names of the built-in presets.
+    pub const PRESETS: [&'static str; 2] = ["zen2", "zen3"];
+
+    pub fn zen2() -> Self {
+        Self::default()
+    }
+
+    /// An approximation of Zen 3 (larger ROB and schedulers, a wider
+    /// load/store pipeline, a unified 32 MiB L3, and more page walkers).
+    pub fn zen3() -> Self {
+        let mut cfg = Self::zen2();
+        cfg.backend.rob_size = 256;
+        cfg.backend.prf_size = 192;
+        cfg.backend.alu_sched_size = 24;
+        cfg.backend.agu_sched_size = 32;
+        cfg.lsq.lq_size = 72;
+        cfg.lsq.sq_size = 64;
+        cfg.lsq.loads_per_cycle = 3;
+        cfg.lsq.stores_per_cycle = 2;
+        cfg.caches.l3.size = 32 * 1024 * 1024;
+        cfg.caches.l3.hit_latency = 34;
+        cfg.tlb.max_walks = 6;
+        cfg
+    }
+
+    /// Return one of the built-in presets.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "zen2" => Some(Self::zen2()),
+            "zen3" => Some(Self::zen3()),
+            _ => None,
+        }
+    }
+
+    /// Load a configuration from a TOML file (or a JSON file, if the name
+    /// ends with '.json').
+    pub fn load(path: &str) -> Result<Self, String> {
+        let err = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+        let s = std::fs::read_to_string(path).map_err(|e| err(&e))?;
+        let mut over: Value = if path.ends_with(".json") {
+            serde_json::from_str(&s).map_err(|e| err(&e))?
+        } else {
+            toml::from_str(&s).map_err(|e| err(&e))?
+        };
+
+        // Fill in everything else from the selected preset
+        let preset = over.as_object_mut().and_then(|o| o.remove("preset"));
+        let preset = match preset {
+            Some(Value::String(name)) => name,
+            Some(v) => return Err(err(&format!("invalid preset {}", v))),
+            None => "zen2".to_string(),
+        };
+        let base = Self::preset(&preset)
+            .ok_or_else(|| err(&format!("unknown preset '{}'", preset)))?;
+        let mut res = serde_json::to_value(base).unwrap();
+        merge(&mut res, over);
+        let cfg: Self = serde_json::from_value(res).map_err(|e| err(&e))?;
+        cfg.validate().map_err(|e| err(&e))?;
+        Ok(cfg)
+    }
+
+    /// Check that the parameters can be simulated, returning an error which
+    /// names the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        let (fe, oc, be) = (&self.frontend, &self.opcache, &self.backend);
+        let (lsq, c, tlb) = (&self.lsq, &self.caches, &self.tlb);
+        let nonzero = [
+            ("frontend.ftq_size", fe.ftq_size),
+            ("frontend.opq_size", fe.opq_size),
+            ("opcache.sets", oc.sets),
+            ("opcache.ways", oc.ways),
+            ("opcache.entry_ops", oc.entry_ops),
+            ("opcache.entry_branches", oc.entry_branches),
+            ("opcache.width", oc.width),
+            ("backend.dispatch_width", be.dispatch_width),
+            ("backend.retire_width", be.retire_width),
+            ("backend.rob_size", be.rob_size),
+            ("backend.prf_size", be.prf_size),
+            ("backend.num_alus", be.num_alus),
+            ("backend.num_agus", be.num_agus),
+            ("backend.num_alu_scheds", be.num_alu_scheds),
+            ("backend.alu_sched_size", be.alu_sched_size),
+            ("backend.agu_sched_size", be.agu_sched_size),
+            ("lsq.lq_size", lsq.lq_size),
+            ("lsq.sq_size", lsq.sq_size),
+            ("lsq.loads_per_cycle", lsq.loads_per_cycle),
+            ("lsq.stores_per_cycle", lsq.stores_per_cycle),
+            ("caches.dram.bytes_per_cycle", c.dram.bytes_per_cycle),
+            ("caches.dram.line_size", c.dram.line_size),
+            ("tlb.l1_itlb_size", tlb.l1_itlb_size),
+            ("tlb.l2_itlb_size", tlb.l2_itlb_size),
+            ("tlb.l1_dtlb_size", tlb.l1_dtlb_size),
+            ("tlb.l2_dtlb_size", tlb.l2_dtlb_size),
+            ("tlb.max_walks", tlb.max_walks),
+            ("tlb.pwc_size", tlb.pwc_size),
+        ];
+        for (name, val) in nonzero {
+            if val == 0 {
+                return Err(format!("{} must be non-zero", name));
+            }
+        }
+        // The fetch unit always pushes two entries onto the IBQ
+        if fe.ibq_size < 2 {
+            return Err("frontend.ibq_size must be at least 2".to_string());
+        }
+        for (name, cache) in [
+            ("caches.l1i", &c.l1i), ("caches.l1d", &c.l1d),
+            ("caches.l2", &c.l2), ("caches.l3", &c.l3),
+        ] {
+            validate_cache(name, cache)?;
+        }
+        Ok(())
+    }
+
+    /// Return a preset by name, or otherwise load a configuration file.
+    pub fn from_arg(arg: &str) -> Result<Self, String> {
+        match Self::preset(arg) {
+            Some(cfg) => Ok(cfg),
+            None => Self::load(arg),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Check the geometry of a cache (see [Cache::new]).
+fn validate_cache(name: &str, cfg: &CacheConfig) -> Result<(), String> {
+    let nonzero = [
+        ("size", cfg.size), ("ways", cfg.ways), 
+        ("line_size", cfg.line_size), ("num_banks", cfg.num_banks), 
+        ("bank_width", cfg.bank_width), ("num_mshrs", cfg.num_mshrs),
+    ];
+    for (field, val) in nonzero {
+        if val == 0 {
+            return Err(format!("{}.{} must be non-zero", name, field));
+        }
+    }
+    if !cfg.line_size.is_power_of_two() {
+        return Err(format!("{}.line_size must be a power of two", name));
+    }
+    if cfg.policy == ReplacementPolicy::TreePlru 
+        && !cfg.ways.is_power_of_two() 
+    {
+        return Err(format!("{}.ways must be a power of two for tree-plru",
+            name));
+    }
+    let set_size = cfg.ways * cfg.line_size;
+    if !cfg.size.is_multiple_of(set_size)
+        || !(cfg.size / set_size).is_power_of_two() 
+    {
+        return Err(format!("{}.size must be a power-of-two number of sets \
+            ({} ways of {} bytes)", name, cfg.ways, cfg.line_size));
+    }
+    Ok(())
+}
+
+/// Recursively replace the fields in 'base' with the fields in 'over'.
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => { base.insert(k, v); },
+                }
+            }
+        },
+        (base, over) => *base = over,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a configuration file to a temporary path and load it.
+    fn load(name: &str, contents: &str) -> Result<Config, String> {
+        let path = std::env::temp_dir()
+            .join(format!("config-test-{}-{}", std::process::id(), name));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, contents).unwrap();
+        let res = Config::load(path);
+        std::fs::remove_file(path).unwrap();
+        res
+    }
+
+    #[test]
+    fn presets() {
+        for name in Config::PRESETS {
+            let cfg = Config::preset(name).unwrap();
+            cfg.validate().unwrap();
+            let s = cfg.to_toml();
+            assert_eq!(load(&format!("{}.toml", name), &s).unwrap().to_toml(),
+                s);
+        }
+        assert!(Config::preset("zen4").is_none());
+    }
+
+    #[test]
+    fn merge_with_preset() {
+        let cfg = load("merge.toml", "preset = \"zen3\"\n\
+            [backend]\nrob_size = 320\n\
+            [caches.l3]\nsize = 67108864\n").unwrap();
+        assert_eq!(cfg.backend.rob_size, 320);
+        assert_eq!(cfg.caches.l3.size, 64 * 1024 * 1024);
+        assert_eq!(cfg.caches.l3.hit_latency, 34);
+        assert_eq!(cfg.lsq.lq_size, Config::zen3().lsq.lq_size);
+
+        let cfg = load("merge.json", r#"{"lsq": {"sq_size": 8}}"#).unwrap();
+        assert_eq!(cfg.lsq.sq_size, 8);
+        assert_eq!(cfg.backend.rob_size, Config::zen2().backend.rob_size);
+    }
+
+    #[test]
+    fn invalid() {
+        let err = load("unknown.toml", "[backend]\nrob_sise = 1\n");
+        assert!(err.unwrap_err().contains("rob_sise"));
+        let err = load("preset.toml", "preset = \"zen4\"\n");
+        assert!(err.unwrap_err().contains("unknown preset 'zen4'"));
+        let err = load("zero.toml", "[backend]\nrob_size = 0\n");
+        assert!(err.unwrap_err().ends_with("backend.rob_size must be \
+            non-zero"));
+
+        let mut cfg = Config::zen2();
+        cfg.frontend.ibq_size = 1;
+        assert!(cfg.validate().is_err());
+
+        let mut cfg = Config::zen2();
+        cfg.caches.l1d.policy = ReplacementPolicy::TreePlru;
+        cfg.caches.l1d.ways = 3;
+        cfg.caches.l1d.size = 3 * 64 * 64;
+        assert!(cfg.validate().unwrap_err().contains("tree-plru"));
+        cfg.caches.l1d.policy = ReplacementPolicy::Lru;
+        cfg.validate().unwrap();
+        cfg.caches.l1d.size = 3 * 64 * 48;
+        assert!(cfg.validate().unwrap_err().starts_with("caches.l1d.size"));
+        cfg.caches.l1d.line_size = 48;
+        assert!(cfg.validate().unwrap_err()
+            .starts_with("caches.l1d.line_size"));
+    }
+}
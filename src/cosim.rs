@@ -0,0 +1,184 @@
+//! Lock-step co-simulation.
+//!
+//! [CoSim] runs the functional model ([Emulator]) alongside the pipeline.
+//! Whenever the last micro-op of an instruction retires, the reference
+//! executes the same instruction and the committed architectural state
+//! (registers, flags, and stores) is compared against it.
+
+use iced_x86::Register;
+
+use crate::emu::*;
+use crate::elf::*;
+use crate::flags::*;
+use crate::lsq::CommittedStore;
+use crate::retire::*;
+use crate::rf::*;
+
+pub struct CoSim {
+    pub emu: Emulator,
+    /// Stores committed by the instruction which is currently retiring
+    stores: Vec<CommittedStore>,
+    /// Number of instructions checked
+    pub insts: usize,
+    /// A description of the first mismatch (if any)
+    pub error: Option<String>,
+}
+impl CoSim {
+    pub fn new(emu: Emulator) -> Self {
+        Self { emu, stores: Vec::new(), insts: 0, error: None }
+    }
+
+    /// Check a retired micro-op (after its effects have been committed).
+    pub fn retire(&mut self, ent: &ROBEntry, store: Option<CommittedStore>,
+        rat: &RegisterAliasTable,
+        prf: &PhysicalRegisterFile,
+        syms: &SymbolTable,
+    ) {
+        self.stores.extend(store);
+        if !ent.last || self.error.is_some() {
+            return;
+        }
+        let stores = std::mem::take(&mut self.stores);
+        let pc = ent.uop.addr;
+        let mut diff = Vec::new();
+
+        if self.emu.pc != pc {
+            diff.push(format!("pc: pipeline={:08x} reference={:08x}",
+                pc, self.emu.pc));
+        } else {
+            match self.emu.step(ent.mop, ent.uop.len) {
+                Ok(ref_stores) => {
+                    diff.extend(self.compare(rat, prf, &stores, &ref_stores));
+                },
+                Err(e) => diff.push(e),
+            }
+        }
+        self.insts += 1;
+        if diff.is_empty() {
+            return;
+        }
+        let mut msg = format!("mismatch at instruction #{} {:08x} {:?}",
+            self.insts, pc, ent.mop);
+        if syms.lookup(pc).is_some() {
+            msg += &format!(" <{}>", syms.fmt(pc));
+        }
+        for line in diff {
+            trace!("[COSIM] {}", line);
+            msg += &format!("\n  {}", line);
+        }
+        self.error = Some(msg);
+    }
+
+    fn compare(&self,
+        rat: &RegisterAliasTable,
+        prf: &PhysicalRegisterFile,
+        stores: &[CommittedStore],
+        ref_stores: &[CommittedStore],
+    ) -> Vec<String> {
+        let mut diff = Vec::new();
+        for (i, val) in self.emu.regs.iter().enumerate() {
+            let arn = Arn(i);
+            let pipe = prf.read(rat.resolve_arn(arn));
+            if pipe != *val {
+                diff.push(format!("{:?}: pipeline={:016x} reference={:016x}",
+                    Register::from(arn), pipe, val));
+            }
+        }
+        let flags = prf.read(rat.resolve_arn(Arn::flags(FlagGroup::Arith)))
+            | prf.read(rat.resolve_arn(Arn::flags(FlagGroup::Carry)));
+        if flags != self.emu.flags {
+            diff.push(format!("flags: pipeline={:04x} reference={:04x}",
+                flags, self.emu.flags));
+        }
+        if stores != ref_stores {
+            diff.push(format!("stores: pipeline={:x?} reference={:x?}",
+                stores, ref_stores));
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Core;
+    use crate::config::Config;
+    use crate::mem::RAM_LEN;
+
+    /// Load a flat binary at address zero with co-simulation enabled.
+    fn core(prog: &[u8]) -> Core {
+        let mut core = Core::new(&Config::default());
+        core.load(0, prog);
+        core.enable_cosim();
+        core
+    }
+
+    fn prog() -> Vec<u8> {
+        let mut prog = vec![
+            0xbc, 0x00, 0x80, 0x00, 0x00,       // mov esp, 0x8000
+            0xb9, 0x05, 0x00, 0x00, 0x00,       // mov ecx, 5
+            0xe8, 0x11, 0x00, 0x00, 0x00,       // 1: call 2f
+            0xff, 0xc9,                         // dec ecx
+            0x75, 0xf7,                         // jnz 1b
+            0x0f, 0x0b,                         // ud2
+        ];
+        prog.resize(0x20, 0x90);                // nop
+        prog.extend([
+            0x48, 0x89, 0x4c, 0x24, 0xf0,       // 2: mov [rsp-16], rcx
+            0x48, 0x8b, 0x44, 0x24, 0xf0,       // mov rax, [rsp-16]
+            0x48, 0x01, 0xc2,                   // add rdx, rax
+            0xc3,                               // ret
+        ]);
+        prog
+    }
+
+    #[test]
+    fn matches_pipeline() {
+        let mut core = core(&prog());
+        core.run_until(10_000);
+        assert!(core.halted());
+        let cosim = core.rcu.cosim.as_ref().unwrap();
+        assert_eq!(cosim.error, None);
+        assert_eq!(cosim.insts, core.rcu.stats.insts);
+        assert_eq!(cosim.insts, 2 + 5 * 7);
+        assert_eq!(cosim.emu.reg(Register::RDX), 15);
+    }
+
+    #[test]
+    fn out_of_range_accesses() {
+        // Both models drop a store which runs off the end of memory, and
+        // a load from the same address returns zero
+        let mut core = core(&[
+            0x48, 0xbb, 0xfc, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00, 0x00,
+                                                // mov rbx, 0x1fffffc
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff,
+                                                // mov rax, -1
+            0x48, 0x89, 0x03,                   // mov [rbx], rax
+            0x48, 0x8b, 0x0b,                   // mov rcx, [rbx]
+            0x48, 0x89, 0x43, 0xf8,             // mov [rbx-8], rax
+            0x0f, 0x0b,                         // ud2
+        ]);
+        core.run_until(10_000);
+        assert!(core.halted());
+        let cosim = core.rcu.cosim.as_ref().unwrap();
+        assert_eq!(cosim.error, None);
+        assert_eq!(cosim.insts, 5);
+        assert_eq!(cosim.emu.reg(Register::RCX), 0);
+        assert_eq!(core.mem.read(RAM_LEN - 12, 8), &[0xff; 8]);
+        assert_eq!(core.mem.read(RAM_LEN - 4, 3), &[0; 3]);
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut core = core(&prog());
+        let rdx = Arn::from(Register::RDX).0;
+        core.rcu.cosim.as_mut().unwrap().emu.regs[rdx] = 1;
+        core.run_until(10_000);
+        assert!(!core.halted() && core.rcu.cosim_failed());
+        let err = core.rcu.cosim.as_ref().unwrap().error.clone().unwrap();
+        assert!(err.starts_with("mismatch at instruction #1 00000000 "),
+            "{}", err);
+        assert!(err.contains("\n  RDX: pipeline=0000000000000000 \
+            reference=0000000000000001"), "{}", err);
+    }
+}
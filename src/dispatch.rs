@@ -1,17 +1,24 @@
 
-use crate::front::*;
+use crate::bp::*;
 use crate::rf::*;
 use crate::op::*;
 use crate::retire::*;
 use crate::issue::*;
 use crate::util::*;
+use crate::lsq::*;
+use crate::event::*;
+use crate::topdown::*;
 
 /// An entry in the macro-op queue.
 #[derive(Debug, Copy, Clone)]
 pub struct OPQEntry {
     /// The program counter value associated with this instruction
     pub addr: usize,
+    /// The length of this instruction
+    pub len: usize,
     pub op: MacroOp,
+    /// Front-end prediction for this instruction
+    pub pred: Prediction,
 }
 
 #[derive(Debug)]
@@ -26,13 +33,59 @@ pub enum DispatchErr {
     ALQAlloc, 
     /// Could not reserve an AGU scheduler queue entry.
     AGQAlloc,
+    /// Could not allocate a load queue entry.
+    LQAlloc,
+    /// Could not allocate a store queue entry.
+    SQAlloc,
+}
+
+/// Counters for cycles where dispatch stalled (for each resource).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DispatchStats {
+    /// Cycles where the op queue was empty
+    pub opq_empty: usize,
+    pub rob_full: usize,
+    pub prf_full: usize,
+    pub alu_sched_full: usize,
+    pub agu_sched_full: usize,
+    pub lq_full: usize,
+    pub sq_full: usize,
+}
+impl DispatchStats {
+    fn count(&mut self, reason: StallReason) {
+        match reason {
+            StallReason::RobFull      => self.rob_full += 1,
+            StallReason::PrfFull      => self.prf_full += 1,
+            StallReason::AluSchedFull => self.alu_sched_full += 1,
+            StallReason::AguSchedFull => self.agu_sched_full += 1,
+            StallReason::LqFull       => self.lq_full += 1,
+            StallReason::SqFull       => self.sq_full += 1,
+            _ => unreachable!("{:?}", reason),
+        }
+    }
 }
 
 /// Abstract representation of the dispatch unit.
-pub struct DispatchUnit;
+pub struct DispatchUnit {
+    /// Number of macro-ops dispatched per cycle
+    pub width: usize,
+    /// Sequence number for the next micro-op
+    pub next_id: usize,
+    pub stats: DispatchStats,
+    pub topdown: TopDown,
+}
 impl DispatchUnit {
+    pub fn new(width: usize) -> Self {
+        Self { 
+            width, 
+            next_id: 0, 
+            stats: DispatchStats::default(),
+            topdown: TopDown::new(width),
+        }
+    }
 
-    /// Dispatch up to 6 macro-ops per cycle from the op queue.
+    /// Dispatch up to 'width' (6 on Zen 2) macro-ops per cycle from the 
+    /// op queue.
     /// For each macro-op, this entails (not necessarily in this order):
     ///
     /// - Converting into one or more micro-ops
@@ -43,28 +96,43 @@ impl DispatchUnit {
     ///
     /// After this point, micro-ops are issued, executed, and completed
     /// out-of-order.
-
+    ///
+    /// NOTE: `rat` is the speculative (front-end) register alias table.
+    /// Architectural state is only committed to a separate table at 
+    /// retirement (see [RetireControlUnit]).
+    #[allow(clippy::too_many_arguments)]
     pub fn cycle(&mut self, 
-        btb: &mut BranchTargetBuffer,
+        _btb: &mut BranchTargetBuffer,
         opq: &mut Queue<OPQEntry>,
-        alu_sched: &mut [ALUScheduler; 4],
+        alu_sched: &mut [ALUScheduler],
         agu_sched: &mut AGUScheduler,
         prf: &mut PhysicalRegisterFile, 
         rob: &mut ReorderBuffer,
         rat: &mut RegisterAliasTable,
+        lsq: &mut LoadStoreQueue,
+        tr: &mut Tracer,
     ) {
-        'dispatch: for idx in 0..6 {
+        // Number of macro-ops dispatched, and the resource which stopped
+        // dispatch (if any)
+        let mut num = 0;
+        let mut stalled = None;
+        'dispatch: for idx in 0..self.width {
 
             // Get a reference to the next candidate for dispatch.
-            let (mop_addr, mop) = if let Ok(e) = opq.peek(0) { (e.addr, e.op) } 
+            let (mop_addr, mop_len, mop, pred) = if let Ok(e) = opq.peek(0) { 
+                (e.addr, e.len, e.op, e.pred) 
+            } 
             else { 
-                println!("[SCH] Op queue is empty, nothing to dispatch");
+                detail!("[SCH] Op queue is empty, nothing to dispatch");
+                if idx == 0 {
+                    self.stats.opq_empty += 1;
+                }
                 break 'dispatch;
             };
 
             // Decompose a macro-op into one or two micro-ops
-            let mut uops = Uop::from_mop(mop, mop_addr);
-            println!("[SCH] Trying to dispatch macro-op #{} {:x?}", idx, mop);
+            let mut uops = Uop::from_mop(mop, mop_addr, mop_len);
+            trace!("[SCH] Trying to dispatch macro-op #{} {:x?}", idx, mop);
 
             // Get the number of required physical registers
             let num_prn_alloc = uops.iter().map(|u| u.preg_allocs()).sum();
@@ -77,6 +145,10 @@ impl DispatchUnit {
                 .map(|s| s.num_free()).sum();
             let num_agu_free = agu_sched.num_free();
 
+            // Get the number of required load/store queue entries
+            let num_ld_alloc = uops.iter().filter(|&u| u.is_load()).count();
+            let num_st_alloc = uops.iter().filter(|&u| u.is_store()).count();
+
             // Get the number of required ROB entries
             let num_rob_alloc = uops.len();
             let num_rob_free  = rob.num_free();
@@ -87,50 +159,71 @@ impl DispatchUnit {
             let alu_alloc_ok = num_alu_free >= num_alu_alloc;
             let agu_alloc_ok = num_agu_free >= num_agu_alloc;
             let rob_alloc_ok = num_rob_free >= num_rob_alloc;
+            let mut stall = |tr: &mut Tracer, reason| {
+                tr.stall(Stage::Dispatch, reason, Some(mop_addr));
+                self.stats.count(reason);
+                stalled = Some(reason);
+            };
             if !rob_alloc_ok {
-                println!("[SCH] Stalled for ROB allocation");
-                println!("[SCH] Free ROB slots:   {:3} (need {})",
+                stall(tr, StallReason::RobFull);
+                detail!("[SCH] Free ROB slots:   {:3} (need {})",
                          num_rob_free, num_rob_alloc);
                 break 'dispatch;
             }
             if !prn_alloc_ok {
-                println!("[SCH] Stalled for physical register allocation");
-                println!("[SCH] Free PRF entries: {:3} (need {})", 
+                stall(tr, StallReason::PrfFull);
+                detail!("[SCH] Free PRF entries: {:3} (need {})", 
                          num_prn_free, num_prn_alloc);
                 break 'dispatch;
             }
             if !alu_alloc_ok {
-                println!("[SCH] Stalled for ALU scheduler allocation");
-                println!("[SCH] Free ALSQ slots:  {:3} (need {})", 
+                stall(tr, StallReason::AluSchedFull);
+                detail!("[SCH] Free ALSQ slots:  {:3} (need {})", 
                          num_alu_free, num_alu_alloc);
                 break 'dispatch;
             }
             if !agu_alloc_ok {
-                println!("[SCH] Stalled for AGU scheduler allocation");
-                println!("[SCH] Free AGSQ slots:  {:3} (need {})", 
+                stall(tr, StallReason::AguSchedFull);
+                detail!("[SCH] Free AGSQ slots:  {:3} (need {})", 
                          num_agu_free, num_agu_alloc);
                 break 'dispatch;
             }
+            if lsq.lq_free() < num_ld_alloc {
+                stall(tr, StallReason::LqFull);
+                break 'dispatch;
+            }
+            if lsq.sq_free() < num_st_alloc {
+                stall(tr, StallReason::SqFull);
+                break 'dispatch;
+            }
+
+            for (n, uop) in uops.iter_mut().enumerate() {
+                let last = n + 1 == num_rob_alloc;
+                uop.id = self.next_id;
+                self.next_id += 1;
 
-            for uop in uops.iter_mut() {
                 // Resolve all architectural source registers
                 for arg in uop.arg.iter_mut() {
-                    if let Storage::Arn(r) = arg {
-                        let p = rat.resolve(*r);
-                        println!("[SCH] Resolved {:?} to {:?}", r, p);
-                        *arg = Storage::Prn(p);
-                    }
+                    let arn = match arg {
+                        Storage::Arn(r) => Arn::from(*r),
+                        Storage::Tmp(n) => Arn::tmp(*n),
+                        Storage::Flags(g) => Arn::flags(*g),
+                        _ => continue,
+                    };
+                    let p = rat.resolve_arn(arn);
+                    detail!("[SCH] Resolved {:?} to {:?}", arn, p);
+                    *arg = Storage::Prn(p);
                 }
 
                 // Allocate for architectural destination register
                 for eff in uop.eff.iter_mut() {
-                    if let Effect::RegWrite(rd, prn) = eff {
-                        if prn == &Prn::alloc() {
+                    if let Some((rd, prn)) = eff.dest() {
+                        if prn == Prn::alloc() {
                             let nprn = prf.alloc().unwrap();
-                            println!("[SCH] Allocated {:?} for result {:?}", 
+                            trace!("[SCH] Allocated {:?} for result {:?}", 
                                      nprn, rd);
-                            //rat.bind(rd.clone(), nprn);
-                            *eff = Effect::RegWrite(rd.clone(), nprn);
+                            rat.update_arn(rd, nprn);
+                            *eff = eff.with_prn(nprn);
                         }
                     }
                 }
@@ -144,47 +237,63 @@ impl DispatchUnit {
 
                     UopKind::Alu(_) => {
                         // Naively prioritize the emptiest queue
-                        let (i, mut tgt_alq) = alu_sched.iter_mut()
-                            .enumerate().max_by(|(i,x),(j,y)| { 
+                        let (i, tgt_alq) = alu_sched.iter_mut()
+                            .enumerate().max_by(|(_, x), (_, y)| { 
                                 x.num_free().cmp(&y.num_free()) 
                         }).unwrap();
 
-                        let rob_ent = ROBEntry::new(mop, *uop);
+                        let rob_ent = ROBEntry::new(mop, *uop, pred, last);
                         let rob_idx = rob.push(rob_ent).unwrap();
-                        println!("[SCH] ALSQ{} dispatch {:08x} {:?} rob_idx={} ", 
-                                 i, uop.addr, uop.kind, rob_idx
-                        );
+                        tr.uop(uop, rob_idx, 
+                            EventKind::Dispatch { kind: uop.kind, last });
+                        detail!("[SCH] Sent rob_idx={} to ALSQ{}", rob_idx, i);
                         tgt_alq.alloc(
                             Reservation { mop, uop: *uop, rob_idx }
                         ).unwrap();
                     },
 
-                    UopKind::Agu(_) => {
-                        let rob_ent = ROBEntry::new(mop, *uop);
+                    UopKind::Agu(agu_op) => {
+                        let rob_ent = ROBEntry::new(mop, *uop, pred, last);
                         let rob_idx = rob.push(rob_ent).unwrap();
-                        println!("[SCH] AGSQ dispatch {:08x} {:?} rob_idx={} ", 
-                                 uop.addr, uop.kind, rob_idx
-                        );
+                        tr.uop(uop, rob_idx, 
+                            EventKind::Dispatch { kind: uop.kind, last });
+                        match agu_op {
+                            AGUOp::Ld(sz) => {
+                                let (_, dst) = uop.eff[0].dest().unwrap();
+                                lsq.alloc_load(rob_idx, uop.addr, dst, 
+                                    sz.size());
+                            },
+                            AGUOp::St(sz) => {
+                                lsq.alloc_store(rob_idx, uop.addr, sz.size());
+                            },
+                        }
                         agu_sched.alloc( 
                             Reservation { mop, uop: *uop, rob_idx }
                         ).unwrap();
                     },
 
-                    // Let's assume that UD2 doesn't consume a scheduler entry
-                    // and only lives as a marker in the ROB
+                    // UD2 (or a fetch fault) doesn't consume a scheduler
+                    // entry. It only lives as a marker in the ROB, and 
+                    // faults when it reaches retirement.
                     UopKind::Illegal => {
-                        let rob_ent = ROBEntry::new(mop, *uop);
+                        let mut rob_ent = ROBEntry::new(mop, *uop, pred, last);
+                        rob_ent.complete = true;
+                        rob_ent.fault = true;
                         let rob_idx = rob.push(rob_ent).unwrap();
-                        println!("[SCH] Allocated ROB entry {} for uop", rob_idx);
+                        tr.uop(uop, rob_idx, 
+                            EventKind::Dispatch { kind: uop.kind, last });
                     },
 
-                    _ => unreachable!(),
+                    // Every micro-op has a kind (see [Uop::from_mop])
+                    UopKind::None => unreachable!("micro-op without a kind"),
                 }
             }
 
             // It's safe to finally pop this macro-op from the queue.
             opq.pop().unwrap();
+            num += 1;
         }
+        self.topdown.cycle(num, stalled);
     }
 }
 
@@ -0,0 +1,91 @@
+//! Main memory timing.
+
+use std::io::{ self, Write };
+use serde::{ Serialize, Deserialize };
+
+/// Parameters for main memory.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DramConfig {
+    /// Number of cycles between a request and the data being returned
+    pub latency: usize,
+    /// Peak bandwidth in bytes per cycle
+    pub bytes_per_cycle: usize,
+    /// Size of a request in bytes
+    pub line_size: usize,
+}
+
+/// Dual-channel DDR4-3200 (about 51 GB/s) with a core clock of 3.5 GHz.
+pub const DRAM_CONFIG: DramConfig = DramConfig {
+    latency: 200,
+    bytes_per_cycle: 14,
+    line_size: 64,
+};
+
+/// Counters for main memory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DramStats {
+    pub reads: usize,
+    /// Dirty lines written back from the L3
+    pub writes: usize,
+    /// Total number of cycles that requests spent waiting for bandwidth
+    pub queued_cycles: usize,
+}
+
+/// A simple model of main memory with a fixed latency and a single
+/// channel shared by all requests.
+pub struct Dram {
+    pub cfg: DramConfig,
+    /// The first cycle when the channel is free
+    busy_until: usize,
+    pub stats: DramStats,
+}
+impl Dram {
+    pub fn new(cfg: DramConfig) -> Self {
+        Self { cfg, busy_until: 0, stats: DramStats::default() }
+    }
+
+    /// Read a line for a request made on cycle 't', returning the cycle
+    /// when the data is returned.
+    pub fn read(&mut self, t: usize) -> usize {
+        let start = t.max(self.busy_until);
+        let xfer = self.cfg.line_size.div_ceil(self.cfg.bytes_per_cycle);
+        self.busy_until = start + xfer;
+        self.stats.reads += 1;
+        self.stats.queued_cycles += start - t;
+        start + self.cfg.latency
+    }
+
+    /// Write a line back for a request made on cycle 't'.
+    ///
+    /// NOTE: Nothing waits for a writeback, so they only use bandwidth.
+    pub fn write(&mut self, t: usize) {
+        let start = t.max(self.busy_until);
+        let xfer = self.cfg.line_size.div_ceil(self.cfg.bytes_per_cycle);
+        self.busy_until = start + xfer;
+        self.stats.writes += 1;
+        self.stats.queued_cycles += start - t;
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "[DRAM] reads={} writes={} queued_cycles={}",
+            self.stats.reads, self.stats.writes, self.stats.queued_cycles)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth() {
+        // Each line takes 5 cycles to transfer
+        let mut d = Dram::new(DRAM_CONFIG);
+        assert_eq!(d.read(10), 210);
+        assert_eq!(d.read(10), 215);
+        d.write(12);
+        assert_eq!(d.read(30), 230);
+        assert_eq!(d.stats.queued_cycles, 5 + 8);
+    }
+}
@@ -0,0 +1,284 @@
+//! Loading ELF64 executables.
+
+/// Program header type for loadable segments.
+const PT_LOAD: u32 = 1;
+/// Section header type for a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// Symbol types which are kept in the [SymbolTable].
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+/// Machine type for x86-64.
+const EM_X86_64: u16 = 62;
+
+/// A loadable segment.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub vaddr: usize,
+    /// Bytes from the file (the rest of the segment is zero-filled)
+    pub data: Vec<u8>,
+    /// Size of the segment in memory
+    pub memsz: usize,
+}
+
+/// A named address from the symbol table.
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub addr: usize,
+    /// Size in bytes (zero for plain labels)
+    pub size: usize,
+    pub name: String,
+}
+
+/// Symbols sorted by address, used to print addresses as `function+offset`.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    syms: Vec<Symbol>,
+}
+impl SymbolTable {
+    pub fn new(mut syms: Vec<Symbol>) -> Self {
+        syms.sort_by_key(|s| s.addr);
+        syms.dedup_by_key(|s| s.addr);
+        Self { syms }
+    }
+
+    pub fn is_empty(&self) -> bool { self.syms.is_empty() }
+
+    /// Return the symbol containing some address, along with the offset of
+    /// the address from the start of the symbol.
+    ///
+    /// NOTE: Symbols without a size (ie. labels in assembly) are assumed to
+    /// extend up to the next symbol.
+    pub fn lookup(&self, addr: usize) -> Option<(&Symbol, usize)> {
+        let idx = self.syms.partition_point(|s| s.addr <= addr);
+        let sym = self.syms.get(idx.checked_sub(1)?)?;
+        let off = addr - sym.addr;
+        if sym.size != 0 && off >= sym.size {
+            return None;
+        }
+        Some((sym, off))
+    }
+
+    /// Format an address as `function+offset` (or as a plain hexadecimal
+    /// address if there is no symbol for it).
+    pub fn fmt(&self, addr: usize) -> String {
+        match self.lookup(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, off)) => format!("{}+{:#x}", sym.name, off),
+            None => format!("{:08x}", addr),
+        }
+    }
+}
+
+/// A parsed ELF64 executable.
+#[derive(Clone, Debug)]
+pub struct Elf {
+    pub entry: usize,
+    pub segments: Vec<Segment>,
+    pub syms: SymbolTable,
+}
+impl Elf {
+    /// Returns true if some file starts with the ELF magic number.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(b"\x7fELF")
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let err = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+        let data = std::fs::read(path).map_err(|e| err(&e))?;
+        Self::parse(&data).map_err(|e| err(&e))
+    }
+
+    /// Parse a little-endian x86-64 ELF64 executable.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if !Self::is_elf(data) {
+            return Err("not an ELF file".to_string());
+        }
+        if read_u8(data, 4)? != 2 || read_u8(data, 5)? != 1 {
+            return Err("not a little-endian ELF64 file".to_string());
+        }
+        let machine = read_u16(data, 18)?;
+        if machine != EM_X86_64 {
+            return Err(format!("unsupported machine type {}", machine));
+        }
+        let entry     = read_u64(data, 24)? as usize;
+        let phoff     = read_u64(data, 32)? as usize;
+        let shoff     = read_u64(data, 40)? as usize;
+        let phentsize = read_u16(data, 54)? as usize;
+        let phnum     = read_u16(data, 56)? as usize;
+        let shentsize = read_u16(data, 58)? as usize;
+        let shnum     = read_u16(data, 60)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if read_u32(data, ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u64(data, ph + 8)? as usize;
+            let vaddr  = read_u64(data, ph + 16)? as usize;
+            let filesz = read_u64(data, ph + 32)? as usize;
+            let memsz  = read_u64(data, ph + 40)? as usize;
+            let bytes = read_bytes(data, offset, filesz)?.to_vec();
+            segments.push(Segment { vaddr, data: bytes, memsz });
+        }
+
+        // The symbol table is optional (ie. for stripped executables)
+        let mut syms = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            if read_u32(data, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset  = read_u64(data, sh + 24)? as usize;
+            let size    = read_u64(data, sh + 32)? as usize;
+            let link    = read_u32(data, sh + 40)? as usize;
+            let entsize = read_u64(data, sh + 56)? as usize;
+            let strtab  = shoff + link * shentsize;
+            let stroff  = read_u64(data, strtab + 24)? as usize;
+            let strsize = read_u64(data, strtab + 32)? as usize;
+            let strs = read_bytes(data, stroff, strsize)?;
+            if entsize == 0 {
+                return Err("invalid symbol table".to_string());
+            }
+            for ent in (offset..offset + size).step_by(entsize) {
+                let name  = read_u32(data, ent)? as usize;
+                let info  = read_u8(data, ent + 4)?;
+                let shndx = read_u16(data, ent + 6)?;
+                let addr  = read_u64(data, ent + 8)? as usize;
+                let size  = read_u64(data, ent + 16)? as usize;
+                let kind_ok = matches!(info & 0xf,
+                    STT_NOTYPE | STT_OBJECT | STT_FUNC);
+                if !kind_ok || shndx == 0 {
+                    continue;
+                }
+                let name = read_str(strs, name)?;
+                if name.is_empty() {
+                    continue;
+                }
+                syms.push(Symbol { addr, size, name });
+            }
+        }
+
+        Ok(Self { entry, segments, syms: SymbolTable::new(syms) })
+    }
+}
+
+fn read_bytes(data: &[u8], off: usize, len: usize) -> Result<&[u8], String> {
+    off.checked_add(len).and_then(|end| data.get(off..end))
+        .ok_or_else(|| format!("truncated file (offset {:#x})", off))
+}
+fn read_u8(data: &[u8], off: usize) -> Result<u8, String> {
+    Ok(read_bytes(data, off, 1)?[0])
+}
+fn read_u16(data: &[u8], off: usize) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(read_bytes(data, off, 2)?.try_into().unwrap()))
+}
+fn read_u32(data: &[u8], off: usize) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(read_bytes(data, off, 4)?.try_into().unwrap()))
+}
+fn read_u64(data: &[u8], off: usize) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(read_bytes(data, off, 8)?.try_into().unwrap()))
+}
+/// Read a NUL-terminated string from a string table.
+fn read_str(strs: &[u8], off: usize) -> Result<String, String> {
+    let s = strs.get(off..)
+        .ok_or_else(|| format!("invalid string offset {:#x}", off))?;
+    let len = s.iter().position(|b| *b == 0).unwrap_or(s.len());
+    Ok(String::from_utf8_lossy(&s[..len]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an executable with a single segment and two symbols.
+    fn build_elf(code: &[u8]) -> Vec<u8> {
+        let strs = b"\0main\0buf\0";
+        let (code_off, str_off) = (0x78, 0x78 + code.len());
+        let sym_off = (str_off + strs.len() + 7) & !7;
+        let sh_off = sym_off + 3 * 24;
+
+        let mut d = vec![0u8; sh_off + 3 * 64];
+        let mut put = |off: usize, bytes: &[u8]| {
+            d[off..off + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"\x7fELF\x02\x01\x01");
+        put(18, &EM_X86_64.to_le_bytes());
+        put(24, &0x401000u64.to_le_bytes());
+        put(32, &0x40u64.to_le_bytes());
+        put(40, &(sh_off as u64).to_le_bytes());
+        put(54, &56u16.to_le_bytes());
+        put(56, &1u16.to_le_bytes());
+        put(58, &64u16.to_le_bytes());
+        put(60, &3u16.to_le_bytes());
+
+        // Text segment, with some zero-filled space after the code
+        put(0x40, &PT_LOAD.to_le_bytes());
+        put(0x48, &(code_off as u64).to_le_bytes());
+        put(0x50, &0x401000u64.to_le_bytes());
+        put(0x60, &(code.len() as u64).to_le_bytes());
+        put(0x68, &0x2000u64.to_le_bytes());
+        put(code_off, code);
+        put(str_off, strs);
+
+        // A null symbol, main (a function), and buf (a label)
+        for (i, (name, info, addr, size)) in [
+            (1u32, STT_FUNC, 0x401000u64, code.len() as u64),
+            (6, STT_NOTYPE, 0x402000, 0),
+        ].into_iter().enumerate() {
+            let ent = sym_off + (i + 1) * 24;
+            put(ent, &name.to_le_bytes());
+            put(ent + 4, &[info]);
+            put(ent + 6, &1u16.to_le_bytes());
+            put(ent + 8, &addr.to_le_bytes());
+            put(ent + 16, &size.to_le_bytes());
+        }
+
+        // Sections: null, the string table, then the symbol table (so
+        // that the last byte of the file is used)
+        let strtab = sh_off + 64;
+        put(strtab + 24, &(str_off as u64).to_le_bytes());
+        put(strtab + 32, &(strs.len() as u64).to_le_bytes());
+        let symtab = sh_off + 128;
+        put(symtab + 4, &SHT_SYMTAB.to_le_bytes());
+        put(symtab + 24, &(sym_off as u64).to_le_bytes());
+        put(symtab + 32, &(3 * 24u64).to_le_bytes());
+        put(symtab + 40, &1u32.to_le_bytes());
+        put(symtab + 56, &24u64.to_le_bytes());
+        d
+    }
+
+    #[test]
+    fn parse() {
+        let elf = Elf::parse(&build_elf(&[0x90, 0x90, 0x0f, 0x0b])).unwrap();
+        assert_eq!(elf.entry, 0x401000);
+        assert_eq!(elf.segments.len(), 1);
+        let seg = &elf.segments[0];
+        assert_eq!(seg.vaddr, 0x401000);
+        assert_eq!(seg.data, [0x90, 0x90, 0x0f, 0x0b]);
+        assert_eq!(seg.memsz, 0x2000);
+
+        let syms = &elf.syms;
+        assert_eq!(syms.fmt(0x401000), "main");
+        assert_eq!(syms.fmt(0x401002), "main+0x2");
+        assert_eq!(syms.fmt(0x401004), "00401004");
+        assert_eq!(syms.fmt(0x400fff), "00400fff");
+        assert_eq!(syms.fmt(0x412345), "buf+0x10345");
+    }
+
+    #[test]
+    fn invalid() {
+        let data = build_elf(&[0x0f, 0x0b]);
+        for len in 0..data.len() {
+            assert!(Elf::parse(&data[..len]).is_err(), "{} bytes", len);
+        }
+        let mut bad = data.clone();
+        bad[18] = 3;
+        assert_eq!(Elf::parse(&bad).unwrap_err(),
+            "unsupported machine type 3");
+        let mut bad = data.clone();
+        bad[4] = 1;
+        assert!(Elf::parse(&bad).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+//! Functional reference model.
+//!
+//! [Emulator] executes macro-ops one instruction at a time, without any
+//! notion of timing. It's used to check the results computed by the
+//! pipeline (see [crate::cosim]).
+//!
+//! NOTE: Addresses are used as physical addresses, so this only agrees
+//! with the pipeline when paging is disabled or memory is identity-mapped.
+//! Flags are computed with the same helpers as the ALUs (see
+//! [crate::flags]).
+
+use iced_x86::{ MemorySize, Register };
+
+use crate::op::*;
+use crate::rf::*;
+use crate::mem::*;
+use crate::flags::*;
+use crate::lsq::CommittedStore;
+
+/// Architectural state for the reference model.
+#[derive(Clone)]
+pub struct Emulator {
+    /// Address of the next instruction
+    pub pc: usize,
+    /// General-purpose registers (indexed by [Arn])
+    pub regs: [usize; NUM_GPR],
+    /// Laid out like RFLAGS (only the arithmetic flags are kept)
+    pub flags: usize,
+    pub mem: Memory,
+}
+impl Emulator {
+    pub fn new(pc: usize, mem: Memory) -> Self {
+        Self { pc, regs: [0; NUM_GPR], flags: 0, mem }
+    }
+
+    /// Read a register (truncated to the size of the register).
+    pub fn reg(&self, r: Register) -> usize {
+        self.regs[Arn::from(r).0] & width_mask(r.size())
+    }
+    /// Write a register (see [merge_gpr]).
+    pub fn set_reg(&mut self, r: Register, val: usize) {
+        let arn = Arn::from(r).0;
+        self.regs[arn] = merge_gpr(r.size(), self.regs[arn], val);
+    }
+
+    fn addr(&self, mem: MemOperand) -> usize {
+        let reg = |r: Register| {
+            if r == Register::None { 0 } else { self.reg(r) }
+        };
+        reg(mem.base).wrapping_add(reg(mem.idx).wrapping_mul(mem.scale))
+            .wrapping_add(mem.disp)
+    }
+
+    /// NOTE: Like the load/store queue, loads from outside of memory
+    /// return zero (see [in_memory]).
+    fn load(&self, addr: usize, sz: MemorySize) -> usize {
+        let size = sz.size();
+        if !in_memory(addr, size) {
+            return 0;
+        }
+        let mut buf = [0u8; 8];
+        buf[..size].copy_from_slice(self.mem.read(addr, size));
+        usize::from_le_bytes(buf)
+    }
+
+    /// NOTE: Like the load/store queue, stores to addresses outside of
+    /// memory are dropped.
+    fn store(&mut self, addr: usize, sz: MemorySize, val: usize,
+             stores: &mut Vec<CommittedStore>)
+    {
+        let size = sz.size();
+        if !in_memory(addr, size) {
+            return;
+        }
+        self.mem.write(addr, &val.to_le_bytes()[..size]);
+        stores.push(CommittedStore::new(addr, size, val));
+    }
+
+    fn push(&mut self, val: usize, stores: &mut Vec<CommittedStore>) {
+        let rsp = self.reg(Register::RSP).wrapping_sub(8);
+        self.set_reg(Register::RSP, rsp);
+        self.store(rsp, MemorySize::UInt64, val, stores);
+    }
+
+    /// Compute the result and flags for an ALU operation with some
+    /// operand size (in bytes).
+    fn alu(&self, op: ALUOp, x: usize, y: usize, width: usize) 
+        -> (usize, usize) 
+    {
+        let mask = width_mask(width);
+        let (x, y) = (x & mask, y & mask);
+        let res = match op {
+            ALUOp::Add => x.wrapping_add(y),
+            ALUOp::Sub => x.wrapping_sub(y),
+            ALUOp::Or  => x | y,
+            ALUOp::And => x & y,
+            ALUOp::Xor => x ^ y,
+            ALUOp::Shl => x << shift_count(y, width),
+            ALUOp::Shr => x >> shift_count(y, width),
+            _ => unreachable!("{:?}", op),
+        } & mask;
+        let flags = FlagGroup::Arith.mask() | FlagGroup::Carry.mask();
+        (res, alu_flags(op, x, y, res, self.flags, width) & flags)
+    }
+
+    /// Execute a single instruction at the current program counter,
+    /// returning the stores it made.
+    pub fn step(&mut self, mop: MacroOp, len: usize)
+        -> Result<Vec<CommittedStore>, String>
+    {
+        let next = self.pc + len;
+        let mut npc = next;
+        let mut stores = Vec::new();
+        match mop {
+            MacroOp::Nop => {},
+            MacroOp::MovRI(rd, imm) => self.set_reg(rd, imm as usize),
+            MacroOp::MovRM(rd, mem, sz) => {
+                let val = self.load(self.addr(mem), sz);
+                self.set_reg(rd, val);
+            },
+            MacroOp::MovMR(mem, sz, rs) => {
+                self.store(self.addr(mem), sz, self.reg(rs), &mut stores);
+            },
+            MacroOp::AluRI(op, rd, imm) => {
+                let (res, flags) = self.alu(op, self.reg(rd), imm as usize,
+                    rd.size());
+                self.set_reg(rd, res);
+                self.flags = flags;
+            },
+            MacroOp::AluRR(op, rd, rs) => {
+                let (res, flags) = self.alu(op, self.reg(rd), self.reg(rs),
+                    rd.size());
+                self.set_reg(rd, res);
+                self.flags = flags;
+            },
+            MacroOp::IncDec(op, rd) => {
+                let (res, flags) = self.alu(op, self.reg(rd), 1, rd.size());
+                let cf = FlagGroup::Carry.mask();
+                self.set_reg(rd, res);
+                self.flags = (flags & !cf) | (self.flags & cf);
+            },
+            // NOTE: A 32-bit destination is zero-extended even if the
+            // condition doesn't hold
+            MacroOp::CmovRR(cc, rd, rs) => {
+                let src = if eval_cond(cc, self.flags) { rs } else { rd };
+                self.set_reg(rd, self.reg(src));
+            },
+            MacroOp::CmpRI(op, rd, imm) => {
+                self.flags = self.alu(op, self.reg(rd), imm as usize, 
+                    rd.size()).1;
+            },
+            MacroOp::CmpRR(op, rd, rs) => {
+                self.flags = self.alu(op, self.reg(rd), self.reg(rs), 
+                    rd.size()).1;
+            },
+            MacroOp::Jcc(cc, tgt) => {
+                if eval_cond(cc, self.flags) {
+                    npc = tgt;
+                }
+            },
+            MacroOp::JmpI(tgt) => npc = tgt,
+            MacroOp::JmpR(rs) => npc = self.reg(rs),
+            MacroOp::JmpM(mem) => {
+                npc = self.load(self.addr(mem), MemorySize::UInt64);
+            },
+            // The target is read before the return address is pushed
+            MacroOp::CallI(tgt) => {
+                npc = tgt;
+                self.push(next, &mut stores);
+            },
+            MacroOp::CallR(rs) => {
+                npc = self.reg(rs);
+                self.push(next, &mut stores);
+            },
+            MacroOp::CallM(mem) => {
+                npc = self.load(self.addr(mem), MemorySize::UInt64);
+                self.push(next, &mut stores);
+            },
+            MacroOp::Ret => {
+                let rsp = self.reg(Register::RSP);
+                npc = self.load(rsp, MemorySize::UInt64);
+                self.set_reg(Register::RSP, rsp.wrapping_add(8));
+            },
+            MacroOp::Ud2 | MacroOp::FetchFault | MacroOp::Unsupported(_) => {
+                return Err(format!("can't execute {:?}", mop));
+            },
+        }
+        self.pc = npc;
+        Ok(stores)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iced_x86::ConditionCode;
+
+    fn mem(base: Register, disp: usize) -> MemOperand {
+        MemOperand { base, idx: Register::None, scale: 1, disp }
+    }
+
+    #[test]
+    fn alu() {
+        let mut emu = Emulator::new(0, Memory::new());
+        emu.step(MacroOp::MovRI(Register::RAX, -1), 7).unwrap();
+        emu.step(MacroOp::MovRI(Register::AL, 1), 2).unwrap();
+        emu.step(MacroOp::AluRI(ALUOp::Add, Register::AL, 0xff), 2)
+            .unwrap();
+        assert_eq!(emu.reg(Register::RAX), 0xffff_ffff_ffff_ff00);
+        assert!(eval_cond(ConditionCode::b, emu.flags));
+        assert!(eval_cond(ConditionCode::e, emu.flags));
+
+        // INC preserves CF, and a 32-bit destination is zero-extended
+        emu.step(MacroOp::IncDec(ALUOp::Add, Register::EAX), 2).unwrap();
+        assert_eq!(emu.reg(Register::RAX), 0xffff_ff01);
+        assert!(eval_cond(ConditionCode::b, emu.flags));
+        assert!(eval_cond(ConditionCode::ne, emu.flags));
+
+        emu.step(MacroOp::CmpRI(ALUOp::Sub, Register::EAX, 0x7fff_ffff), 5)
+            .unwrap();
+        assert!(eval_cond(ConditionCode::a, emu.flags));
+        emu.step(MacroOp::CmovRR(ConditionCode::b, Register::ECX,
+            Register::EAX), 3).unwrap();
+        assert_eq!(emu.reg(Register::RCX), 0);
+        emu.step(MacroOp::CmovRR(ConditionCode::a, Register::ECX,
+            Register::EAX), 3).unwrap();
+        assert_eq!(emu.reg(Register::RCX), 0xffff_ff01);
+        assert_eq!(emu.pc, 24);
+    }
+
+    #[test]
+    fn memory() {
+        let mut emu = Emulator::new(0, Memory::new());
+        emu.set_reg(Register::RBX, 0x1000);
+        emu.set_reg(Register::RAX, 0x1122_3344_5566_7788);
+        let stores = emu.step(MacroOp::MovMR(mem(Register::RBX, 4),
+            MemorySize::UInt32, Register::EAX), 3).unwrap();
+        assert_eq!(stores, [CommittedStore::new(0x1004, 4, 0x5566_7788)]);
+        emu.step(MacroOp::MovRM(Register::CX, mem(Register::RBX, 5),
+            MemorySize::UInt16), 4).unwrap();
+        assert_eq!(emu.reg(Register::RCX), 0x6677);
+
+        // Accesses outside of memory are dropped (and loads return zero)
+        let stores = emu.step(MacroOp::MovMR(mem(Register::None, RAM_LEN),
+            MemorySize::UInt64, Register::RAX), 8).unwrap();
+        assert!(stores.is_empty());
+        emu.step(MacroOp::MovRM(Register::RCX, mem(Register::None,
+            RAM_LEN - 4), MemorySize::UInt64), 8).unwrap();
+        assert_eq!(emu.reg(Register::RCX), 0);
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut emu = Emulator::new(0x100, Memory::new());
+        emu.set_reg(Register::RSP, 0x2000);
+        let stores = emu.step(MacroOp::CallI(0x200), 5).unwrap();
+        assert_eq!(emu.pc, 0x200);
+        assert_eq!(emu.reg(Register::RSP), 0x1ff8);
+        assert_eq!(stores, [CommittedStore::new(0x1ff8, 8, 0x105)]);
+
+        emu.step(MacroOp::CmpRR(ALUOp::Sub, Register::RAX, Register::RAX),
+            3).unwrap();
+        emu.step(MacroOp::Jcc(ConditionCode::ne, 0x300), 2).unwrap();
+        assert_eq!(emu.pc, 0x205);
+        emu.step(MacroOp::Jcc(ConditionCode::e, 0x300), 2).unwrap();
+        assert_eq!(emu.pc, 0x300);
+
+        // Indirect call through the return address on the stack
+        emu.step(MacroOp::CallM(mem(Register::RSP, 0)), 3).unwrap();
+        assert_eq!(emu.pc, 0x105);
+        assert_eq!(emu.reg(Register::RSP), 0x1ff0);
+        emu.step(MacroOp::Ret, 1).unwrap();
+        assert_eq!(emu.pc, 0x303);
+        assert_eq!(emu.reg(Register::RSP), 0x1ff8);
+
+        assert!(emu.step(MacroOp::Ud2, 2).is_err());
+        assert_eq!(emu.pc, 0x303);
+    }
+}
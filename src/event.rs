@@ -0,0 +1,430 @@
+//! Structured pipeline events.
+//!
+//! Each stage reports what happens to instructions as a stream of typed
+//! [Event]s, which are passed to every [EventSink] attached to the
+//! [Tracer]. The human-readable trace is just one of these sinks
+//! ([TextSink]).
+
+use std::io::{ self, Write };
+use serde::Serialize;
+use serde_json::json;
+
+use crate::op::*;
+use crate::elf::*;
+use crate::log::Logger;
+
+/// A pipeline stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Fetch,
+    Decode,
+    Dispatch,
+    Issue,
+    Retire,
+}
+impl Stage {
+    /// Tag used for this stage in the text trace.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Self::Fetch    => "IFU",
+            Self::Decode   => "IDU",
+            Self::Dispatch => "SCH",
+            Self::Issue    => "ISS",
+            Self::Retire   => "RCU",
+        }
+    }
+}
+
+/// The reason that a stage could not make progress on some cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StallReason {
+    FtqEmpty,
+    IbqFull,
+    ItlbMiss,
+    /// Fetch from an unmapped address, or from outside memory (waiting
+    /// for a redirect)
+    FetchFault,
+    IcacheMiss,
+    IbqEmpty,
+    OpqFull,
+    RobFull,
+    PrfFull,
+    AluSchedFull,
+    AguSchedFull,
+    LqFull,
+    SqFull,
+    /// All ALUs are busy
+    NoFreeAlu,
+    /// The oldest micro-op in the reorder buffer hasn't completed
+    Incomplete,
+}
+impl StallReason {
+    pub fn desc(&self) -> &'static str {
+        match self {
+            Self::FtqEmpty     => "empty FTQ",
+            Self::IbqFull      => "full IBQ",
+            Self::ItlbMiss     => "ITLB miss",
+            Self::FetchFault   => "fetch fault",
+            Self::IcacheMiss   => "L1I miss",
+            Self::IbqEmpty     => "IBQ entries",
+            Self::OpqFull      => "full OPQ",
+            Self::RobFull      => "ROB allocation",
+            Self::PrfFull      => "physical register allocation",
+            Self::AluSchedFull => "ALU scheduler allocation",
+            Self::AguSchedFull => "AGU scheduler allocation",
+            Self::LqFull       => "load queue allocation",
+            Self::SqFull       => "store queue allocation",
+            Self::NoFreeAlu    => "ALU availability",
+            Self::Incomplete   => "incomplete micro-op",
+        }
+    }
+}
+
+/// The execution resource handling a micro-op.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecUnit {
+    Alu(usize),
+    Agu(usize),
+    /// The load/store queue (for loads and stores after address
+    /// generation)
+    Lsq,
+}
+impl ExecUnit {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Self::Alu(_) => "ALU",
+            Self::Agu(_) => "AGU",
+            Self::Lsq    => "LSQ",
+        }
+    }
+}
+impl std::fmt::Display for ExecUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Alu(i) => write!(f, "ALU{}", i),
+            Self::Agu(i) => write!(f, "AGU{}", i),
+            Self::Lsq    => write!(f, "LSQ"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum EventKind {
+    /// A 32-byte fetch block was read from the L1I
+    Fetch { paddr: usize },
+    /// An instruction was decoded (or delivered by the op cache)
+    Decode { op: MacroOp, oc: bool },
+    /// A micro-op was allocated in the reorder buffer and a scheduler.
+    /// 'last' is set for the last micro-op of an instruction.
+    Dispatch { kind: UopKind, last: bool },
+    Issue { unit: ExecUnit },
+    Complete { unit: ExecUnit },
+    Retire,
+    /// The back-end redirected the front-end to 'pc', squashing all
+    /// younger micro-ops (and everything in the front-end)
+    Redirect { inclusive: bool },
+    /// A micro-op was squashed after a redirect
+    Flush,
+    Stall { stage: Stage, reason: StallReason },
+}
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fetch { .. }    => "fetch",
+            Self::Decode { .. }   => "decode",
+            Self::Dispatch { .. } => "dispatch",
+            Self::Issue { .. }    => "issue",
+            Self::Complete { .. } => "complete",
+            Self::Retire          => "retire",
+            Self::Redirect { .. } => "redirect",
+            Self::Flush           => "flush",
+            Self::Stall { .. }    => "stall",
+        }
+    }
+}
+
+/// Something which happened in the pipeline.
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    pub clk: usize,
+    /// Address of the instruction (if any)
+    pub pc: Option<usize>,
+    /// Reorder buffer index (for dispatched micro-ops)
+    pub rob_idx: Option<usize>,
+    /// Micro-op sequence number (for dispatched micro-ops)
+    pub id: Option<usize>,
+    pub kind: EventKind,
+}
+
+/// A consumer of pipeline events.
+pub trait EventSink: Send {
+    fn event(&mut self, ev: &Event, syms: &SymbolTable);
+
+    /// Called once after the simulation has finished, returning the first
+    /// error from writing any output.
+    fn finish(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// Passes events from the pipeline to a set of sinks.
+pub struct Tracer {
+    /// The current cycle
+    pub clk: usize,
+    /// Symbols for the loaded program (if any)
+    pub syms: SymbolTable,
+    /// Trace output (for [TextSink], and for [trace!] and [detail!])
+    pub log: Logger,
+    pub sinks: Vec<Box<dyn EventSink>>,
+}
+impl Tracer {
+    /// Create a tracer without any sinks (and with tracing disabled).
+    pub fn new() -> Self {
+        Self { 
+            clk: 0, 
+            syms: SymbolTable::default(), 
+            log: Logger::new(), 
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn emit(&mut self, ev: Event) {
+        for sink in self.sinks.iter_mut() {
+            sink.event(&ev, &self.syms);
+        }
+    }
+
+    /// Emit an event for an instruction in the front-end.
+    pub fn inst(&mut self, pc: usize, kind: EventKind) {
+        self.emit(Event {
+            clk: self.clk, pc: Some(pc), rob_idx: None, id: None, kind
+        });
+    }
+
+    /// Emit an event for a micro-op in the reorder buffer.
+    pub fn uop(&mut self, uop: &Uop, rob_idx: usize, kind: EventKind) {
+        self.emit(Event {
+            clk: self.clk, pc: Some(uop.addr), rob_idx: Some(rob_idx),
+            id: Some(uop.id), kind
+        });
+    }
+
+    /// Emit a stall which isn't associated with a particular micro-op.
+    pub fn stall(&mut self, stage: Stage, reason: StallReason,
+                 pc: Option<usize>)
+    {
+        self.emit(Event {
+            clk: self.clk, pc, rob_idx: None, id: None,
+            kind: EventKind::Stall { stage, reason },
+        });
+    }
+
+    /// Finish all of the sinks and flush the trace output, returning the
+    /// first error from any of them.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut res = Ok(());
+        for sink in self.sinks.iter_mut() {
+            res = res.and(sink.finish());
+        }
+        res.and(self.log.flush())
+    }
+}
+impl Default for Tracer {
+    fn default() -> Self { Self::new() }
+}
+
+/// Writes events to the human-readable trace (see [crate::log]).
+///
+/// NOTE: This isn't added to a [Tracer] by default.
+pub struct TextSink;
+impl EventSink for TextSink {
+    fn event(&mut self, ev: &Event, syms: &SymbolTable) {
+        use EventKind::*;
+        let pc = ev.pc.unwrap_or(0);
+        let sym = match syms.lookup(pc) {
+            Some(_) => format!(" <{}>", syms.fmt(pc)),
+            None => String::new(),
+        };
+        let uop = match (ev.rob_idx, ev.id) {
+            (Some(rob_idx), Some(id)) =>
+                format!(" rob_idx={} id={}", rob_idx, id),
+            _ => String::new(),
+        };
+        match ev.kind {
+            Fetch { paddr } => trace!("[IFU] Fetching 32b at {:08x} ({:08x})",
+                pc, paddr),
+            Decode { op, oc: false } => trace!("[IDU] Decoded {:08x}{} {:?}",
+                pc, sym, op),
+            Decode { op, oc: true } => trace!("[OC] Delivered {:08x}{} {:?}",
+                pc, sym, op),
+            Dispatch { kind, .. } => trace!("[SCH] Dispatched {:08x}{} {:?}{}",
+                pc, sym, kind, uop),
+            Issue { unit } => trace!("[ISS] {} issued {:08x}{}{}",
+                unit, pc, sym, uop),
+            Complete { unit } => trace!("[{}] Completed {:08x}{}{}",
+                unit.tag(), pc, sym, uop),
+            Retire => trace!("[RCU] Retired {:08x}{}{}", pc, sym, uop),
+            Redirect { inclusive } => trace!("[FLUSH] Redirected to {:08x}{} \
+                after rob_idx={}{}", pc, sym, ev.rob_idx.unwrap_or(0),
+                if inclusive { " (inclusive)" } else { "" }),
+            Flush => trace!("[FLUSH] Squashed {:08x}{}{}", pc, sym, uop),
+            Stall { stage, reason } => match ev.pc {
+                Some(pc) => trace!("[{}] Stalled for {} on {:08x}{}{}",
+                    stage.tag(), reason.desc(), pc, sym, uop),
+                None => trace!("[{}] Stalled for {}",
+                    stage.tag(), reason.desc()),
+            },
+        }
+    }
+}
+
+/// Writes events as JSON, one object per line.
+///
+/// NOTE: Nothing else is written after an error, which is returned by
+/// [EventSink::finish].
+pub struct JsonSink {
+    out: Box<dyn Write + Send>,
+    err: Option<io::Error>,
+}
+impl JsonSink {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out, err: None }
+    }
+}
+impl EventSink for JsonSink {
+    fn event(&mut self, ev: &Event, syms: &SymbolTable) {
+        use EventKind::*;
+        if self.err.is_some() {
+            return;
+        }
+        let mut obj = json!({
+            "clk": ev.clk,
+            "event": ev.kind.name(),
+            "pc": ev.pc,
+            "rob_idx": ev.rob_idx,
+            "id": ev.id,
+        });
+        if let Some(pc) = ev.pc.filter(|pc| syms.lookup(*pc).is_some()) {
+            obj["sym"] = json!(syms.fmt(pc));
+        }
+        match ev.kind {
+            Fetch { paddr } => obj["paddr"] = json!(paddr),
+            Decode { op, oc } => {
+                obj["op"] = json!(format!("{:?}", op));
+                obj["oc"] = json!(oc);
+            },
+            Dispatch { kind, last } => {
+                obj["uop"] = json!(format!("{:?}", kind));
+                obj["last"] = json!(last);
+            },
+            Redirect { inclusive } => obj["inclusive"] = json!(inclusive),
+            Issue { unit } | Complete { unit } => {
+                obj["unit"] = json!(unit.to_string());
+            },
+            Stall { stage, reason } => {
+                obj["stage"] = json!(stage);
+                obj["reason"] = json!(reason);
+            },
+            Retire | Flush => {},
+        }
+        if let Err(e) = writeln!(self.out, "{}", obj) {
+            self.err = Some(e);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.err.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+
+    /// Output which can be read back after the events are written.
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+    impl Buf {
+        fn lines(&self) -> Vec<String> {
+            let data = self.0.lock().unwrap();
+            String::from_utf8_lossy(&data).lines().map(String::from)
+                .collect()
+        }
+    }
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    /// Output which fails on every write.
+    struct Broken;
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn with_syms() -> Tracer {
+        let mut tracer = Tracer::new();
+        tracer.syms = SymbolTable::new(vec![Symbol {
+            addr: 0x1000, size: 0x10, name: "main".to_string(),
+        }]);
+        tracer
+    }
+
+    #[test]
+    fn json() {
+        let buf = Buf::default();
+        let mut tracer = with_syms();
+        tracer.add_sink(Box::new(JsonSink::new(Box::new(buf.clone()))));
+        tracer.clk = 3;
+        tracer.inst(0x1004, EventKind::Fetch { paddr: 0x5004 });
+        tracer.stall(Stage::Dispatch, StallReason::RobFull, None);
+        tracer.finish().unwrap();
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 2);
+        let ev: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(ev, json!({
+            "clk": 3, "event": "fetch", "pc": 0x1004, "rob_idx": null,
+            "id": null, "sym": "main+0x4", "paddr": 0x5004,
+        }));
+        let ev: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(ev["stage"], "dispatch");
+        assert_eq!(ev["reason"], "rob_full");
+
+        let mut tracer = with_syms();
+        tracer.add_sink(Box::new(JsonSink::new(Box::new(Broken))));
+        tracer.inst(0x1000, EventKind::Retire);
+        assert!(tracer.finish().is_err());
+    }
+
+    #[test]
+    fn text() {
+        let buf = Buf::default();
+        let mut tracer = with_syms();
+        tracer.add_sink(Box::new(TextSink));
+        tracer.log.set_output(Box::new(buf.clone()));
+        tracer.log.set_stage_level("ifu", crate::log::LEVEL_EVENT);
+
+        let mut log = std::mem::take(&mut tracer.log);
+        crate::log::scope(&mut log, || {
+            tracer.inst(0x1000, EventKind::Fetch { paddr: 0x1000 });
+            tracer.inst(0x1000, EventKind::Retire);
+        });
+        tracer.log = log;
+        tracer.finish().unwrap();
+        assert_eq!(buf.lines(), ["[IFU] Fetching 32b at 00001000 (00001000)"]);
+    }
+}
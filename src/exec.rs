@@ -1,43 +1,179 @@
 
-use crate::mem::clk;
+use crate::mem::*;
 use crate::op::*;
 use crate::issue::*;
 use crate::retire::*;
 use crate::rf::*;
+use crate::pipeline::*;
+use crate::flags::*;
+use crate::lsq::*;
+use crate::hier::*;
+use crate::mmu::*;
+use crate::event::*;
 
 pub struct ExecutionUnits {
-    pub alu: [ALU; 4],
+    pub alu: Vec<ALU>,
+    pub agu: Vec<AGU>,
+    /// Result tags (and reorder buffer indexes) for micro-ops which 
+    /// completed this cycle, to be broadcast on the next cycle
+    pub wakeup: Vec<(usize, Prn)>,
 }
 impl ExecutionUnits {
-    pub fn new() -> Self {
+    pub fn new(num_alus: usize, num_agus: usize) -> Self {
         Self {
-            alu: [ALU::new(); 4],
+            alu: vec![ALU::new(); num_alus],
+            agu: vec![AGU::new(); num_agus],
+            wakeup: Vec::new(),
         }
     }
+
+    /// Broadcast the result tags for micro-ops which completed on the 
+    /// previous cycle, waking up any dependent micro-ops waiting in the 
+    /// schedulers.
+    ///
+    /// NOTE: This happens at the start of a cycle (before retirement), so
+    /// dependent micro-ops issue on the cycle after their producers 
+    /// complete.
+    pub fn wakeup(&mut self, prf: &mut PhysicalRegisterFile) {
+        for (_, prn) in self.wakeup.drain(..) {
+            trace!("[ISS] Broadcast {:?}", prn);
+            prf.set_ready(prn);
+        }
+    }
+
+    /// Complete any micro-ops that have finished executing this cycle.
+    ///
+    /// Branches are resolved here: if the outcome of a branch doesn't match
+    /// the prediction made by the front-end, this returns a [Redirect] 
+    /// for the oldest mispredicted branch. Memory-ordering violations 
+    /// detected when a store address is generated are also reported here.
+    #[allow(clippy::too_many_arguments)]
     pub fn cycle(&mut self, 
         rob: &mut ReorderBuffer, 
-        prf: &mut PhysicalRegisterFile
-    ) {
+        prf: &mut PhysicalRegisterFile,
+        lsq: &mut LoadStoreQueue,
+        caches: &mut CacheHierarchy,
+        mmu: &mut Mmu,
+        mem: &Memory,
+        tr: &mut Tracer,
+        clk: usize,
+    ) -> Option<Redirect> {
 
+        let mut redirect: Option<Redirect> = None;
         for (idx, tgt_alu) in self.alu.iter_mut().enumerate() {
-            let res = tgt_alu.cycle(rob, prf);
+            let res = tgt_alu.cycle(prf, clk);
             match res {
-                Ok(comp) => {
-                    println!("[ALU] {:08x}: {:?}, rob_idx={} complete", 
-                        comp.uop.addr, comp.uop.kind, comp.rob_idx);
+                Ok(Completion { res: comp, npc }) => {
+                    tr.uop(&comp.uop, comp.rob_idx, 
+                        EventKind::Complete { unit: ExecUnit::Alu(idx) });
 
-                    rob.get_mut(comp.rob_idx).unwrap().complete = true;
+                    // Result tags are broadcast on the next cycle
+                    let dests = comp.uop.eff.iter().filter_map(|e| e.dest());
+                    for (_, prn) in dests {
+                        self.wakeup.push((comp.rob_idx, prn));
+                    }
+
+                    let rob_ent = rob.get_mut(comp.rob_idx).unwrap();
+                    rob_ent.complete = true;
+
+                    // Compare the outcome of a branch to the prediction
+                    rob_ent.npc = npc;
+                    if let Some(tgt) = npc {
+                        let pred_npc = rob_ent.pred.tgt
+                            .unwrap_or(comp.uop.next_addr());
+                        if pred_npc != tgt {
+                            trace!("[ALU] {:08x}: mispredicted ({:x?}, {:08x})",
+                                comp.uop.addr, rob_ent.pred.tgt, tgt);
+                            let older = redirect.is_none_or(|r| 
+                                rob.is_younger(r.rob_idx, comp.rob_idx)
+                            );
+                            if older {
+                                redirect = Some(Redirect { 
+                                    rob_idx: comp.rob_idx, tgt, 
+                                    inclusive: false,
+                                });
+                            }
+                        }
+                    }
                 },
                 Err(ALUErr::PendingCompletion) => {
                     let op = tgt_alu.op.unwrap();
-                    println!("[ALU] {:08x}: {:?}", op.uop.addr, op.uop.kind);
+                    detail!("[ALU] {:08x}: {:?}", op.uop.addr, op.uop.kind);
                 },
                 Err(ALUErr::Empty) => {},
             }
         }
+
+        // Addresses for loads and stores are sent to the load/store queue.
+        // Stores are complete after their address is translated, and are 
+        // written back to memory after retirement.
+        for agu in self.agu.iter_mut() {
+            if let Some(comp) = agu.cycle(prf, lsq, clk) {
+                // A younger load already read stale data: replay it
+                // (along with everything younger) from the front-end.
+                //
+                // NOTE: Loads are always the first micro-op in a macro-op,
+                // so the whole macro-op is refetched from its address.
+                if let Some(ld_idx) = comp.violation {
+                    let older = redirect.is_none_or(|r| 
+                        rob.is_younger(r.rob_idx, ld_idx)
+                    );
+                    if older {
+                        let tgt = rob.get(ld_idx).unwrap().uop.addr;
+                        redirect = Some(Redirect { 
+                            rob_idx: ld_idx, tgt, inclusive: true,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Loads are complete when their data has been returned (and their
+        // result tags are broadcast on the next cycle)
+        for (rob_idx, prn) in lsq.cycle(prf, caches, mmu, mem, clk) {
+            if let Some(prn) = prn {
+                self.wakeup.push((rob_idx, prn));
+            }
+            let rob_ent = rob.get_mut(rob_idx).unwrap();
+            rob_ent.complete = true;
+            tr.uop(&rob_ent.uop, rob_idx, 
+                EventKind::Complete { unit: ExecUnit::Lsq });
+        }
+        redirect
+    }
+
+    /// Discard any in-flight operations (and pending result tags) for 
+    /// reorder buffer entries matching some predicate.
+    pub fn squash(&mut self, f: impl Fn(usize) -> bool) {
+        for alu in self.alu.iter_mut() {
+            if alu.op.as_ref().is_some_and(|r| f(r.rob_idx)) {
+                alu.op = None;
+            }
+        }
+        for agu in self.agu.iter_mut() {
+            if agu.op.as_ref().is_some_and(|r| f(r.rob_idx)) {
+                agu.op = None;
+            }
+        }
+        self.wakeup.retain(|(rob_idx, _)| !f(*rob_idx));
     }
 }
 
+/// A micro-op which has finished executing.
+pub struct Completion {
+    pub res: Reservation,
+    /// The resolved next program counter (only for branch micro-ops)
+    pub npc: Option<usize>,
+}
+
+/// A load or store micro-op which has finished address generation.
+pub struct AGUCompletion {
+    pub res: Reservation,
+    /// Reorder buffer index of a younger load which violated memory 
+    /// ordering with this store (only for store micro-ops)
+    pub violation: Option<usize>,
+}
+
 
 pub enum ALUErr {
     PendingCompletion,
@@ -60,56 +196,112 @@ impl ALU {
 
     pub fn busy(&self) -> bool { self.op.is_some() }
 
-    pub fn cycle(&mut self, rob: &mut ReorderBuffer, 
-                 prf: &mut PhysicalRegisterFile) 
-        -> Result<Reservation, ALUErr>
+    pub fn cycle(&mut self, prf: &mut PhysicalRegisterFile, clk: usize) 
+        -> Result<Completion, ALUErr>
     {
         if let Some(tgt) = self.op {
             // Determine if this operation needs to be completed this cycle
             // (according to our assumptions about micro-op latencies)
-            if (clk() - self.cycle_in) >= tgt.uop.latency() {
+            if (clk - self.cycle_in) >= tgt.uop.latency() {
                 let alu_op = {
                     if let UopKind::Alu(alu_op) = tgt.uop.kind { alu_op }
-                    else { unreachable!() }
+                    else { unreachable!("{:?} issued to an ALU", tgt.uop.kind) }
                 };
 
                 // Short circuit for NOPs
-                if alu_op == ALUOp::Nop || alu_op == ALUOp::Brn {
+                if alu_op == ALUOp::Nop {
                     self.op = None;
-                    return Ok(tgt);
+                    return Ok(Completion { res: tgt, npc: None });
+                }
+
+                // Flags are read from the third and fourth operands
+                let flags = tgt.uop.arg[2..4].iter().fold(0, |f, a| {
+                    if let Storage::Prn(rs) = a { f | prf.read(*rs) } else { f }
+                });
+
+                // Resolve the target address for branches
+                if alu_op == ALUOp::Brn {
+                    let npc = match (tgt.uop.eff[0], tgt.uop.arg[0]) {
+                        (Effect::BrnImm(imm), _) => imm,
+                        (Effect::BrnCond(cc, imm), _) => {
+                            let taken = eval_cond(cc, flags);
+                            if taken { imm } else { tgt.uop.next_addr() }
+                        },
+                        (Effect::BrnInd, Storage::Prn(rs)) => prf.read(rs),
+                        // Branch micro-ops are only created with these 
+                        // effects (see [Uop::from_mop])
+                        (eff, arg) => {
+                            unreachable!("branch with {:?} ({:?})", eff, arg)
+                        },
+                    };
+                    self.op = None;
+                    return Ok(Completion { res: tgt, npc: Some(npc) });
                 }
 
                 let x = match tgt.uop.arg[0] {
                     Storage::Imm64(v)  => v as usize,
                     Storage::Zero      => 0,
                     Storage::Prn(rs)   => prf.read(rs),
-                    Storage::Bypass(_) => unimplemented!(),
-                    Storage::Arn(_)    => unreachable!(),
-                    Storage::None      => unreachable!(),
+                    // Sources are renamed at dispatch
+                    Storage::Arn(_) | Storage::Tmp(_) | Storage::Flags(_) => {
+                        unreachable!("{:?} wasn't renamed", tgt.uop.arg[0])
+                    },
+                    Storage::None => unreachable!("missing operand"),
                 };
                 let y = match tgt.uop.arg[1] {
                     Storage::Imm64(v)  => v as usize,
                     Storage::Zero      => 0,
                     Storage::Prn(rs)   => prf.read(rs),
-                    Storage::Bypass(_) => unimplemented!(),
-                    Storage::Arn(_)    => unreachable!(),
-                    Storage::None      => unreachable!(),
+                    // Sources are renamed at dispatch
+                    Storage::Arn(_) | Storage::Tmp(_) | Storage::Flags(_) => {
+                        unreachable!("{:?} wasn't renamed", tgt.uop.arg[1])
+                    },
+                    Storage::None => unreachable!("missing operand"),
+                };
+
+                // Operands and results are truncated to the operand size,
+                // and 8/16-bit results are merged with the old value of 
+                // the destination (from the fifth operand)
+                let width = tgt.uop.width;
+                let mask = width_mask(width);
+                let (x, y) = (x & mask, y & mask);
+                let old = match tgt.uop.arg[4] {
+                    Storage::Prn(rs) => prf.read(rs),
+                    _ => 0,
                 };
 
                 // Perform the actual computation
                 let res = match alu_op {
                     ALUOp::Add => x.wrapping_add(y),
-                    _ => unimplemented!(),
-                };
+                    ALUOp::Sub => x.wrapping_sub(y),
+                    ALUOp::Or  => x | y,
+                    ALUOp::And => x & y,
+                    ALUOp::Xor => x ^ y,
+                    ALUOp::Shl => x << shift_count(y, width),
+                    ALUOp::Shr => x >> shift_count(y, width),
+                    ALUOp::Cmov(cc) => if eval_cond(cc, flags) { y } else { x },
+                    ALUOp::Nop | ALUOp::Brn => unreachable!("handled above"),
+                } & mask;
 
                 // Phyiscal register file write 
-                if let Effect::RegWrite(arn, prn) = tgt.uop.eff[0] {
-                    println!("[ALU] PRF write {:016x} to {:?}", res, prn);
-                    prf.write(prn, res);
+                for eff in tgt.uop.eff {
+                    let val = match eff {
+                        Effect::RegWrite(..) => merge_gpr(width, old, res),
+                        Effect::TmpWrite(..) => res,
+                        Effect::FlagsWrite(g, _) => {
+                            alu_flags(alu_op, x, y, res, flags, width) 
+                                & g.mask()
+                        },
+                        _ => continue,
+                    };
+                    let (arn, prn) = eff.dest().unwrap();
+                    trace!("[ALU] PRF write {:016x} to {:?} ({:?})", 
+                        val, prn, arn);
+                    prf.write(prn, val);
                 }
 
                 self.op = None;
-                Ok(tgt)
+                Ok(Completion { res: tgt, npc: None })
             } else {
                 Err(ALUErr::PendingCompletion)
             }
@@ -124,3 +316,128 @@ impl ALU {
         self.cycle_in = cyc;
     }
 }
+impl Default for ALU {
+    fn default() -> Self { Self::new() }
+}
+
+/// Address generation unit.
+#[derive(Debug, Copy, Clone)]
+pub struct AGU {
+    /// Micro-op currently occupying this AGU
+    pub op: Option<Reservation>,
+    /// The cycle number that an operation started on
+    pub cycle_in: usize,
+}
+impl AGU {
+    pub fn new() -> Self {
+        Self { op: None, cycle_in: 0 }
+    }
+
+    pub fn busy(&self) -> bool { self.op.is_some() }
+
+    /// Generate the address for a load or store, returning the micro-op
+    /// after it has been sent to the load/store queue.
+    pub fn cycle(&mut self, prf: &PhysicalRegisterFile, 
+                 lsq: &mut LoadStoreQueue, clk: usize) 
+        -> Option<AGUCompletion>
+    {
+        let tgt = self.op?;
+        if clk - self.cycle_in < 1 {
+            return None;
+        }
+        let val = |arg: Storage| match arg {
+            Storage::Imm64(v) => v as usize,
+            Storage::Prn(rs)  => prf.read(rs),
+            Storage::Zero | Storage::None => 0,
+            _ => unreachable!("{:?}", arg),
+        };
+
+        // The address is (base + index * scale + displacement)
+        let [base, idx, disp, data, scale] = tgt.uop.arg.map(val);
+        let addr = base.wrapping_add(idx.wrapping_mul(scale))
+            .wrapping_add(disp);
+        let violation = match tgt.uop.kind {
+            UopKind::Agu(AGUOp::Ld(_)) => {
+                trace!("[AGU] {:08x}: load address {:016x}", 
+                    tgt.uop.addr, addr);
+                lsq.set_load_addr(tgt.rob_idx, addr);
+                None
+            },
+            UopKind::Agu(AGUOp::St(_)) => {
+                trace!("[AGU] {:08x}: store address {:016x} data {:016x}", 
+                    tgt.uop.addr, addr, data);
+                lsq.set_store(tgt.rob_idx, addr, data)
+            },
+            // Only AGU micro-ops are allocated in the AGU scheduler
+            _ => unreachable!("{:?} issued to an AGU", tgt.uop.kind),
+        };
+        self.op = None;
+        Some(AGUCompletion { res: tgt, violation })
+    }
+
+    pub fn do_issue(&mut self, cyc: usize, tgt: Reservation) {
+        assert!(self.op.is_none());
+        self.op = Some(tgt);
+        self.cycle_in = cyc;
+    }
+}
+impl Default for AGU {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::bp::Prediction;
+    use iced_x86::Register;
+
+    /// Issue 'mov eax, 1' to an ALU and run until it completes, returning 
+    /// its reorder buffer index and destination register.
+    fn complete_mov(eu: &mut ExecutionUnits, rob: &mut ReorderBuffer,
+        prf: &mut PhysicalRegisterFile) -> (usize, Prn)
+    {
+        let prn = prf.alloc().unwrap();
+        let mop = MacroOp::MovRI(Register::RAX, 1);
+        let mut uop = Uop::from_mop(mop, 0, 5)[0];
+        uop.eff[0] = Effect::RegWrite(Register::RAX, prn);
+        let pred = Prediction::default();
+        let rob_idx = rob.push(ROBEntry::new(mop, uop, pred, true)).unwrap();
+        eu.alu[0].do_issue(0, Reservation { mop, uop, rob_idx });
+        eu.cycle(rob, prf, &mut LoadStoreQueue::new(&LsqConfig::default()), 
+            &mut CacheHierarchy::new(&CachesConfig::default()),
+            &mut Mmu::new(None, &TlbConfig::default()), &Memory::new(), 
+            &mut Tracer::new(), 1);
+        assert!(rob.get_mut(rob_idx).unwrap().complete);
+        (rob_idx, prn)
+    }
+
+    #[test]
+    fn wakeup_on_next_cycle() {
+        let mut prf = PhysicalRegisterFile::new(180);
+        let mut rob = ReorderBuffer::new(4);
+        let mut eu = ExecutionUnits::new(4, 3);
+
+        // The producer completes, but its tag isn't broadcast until the 
+        // start of the next cycle
+        let (_, prn) = complete_mov(&mut eu, &mut rob, &mut prf);
+        assert!(!prf.is_ready(prn));
+
+        eu.wakeup(&mut prf);
+        assert!(prf.is_ready(prn));
+        assert_eq!(prf.read(prn), 1);
+    }
+
+    #[test]
+    fn squash_pending_wakeup() {
+        let mut prf = PhysicalRegisterFile::new(180);
+        let mut rob = ReorderBuffer::new(4);
+        let mut eu = ExecutionUnits::new(4, 3);
+
+        // A squashed producer never broadcasts its tag
+        let (rob_idx, prn) = complete_mov(&mut eu, &mut rob, &mut prf);
+        eu.squash(|idx| idx == rob_idx);
+        eu.wakeup(&mut prf);
+        assert!(!prf.is_ready(prn));
+    }
+}
@@ -0,0 +1,231 @@
+//! Status flags.
+
+use iced_x86::ConditionCode;
+
+use crate::op::ALUOp;
+
+pub const FLAG_CF: usize = 1 << 0;
+pub const FLAG_PF: usize = 1 << 2;
+pub const FLAG_AF: usize = 1 << 4;
+pub const FLAG_ZF: usize = 1 << 6;
+pub const FLAG_SF: usize = 1 << 7;
+pub const FLAG_OF: usize = 1 << 11;
+
+/// Number of independently-renamed groups of flags.
+pub const NUM_FLAG_GROUPS: usize = 2;
+
+/// A group of flags which is renamed as a single unit.
+///
+/// NOTE: CF is renamed separately from the other arithmetic flags, since
+/// instructions like INC and DEC preserve CF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagGroup {
+    /// OF, SF, ZF, AF, and PF
+    Arith,
+    /// CF
+    Carry,
+}
+impl FlagGroup {
+    pub fn mask(&self) -> usize {
+        match self {
+            Self::Arith => FLAG_OF | FLAG_SF | FLAG_ZF | FLAG_AF | FLAG_PF,
+            Self::Carry => FLAG_CF,
+        }
+    }
+
+    /// Return the groups of flags read when evaluating a condition code.
+    pub fn for_cond(cc: ConditionCode) -> (Option<Self>, Option<Self>) {
+        match cc {
+            ConditionCode::b | ConditionCode::ae => (None, Some(Self::Carry)),
+            ConditionCode::be | ConditionCode::a => {
+                (Some(Self::Arith), Some(Self::Carry))
+            },
+            ConditionCode::None => (None, None),
+            _ => (Some(Self::Arith), None),
+        }
+    }
+}
+
+/// Return the number of bits to shift by for some operand size (in bytes).
+///
+/// NOTE: The count is masked to 6 bits for 64-bit operands, and to 5 bits
+/// otherwise.
+pub fn shift_count(y: usize, width: usize) -> usize {
+    if width == 8 { y & 0x3f } else { y & 0x1f }
+}
+
+/// Compute the flags for the result of some ALU operation, given the 
+/// previous value of the flags.
+///
+/// NOTE: Flags are laid out like RFLAGS. The operands and the result must
+/// already be truncated to the operand size 'width' (in bytes). Flags which
+/// are undefined after an operation are cleared.
+pub fn alu_flags(op: ALUOp, x: usize, y: usize, res: usize, old: usize, 
+    width: usize) -> usize 
+{
+    let bits = 8 * width;
+    let sign = |v: usize| (v >> (bits - 1)) & 1;
+    let mut f = 0;
+    match op {
+        ALUOp::Add => {
+            if res < x { f |= FLAG_CF; }
+            if sign((x ^ res) & (y ^ res)) != 0 { f |= FLAG_OF; }
+            if (x ^ y ^ res) & 0x10 != 0 { f |= FLAG_AF; }
+        },
+        ALUOp::Sub => {
+            if x < y { f |= FLAG_CF; }
+            if sign((x ^ y) & (x ^ res)) != 0 { f |= FLAG_OF; }
+            if (x ^ y ^ res) & 0x10 != 0 { f |= FLAG_AF; }
+        },
+        ALUOp::Shl | ALUOp::Shr => {
+            // Shifting by zero leaves the flags unchanged
+            let cnt = shift_count(y, width);
+            if cnt == 0 { 
+                return old; 
+            }
+            let cf = match op {
+                ALUOp::Shl if cnt <= bits => (x >> (bits - cnt)) & 1,
+                ALUOp::Shl => 0,
+                _ => (x >> (cnt - 1)) & 1,
+            };
+            let of = if op == ALUOp::Shl { sign(res) ^ cf } else { sign(x) };
+            if cf != 0 { f |= FLAG_CF; }
+            if of != 0 { f |= FLAG_OF; }
+        },
+        // Logical operations always clear CF and OF
+        ALUOp::And | ALUOp::Or | ALUOp::Xor => {},
+        // These don't write the flags
+        ALUOp::Nop | ALUOp::Brn | ALUOp::Cmov(_) => return old,
+    }
+    if res == 0 { f |= FLAG_ZF; }
+    if sign(res) != 0 { f |= FLAG_SF; }
+    if (res as u8).count_ones().is_multiple_of(2) { f |= FLAG_PF; }
+    f
+}
+
+/// Evaluate a condition code with some flags.
+pub fn eval_cond(cc: ConditionCode, f: usize) -> bool {
+    let cf = f & FLAG_CF != 0;
+    let pf = f & FLAG_PF != 0;
+    let zf = f & FLAG_ZF != 0;
+    let sf = f & FLAG_SF != 0;
+    let of = f & FLAG_OF != 0;
+    match cc {
+        ConditionCode::o  => of,
+        ConditionCode::no => !of,
+        ConditionCode::b  => cf,
+        ConditionCode::ae => !cf,
+        ConditionCode::e  => zf,
+        ConditionCode::ne => !zf,
+        ConditionCode::be => cf || zf,
+        ConditionCode::a  => !cf && !zf,
+        ConditionCode::s  => sf,
+        ConditionCode::ns => !sf,
+        ConditionCode::p  => pf,
+        ConditionCode::np => !pf,
+        ConditionCode::l  => sf != of,
+        ConditionCode::ge => sf == of,
+        ConditionCode::le => zf || (sf != of),
+        ConditionCode::g  => !zf && (sf == of),
+        ConditionCode::None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rf::width_mask;
+
+    /// Compute the flags for an operation with some operand size.
+    fn flags(op: ALUOp, x: usize, y: usize, width: usize) -> usize {
+        let mask = width_mask(width);
+        let (x, y) = (x & mask, y & mask);
+        let res = match op {
+            ALUOp::Add => x.wrapping_add(y),
+            ALUOp::Sub => x.wrapping_sub(y),
+            ALUOp::And => x & y,
+            ALUOp::Shl => x << shift_count(y, width),
+            ALUOp::Shr => x >> shift_count(y, width),
+            _ => unreachable!(),
+        } & mask;
+        alu_flags(op, x, y, res, FLAG_CF | FLAG_OF, width)
+    }
+
+    #[test]
+    fn add() {
+        assert_eq!(flags(ALUOp::Add, 0xff, 1, 1),
+            FLAG_CF | FLAG_ZF | FLAG_AF | FLAG_PF);
+        assert_eq!(flags(ALUOp::Add, 0x7fff_ffff, 1, 4),
+            FLAG_OF | FLAG_SF | FLAG_AF | FLAG_PF);
+        assert_eq!(flags(ALUOp::Add, 0x7fff_ffff, 1, 8), FLAG_AF | FLAG_PF);
+    }
+
+    #[test]
+    fn sub() {
+        assert_eq!(flags(ALUOp::Sub, 0, 1, 8),
+            FLAG_CF | FLAG_SF | FLAG_AF | FLAG_PF);
+        assert_eq!(flags(ALUOp::Sub, 0x8000, 1, 2),
+            FLAG_OF | FLAG_AF | FLAG_PF);
+        assert_eq!(flags(ALUOp::Sub, 5, 5, 4), FLAG_ZF | FLAG_PF);
+    }
+
+    #[test]
+    fn logical() {
+        assert_eq!(flags(ALUOp::And, 0x80, 0xf0, 1), FLAG_SF);
+        assert_eq!(flags(ALUOp::And, 0x100, 0xff, 8), FLAG_ZF | FLAG_PF);
+    }
+
+    #[test]
+    fn shift() {
+        assert_eq!(flags(ALUOp::Shl, 0x81, 1, 1), FLAG_CF | FLAG_OF);
+        assert_eq!(flags(ALUOp::Shr, 0x8000_0001, 1, 4), 
+            FLAG_CF | FLAG_OF | FLAG_PF);
+
+        // The count is masked to 5 bits for 32-bit operands
+        assert_eq!(flags(ALUOp::Shr, 0x8000_0001, 33, 4),
+            flags(ALUOp::Shr, 0x8000_0001, 1, 4));
+        assert_eq!(flags(ALUOp::Shl, 1, 33, 8), FLAG_PF);
+
+        // Shifting by zero leaves the flags unchanged
+        assert_eq!(flags(ALUOp::Shl, 1, 32, 4), FLAG_CF | FLAG_OF);
+    }
+
+    #[test]
+    fn conditions() {
+        use ConditionCode as CC;
+        let check = |f: usize, yes: &[CC], no: &[CC]| {
+            for cc in yes {
+                assert!(eval_cond(*cc, f), "{:?} {:04x}", cc, f);
+            }
+            for cc in no {
+                assert!(!eval_cond(*cc, f), "{:?} {:04x}", cc, f);
+            }
+        };
+
+        // cmp al, 1 (with al = -1)
+        check(flags(ALUOp::Sub, 0xff, 1, 1), 
+            &[CC::l, CC::le, CC::ne, CC::a, CC::ae, CC::s],
+            &[CC::g, CC::ge, CC::e, CC::b, CC::be, CC::o]);
+
+        // cmp eax, eax
+        check(flags(ALUOp::Sub, 7, 7, 4),
+            &[CC::e, CC::be, CC::ge, CC::le, CC::p, CC::ns],
+            &[CC::ne, CC::a, CC::b, CC::l, CC::g, CC::np]);
+
+        // cmp eax, 1 (with eax = INT_MIN) overflows
+        check(flags(ALUOp::Sub, 0x8000_0000, 1, 4),
+            &[CC::o, CC::l, CC::ns, CC::a], 
+            &[CC::no, CC::ge, CC::g, CC::s]);
+        assert!(eval_cond(CC::None, 0));
+    }
+
+    #[test]
+    fn groups_for_cond() {
+        use ConditionCode as CC;
+        let (arith, carry) = (Some(FlagGroup::Arith), Some(FlagGroup::Carry));
+        assert_eq!(FlagGroup::for_cond(CC::b), (None, carry));
+        assert_eq!(FlagGroup::for_cond(CC::a), (arith, carry));
+        assert_eq!(FlagGroup::for_cond(CC::l), (arith, None));
+        assert_eq!(FlagGroup::for_cond(CC::None), (None, None));
+    }
+}
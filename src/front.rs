@@ -1,45 +1,91 @@
 
-use std::collections::BTreeMap;
-
+use std::io::{ self, Write };
 use crate::util::*;
+use crate::bp::*;
 use crate::mem::*;
 use crate::op::*;
 use crate::dispatch::*;
-use crate::rf::*;
-use iced_x86::{
-    Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter,
-    ConditionCode, InstructionInfoFactory, OpKind, RflagsBits,
-    Mnemonic, Register, Code, MemorySize
-};
-
-pub struct NextPCLogic;
+use crate::hier::*;
+use crate::opcache::*;
+use crate::mmu::*;
+use crate::event::*;
+use iced_x86::{ Decoder, DecoderOptions, Instruction, OpKind };
+
+pub struct NextPCLogic {
+    /// Number of cycles remaining until the next-PC logic can proceed
+    pub stall: usize,
+    /// Number of cycles that the next-PC logic is stalled after being 
+    /// redirected by the back-end (in addition to the latency of the 
+    /// front-end itself)
+    pub redirect_penalty: usize,
+}
 impl NextPCLogic {
+    pub fn new(redirect_penalty: usize) -> Self {
+        Self { stall: 0, redirect_penalty }
+    }
+
+    /// Restart the front-end at some new target address.
+    pub fn redirect(&mut self, pc: &mut usize, tgt: usize, penalty: usize) {
+        trace!("[NPC] Redirected to {:08x}", tgt);
+        *pc = tgt;
+        self.stall = penalty;
+    }
+
     pub fn cycle(&mut self, 
         pc: &mut usize,
-        pq: &mut Queue<usize>, 
-        ftq: &mut Queue<usize>
+        bpu: &mut BranchPredictionUnit,
+        btb: &mut BranchTargetBuffer,
+        ftq: &mut Queue<FTQEntry>
     ) {
 
+        if self.stall != 0 {
+            trace!("[NPC] Stalled for redirect ({} cycles left)", self.stall);
+            self.stall -= 1;
+            return;
+        }
+
         if ftq.is_full() {
-            println!("[NPC] Stalled for full FTQ");
+            trace!("[NPC] Stalled for full FTQ");
             return; 
         }
 
-        // If a prediction is queued up, send it to the FTQ
-        if let Ok(p) = pq.pop() {
-            println!("[FTQ] Using predicted address {:08x}", p);
-            ftq.push(p).unwrap();
-            *pc = p;
-        } 
-        // Otherwise, send the next-sequential fetch block address
-        else {
-            println!("[FTQ] Using next-sequential address {:08x}", pc);
-            ftq.push(*pc).unwrap();
-            *pc += 0x20;
+        // Ask the branch predictor where this fetch block ends, and send
+        // it to the FTQ
+        let (ent, next, bubbles) = bpu.predict(btb, *pc);
+        trace!("[FTQ] Pushed fetch block {:08x} [{:02x}..{:02x}]", 
+                 ent.addr, ent.start, ent.end);
+        ftq.push(ent).unwrap();
+
+        // Continue with the predicted target address, or otherwise with 
+        // the next-sequential fetch block address
+        if ent.end != 0x20 {
+            trace!("[NPC] Using predicted address {:08x}", next);
+        } else {
+            trace!("[NPC] Using next-sequential address {:08x}", next);
         }
+        *pc = next;
+        self.stall = bubbles;
     }
 }
 
+/// Entry in the fetch target queue, describing a 32-byte fetch block.
+#[derive(Copy, Clone, Debug)]
+pub struct FTQEntry {
+    /// 32B-aligned address of the fetch block
+    pub addr: usize,
+    /// Offset of the first valid byte in the block
+    pub start: usize,
+    /// Offset of the last valid byte in the block (exclusive)
+    pub end: usize,
+    /// Predictions for the branches in this block: (branch address, 
+    /// prediction). A predicted-taken branch terminates the block.
+    pub preds: [Option<(usize, Prediction)>; 2],
+    /// Checkpoint of the branch predictor at the start of this block
+    pub cp: SpecCheckpoint,
+    /// The address of this block is outside memory (see [FetchUnit::cycle])
+    pub fault: bool,
+}
+
 
 /// A 64-byte cache line.
 #[derive(Copy, Clone)]
@@ -47,16 +93,72 @@ pub struct CacheLine { pub addr: usize, pub data: [u8; 64] }
 
 /// Entry in the instruction byte queue.
 #[derive(Copy, Clone)]
-pub struct IBQEntry { pub addr: usize, pub data: [u8; 16] }
+pub struct IBQEntry { 
+    pub addr: usize, 
+    pub data: [u8; 16],
+    /// Offset of the first valid byte
+    pub start: usize,
+    /// Offset of the last valid byte (exclusive)
+    pub end: usize,
+    /// Branch predictions from the associated fetch block
+    pub preds: [Option<(usize, Prediction)>; 2],
+    /// Checkpoint of the branch predictor at the start of the fetch block
+    pub cp: SpecCheckpoint,
+    /// The associated fetch block is outside memory (there are no valid 
+    /// bytes, and the entry stands for the rest of the block)
+    pub fault: bool,
+}
+
+/// Counters for fetch blocks, and for cycles where the fetch unit didn't
+/// push any bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchStats {
+    /// Cycles where a fetch block was sent to the IBQ
+    pub fetches: usize,
+    /// Fetch blocks which missed in the L1I
+    pub block_misses: usize,
+    /// Cycles stalled for an L1I miss
+    pub icache_miss: usize,
+    /// Cycles stalled for an ITLB miss
+    pub itlb_miss: usize,
+    /// Cycles stalled for an empty FTQ
+    pub ftq_empty: usize,
+    /// Cycles stalled for a full IBQ
+    pub ibq_full: usize,
+}
 
 /// Abstract representation of the instruction fetch unit.
 ///
 /// NOTE: For now, we're assuming that the fetch unit *always* pushes the
 /// whole window into the IBQ; otherwise, if there's no room for both entries,
 /// the fetch unit is stalled. 
-pub struct FetchUnit;
+pub struct FetchUnit {
+    /// The line that the head of the FTQ is waiting on (if any)
+    pub miss: Option<usize>,
+    pub stats: FetchStats,
+}
 impl FetchUnit {
-    pub fn cycle(&mut self, ftq: &mut Queue<usize>, ibq: &mut Queue<IBQEntry>) {
+    pub fn new() -> Self {
+        Self { miss: None, stats: FetchStats::default() }
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        let s = &self.stats;
+        writeln!(out, "[IFU] fetches={} block_misses={} icache_miss={} \
+            itlb_miss={} ftq_empty={} ibq_full={}", s.fetches, 
+            s.block_misses, s.icache_miss, s.itlb_miss, s.ftq_empty, 
+            s.ibq_full)?;
+        Ok(())
+    }
+
+    pub fn cycle(&mut self, 
+        ftq: &mut Queue<FTQEntry>, 
+        ibq: &mut Queue<IBQEntry>,
+        caches: &mut CacheHierarchy,
+        mmu: &mut Mmu,
+        mem: &Memory,
+        tr: &mut Tracer,
+    ) {
 
         // NOTE: Right now we assume that the fetch unit *always* pushes
         // two entries onto the IBQ (otherwise, if there aren't two free
@@ -65,30 +167,117 @@ impl FetchUnit {
         // Also, is it 32B-per-cycle with SMT, or in single-threaded too?
 
         if ibq.num_free() < 2 {
-            println!("[IFU] Stalled for full IBQ");
+            tr.stall(Stage::Fetch, StallReason::IbqFull, None);
+            self.stats.ibq_full += 1;
             return;
         }
         if ftq.is_empty() {
-            println!("[IFU] Stalled for empty FTQ");
+            tr.stall(Stage::Fetch, StallReason::FtqEmpty, None);
+            self.stats.ftq_empty += 1;
+            return;
+        }
+
+        // The head of the FTQ waits until its address has been translated.
+        let ent = *ftq.peek(0).unwrap();
+        if ent.fault {
+            tr.stall(Stage::Fetch, StallReason::FetchFault, Some(ent.addr));
+            return;
+        }
+        let vaddr = ent.addr;
+        let res = mmu.translate(mem, AccessKind::Inst, vaddr);
+        if res == Err(TranslateErr::Miss) {
+            tr.stall(Stage::Fetch, StallReason::ItlbMiss, Some(vaddr));
+            self.stats.itlb_miss += 1;
             return;
         }
 
+        // A fetch block which isn't mapped, or which is outside memory (ie.
+        // after a branch to a bogus target on the wrong path) can't be 
+        // read. Send a single entry to the decoder which becomes a faulting
+        // macro-op, and stall until the front-end is redirected. The fault 
+        // is only taken if it reaches retirement (we don't model page 
+        // faults).
+        //
+        // The fault is latched in the FTQ entry, which is discarded on a
+        // redirect.
+        let addr = match res {
+            Ok(addr) if addr < RAM_LEN - 0x20 => addr,
+            _ => {
+                trace!("[IFU] Fetch fault at {:08x}", vaddr);
+                let lo = if ent.start >= 0x10 { 0x10 } else { 0x00 };
+                ibq.push(IBQEntry { 
+                    addr: vaddr + lo, 
+                    data: [0; 16],
+                    start: ent.start - lo,
+                    end: ent.end.min(lo + 0x10) - lo,
+                    preds: ent.preds,
+                    cp: ent.cp,
+                    fault: true,
+                }).unwrap();
+                ftq.get_mut(0).fault = true;
+                return;
+            },
+        };
+
+        // The head of the FTQ waits until its line is present in the L1I.
+        // Fetch is pipelined, so hits don't add any bubbles.
+        let line = caches.l1i.line_addr(addr);
+
+        // Next-line prefetch: start filling the following line as soon as
+        // the fetch unit touches this one (unless it's outside memory)
+        let next = line + caches.l1i.cfg.line_size;
+        if next + caches.l1i.cfg.line_size <= RAM_LEN {
+            caches.prefetch(AccessKind::Inst, next);
+        }
+
+        if !caches.l1i.probe(line) {
+            if self.miss != Some(line) {
+                match caches.access(AccessKind::Inst, addr, line) {
+                    Ok(_) => {
+                        self.miss = Some(line);
+                        self.stats.block_misses += 1;
+                    },
+                    Err(e) => trace!("[IFU] L1I access failed: {:?}", e),
+                }
+            }
+            tr.stall(Stage::Fetch, StallReason::IcacheMiss, Some(vaddr));
+            self.stats.icache_miss += 1;
+            return;
+        }
+        if self.miss.take() != Some(line) {
+            caches.access(AccessKind::Inst, addr, line).unwrap();
+        }
+
         // Consume an entry from the FTQ and read 32 bytes from the L1 cache
         // per cycle. Push the resulting bytes onto the IBQ.
 
-        let addr = ftq.pop().unwrap();
-        let data = cache_read(addr);
-        println!("[IFU] Fetching 32b at {:08x}", addr);
-        ibq.push(IBQEntry { 
-            addr: addr + 0x00, data: data[0x00..0x10].try_into().unwrap() 
-        }).unwrap();
-        ibq.push(IBQEntry { 
-            addr: addr + 0x10, data: data[0x10..].try_into().unwrap() 
-        }).unwrap();
-        println!("[IFU] Pushed IBQ entry {:08x}", addr + 0x00);
-        println!("[IFU] Pushed IBQ entry {:08x}", addr + 0x10);
+        let ent  = ftq.pop().unwrap();
+        let data = mem.cache_read(addr);
+        tr.inst(vaddr, EventKind::Fetch { paddr: addr });
+        self.stats.fetches += 1;
+
+        // Only push the halves of the block which contain valid bytes
+        for lo in [0x00, 0x10] {
+            let hi = lo + 0x10;
+            if ent.end <= lo || ent.start >= hi { 
+                continue; 
+            }
+            ibq.push(IBQEntry { 
+                addr: vaddr + lo, 
+                data: data[lo..hi].try_into().unwrap(),
+                start: ent.start.max(lo) - lo,
+                end: ent.end.min(hi) - lo,
+                preds: ent.preds,
+                cp: ent.cp,
+                fault: false,
+            }).unwrap();
+            detail!("[IFU] Pushed IBQ entry {:08x}", vaddr + lo);
+        }
     }
 }
+impl Default for FetchUnit {
+    fn default() -> Self { Self::new() }
+}
 
 /// Representing a decoded instruction.
 #[derive(Clone, Copy)]
@@ -121,37 +310,65 @@ impl DecodeUnit {
     pub fn cycle(&mut self, 
         ibq: &mut Queue<IBQEntry>, 
         opq: &mut Queue<OPQEntry>,
-        bpu: &mut BranchPredictionUnit,
+        oc: &mut OpCache,
+        tr: &mut Tracer,
     ) {
-        use Mnemonic::*;
-
         if opq.is_full() {
-            println!("[IDU] Stalled for full OPQ");
+            tr.stall(Stage::Decode, StallReason::OpqFull, None);
+            return;
+        }
+        if ibq.is_empty() {
+            tr.stall(Stage::Decode, StallReason::IbqEmpty, None);
             return;
         }
-        if ibq.len() < 2 {
-            println!("[IDU] Stalled for IBQ entries");
+
+        // A fetch fault at the head of the IBQ becomes a faulting macro-op.
+        // Nothing else arrives from the fetch unit until a redirect.
+        let bot = *ibq.peek(0).unwrap();
+        if bot.fault {
+            let addr = bot.addr + self.pick_offset.max(bot.start);
+            self.push_fault(ibq, opq, addr, 1);
             return;
         }
 
+        // The pick window only spans two entries when the bytes are 
+        // contiguous (ie. the head entry isn't terminated by a predicted-
+        // taken branch, and the next entry is the next-sequential 16 bytes).
+        // Bytes from a fetch fault aren't valid.
+        let next = ibq.peek(1).ok().copied();
+        let contiguous = bot.end == 0x10 && next.is_none_or(|n| 
+            n.addr == bot.addr + 0x10 && n.start == 0
+        );
+        if contiguous && next.is_none() {
+            tr.stall(Stage::Decode, StallReason::IbqEmpty, None);
+            return;
+        }
+        let top = if contiguous { next } else { None };
+        let fault = top.is_some_and(|t| t.fault);
+        let top = top.filter(|t| !t.fault);
+
         // Build the pick window
-        let mut cursor = self.pick_offset;
+        let mut cursor = self.pick_offset.max(bot.start);
         let mut pick   = [0u8; 32];
-        let (bot, top) = (ibq.peek(0).unwrap(), ibq.peek(1).unwrap());
         let pick_addr  = bot.addr;
         pick[0x00..0x10].copy_from_slice(&bot.data);
-        pick[0x10..].copy_from_slice(&top.data);
-        println!("[IDU] Decode started at pick window offset {:02x}", cursor);
+        let pick_end = if let Some(top) = top {
+            pick[0x10..].copy_from_slice(&top.data);
+            0x10 + top.end
+        } else {
+            bot.end
+        };
+        detail!("[IDU] Decode started at pick window offset {:02x}", cursor);
 
         let mut output: [Option<DecodedInst>; 4] = [None; 4];
         let mut inst = Instruction::default();
         let mut decoder = Decoder::with_ip(
-            64, &pick[cursor..], (pick_addr + cursor) as u64, 
+            64, &pick[cursor..pick_end], (pick_addr + cursor) as u64, 
             DecoderOptions::NONE
         );
 
         // Decode up to four instructions
-        for idx in 0..4 {
+        for (idx, out) in output.iter_mut().enumerate() {
             decoder.decode_out(&mut inst);
             if idx != 0 && inst.len() > 8 { break; }
             if inst.is_invalid() { break; }
@@ -159,218 +376,204 @@ impl DecodeUnit {
             bytes[..inst.len()]
                 .copy_from_slice(&pick[cursor..(cursor + inst.len())]);
             let addr = pick_addr + cursor;
-            output[idx] = Some(DecodedInst { inst, bytes, addr });
+            *out = Some(DecodedInst { inst, bytes, addr });
             cursor += inst.len();
         }
 
+        // The next instruction runs into a fetch fault
+        let num_inst = output.iter().filter_map(|i| *i).count();
+        if num_inst == 0 && fault {
+            self.push_fault(ibq, opq, pick_addr + cursor, 2);
+            return;
+        }
+
         // If the OPQ can't accept all of the decoded instructions,
         // we need to stall until some entries are free?
-        let num_inst = output.iter().filter_map(|i| *i).count();
         if opq.num_free() < num_inst {
-            println!("[IDU] Stall for OPQ entries");
+            tr.stall(Stage::Decode, StallReason::OpqFull, None);
             return; 
         }
 
         // Adjust the pick window for the next cycle
-        match cursor {
+        if cursor < bot.end {
             // Haven't finished decoding the head entry, roll over cursor
-            0x00..=0x0f => { self.pick_offset = cursor; },
-            // Finished first entry: pop first entry and roll over cursor
-            0x10..=0x1f => {
+            self.pick_offset = cursor;
+        } else {
+            // Finished the head entry: pop it and roll over the cursor
+            detail!("[IDU] Decode popped IBQ entry {:08x}", bot.addr);
+            ibq.pop().unwrap();
+            self.pick_offset = 0;
+            if let Some(top) = top {
                 self.pick_offset = cursor - 0x10;
-                println!("[IDU] Decode popped IBQ entry {:08x}", bot.addr);
-                ibq.pop().unwrap();
-            },
-            // Exhausted the whole window: reset cursor and pop both entries
-            0x20 => {
-                self.pick_offset = 0;
-                println!("[IDU] Decode popped IBQ entry {:08x}", bot.addr);
-                println!("[IDU] Decode popped IBQ entry {:08x}", top.addr);
-                ibq.popn_exact(2).unwrap();
+                // Exhausted the whole window: reset cursor and pop both
+                if self.pick_offset >= top.end {
+                    detail!("[IDU] Decode popped IBQ entry {:08x}", 
+                        top.addr);
+                    ibq.pop().unwrap();
+                    self.pick_offset = 0;
+                }
             }
-            _ => unreachable!(),
         }
 
         // Scan over all decoded instructions for this cycle
         for inst in output.iter().filter_map(|i| *i) {
 
+            // Attach the prediction made for this instruction (if any).
+            // Branches are predicted with the fetch block containing their
+            // last byte.
+            let last = inst.addr + inst.inst.len() - 1;
+            let blk = if last < bot.addr + 0x10 { &bot } 
+                else { top.as_ref().unwrap() };
+            let pred = Prediction::for_addr(inst.addr, &blk.preds, blk.cp);
+
             // Create a new entry in the OPQ
             let mop = get_macro_ops(&inst);
-            let opq_entry = OPQEntry { op: mop, addr: inst.addr };
+            let opq_entry = OPQEntry { 
+                op: mop, addr: inst.addr, len: inst.inst.len(), pred,
+            };
             opq.push(opq_entry).unwrap();
+            oc.stats.decode_ops += 1;
+            tr.inst(inst.addr, EventKind::Decode { op: mop, oc: false });
+
+            // Fill the op cache. Instructions with a 64-bit immediate
+            // use two slots in an entry.
+            let imm64 = (0..inst.inst.op_count())
+                .any(|i| inst.inst.op_kind(i) == OpKind::Immediate64);
+            oc.fill_inst(
+                OpCacheInst { addr: inst.addr, len: inst.inst.len(), op: mop },
+                if imm64 { 2 } else { 1 }, &pred
+            );
 
-            // If this is a branch instruction, send it to the BPU
             let mn = inst.inst.mnemonic();
-            match mn {
-                Jmp | Jmpe | Jne | Je | Jge | Jle | Call | Ret => {
-                    println!("[IDU] Encountered branch {:?}", mn);
-                    bpu.push_branch(inst);
-                },
-                _ => {},
+            if BranchKind::from(mop) != BranchKind::None {
+                trace!("[IDU] Encountered branch {:?} {:x?}", mn, pred);
             }
 
         }
 
     }
-}
-
-
-
-pub struct BranchPredictionUnit {
-    pub branches: Queue<DecodedInst>,
-}
-impl BranchPredictionUnit {
-    pub fn new() -> Self {
-        Self {
-            branches: Queue::new(32),
-        }
-    }
 
-    pub fn push_branch(&mut self, d: DecodedInst) {
-        self.branches.push(d).unwrap();
-    }
-
-    // NOTE: How many branches can be predicted per-cycle?
-    pub fn cycle(&mut self,
-        btb: &mut BranchTargetBuffer,
-        pq: &mut Queue<usize>,
+    /// Send a faulting macro-op for a fetch fault at 'addr' to the OPQ, 
+    /// and discard 'num' entries from the IBQ.
+    fn push_fault(&mut self, 
+        ibq: &mut Queue<IBQEntry>, 
+        opq: &mut Queue<OPQEntry>,
+        addr: usize,
+        num: usize,
     ) {
-
-        if let Ok(brn) = self.branches.pop() {
-            let fetch_addr = brn.fetch_addr();
-            let info = BranchInfo { 
-                kind: BranchKind::from(get_macro_ops(&brn)),
-                bytes: brn.bytes,
-                len: brn.inst.len(),
-                addr: brn.addr,
-            };
-
-            // If a BTB entry exists for this fetch block
-            if let Some(e) = btb.get_mut(fetch_addr) {
-
-                // If this branch matches the entry
-                if e.info == info {
-                    if let Some(tgt) = e.tgt {
-                        println!("[BPU] Predicted {:08x} for {:08x} {:?}", 
-                                 tgt, info.addr, info.kind);
-                        pq.push(tgt).unwrap();
-                    } 
-                    else 
-                    {
-                        println!("[BPU] No prediction for {:08x} {:?}", 
-                                 info.addr, info.kind);
-                    }
-                } 
-                else 
-                {
-                    println!("[BPU] Invalidate BTB entry {:08x}", fetch_addr);
-                    e.info = info;
-                    e.tgt  = None;
-                }
-
-
-            } else {
-                println!("[BPU] No BTB entry for {:08x}", fetch_addr);
-                btb.create(fetch_addr, info);
-                println!("[BPU] Created new BTB entry");
-            }
-
-        } else {
-            println!("[BPU] No branches to predict this cycle");
-        }
+        trace!("[IDU] Fetch fault at {:08x}", addr);
+        opq.push(OPQEntry { 
+            op: MacroOp::FetchFault, addr, len: 0, 
+            pred: Prediction::default(),
+        }).unwrap();
+        ibq.popn_exact(num).unwrap();
+        self.pick_offset = 0;
     }
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct BranchInfo {
-    kind: BranchKind,
-    bytes: [u8; 0x10],
-    len: usize,
-    addr: usize,
-}
-impl Default for BranchInfo {
-    fn default() -> Self {
-        Self {
-            kind: BranchKind::None,
-            bytes: [0; 0x10],
-            len: 0,
-            addr: 0,
-        }
-    }
-}
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
 
-/// Different kinds of x86 branch instructions.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum BranchKind {
-    None,
-    UnconditionalDirect,
-    UnconditionalIndirect,
-    ConditionalDirect,
-    Call,
-    Return,
-}
-impl From<MacroOp> for BranchKind {
-    fn from(x: MacroOp) -> Self {
-        match x {
-            MacroOp::JmpI(_) => Self::UnconditionalDirect,
-            _ => Self::None,
+    fn block(addr: usize, start: usize) -> FTQEntry {
+        FTQEntry { 
+            addr, start, end: 0x20, preds: [None; 2], 
+            cp: SpecCheckpoint::default(), fault: false,
         }
     }
-}
 
-
-pub enum BTBErr { NotBranch, Miss }
-
-pub struct BTBEntry {
-    info: BranchInfo,
-    tgt: Option<usize>,
-}
-impl Default for BTBEntry {
-    fn default() -> Self {
-        Self { 
-            info: BranchInfo::default(),
-            tgt: None,
-        }
+    #[test]
+    fn fetch_fault() {
+        let mut ftq = Queue::new(8);
+        let mut ibq = Queue::new(20);
+        let mut opq = Queue::new(32);
+        let mut ifu = FetchUnit::new();
+        let mut caches = CacheHierarchy::new(&CachesConfig::default());
+        let mut mmu = Mmu::new(None, &TlbConfig::default());
+        let mem = Memory::new();
+        let mut idu = DecodeUnit { pick_offset: 0 };
+        let mut oc = OpCache::new(&OpCacheConfig::default());
+        let mut tr = Tracer::new();
+
+        // Fetch from outside memory sends a single entry to the IBQ, and
+        // then stalls until a redirect
+        let addr = 0x4000_0000_0000;
+        ftq.push(block(addr, 0x14)).unwrap();
+        ftq.push(block(addr + 0x20, 0)).unwrap();
+        ifu.cycle(&mut ftq, &mut ibq, &mut caches, &mut mmu, &mem, 
+            &mut tr);
+        ifu.cycle(&mut ftq, &mut ibq, &mut caches, &mut mmu, &mem, 
+            &mut tr);
+        assert_eq!(ibq.len(), 1);
+        assert!(ftq.peek(0).unwrap().fault);
+        assert!(caches.l1i.mshrs.is_empty());
+
+        idu.cycle(&mut ibq, &mut opq, &mut oc, &mut tr);
+        let ent = opq.pop().unwrap();
+        assert!(matches!(ent.op, MacroOp::FetchFault));
+        assert_eq!(ent.addr, addr + 0x14);
+        assert!(ibq.is_empty());
+        assert_eq!(idu.pick_offset, 0);
     }
-}
 
-pub struct BranchTargetBuffer {
-    data: BTreeMap<usize, BTBEntry>,
-}
-impl BranchTargetBuffer {
-    pub fn new() -> Self {
-        Self { data: BTreeMap::new() }
-    }
-
-    /// Returns true if an entry exists for the provided fetch block. 
-    pub fn hit(&self, fetch_pc: usize) -> bool {
-        self.data.contains_key(&fetch_pc)
-    }
-
-    pub fn invalidate(&mut self, fetch_pc: usize) {
-        self.data.remove(&fetch_pc);
+    #[test]
+    fn fetch_last_block() {
+        let mut ftq = Queue::new(8);
+        let mut ibq = Queue::new(20);
+        let mut ifu = FetchUnit::new();
+        let mut caches = CacheHierarchy::new(&CachesConfig::default());
+        let mut mmu = Mmu::new(None, &TlbConfig::default());
+        let mem = Memory::new();
+        let mut tr = Tracer::new();
+
+        // The last line in memory is fetched, but the next-line prefetch
+        // doesn't run off the end of memory
+        ftq.push(block(RAM_LEN - 0x40, 0)).unwrap();
+        for clk in 1..1000 {
+            if !ibq.is_empty() { break; }
+            caches.cycle(clk);
+            ifu.cycle(&mut ftq, &mut ibq, &mut caches, &mut mmu, &mem, 
+            &mut tr);
+            assert!(caches.l1i.mshrs.iter().all(|m| m.line < RAM_LEN));
+        }
+        assert_eq!(ibq.len(), 2);
+        assert!(!ibq.peek(0).unwrap().fault);
     }
 
-    /// Create a new BTB entry 
-    pub fn create(&mut self, fetch_pc: usize, info: BranchInfo) {
-        let e = BTBEntry { info, tgt: None };
-        self.data.insert(fetch_pc, e);
-    }
+    #[test]
+    fn fetch_fault_straddle() {
+        let mut ibq = Queue::new(20);
+        let mut opq = Queue::new(32);
+        let mut idu = DecodeUnit { pick_offset: 0 };
+        let mut oc = OpCache::new(&OpCacheConfig::default());
+        let mut tr = Tracer::new();
+
+        // A nop, and then 'mov rax, imm64' which runs into a block outside
+        // memory
+        let addr = RAM_LEN - 0x30;
+        let mut data = [0u8; 16];
+        data[0x0d..].copy_from_slice(&[0x90, 0x48, 0xb8]);
+        let cp = SpecCheckpoint::default();
+        ibq.push(IBQEntry { 
+            addr, data, start: 0x0d, end: 0x10, preds: [None; 2], cp,
+            fault: false,
+        }).unwrap();
+        ibq.push(IBQEntry { 
+            addr: addr + 0x10, data: [0; 16], start: 0, end: 0x10, 
+            preds: [None; 2], cp, fault: true,
+        }).unwrap();
 
-    /// Get a reference to some BTB entry
-    pub fn get(&self, fetch_pc: usize) -> Option<&BTBEntry> {
-        self.data.get(&fetch_pc)
-    }
+        idu.cycle(&mut ibq, &mut opq, &mut oc, &mut tr);
+        assert!(matches!(opq.pop().unwrap().op, MacroOp::Nop));
+        assert_eq!(ibq.len(), 2);
 
-    /// Get a mutable reference to some BTB entry
-    pub fn get_mut(&mut self, fetch_pc: usize) -> Option<&mut BTBEntry> {
-        self.data.get_mut(&fetch_pc)
+        idu.cycle(&mut ibq, &mut opq, &mut oc, &mut tr);
+        let ent = opq.pop().unwrap();
+        assert!(matches!(ent.op, MacroOp::FetchFault));
+        assert_eq!(ent.addr, addr + 0x0e);
+        assert!(ibq.is_empty());
+        assert!(!oc.probe(addr + 0x0e));
     }
-
-
 }
-
-
-
@@ -0,0 +1,393 @@
+//! The cache hierarchy.
+
+use std::io::{ self, Write };
+use crate::mem::RAM_LEN;
+use crate::cache::*;
+use crate::dram::*;
+use crate::prefetch::*;
+use crate::config::*;
+use serde::{ Serialize, Deserialize };
+
+/// Relationship between the contents of the L2 and the L1 caches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InclusionPolicy {
+    /// Lines in the L1s are always present in the L2. Lines evicted from
+    /// the L2 are also invalidated in the L1s.
+    Inclusive,
+    /// Lines are filled into both the L1 and the L2, but evictions from the
+    /// L2 don't affect the L1s.
+    NonInclusive,
+    /// Lines are never present in both the L1s and the L2. Lines are moved
+    /// from the L2 into the L1s, and L1 victims are filled into the L2.
+    Exclusive,
+}
+impl std::str::FromStr for InclusionPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inclusive" => Ok(Self::Inclusive),
+            "non-inclusive" => Ok(Self::NonInclusive),
+            "exclusive" => Ok(Self::Exclusive),
+            _ => Err(format!("unknown inclusion policy '{}'", s)),
+        }
+    }
+}
+
+/// The kind of L1 cache used for an access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Inst,
+    Data,
+}
+
+/// The L1I and L1D, a private L2, and an L3 in front of main memory.
+///
+/// NOTE: As on Zen 2, the L3 is a victim cache: it's only filled with
+/// lines evicted from the L2, and lines are moved back into the L2 when
+/// they hit in the L3.
+///
+/// NOTE: Requests below the L1 look up each level immediately, and only
+/// the latency of the response is modeled. Dirty lines are written back
+/// to the level below when they're evicted (and dirty L3 victims are 
+/// written to main memory), but nothing waits for a writeback. Dirty lines
+/// which are moved up to another level stay dirty.
+pub struct CacheHierarchy {
+    pub l1i: Cache,
+    pub l1d: Cache,
+    pub l2: Cache,
+    pub l3: Cache,
+    pub dram: Dram,
+    pub inclusion: InclusionPolicy,
+    /// Prefetchers trained on demand accesses to the L1D
+    pub l1d_pf: Vec<Box<dyn Prefetcher>>,
+    /// Prefetchers trained on demand requests to the L2
+    pub l2_pf: Vec<Box<dyn Prefetcher>>,
+    /// The current cycle (updated by [CacheHierarchy::cycle])
+    clk: usize,
+}
+impl CacheHierarchy {
+    pub fn new(cfg: &CachesConfig) -> Self {
+        let line_size = cfg.l2.line_size;
+        Self {
+            l1i: Cache::new("L1I", cfg.l1i),
+            l1d: Cache::new("L1D", cfg.l1d),
+            l2: Cache::new("L2", cfg.l2),
+            l3: Cache::new("L3", cfg.l3),
+            dram: Dram::new(cfg.dram),
+            inclusion: cfg.inclusion,
+            l1d_pf: vec![
+                Box::new(StridePrefetcher::new(2)),
+                Box::new(NextLinePrefetcher::new(cfg.l1d.line_size)),
+            ],
+            l2_pf: vec![
+                Box::new(RegionPrefetcher::new(line_size, 4)),
+            ],
+            clk: 0,
+        }
+    }
+
+    pub fn l1(&mut self, kind: AccessKind) -> &mut Cache {
+        match kind {
+            AccessKind::Inst => &mut self.l1i,
+            AccessKind::Data => &mut self.l1d,
+        }
+    }
+
+    /// Complete any line fills which have finished on this cycle, and
+    /// move evicted lines to the levels below.
+    pub fn cycle(&mut self, clk: usize) {
+        self.clk = clk;
+        self.l3.cycle(clk);
+        for victim in self.l2.cycle(clk) {
+            self.evict_l2(victim);
+        }
+        for kind in [AccessKind::Inst, AccessKind::Data] {
+            for victim in self.l1(kind).cycle(clk) {
+                // L1 victims are moved into an exclusive L2. Otherwise, 
+                // only dirty victims are written back.
+                let exclusive = self.inclusion == InclusionPolicy::Exclusive;
+                if exclusive || victim.dirty {
+                    if let Some(v) = self.l2.fill(victim.line, victim.dirty) {
+                        self.evict_l2(v);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a line evicted from the L2.
+    fn evict_l2(&mut self, victim: Victim) {
+        trace!("[L2] Evicted line {:08x}", victim.line);
+        let mut dirty = victim.dirty;
+        if self.inclusion == InclusionPolicy::Inclusive {
+            self.l1i.invalidate(victim.line);
+            dirty |= self.l1d.invalidate(victim.line).unwrap_or(false);
+        }
+        if let Some(v) = self.l3.fill(victim.line, dirty) {
+            trace!("[L3] Evicted line {:08x}", v.line);
+            if v.dirty {
+                self.dram.write(self.clk);
+            }
+        }
+    }
+
+    /// Request a line from the L3 (or main memory) on cycle 't', returning
+    /// the cycle when the data is returned to the L2 (and whether the line
+    /// is dirty).
+    fn request_l3(&mut self, addr: usize, t: usize, demand: bool) 
+        -> (usize, bool) 
+    {
+        // Lines which hit in the L3 are moved back into the L2
+        if self.l3.lookup(addr, demand) {
+            let dirty = self.l3.invalidate(addr).unwrap_or(false);
+            (t + self.l3.cfg.hit_latency, dirty)
+        } else {
+            (self.dram.read(t + self.l3.cfg.hit_latency), false)
+        }
+    }
+
+    /// Request a line from the L2 on cycle 't', returning the cycle when
+    /// the data is returned to the L1 (and whether the line is dirty).
+    fn request_l2(&mut self, pc: usize, addr: usize, t: usize, demand: bool)
+        -> (usize, bool)
+    {
+        let exclusive = self.inclusion == InclusionPolicy::Exclusive;
+        if demand {
+            let hit = self.l2.probe(addr);
+            let mut addrs = Vec::new();
+            for pf in self.l2_pf.iter_mut() {
+                addrs.extend(pf.access(pc, addr, hit));
+            }
+            for a in addrs {
+                self.prefetch_l2(a, t);
+            }
+        }
+
+        let pending = if demand { 
+            self.l2.merge(addr) 
+        } else { 
+            self.l2.pending(addr) 
+        };
+        if let Some(done) = pending {
+            return (done.max(t), false);
+        }
+        if self.l2.lookup(addr, demand) {
+            let dirty = exclusive 
+                && self.l2.invalidate(addr).unwrap_or(false);
+            return (t + self.l2.cfg.hit_latency, dirty);
+        }
+        let (done, dirty) = self.request_l3(addr, 
+            t + self.l2.cfg.hit_latency, demand);
+        if exclusive {
+            return (done, dirty);
+        }
+
+        // NOTE: When the L2 is out of MSHRs, the line is only filled into
+        // the L1.
+        if !self.l2.mshr_free() {
+            self.l2.stats.mshr_full += 1;
+            return (done, dirty);
+        }
+        self.l2.start_fill(addr, done, false);
+        if dirty {
+            self.l2.set_dirty(addr);
+        }
+        (done, false)
+    }
+
+    /// Start filling the line containing some address into the L2 on 
+    /// cycle 't'.
+    fn prefetch_l2(&mut self, addr: usize, t: usize) -> bool {
+        if addr >= RAM_LEN || self.l2.probe(addr) 
+            || self.l2.pending(addr).is_some() || !self.l2.mshr_free() 
+        {
+            return false;
+        }
+        let (done, dirty) = self.request_l3(addr, 
+            t + self.l2.cfg.hit_latency, false);
+        self.l2.start_fill(addr, done, true);
+        if dirty {
+            self.l2.set_dirty(addr);
+        }
+        true
+    }
+
+    /// Access some address in the L1I or L1D on behalf of the instruction
+    /// at 'pc', returning the cycle when the data is available.
+    pub fn access(&mut self, kind: AccessKind, pc: usize, addr: usize)
+        -> Result<usize, CacheErr>
+    {
+        let hit = self.l1(kind).probe(addr);
+        let res = match self.l1(kind).access(addr)? {
+            Lookup::Ready(done) => done,
+            Lookup::Miss => {
+                let t = self.clk + self.l1(kind).cfg.hit_latency;
+                let (done, dirty) = self.request_l2(pc, addr, t, true);
+                self.l1(kind).start_fill(addr, done, false);
+                if dirty {
+                    self.l1(kind).set_dirty(addr);
+                }
+                done
+            },
+        };
+
+        if kind == AccessKind::Data {
+            let mut addrs = Vec::new();
+            for pf in self.l1d_pf.iter_mut() {
+                addrs.extend(pf.access(pc, addr, hit));
+            }
+            for a in addrs {
+                self.prefetch(kind, a);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Start filling the line containing some address into the L1I or L1D
+    /// without waiting for the result. Returns false if the line is already
+    /// present or being filled, or if there are no free MSHRs.
+    pub fn prefetch(&mut self, kind: AccessKind, addr: usize) -> bool {
+        let clk = self.clk;
+        let l1 = self.l1(kind);
+        if addr >= RAM_LEN || l1.probe(addr) || l1.pending(addr).is_some() 
+            || !l1.mshr_free() 
+        {
+            return false;
+        }
+        let t = clk + l1.cfg.hit_latency;
+        let (done, dirty) = self.request_l2(addr, addr, t, false);
+        self.l1(kind).start_fill(addr, done, true);
+        if dirty {
+            self.l1(kind).set_dirty(addr);
+        }
+        true
+    }
+
+    /// Access some address in the L1D on behalf of a store, returning the
+    /// cycle when the line is available. The line is marked dirty (or will
+    /// be dirty once it's filled).
+    pub fn store(&mut self, pc: usize, addr: usize) 
+        -> Result<usize, CacheErr>
+    {
+        let done = self.access(AccessKind::Data, pc, addr)?;
+        self.l1d.set_dirty(addr);
+        Ok(done)
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        let names = |pfs: &[Box<dyn Prefetcher>]| {
+            pfs.iter().map(|p| p.name()).collect::<Vec<_>>().join(", ")
+        };
+        writeln!(out, "[L1D] Prefetchers: {}", names(&self.l1d_pf))?;
+        writeln!(out, "[L2] Prefetchers: {}", names(&self.l2_pf))?;
+        self.l1i.print_stats(out)?;
+        self.l1d.print_stats(out)?;
+        self.l2.print_stats(out)?;
+        self.l3.print_stats(out)?;
+        self.dram.print_stats(out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hierarchy with a single-line L1D, and an L2 and L3 which each
+    /// hold two lines (without any prefetchers).
+    fn hier(inclusion: InclusionPolicy) -> CacheHierarchy {
+        let tiny = |ways: usize, hit_latency: usize| CacheConfig {
+            size: 64 * ways, ways, hit_latency, num_banks: 1, 
+            bank_width: 64, policy: ReplacementPolicy::Lru, ..L2_CONFIG
+        };
+        let mut h = CacheHierarchy::new(&CachesConfig {
+            inclusion,
+            l1d: tiny(1, 4),
+            l2: tiny(2, 8),
+            l3: tiny(2, 27),
+            ..CachesConfig::default()
+        });
+        h.l1d_pf.clear();
+        h.l2_pf.clear();
+        h
+    }
+
+    /// Access some address in the L1D (and wait for it), returning the 
+    /// latency.
+    fn access(h: &mut CacheHierarchy, addr: usize, store: bool) -> usize {
+        let t = h.clk;
+        let done = if store {
+            h.store(0, addr).unwrap()
+        } else {
+            h.access(AccessKind::Data, 0, addr).unwrap()
+        };
+        for clk in t + 1..=done + 1 {
+            h.cycle(clk);
+        }
+        done - t
+    }
+
+    #[test]
+    fn latency() {
+        let mut h = hier(InclusionPolicy::NonInclusive);
+        let dram = h.dram.cfg.latency;
+        assert_eq!(access(&mut h, 0x1000, false), 4 + 8 + 27 + dram);
+        assert_eq!(access(&mut h, 0x1000, false), 4);
+
+        // The L1D only holds one line
+        assert_eq!(access(&mut h, 0x2000, false), 4 + 8 + 27 + dram);
+        assert_eq!(access(&mut h, 0x1000, false), 4 + 8);
+
+        // Lines evicted from the L2 are moved into the L3
+        access(&mut h, 0x3000, false);
+        access(&mut h, 0x4000, false);
+        assert!(!h.l2.probe(0x2000) && h.l3.probe(0x2000));
+        assert_eq!(access(&mut h, 0x2000, false), 4 + 8 + 27);
+        assert!(!h.l3.probe(0x2000));
+    }
+
+    #[test]
+    fn exclusive() {
+        // L1D victims are moved into the L2, and L2 hits are moved into
+        // the L1D
+        let mut h = hier(InclusionPolicy::Exclusive);
+        access(&mut h, 0x1000, false);
+        assert!(!h.l2.probe(0x1000));
+        access(&mut h, 0x2000, false);
+        assert!(h.l2.probe(0x1000));
+        assert_eq!(access(&mut h, 0x1000, false), 4 + 8);
+        assert!(!h.l2.probe(0x1000) && h.l2.probe(0x2000));
+    }
+
+    #[test]
+    fn inclusive() {
+        // Lines evicted from the L2 are invalidated in the L1D
+        let mut h = hier(InclusionPolicy::Inclusive);
+        access(&mut h, 0x1000, false);
+        for addr in [0x2000, 0x3000] {
+            if let Some(v) = h.l2.fill(addr, false) {
+                h.evict_l2(v);
+            }
+        }
+        assert!(!h.l2.probe(0x1000) && !h.l1d.probe(0x1000));
+        assert!(h.l3.probe(0x1000));
+    }
+
+    #[test]
+    fn dirty_writeback() {
+        let mut h = hier(InclusionPolicy::Inclusive);
+        access(&mut h, 0x1000, true);
+
+        // The dirty line is written back to each level in turn as it's 
+        // evicted, and finally to main memory
+        access(&mut h, 0x2000, false);
+        assert_eq!(h.l1d.stats.writebacks, 1);
+        for addr in [0x3000, 0x4000, 0x5000, 0x6000] {
+            access(&mut h, addr, false);
+        }
+        assert_eq!(h.l2.stats.writebacks, 1);
+        assert_eq!(h.l3.stats.writebacks, 1);
+        assert_eq!(h.dram.stats.writes, 1);
+    }
+}
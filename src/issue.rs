@@ -1,7 +1,9 @@
 
-use crate::mem::*;
 use crate::op::*;
 use crate::exec::*;
+use crate::rf::*;
+use crate::util::*;
+use crate::event::*;
 
 /// Entry in a scheduler.
 #[derive(Clone, Copy, Debug)]
@@ -13,13 +15,13 @@ pub struct Reservation {
 
 
 /// A scheduler/reservation station for dispatched micro-ops.
-#[derive(Clone, Copy, Debug)]
-pub struct Scheduler<const SIZE: usize> {
-    pub data: [Option<Reservation>; SIZE],
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    pub data: Vec<Option<Reservation>>,
 }
-impl <const SIZE: usize> Scheduler<SIZE> {
-    pub fn new() -> Self {
-        Self { data: [None; SIZE] }
+impl Scheduler {
+    pub fn new(size: usize) -> Self {
+        Self { data: vec![None; size] }
     }
 
     /// Returns true if there is at least one free slot.
@@ -43,33 +45,45 @@ impl <const SIZE: usize> Scheduler<SIZE> {
     }
 
     /// Fill a slot in the scheduler.
-    pub fn alloc(&mut self, new: Reservation) -> Result<(), ()> {
-        if let Some((i, e)) = self.data.iter_mut().enumerate()
-            .find(|(i, e)| e.is_none()) 
-        {
-            self.data[i] = Some(new);
+    pub fn alloc(&mut self, new: Reservation) -> Result<(), QueueErr> {
+        if let Some(slot) = self.data.iter_mut().find(|e| e.is_none()) {
+            *slot = Some(new);
             Ok(())
-        } else { Err(()) }
+        } else { Err(QueueErr::Full) }
+    }
+
+    /// Remove all reservations matching some predicate, returning the 
+    /// number of reservations that were removed.
+    pub fn squash(&mut self, f: impl Fn(&Reservation) -> bool) -> usize {
+        let mut num = 0;
+        for slot in self.data.iter_mut() {
+            if slot.as_ref().is_some_and(&f) {
+                *slot = None;
+                num += 1;
+            }
+        }
+        num
     }
 
     /// Return the number of reservations which are ready-for-issue.
-    pub fn num_ready(&self) -> usize {
+    pub fn num_ready(&self, prf: &PhysicalRegisterFile) -> usize {
         if self.num_pending() == 0 { return 0; }
         let pending_slots = self.data.iter().filter_map(|s| *s);
-        pending_slots.filter(|res| res.uop.fire()).count()
+        pending_slots.filter(|res| res.uop.fire(prf)).count()
     }
 
     // Find and return a reservation which is ready-for-issue, removing the 
     // reservation from the scheduler queue.
-    pub fn take_ready(&mut self) -> Option<Reservation> {
+    pub fn take_ready(&mut self, prf: &PhysicalRegisterFile) 
+        -> Option<Reservation> 
+    {
         if self.num_pending() == 0 {
             return None;
         }
 
-        let mut iter = self.data.iter_mut();
-        while let Some(slot) = iter.next() {
+        for slot in self.data.iter_mut() {
             if let Some(entry) = slot {
-                if entry.uop.fire() {
+                if entry.uop.fire(prf) {
                     return slot.take();
                 }
             }
@@ -78,28 +92,36 @@ impl <const SIZE: usize> Scheduler<SIZE> {
     }
 }
 
-/// A 16-entry ALU scheduler.
-pub type ALUScheduler = Scheduler<16>;
+/// An ALU scheduler (16 entries on Zen 2).
+pub type ALUScheduler = Scheduler;
 
-/// A 28-entry AGU scheduler.
-pub type AGUScheduler = Scheduler<28>;
+/// The AGU scheduler (28 entries on Zen 2).
+pub type AGUScheduler = Scheduler;
 
 
 pub struct IssueUnit;
 impl IssueUnit {
-    pub fn cycle(&mut self, alu_sched: &mut [ALUScheduler; 4], 
-                 eu: &mut ExecutionUnits)
+    pub fn cycle(&mut self, alu_sched: &mut [ALUScheduler], 
+                 agu_sched: &mut AGUScheduler,
+                 eu: &mut ExecutionUnits,
+                 prf: &PhysicalRegisterFile,
+                 tr: &mut Tracer,
+                 clk: usize)
     {
         // Iterate over all ALU schedulers and attempt to fire any pending
         // reservations that are ready-for-issue.
         //
         // Each ALQ can only issue 1 micro-op per cycle.
+        //
+        // NOTE: Result tags are broadcast at the start of the cycle after
+        // a producer completes (see [ExecutionUnits::wakeup]), so its
+        // dependents can issue on that cycle.
 
         let mut free_alus = eu.alu.iter_mut().enumerate()
-            .filter(|(idx, s)| s.op.is_none());
+            .filter(|(_, s)| s.op.is_none());
         for (idx, alq) in alu_sched.iter_mut().enumerate() {
-            println!("[ISS] Checking ALQ{}", idx);
-            println!("[ISS]   {} pending reservation[s]", alq.num_pending());
+            detail!("[ISS] Checking ALQ{}", idx);
+            detail!("[ISS]   {} pending reservation[s]", alq.num_pending());
 
             // Try to get a mutable reference to an unoccupied ALU.
             // Otherwise, if all ALUs are currently busy, no more micro-ops
@@ -111,22 +133,38 @@ impl IssueUnit {
                 // If there are none, move on to the next ALQ.
                 // Otherwise, *consume* the reservation from the ALQ and
                 // pass it onto the appropriate ALU.
-                match alq.take_ready() {
+                match alq.take_ready(prf) {
                     None => {
-                        println!("[ISS]   No ready-to-issue reservations");
+                        detail!("[ISS]   No ready-to-issue reservations");
                         continue;
                     },
                     Some(iss_res) => {
-                        println!("[ISS]   ALU{} issued {:08x}: {:?}", 
-                                 alu_idx, iss_res.uop.addr, iss_res.uop.kind);
-                        tgt_alu.do_issue(clk(), iss_res);
+                        tr.uop(&iss_res.uop, iss_res.rob_idx, 
+                            EventKind::Issue { unit: ExecUnit::Alu(alu_idx) });
+                        tgt_alu.do_issue(clk, iss_res);
                     },
                 }
             } else {
-                println!("[ISS]   No free ALUs to consume a reservation");
-                println!("[ISS]   Issue stalled for ALU availability");
+                tr.stall(Stage::Issue, StallReason::NoFreeAlu, None);
                 break;
             }
         }
+
+        // The AGU scheduler can issue to any free AGU
+        detail!("[ISS] Checking AGQ");
+        detail!("[ISS]   {} pending reservation[s]", agu_sched.num_pending());
+        for (agu_idx, tgt_agu) in eu.agu.iter_mut().enumerate() {
+            if tgt_agu.busy() { 
+                continue; 
+            }
+            match agu_sched.take_ready(prf) {
+                None => break,
+                Some(iss_res) => {
+                    tr.uop(&iss_res.uop, iss_res.rob_idx, 
+                        EventKind::Issue { unit: ExecUnit::Agu(agu_idx) });
+                    tgt_agu.do_issue(clk, iss_res);
+                },
+            }
+        }
     }
 }
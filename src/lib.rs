@@ -0,0 +1,523 @@
+#[macro_use]
+pub mod log;
+pub mod util;
+pub mod bp;
+pub mod front;
+
+pub mod dispatch;
+pub mod issue;
+pub mod retire;
+
+pub mod mem;
+pub mod rf;
+pub mod exec;
+pub mod flags;
+pub mod lsq;
+pub mod mdp;
+pub mod cache;
+pub mod hier;
+pub mod dram;
+pub mod prefetch;
+pub mod opcache;
+pub mod tlb;
+pub mod mmu;
+pub mod op;
+pub mod pipeline;
+pub mod config;
+pub mod elf;
+pub mod event;
+pub mod pipeview;
+pub mod pmc;
+pub mod topdown;
+pub mod emu;
+pub mod cosim;
+
+use std::io::{ self, Write };
+
+use iced_x86::Register;
+
+use crate::util::*;
+use crate::bp::*;
+use crate::front::*;
+use crate::dispatch::*;
+use crate::issue::*;
+use crate::retire::*;
+use crate::mem::*;
+use crate::rf::*;
+use crate::flags::*;
+use crate::exec::*;
+use crate::pipeline::*;
+use crate::lsq::*;
+use crate::hier::*;
+use crate::opcache::*;
+use crate::tlb::*;
+use crate::mmu::*;
+use crate::config::*;
+use crate::elf::*;
+use crate::event::*;
+use crate::pmc::*;
+use crate::emu::*;
+use crate::cosim::*;
+
+/// A single core, owning all of the pipeline state and its memory.
+pub struct Core {
+    pub cfg: Config,
+    /// The current cycle
+    pub clk: usize,
+    /// Simulated physical memory
+    pub mem: Memory,
+    /// Pipeline events (and symbols for the loaded program)
+    pub tracer: Tracer,
+
+    // Branch prediction
+    pub bpu: BranchPredictionUnit,
+    pub btb: BranchTargetBuffer,
+
+    // Cache hierarchy and address translation
+    pub caches: CacheHierarchy,
+    pub mmu: Mmu,
+
+    // Next PC
+    pub npc: NextPCLogic,
+    pub next_pc: usize,
+
+    // Instruction fetch
+    pub ftq: Queue<FTQEntry>,
+    pub ifu: FetchUnit,
+
+    // Instruction decode
+    pub ibq: Queue<IBQEntry>,
+    pub idu: DecodeUnit,
+
+    // Op cache
+    pub ocu: OpCacheUnit,
+
+    // In-order dispatch
+    pub opq: Queue<OPQEntry>,
+    pub dispatch: DispatchUnit,
+
+    // Out-of-order issue
+    pub isu: IssueUnit,
+    pub alu_sched: Vec<ALUScheduler>,
+    pub agu_sched: AGUScheduler,
+
+    // Execution units
+    pub prf: PhysicalRegisterFile,
+    pub eu: ExecutionUnits,
+    pub lsq: LoadStoreQueue,
+
+    /// Speculative register state (at dispatch)
+    pub frat: RegisterAliasTable,
+    /// Architectural register state (at retirement)
+    pub rat: RegisterAliasTable,
+
+    // Retire control unit
+    pub rob: ReorderBuffer,
+    pub rcu: RetireControlUnit,
+}
+impl Core {
+    pub fn new(cfg: &Config) -> Self {
+        let fe = &cfg.frontend;
+        let be = &cfg.backend;
+        Self {
+            cfg: cfg.clone(),
+            clk: 0,
+            mem: Memory::new(),
+            tracer: Tracer::new(),
+            bpu: BranchPredictionUnit::new(fe.direction_predictor),
+            btb: BranchTargetBuffer::new(),
+            caches: CacheHierarchy::new(&cfg.caches),
+            mmu: Mmu::new(None, &cfg.tlb),
+            npc: NextPCLogic::new(fe.redirect_penalty),
+            next_pc: 0,
+            ftq: Queue::new(fe.ftq_size),
+            ifu: FetchUnit::new(),
+            ibq: Queue::new(fe.ibq_size),
+            idu: DecodeUnit { pick_offset: 0 },
+            ocu: OpCacheUnit::new(&cfg.opcache),
+            opq: Queue::new(fe.opq_size),
+            dispatch: DispatchUnit::new(be.dispatch_width),
+            isu: IssueUnit,
+            alu_sched: vec![
+                ALUScheduler::new(be.alu_sched_size); be.num_alu_scheds
+            ],
+            agu_sched: AGUScheduler::new(be.agu_sched_size),
+            prf: PhysicalRegisterFile::new(be.prf_size),
+            eu: ExecutionUnits::new(be.num_alus, be.num_agus),
+            lsq: LoadStoreQueue::new(&cfg.lsq),
+            frat: RegisterAliasTable::new(),
+            rat: RegisterAliasTable::new(),
+            rob: ReorderBuffer::new(be.rob_size),
+            rcu: RetireControlUnit::new(be.retire_width),
+        }
+    }
+
+    /// Write a program (or any other data) into memory.
+    pub fn load(&mut self, addr: usize, data: &[u8]) {
+        self.mem.write(addr, data);
+    }
+
+    /// Load an ELF executable, starting fetch at its entry point with an
+    /// initial stack.
+    ///
+    /// NOTE: The stack only holds an empty argument vector, environment and
+    /// auxiliary vector (argc is zero).
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), String> {
+        self.traced(|core| core.load_segments(elf))
+    }
+
+    fn load_segments(&mut self, elf: &Elf) -> Result<(), String> {
+        for seg in elf.segments.iter() {
+            let end = seg.vaddr.checked_add(seg.memsz);
+            if seg.data.len() > seg.memsz || end.is_none_or(|e| e > LOAD_LIMIT)
+            {
+                return Err(format!("segment at {:08x} ({:#x} bytes) does \
+                    not fit in memory", seg.vaddr, seg.memsz));
+            }
+            trace!("[ELF] Loaded segment {:08x}-{:08x}", 
+                seg.vaddr, seg.vaddr + seg.memsz);
+            self.mem.write(seg.vaddr, &seg.data);
+            let bss = seg.vaddr + seg.data.len();
+            self.mem.write(bss, &vec![0; seg.memsz - seg.data.len()]);
+        }
+
+        // argc, argv[0], envp[0], and AT_NULL (with a 16-byte aligned 
+        // stack pointer)
+        let rsp = (STACK_TOP - 5 * 8) & !0xf;
+        self.mem.write(rsp, &[0; 5 * 8]);
+        self.prf.write(self.rat.resolve(Register::RSP), rsp);
+
+        self.next_pc = elf.entry;
+        self.tracer.syms = elf.syms.clone();
+        trace!("[ELF] Entry point {:08x}, rsp={:08x}", elf.entry, rsp);
+        Ok(())
+    }
+
+    /// Enable paging, identity-mapping all of memory with pages of some 
+    /// size.
+    pub fn map_memory(&mut self, size: PageSize) {
+        self.traced(|core| {
+            core.mmu.cr3 = Some(build_identity_map(&mut core.mem, size));
+        });
+    }
+
+    /// Run some function with trace output going to the logger for this 
+    /// core (see [crate::log::scope]).
+    fn traced<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let mut log = std::mem::take(&mut self.tracer.log);
+        let res = crate::log::scope(&mut log, || f(self));
+        self.tracer.log = log;
+        res
+    }
+
+    /// Check every retired instruction against the functional model,
+    /// starting from the current architectural state (see [CoSim]).
+    ///
+    /// NOTE: This must be called after the program has been loaded (and
+    /// after paging is enabled).
+    pub fn enable_cosim(&mut self) {
+        let mut emu = Emulator::new(self.next_pc, self.mem.clone());
+        for (i, val) in emu.regs.iter_mut().enumerate() {
+            *val = self.prf.read(self.rat.resolve_arn(Arn(i)));
+        }
+        emu.flags = [FlagGroup::Arith, FlagGroup::Carry].iter()
+            .map(|g| self.prf.read(self.rat.resolve_arn(Arn::flags(*g))))
+            .fold(0, |f, x| f | x);
+        self.rcu.cosim = Some(CoSim::new(emu));
+    }
+
+    /// Simulate a single cycle.
+    pub fn step(&mut self) {
+        self.traced(Self::cycle);
+    }
+
+    fn cycle(&mut self) {
+        let clk = self.clk;
+        self.tracer.clk = clk;
+        trace!("============ cycle {} ====================", clk);
+
+        self.eu.wakeup(&mut self.prf);
+
+        // Complete any outstanding line fills and TLB fills
+        self.caches.cycle(clk);
+        self.mmu.cycle(clk);
+
+        self.rcu.cycle(&mut self.rob, &mut self.rat, &mut self.prf, 
+            &mut self.bpu, &mut self.btb, &mut self.lsq, &mut self.caches,
+            &mut self.mem, &mut self.tracer, clk);
+        self.rat.print(&self.prf);
+        let redirect = self.eu.cycle(&mut self.rob, &mut self.prf, 
+            &mut self.lsq, &mut self.caches, &mut self.mmu, &self.mem, 
+            &mut self.tracer, clk);
+        if let Some(r) = redirect {
+            flush(r, &mut self.rob, &mut self.prf, &self.rat, &mut self.frat,
+                &mut self.alu_sched, &mut self.agu_sched, &mut self.eu, 
+                &mut self.lsq, &mut self.opq, &mut self.ibq, &mut self.ftq, 
+                &mut self.bpu, &mut self.idu, &mut self.ocu, 
+                &mut self.dispatch, &mut self.npc, &mut self.next_pc, 
+                &mut self.tracer
+            );
+        }
+        self.isu.cycle(&mut self.alu_sched, &mut self.agu_sched, 
+            &mut self.eu, &self.prf, &mut self.tracer, clk);
+        self.dispatch.cycle(
+            &mut self.btb, &mut self.opq, 
+            &mut self.alu_sched, &mut self.agu_sched, 
+            &mut self.prf, &mut self.rob, &mut self.frat, &mut self.lsq,
+            &mut self.tracer
+        );
+        self.ocu.cycle(&mut self.ftq, &self.ibq, &self.idu, &mut self.opq, 
+            &mut self.tracer, clk);
+        self.idu.cycle(&mut self.ibq, &mut self.opq, &mut self.ocu.oc, 
+            &mut self.tracer);
+        if self.ocu.decode_active() {
+            self.ifu.cycle(&mut self.ftq, &mut self.ibq, &mut self.caches, 
+                &mut self.mmu, &self.mem, &mut self.tracer);
+        }
+        self.npc.cycle(&mut self.next_pc, &mut self.bpu, &mut self.btb, 
+            &mut self.ftq);
+
+        self.clk += 1;
+    }
+
+    /// Returns true after a faulting instruction (ie. UD2) reaches 
+    /// retirement, at which point the program is finished.
+    pub fn halted(&self) -> bool {
+        self.rcu.halted.is_some()
+    }
+
+    /// Simulate until some cycle (or until the core halts).
+    pub fn run_until(&mut self, clk: usize) {
+        while self.clk < clk && !self.halted() {
+            self.step();
+        }
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.ifu.print_stats(out)?;
+        self.ocu.oc.print_stats(out)?;
+        self.caches.print_stats(out)?;
+        self.mmu.print_stats(out)?;
+        self.rcu.print_stats(out)?;
+        let retired = self.rcu.stats.insts;
+        self.dispatch.topdown.print_stats(retired, out)?;
+        Pmc::collect(self).print(out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::op::MacroOp;
+    use iced_x86::Register;
+
+    /// Run a flat binary (loaded at address zero) until the core halts.
+    fn run(prog: &[u8]) -> Core {
+        let mut core = Core::new(&Config::default());
+        core.load(0, prog);
+        core.run_until(10_000);
+        assert!(core.halted(), "core didn't halt");
+        core
+    }
+
+    fn reg(core: &Core, r: Register) -> usize {
+        core.prf.read(core.rat.resolve(r))
+    }
+
+    /// Trace output which can be read back after the run.
+    #[derive(Clone, Default)]
+    struct Buf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn halt_on_fault() {
+        let core = run(&[
+            0xb8, 0x01, 0x00, 0x00, 0x00,   // mov eax, 1
+            0x83, 0xc0, 0x02,               // add eax, 2
+            0x0f, 0x0b,                     // ud2
+            0xb8, 0x05, 0x00, 0x00, 0x00,   // mov eax, 5
+        ]);
+        assert!(matches!(core.rcu.halted, Some((8, MacroOp::Ud2))));
+        assert_eq!(reg(&core, Register::RAX), 3);
+    }
+
+    #[test]
+    fn sub_registers() {
+        let core = run(&[
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff,   // mov rax, -1
+            0xb0, 0x05,                                 // mov al, 5
+            0xbb, 0xff, 0xff, 0xff, 0xff,               // mov ebx, -1
+            0x66, 0xb9, 0x34, 0x12,                     // mov cx, 0x1234
+            0x80, 0xc3, 0x01,                           // add bl, 1
+            0x72, 0x02,                                 // jc 1f
+            0x0f, 0x0b,                                 // ud2
+            0x0f, 0x0b,                                 // 1: ud2
+        ]);
+        assert_eq!(core.rcu.halted.map(|(pc, _)| pc), Some(0x19));
+        assert_eq!(reg(&core, Register::RAX), 0xffff_ffff_ffff_ff05);
+        assert_eq!(reg(&core, Register::RBX), 0xffff_ff00);
+        assert_eq!(reg(&core, Register::RCX), 0x1234);
+    }
+
+    #[test]
+    fn dependency_chain() {
+        // 24 dependent adds, then 24 adds spread over four registers
+        let dep: Vec<u8> = [0x83, 0xc0, 0x01].repeat(24);
+        let indep: Vec<u8> = [
+            0x83, 0xc0, 0x01,   // add eax, 1
+            0x83, 0xc3, 0x01,   // add ebx, 1
+            0x83, 0xc1, 0x01,   // add ecx, 1
+            0x83, 0xc2, 0x01,   // add edx, 1
+        ].repeat(6);
+        let ud2 = [0x0f, 0x0b];
+        let a = run(&[dep, ud2.to_vec()].concat());
+        let b = run(&[indep, ud2.to_vec()].concat());
+        assert_eq!(reg(&a, Register::RAX), 24);
+        assert_eq!(reg(&b, Register::RAX), 6);
+        assert_eq!(reg(&b, Register::RDX), 6);
+
+        // Each add in the chain waits for the previous one
+        assert!(a.clk >= b.clk + 12, "{} {}", a.clk, b.clk);
+    }
+
+    #[test]
+    fn independent_cores() {
+        // Interleave two cores on the same thread, with only the first
+        // one traced
+        let (a_out, b_out) = (Buf::default(), Buf::default());
+        let mut a = Core::new(&Config::default());
+        let mut b = Core::new(&Config::default());
+        a.tracer.log.set_level(crate::log::LEVEL_EVENT);
+        a.tracer.log.set_output(Box::new(a_out.clone()));
+        b.tracer.log.set_output(Box::new(b_out.clone()));
+        a.load(0, &[
+            0xb8, 0x01, 0x00, 0x00, 0x00,   // mov eax, 1
+            0x0f, 0x0b,                     // ud2
+        ]);
+        b.load(0, &[
+            0xb8, 0x02, 0x00, 0x00, 0x00,   // mov eax, 2
+            0x0f, 0x0b,                     // ud2
+        ]);
+        while !a.halted() || !b.halted() {
+            assert!(a.clk < 1000 && b.clk < 1000);
+            a.run_until(a.clk + 1);
+            b.run_until(b.clk + 1);
+        }
+        assert_eq!(reg(&a, Register::RAX), 1);
+        assert_eq!(reg(&b, Register::RAX), 2);
+
+        let trace = String::from_utf8(a_out.0.take()).unwrap();
+        assert!(trace.contains("cycle 0 "), "{}", trace);
+        assert!(b_out.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn mispredicted_loop() {
+        let core = run(&[
+            0xb9, 0x0a, 0x00, 0x00, 0x00,   // mov ecx, 10
+            0xff, 0xc9,                     // 1: dec ecx
+            0x75, 0xfc,                     // jnz 1b
+            0x0f, 0x0b,                     // ud2
+        ]);
+        assert_eq!(core.rcu.halted.map(|(pc, _)| pc), Some(9));
+        assert_eq!(reg(&core, Register::RCX), 0);
+    }
+
+    /// A branch which depends on two cold loads (so that it resolves late,
+    /// and is mispredicted as not-taken), followed by some code on the 
+    /// wrong path and 'mov eax, 7; ud2' at the target.
+    fn wrong_path(code: &[u8]) -> Core {
+        let mut prog = vec![
+            0xb9, 0x00, 0x10, 0x00, 0x00,   // mov ecx, 0x1000
+            0x48, 0x8b, 0x01,               // mov rax, [rcx]
+            0x48, 0x8b, 0x00,               // mov rax, [rax]
+            0x48, 0x85, 0xc0,               // test rax, rax
+            0x74, code.len() as u8,         // jz 1f
+        ];
+        prog.extend(code);
+        prog.extend([
+            0xb8, 0x07, 0x00, 0x00, 0x00,   // 1: mov eax, 7
+            0x0f, 0x0b,                     // ud2
+        ]);
+        let mut core = Core::new(&Config::default());
+        core.load(0, &prog);
+        core.load(0x1000, &0x2000u64.to_le_bytes());
+        core.run_until(10_000);
+        assert!(core.halted(), "core didn't halt");
+        assert_eq!(core.rcu.halted.map(|(pc, _)| pc), 
+            Some(0x10 + code.len() + 5));
+        assert_eq!(reg(&core, Register::RAX), 7);
+        core
+    }
+
+    #[test]
+    fn wrong_path_indirect_jump() {
+        // An indirect jump outside memory is only a fault if it retires
+        wrong_path(&[
+            0x48, 0xbb, 0x00, 0x00, 0x00, 0x00, 
+            0x00, 0x40, 0x00, 0x00,         // mov rbx, 0x4000_0000_0000
+            0xff, 0xe3,                     // jmp rbx
+        ]);
+        let core = run(&[
+            0x48, 0xbb, 0x00, 0x00, 0x00, 0x00, 
+            0x00, 0x40, 0x00, 0x00,         // mov rbx, 0x4000_0000_0000
+            0xff, 0xe3,                     // jmp rbx
+        ]);
+        assert!(matches!(core.rcu.halted, 
+            Some((0x4000_0000_0000, MacroOp::FetchFault))));
+    }
+
+    #[test]
+    fn wrong_path_direct_jump() {
+        // A direct jump whose target wraps around the address space
+        wrong_path(&[
+            0xe9, 0x00, 0xff, 0xff, 0xff,   // jmp -0x100
+        ]);
+    }
+
+    #[test]
+    fn icache_misses() {
+        // A loop over two lines, which only misses on the first iteration
+        let mut prog = vec![0xb9, 0x32, 0x00, 0x00, 0x00];    // mov ecx, 50
+        prog.resize(0x7c, 0x90);                            // 1: nop
+        prog.extend([
+            0xff, 0xc9,                                     // dec ecx
+            0x75, 0xc0,                                     // jnz 1b
+            0x0f, 0x0b,                                     // ud2
+        ]);
+        let core = run(&prog);
+        assert_eq!(reg(&core, Register::RCX), 0);
+        let s = &core.caches.l1i.stats;
+        assert!(core.ifu.stats.icache_miss > 0);
+        assert!(s.misses + s.mshr_hits < 8, "{:?}", s);
+    }
+
+    #[test]
+    fn op_cache_loop() {
+        // The loop body is in a single op cache entry, which spans two
+        // fetch blocks
+        let mut prog = vec![0xb9, 0x64, 0x00, 0x00, 0x00];    // mov ecx, 100
+        prog.resize(0x1c, 0x90);                            // nop
+        prog.extend([
+            0x83, 0xc0, 0x01,                               // 1: add eax, 1
+            0x83, 0xc0, 0x01,                               // add eax, 1
+            0xff, 0xc9,                                     // dec ecx
+            0x75, 0xf6,                                     // jnz 1b
+            0x0f, 0x0b,                                     // ud2
+        ]);
+        let core = run(&prog);
+        assert_eq!(reg(&core, Register::RAX), 200);
+
+        // Each delivery of the entry is a single hit (including a few on
+        // the wrong path after the loop exits)
+        let oc = &core.ocu.oc.stats;
+        assert!(oc.oc_ops > 300, "{:?}", oc);
+        assert!(oc.hits * 4 >= oc.oc_ops && oc.hits < 120, "{:?}", oc);
+    }
+}
@@ -0,0 +1,134 @@
+//! Trace output.
+//!
+//! Messages are tagged with the pipeline stage which printed them (ie.
+//! `[IFU] ...`), and each stage has its own verbosity level. A message is
+//! only written when the level for its stage is at least the level of the
+//! message.
+//!
+//! NOTE: Each core has its own [Logger] (see [crate::event::Tracer]).
+//! Messages go to the logger for the core which is being simulated on the
+//! current thread (see [scope]), and are dropped otherwise.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{ self, Write };
+
+/// Nothing is traced.
+pub const LEVEL_OFF: u8 = 0;
+/// Events for individual instructions (fetch, dispatch, retire, etc).
+pub const LEVEL_EVENT: u8 = 1;
+/// Events plus dumps of pipeline state on every cycle.
+pub const LEVEL_DETAIL: u8 = 2;
+
+pub struct Logger {
+    /// Level for stages which aren't in 'levels'
+    pub default: u8,
+    /// Level for each stage (keyed by the lowercase tag)
+    pub levels: HashMap<String, u8>,
+    out: Box<dyn Write>,
+    /// The first error from writing a message (nothing else is written
+    /// after an error)
+    err: Option<io::Error>,
+}
+impl Logger {
+    /// Create a logger which writes to stdout (with tracing disabled).
+    pub fn new() -> Self {
+        Self {
+            default: LEVEL_OFF,
+            levels: HashMap::new(),
+            out: Box::new(std::io::stdout()),
+            err: None,
+        }
+    }
+
+    /// Set the level for all stages without their own level.
+    pub fn set_level(&mut self, level: u8) {
+        self.default = level;
+    }
+
+    /// Set the level for a single stage (ie. "ifu").
+    pub fn set_stage_level(&mut self, stage: &str, level: u8) {
+        self.levels.insert(stage.to_lowercase(), level);
+    }
+
+    /// Redirect trace output (which goes to stdout by default).
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Flush the output, returning the first error from writing any
+    /// message.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.err.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+
+    fn level(&self, tag: Option<&str>) -> u8 {
+        tag.and_then(|t| self.levels.get(&t.to_lowercase()))
+            .copied().unwrap_or(self.default)
+    }
+
+    /// Returns true if some message level might be enabled for any stage.
+    fn any_enabled(&self, level: u8) -> bool {
+        self.default >= level || self.levels.values().any(|l| *l >= level)
+    }
+}
+impl Default for Logger {
+    fn default() -> Self { Self::new() }
+}
+
+thread_local! {
+    /// The logger for the core being simulated on this thread (if any)
+    static LOGGER: RefCell<Option<Logger>> = const { RefCell::new(None) };
+}
+
+/// Run some function with messages going to some logger.
+pub fn scope<R>(logger: &mut Logger, f: impl FnOnce() -> R) -> R {
+    let prev = LOGGER.with(|l| l.replace(Some(std::mem::take(logger))));
+    let res = f();
+    *logger = LOGGER.with(|l| l.replace(prev)).unwrap();
+    res
+}
+
+/// Return the tag at the start of a message (ie. "IFU" for "[IFU] ...").
+fn tag(msg: &str) -> Option<&str> {
+    let end = msg.find(']')?;
+    msg.strip_prefix('[').map(|_| &msg[1..end])
+}
+
+/// Write a message (used by [trace!] and [detail!]).
+pub fn write(level: u8, args: std::fmt::Arguments) {
+    LOGGER.with(|l| {
+        let mut l = l.borrow_mut();
+        let Some(l) = l.as_mut() else {
+            return;
+        };
+        if l.err.is_some() || !l.any_enabled(level) {
+            return;
+        }
+        let msg = args.to_string();
+        if l.level(tag(&msg)) >= level {
+            if let Err(e) = writeln!(l.out, "{}", msg) {
+                l.err = Some(e);
+            }
+        }
+    });
+}
+
+/// Trace an event.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::LEVEL_EVENT, format_args!($($arg)*))
+    };
+}
+
+/// Trace pipeline state which is dumped on every cycle.
+#[macro_export]
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::LEVEL_DETAIL, format_args!($($arg)*))
+    };
+}
@@ -0,0 +1,585 @@
+//! Load/store queue.
+
+use std::collections::VecDeque;
+
+use crate::mem::*;
+use crate::rf::*;
+use crate::mdp::*;
+use crate::cache::*;
+use crate::hier::*;
+use crate::mmu::*;
+use crate::config::LsqConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadState {
+    /// Waiting for address generation
+    Addr,
+    /// Waiting to be executed
+    Ready,
+    /// Executed, and the result is available on some cycle
+    Inflight(usize),
+    /// Result has been written back
+    Done,
+}
+
+/// An entry in the load queue.
+#[derive(Clone, Copy, Debug)]
+pub struct LQEntry {
+    pub rob_idx: usize,
+    /// Program counter of the associated instruction
+    pub pc: usize,
+    /// Destination physical register
+    pub dst: Prn,
+    /// Width of the access in bytes
+    pub size: usize,
+    pub addr: Option<usize>,
+    pub data: usize,
+    /// Sequence number of the next store allocated after this load
+    /// (ie. all stores with a lower sequence number are older)
+    pub sq_seq: usize,
+    /// Sequence number of the store this load is predicted to depend on
+    pub dep_seq: Option<usize>,
+    /// Sequence number of the store that data was forwarded from
+    pub fwd_seq: Option<usize>,
+    pub state: LoadState,
+}
+impl LQEntry {
+    /// Returns true if this load has already read its data.
+    fn executed(&self) -> bool {
+        matches!(self.state, LoadState::Inflight(_) | LoadState::Done)
+    }
+}
+
+/// An entry in the store queue.
+#[derive(Clone, Copy, Debug)]
+pub struct SQEntry {
+    pub rob_idx: usize,
+    /// Program counter of the associated instruction
+    pub pc: usize,
+    pub seq: usize,
+    /// Width of the access in bytes
+    pub size: usize,
+    pub addr: Option<usize>,
+    pub data: Option<usize>,
+    /// Physical address (or `None` if the address isn't mapped)
+    pub paddr: Option<usize>,
+    /// The address has been translated
+    pub translated: bool,
+}
+impl SQEntry {
+    /// Returns true if this store writes any of the bytes in some range.
+    ///
+    /// NOTE: Stores outside of memory are dropped, and never overlap.
+    fn overlaps(&self, addr: usize, size: usize) -> bool {
+        self.addr.is_some_and(|a| in_memory(a, self.size) &&
+            a < addr + size && addr < a + self.size
+        )
+    }
+
+    /// Returns true if this store writes all of the bytes in some range.
+    fn contains(&self, addr: usize, size: usize) -> bool {
+        self.addr.is_some_and(|a| in_memory(a, self.size) &&
+            a <= addr && addr + size <= a + self.size
+        )
+    }
+}
+
+/// Result of checking the store queue for some load.
+enum StoreCheck {
+    /// No older store writes to the same bytes
+    Miss,
+    /// The youngest older store covers all bytes: forward its data
+    Forward(usize, usize),
+    /// The load must wait (for the address of a store it's predicted to
+    /// depend on, or for an older store which only partially overlaps to
+    /// be written back)
+    Stall,
+}
+
+/// The load queue and store queue (both in program order).
+///
+/// NOTE: Loads which forward data from a store take a fixed latency. Cache
+/// accesses take the hit latency of the L1D (or longer when they miss).
+pub struct LoadStoreQueue {
+    pub cfg: LsqConfig,
+    pub lq: VecDeque<LQEntry>,
+    pub sq: VecDeque<SQEntry>,
+    /// Memory dependence predictor
+    pub mdp: StoreSetPredictor,
+    /// Sequence number for the next store
+    next_seq: usize,
+    /// Number of memory-ordering violations
+    pub violations: usize,
+}
+impl LoadStoreQueue {
+    pub fn new(cfg: &LsqConfig) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            lq: VecDeque::new(),
+            sq: VecDeque::new(),
+            mdp: StoreSetPredictor::new(),
+            next_seq: 0,
+            violations: 0,
+        }
+    }
+
+    pub fn lq_free(&self) -> usize { self.cfg.lq_size - self.lq.len() }
+    pub fn sq_free(&self) -> usize { self.cfg.sq_size - self.sq.len() }
+
+    /// Allocate a load queue entry (at dispatch).
+    pub fn alloc_load(&mut self, rob_idx: usize, pc: usize, dst: Prn,
+                      size: usize)
+    {
+        assert!(self.lq.len() < self.cfg.lq_size);
+        let dep_seq = self.mdp.predict_load(pc);
+        if let Some(seq) = dep_seq {
+            trace!("[MDP] Load {:08x} predicted to depend on store #{}",
+                pc, seq);
+        }
+        self.lq.push_back(LQEntry {
+            rob_idx, pc, dst, size, addr: None, data: 0,
+            sq_seq: self.next_seq, dep_seq, fwd_seq: None,
+            state: LoadState::Addr,
+        });
+    }
+
+    /// Allocate a store queue entry (at dispatch).
+    pub fn alloc_store(&mut self, rob_idx: usize, pc: usize, size: usize) {
+        assert!(self.sq.len() < self.cfg.sq_size);
+        self.sq.push_back(SQEntry {
+            rob_idx, pc, seq: self.next_seq, size, addr: None, data: None,
+            paddr: None, translated: false,
+        });
+        self.mdp.dispatch_store(pc, self.next_seq);
+        self.next_seq += 1;
+    }
+
+    /// Write the address of a load (after address generation).
+    pub fn set_load_addr(&mut self, rob_idx: usize, addr: usize) {
+        let ent = self.lq.iter_mut().find(|e| e.rob_idx == rob_idx).unwrap();
+        ent.addr = Some(addr);
+        ent.state = LoadState::Ready;
+    }
+
+    /// Write the address and data of a store (after address generation).
+    ///
+    /// If a younger load to the same bytes has already read stale data,
+    /// this is a memory-ordering violation: returns the reorder buffer
+    /// index of the oldest such load, which must be squashed and replayed.
+    pub fn set_store(&mut self, rob_idx: usize, addr: usize, data: usize)
+        -> Option<usize>
+    {
+        let ent = self.sq.iter_mut().find(|e| e.rob_idx == rob_idx).unwrap();
+        ent.addr = Some(addr);
+        ent.data = Some(data);
+        let st = *ent;
+
+        // Loads which forwarded from a younger store read the right data
+        let ld = self.lq.iter().find(|ld| {
+            ld.sq_seq > st.seq && ld.executed()
+                && st.overlaps(ld.addr.unwrap(), ld.size)
+                && ld.fwd_seq.is_none_or(|seq| seq < st.seq)
+        })?;
+        trace!("[LSQ] Ordering violation: load {:08x} before store {:08x}",
+            ld.pc, st.pc);
+        self.violations += 1;
+        self.mdp.violation(ld.pc, st.pc);
+        Some(ld.rob_idx)
+    }
+
+    /// Check older stores for some load.
+    fn check_stores(&self, ld: &LQEntry) -> StoreCheck {
+        let addr = ld.addr.unwrap();
+        let older = self.sq.iter().filter(|s| s.seq < ld.sq_seq);
+
+        // NOTE: Loads are executed speculatively ahead of older stores with
+        // unknown addresses, unless the memory dependence predictor says
+        // that they depend on one of them.
+        if let Some(dep) = ld.dep_seq {
+            if older.clone().any(|s| s.seq == dep && s.addr.is_none()) {
+                return StoreCheck::Stall;
+            }
+        }
+        if !in_memory(addr, ld.size) {
+            return StoreCheck::Miss;
+        }
+        match older.rev().find(|s| s.overlaps(addr, ld.size)) {
+            None => StoreCheck::Miss,
+            Some(s) if s.contains(addr, ld.size) => {
+                let shift = (addr - s.addr.unwrap()) * 8;
+                StoreCheck::Forward(s.seq, s.data.unwrap() >> shift)
+            },
+            Some(_) => StoreCheck::Stall,
+        }
+    }
+
+    /// Execute ready loads and complete inflight loads, and translate the
+    /// addresses of stores. Returns the reorder buffer index of each 
+    /// completed load or store, along with the destination of each load.
+    pub fn cycle(&mut self, prf: &mut PhysicalRegisterFile, 
+                 caches: &mut CacheHierarchy, mmu: &mut Mmu, 
+                 mem: &Memory, clk: usize) 
+        -> Vec<(usize, Option<Prn>)>
+    {
+        let mut res = Vec::new();
+
+        // Stores are complete once their address has been translated
+        let mut num = 0;
+        for st in self.sq.iter_mut() {
+            if num == self.cfg.stores_per_cycle { break; }
+            let Some(addr) = st.addr else { continue };
+            if st.translated { continue; }
+            num += 1;
+            match mmu.translate(mem, AccessKind::Data, addr) {
+                Err(TranslateErr::Miss) => {
+                    trace!("[LSQ] Store {:08x} stalled for DTLB miss", addr);
+                    continue;
+                },
+                Err(TranslateErr::Fault) => st.paddr = None,
+                Ok(paddr) => st.paddr = Some(paddr),
+            }
+            st.translated = true;
+            res.push((st.rob_idx, None));
+        }
+
+        // Complete loads whose results are available this cycle
+        for ld in self.lq.iter_mut() {
+            if let LoadState::Inflight(done) = ld.state {
+                if done <= clk {
+                    ld.state = LoadState::Done;
+                    trace!("[LSQ] Load {:08x} complete: {:016x}",
+                        ld.addr.unwrap(), ld.data);
+                    prf.write(ld.dst, ld.data);
+                    res.push((ld.rob_idx, Some(ld.dst)));
+                }
+            }
+        }
+
+        // Execute the oldest ready loads
+        let mut num = 0;
+        for i in 0..self.lq.len() {
+            if num == self.cfg.loads_per_cycle { break; }
+            let ld = self.lq[i];
+            if ld.state != LoadState::Ready { continue; }
+            let addr = ld.addr.unwrap();
+            let mut fwd_seq = None;
+            let mut done = clk + self.cfg.fwd_latency;
+            let data = match self.check_stores(&ld) {
+                StoreCheck::Stall => {
+                    trace!("[LSQ] Load {:08x} stalled for older store", addr);
+                    continue;
+                },
+                StoreCheck::Forward(seq, data) => {
+                    trace!("[LSQ] Load {:08x} forwarded from store", addr);
+                    fwd_seq = Some(seq);
+                    data
+                },
+                StoreCheck::Miss => {
+                    let xlat = mmu.translate(mem, AccessKind::Data, addr);
+                    let paddr = match xlat {
+                        Ok(paddr) => paddr,
+                        Err(TranslateErr::Miss) => {
+                            trace!("[LSQ] Load {:08x} stalled for DTLB miss",
+                                addr);
+                            continue;
+                        },
+                        Err(TranslateErr::Fault) => usize::MAX,
+                    };
+                    match l1d_access(caches, ld.pc, paddr, ld.size, false, 
+                                     clk) {
+                        Ok(cyc) => done = cyc,
+                        Err(e) => {
+                            trace!("[LSQ] Load {:08x} stalled: {:?}", 
+                                addr, e);
+                            continue;
+                        },
+                    }
+                    trace!("[LSQ] Load {:08x} from memory", addr);
+                    load(mem, paddr, ld.size)
+                },
+            };
+            let mask = if ld.size == 8 { usize::MAX }
+                else { (1 << (ld.size * 8)) - 1 };
+            let ent = &mut self.lq[i];
+            ent.data  = data & mask;
+            ent.fwd_seq = fwd_seq;
+            ent.state = LoadState::Inflight(done);
+            num += 1;
+        }
+        res
+    }
+
+    /// Release the entry for a retired load.
+    pub fn retire_load(&mut self, rob_idx: usize) {
+        let ent = self.lq.pop_front().unwrap();
+        assert!(ent.rob_idx == rob_idx);
+    }
+
+    /// Write the data for a retired store back to memory, returning the
+    /// store (if it was written).
+    ///
+    /// NOTE: Committed stores don't wait for the L1D. A store which misses
+    /// only allocates the line (if there's a free MSHR). Stores to unmapped
+    /// addresses, or to addresses outside of memory, are dropped.
+    pub fn retire_store(&mut self, rob_idx: usize, 
+                        caches: &mut CacheHierarchy, mem: &mut Memory, 
+                        clk: usize) -> Option<CommittedStore>
+    {
+        let ent = self.sq.pop_front().unwrap();
+        assert!(ent.rob_idx == rob_idx);
+        let Some(addr) = ent.paddr else {
+            trace!("[LSQ] Dropped store to unmapped address {:016x}",
+                ent.addr.unwrap());
+            return None;
+        };
+        if !in_memory(addr, ent.size) {
+            trace!("[LSQ] Store to invalid address {:016x}", addr);
+            return None;
+        }
+        let data = ent.data.unwrap().to_le_bytes();
+        trace!("[LSQ] Store {:08x} committed: {:02x?}",
+            addr, &data[..ent.size]);
+        mem.write(addr, &data[..ent.size]);
+        let _ = l1d_access(caches, ent.pc, addr, ent.size, true, clk);
+        Some(CommittedStore::new(addr, ent.size, ent.data.unwrap()))
+    }
+
+    /// Remove all entries matching some predicate.
+    pub fn squash(&mut self, f: impl Fn(usize) -> bool) {
+        self.lq.retain(|e| !f(e.rob_idx));
+        self.sq.retain(|e| !f(e.rob_idx));
+    }
+}
+
+/// A store which was written back to memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommittedStore {
+    pub addr: usize,
+    pub size: usize,
+    /// The stored bytes (zero-extended)
+    pub data: usize,
+}
+impl CommittedStore {
+    pub fn new(addr: usize, size: usize, data: usize) -> Self {
+        let mask = if size >= 8 { usize::MAX } else { (1 << (size * 8)) - 1 };
+        Self { addr, size, data: data & mask }
+    }
+}
+
+/// Access the L1D on behalf of a load or store, returning the cycle when the
+/// access completes. Stores mark the lines dirty.
+///
+/// NOTE: Accesses which cross a line boundary touch both lines. Accesses 
+/// to invalid addresses don't touch the cache at all. Accesses which cross
+/// a page boundary are translated with the first page.
+fn l1d_access(caches: &mut CacheHierarchy, pc: usize, addr: usize, 
+              size: usize, store: bool, clk: usize) 
+    -> Result<usize, CacheErr>
+{
+    if !in_memory(addr, size) {
+        return Ok(clk + caches.l1d.cfg.hit_latency);
+    }
+    let last = addr + size - 1;
+    let crosses = caches.l1d.line_addr(addr) != caches.l1d.line_addr(last);
+    let mut access = |addr: usize| {
+        if store { 
+            caches.store(pc, addr) 
+        } else { 
+            caches.access(AccessKind::Data, pc, addr) 
+        }
+    };
+    let done = access(addr)?;
+    if crosses {
+        return Ok(done.max(access(last)?));
+    }
+    Ok(done)
+}
+
+/// Read from memory on behalf of a load.
+///
+/// NOTE: Loads on the wrong path may use any address. For now, loads from
+/// outside of memory (or from unmapped addresses) just return zero.
+fn load(mem: &Memory, addr: usize, size: usize) -> usize {
+    if !in_memory(addr, size) {
+        trace!("[LSQ] Load from invalid address {:016x}", addr);
+        return 0;
+    }
+    let mut buf = [0u8; 8];
+    buf[..size].copy_from_slice(mem.read(addr, size));
+    usize::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+
+    /// A load/store queue along with the state it operates on.
+    struct Harness {
+        lsq: LoadStoreQueue,
+        prf: PhysicalRegisterFile,
+        caches: CacheHierarchy,
+        mmu: Mmu,
+        mem: Memory,
+        clk: usize,
+    }
+    impl Harness {
+        fn new() -> Self {
+            Self {
+                lsq: LoadStoreQueue::new(&LsqConfig::default()),
+                prf: PhysicalRegisterFile::new(180),
+                caches: CacheHierarchy::new(&CachesConfig::default()),
+                mmu: Mmu::new(None, &TlbConfig::default()),
+                mem: Memory::new(),
+                clk: 0,
+            }
+        }
+
+        fn load(&mut self, rob_idx: usize, pc: usize, size: usize) -> Prn {
+            let dst = self.prf.alloc().unwrap();
+            self.lsq.alloc_load(rob_idx, pc, dst, size);
+            dst
+        }
+
+        /// Simulate a single cycle.
+        fn cycle(&mut self) {
+            self.clk += 1;
+            self.caches.cycle(self.clk);
+            self.lsq.cycle(&mut self.prf, &mut self.caches, &mut self.mmu,
+                &self.mem, self.clk);
+        }
+
+        /// Simulate until the load for some ROB entry completes.
+        fn run_load(&mut self, rob_idx: usize) -> usize {
+            for _ in 0..1000 {
+                let ld = self.lsq.lq.iter().find(|e| e.rob_idx == rob_idx);
+                if ld.unwrap().state == LoadState::Done {
+                    return self.prf.read(ld.unwrap().dst);
+                }
+                self.cycle();
+            }
+            panic!("load didn't complete");
+        }
+
+        fn state(&self, rob_idx: usize) -> LoadState {
+            self.lsq.lq.iter().find(|e| e.rob_idx == rob_idx).unwrap().state
+        }
+    }
+
+    #[test]
+    fn forward_from_store() {
+        let mut h = Harness::new();
+        h.lsq.alloc_store(0, 0x10, 8);
+        h.load(1, 0x24, 4);
+        h.lsq.set_store(0, 0x1f0_0000, 0x1122_3344_5566_7788);
+        h.lsq.set_load_addr(1, 0x1f0_0004);
+        assert_eq!(h.run_load(1), 0x1122_3344);
+    }
+
+    #[test]
+    fn forward_from_youngest() {
+        let mut h = Harness::new();
+        h.lsq.alloc_store(0, 0x10, 8);
+        h.lsq.alloc_store(1, 0x14, 2);
+        h.load(2, 0x28, 2);
+        h.lsq.set_store(0, 0x1f0_1000, 0x1111);
+        h.lsq.set_store(1, 0x1f0_1000, 0x2222);
+        h.lsq.set_load_addr(2, 0x1f0_1000);
+        assert_eq!(h.run_load(2), 0x2222);
+    }
+
+    #[test]
+    fn load_from_memory() {
+        let mut h = Harness::new();
+        h.mem.write(0x1f0_2000, &0xdead_beef_u32.to_le_bytes());
+        h.lsq.alloc_store(0, 0x10, 8);
+        h.load(1, 0x24, 4);
+        h.lsq.set_store(0, 0x1f0_2100, 0);
+        h.lsq.set_load_addr(1, 0x1f0_2000);
+        assert_eq!(h.run_load(1), 0xdead_beef);
+    }
+
+    #[test]
+    fn partial_overlap_stalls() {
+        // The load must wait until the store is written back
+        let mut h = Harness::new();
+        h.mem.write(0x1f0_3000, &[0xff; 8]);
+        h.lsq.alloc_store(0, 0x10, 4);
+        h.load(1, 0x24, 8);
+        h.lsq.set_store(0, 0x1f0_3000, 0x1234_5678);
+        h.lsq.set_load_addr(1, 0x1f0_3000);
+        for _ in 0..10 {
+            h.cycle();
+        }
+        assert_eq!(h.state(1), LoadState::Ready);
+
+        h.lsq.retire_store(0, &mut h.caches, &mut h.mem, h.clk);
+        assert_eq!(h.run_load(1), 0xffff_ffff_1234_5678);
+    }
+
+    #[test]
+    fn out_of_range_store() {
+        // Stores outside of memory are dropped, and never forward data
+        let mut h = Harness::new();
+        h.mem.write(RAM_LEN - 8, &[0xff; 4]);
+        h.lsq.alloc_store(0, 0x10, 8);
+        h.lsq.alloc_store(1, 0x14, 8);
+        h.load(2, 0x28, 8);
+        h.load(3, 0x2c, 4);
+        h.lsq.set_store(0, 0x4000_0000_0000, 0x1111);
+        h.lsq.set_store(1, RAM_LEN - 4, 0x2222);
+        h.lsq.set_load_addr(2, 0x4000_0000_0000);
+        h.lsq.set_load_addr(3, RAM_LEN - 6);
+        assert_eq!(h.run_load(2), 0);
+        assert_eq!(h.run_load(3), 0xffff);
+
+        h.lsq.retire_store(0, &mut h.caches, &mut h.mem, h.clk);
+        h.lsq.retire_store(1, &mut h.caches, &mut h.mem, h.clk);
+        assert_eq!(h.mem.read(RAM_LEN - 4, 2), &[0; 2]);
+    }
+
+    #[test]
+    fn ordering_violation() {
+        let mut h = Harness::new();
+        h.lsq.alloc_store(0, 0x10, 8);
+        h.load(1, 0x20, 8);
+        h.load(2, 0x30, 8);
+
+        // Both loads execute before the store address is known, but only
+        // the first one reads the same bytes
+        h.lsq.set_load_addr(1, 0x1f0_4000);
+        h.lsq.set_load_addr(2, 0x1f0_5000);
+        while h.state(2) != LoadState::Done {
+            h.cycle();
+        }
+        assert_eq!(h.lsq.set_store(0, 0x1f0_4000, 5), Some(1));
+        assert_eq!(h.lsq.violations, 1);
+
+        // The replayed load is predicted to depend on the next instance of
+        // the store, so it waits for the store address
+        h.lsq.squash(|idx| idx >= 1);
+        h.lsq.alloc_store(3, 0x10, 8);
+        h.load(4, 0x20, 8);
+        assert_eq!(h.lsq.lq[0].dep_seq, Some(h.lsq.sq[1].seq));
+        h.lsq.set_load_addr(4, 0x1f0_4000);
+        for _ in 0..10 {
+            h.cycle();
+        }
+        assert_eq!(h.state(4), LoadState::Ready);
+        h.lsq.set_store(3, 0x1f0_6000, 0);
+        assert_eq!(h.run_load(4), 5);
+    }
+
+    #[test]
+    fn no_violation_after_forwarding() {
+        // A load which forwarded from a younger store read the right data
+        let mut h = Harness::new();
+        h.lsq.alloc_store(0, 0x10, 8);
+        h.lsq.alloc_store(1, 0x14, 8);
+        h.load(2, 0x20, 8);
+        h.lsq.set_store(1, 0x1f0_4000, 7);
+        h.lsq.set_load_addr(2, 0x1f0_4000);
+        assert_eq!(h.run_load(2), 7);
+        assert_eq!(h.lsq.set_store(0, 0x1f0_4000, 5), None);
+    }
+}
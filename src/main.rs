@@ -1,124 +1,247 @@
-#![allow(unused_mut)]
-#![allow(dead_code)]
-#![allow(unused_variables)]
-#![allow(unused_imports)]
-#![allow(unused_assignments)]
+use std::io::Write;
+use std::fs::File;
+use std::io::BufWriter;
+
+use clap::Parser;
+
+use z2pl::Core;
+use z2pl::config::*;
+use z2pl::tlb::*;
+use z2pl::elf::*;
+use z2pl::event::*;
+use z2pl::pipeview::*;
+use z2pl::pmc::*;
+use z2pl::bp::DirectionPredictorKind;
+use z2pl::hier::InclusionPolicy;
+
+/// Cycle-level simulator for the Zen 2 pipeline.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Program to run (an ELF executable, or a flat binary)
+    #[arg(default_value = "./code/test.bin", env = "Z2PL_PROGRAM")]
+    program: String,
+
+    /// Address where a flat binary is loaded
+    #[arg(long, default_value = "0", value_parser = parse_addr)]
+    load_addr: usize,
+
+    /// Address of the first instruction (defaults to the ELF entry point,
+    /// or the load address for flat binaries)
+    #[arg(long, value_parser = parse_addr)]
+    start_pc: Option<usize>,
+
+    /// Stop after simulating this many cycles (by default, run until the
+    /// program halts)
+    #[arg(long)]
+    max_cycles: Option<usize>,
+
+    /// Stop after retiring this many instructions
+    #[arg(long)]
+    max_insts: Option<usize>,
+
+    /// Check every retired instruction against a functional model, and
+    /// stop at the first mismatch
+    #[arg(long)]
+    cosim: bool,
+
+    /// Microarchitecture parameters (a preset name, or a TOML/JSON file)
+    #[arg(long, env = "Z2PL_CONFIG")]
+    config: Option<String>,
+
+    /// Override the direction predictor
+    #[arg(long, env = "Z2PL_DIRECTION_PREDICTOR")]
+    direction_predictor: Option<DirectionPredictorKind>,
+
+    /// Override the inclusion policy for the L2 and L3
+    #[arg(long, env = "Z2PL_INCLUSION_POLICY")]
+    inclusion_policy: Option<InclusionPolicy>,
+
+    /// Enable paging, identity-mapping all of memory with pages of this 
+    /// size (4k, 2m or 1g)
+    #[arg(long, env = "Z2PL_PAGE_SIZE")]
+    page_size: Option<PageSize>,
+
+    /// Trace verbosity for all stages (0: off, 1: events, 2: events and
+    /// pipeline state)
+    #[arg(short, long, default_value_t = 2)]
+    verbosity: u8,
+
+    /// Trace verbosity for a single stage (ie. 'ifu=1' or 'rcu=0'), 
+    /// may be repeated
+    #[arg(long = "trace-stage", value_name = "STAGE=LEVEL", 
+        value_parser = parse_stage_level)]
+    stage_levels: Vec<(String, u8)>,
+
+    /// Write the trace to a file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    trace_out: Option<String>,
+
+    /// Also write pipeline events to a file (as JSON, one per line)
+    #[arg(long, value_name = "FILE")]
+    events_out: Option<String>,
+
+    /// Write a pipeline trace for Konata to a file
+    #[arg(long, value_name = "FILE")]
+    konata_out: Option<String>,
+
+    /// Write a pipeline trace in gem5's O3PipeView format to a file
+    #[arg(long, value_name = "FILE")]
+    o3pipeview_out: Option<String>,
+
+    /// Write statistics to a file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    stats_out: Option<String>,
+
+    /// Also write performance counters to a file (as JSON)
+    #[arg(long, value_name = "FILE")]
+    pmc_out: Option<String>,
+}
 
-pub mod util;
-pub mod front;
+/// Parse a decimal or hexadecimal ('0x' prefix) address.
+fn parse_addr(s: &str) -> Result<usize, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|e| format!("invalid address '{}': {}", s, e))
+}
 
-pub mod dispatch;
-pub mod issue;
-pub mod retire;
+fn parse_stage_level(s: &str) -> Result<(String, u8), String> {
+    let (stage, level) = s.split_once('=')
+        .ok_or_else(|| format!("expected STAGE=LEVEL, got '{}'", s))?;
+    let level = level.parse()
+        .map_err(|e| format!("invalid level '{}': {}", level, e))?;
+    Ok((stage.to_string(), level))
+}
 
-pub mod mem;
-pub mod rf;
-pub mod exec;
-pub mod op;
+fn create(path: &str) -> Box<dyn Write + Send> {
+    let f = File::create(path).unwrap_or_else(|e| die(path, e));
+    Box::new(BufWriter::new(f))
+}
 
-use std::fs::File;
-use std::io::Read;
-use std::collections::{ VecDeque, BTreeMap };
-use std::sync::{ Arc, RwLock };
-
-use crate::util::*;
-use crate::front::*;
-use crate::dispatch::*;
-use crate::issue::*;
-use crate::retire::*;
-use crate::mem::*;
-use crate::rf::*;
-use crate::op::*;
-use crate::exec::*;
+fn die(ctx: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("error: {}: {}", ctx, e);
+    std::process::exit(1);
+}
 
 fn main() {
+    let args = Args::parse();
+
+    // Microarchitecture parameters
+    let mut cfg = match &args.config {
+        Some(s) => Config::from_arg(s).unwrap_or_else(|e| die("config", e)),
+        None => Config::default(),
+    };
+    if let Some(kind) = args.direction_predictor {
+        cfg.frontend.direction_predictor = kind;
+    }
+    if let Some(policy) = args.inclusion_policy {
+        cfg.caches.inclusion = policy;
+    }
+
+    let mut core = Core::new(&cfg);
+
+    // Trace output
+    let log = &mut core.tracer.log;
+    log.set_level(args.verbosity);
+    for (stage, level) in args.stage_levels.iter() {
+        log.set_stage_level(stage, *level);
+    }
+    if let Some(path) = &args.trace_out {
+        log.set_output(create(path));
+    }
+    core.tracer.add_sink(Box::new(TextSink));
+    if let Some(path) = &args.events_out {
+        core.tracer.add_sink(Box::new(JsonSink::new(create(path))));
+    }
+    if let Some(path) = &args.konata_out {
+        let fmt = PipeViewFormat::Konata;
+        core.tracer.add_sink(Box::new(PipeViewSink::new(fmt, create(path))));
+    }
+    if let Some(path) = &args.o3pipeview_out {
+        let fmt = PipeViewFormat::O3PipeView;
+        core.tracer.add_sink(Box::new(PipeViewSink::new(fmt, create(path))));
+    }
 
-    let mut f = File::open("./code/test.bin").expect("no file");
-    unsafe { f.read(&mut RAM).unwrap(); }
-
-    // Branch prediction
-    let mut bpu = BranchPredictionUnit::new();
-    let mut btb = BranchTargetBuffer::new();
-
-    // Next PC
-    let mut pq: Queue<usize> = Queue::new(32);
-    let mut npc = NextPCLogic;
-    let mut next_pc: usize = 0;
-
-    // Instruction fetch
-    let mut ftq: Queue<usize> = Queue::new(8);
-    let mut ifu = FetchUnit;
-
-    // Instruction decode
-    let mut decode_stall = false;
-    let mut ibq: Queue<IBQEntry> = Queue::new(20);
-    let mut idu = DecodeUnit { pick_offset: 0 };
-    let mut dec_out: [Option<DecodedInst>; 4] = [None; 4];
-
-    // In-order dispatch
-    let mut dispatch_stall = false;
-    let mut opq: Queue<OPQEntry> = Queue::new(32);
-    let mut dispatch = DispatchUnit;
-
-    // Out-of-order issue
-    let mut issue_stall = false;
-    let mut isu = IssueUnit;
-    let mut alu_sched = [ALUScheduler::new(); 4];
-    let mut agu_sched = AGUScheduler::new();
-
-    // Execution units
-    let mut prf = PhysicalRegisterFile::new();
-    let mut eu  = ExecutionUnits::new();
-
-    // Retire control unit
-    let mut rat = RegisterAliasTable::new();
-    let mut rob = ReorderBuffer::new(224);
-    let mut rcu = RetireControlUnit;
-
-    while clk() < 32 {
-        println!("============ cycle {} ====================", clk());
-
-        //// Branch prediction unit
-        //bpu.cycle(&mut btb, &mut pq);
-        //// Next program counter
-        //npc.cycle(&mut next_pc, &mut pq, &mut ftq);
-        //// Instruction fetch unit 
-        //ifu.cycle(&mut ftq, &mut ibq);
-        //// Instruction decode
-        //idu.cycle(&mut ibq, &mut opq, &mut bpu);
-        //// Instruction dispatch
-        //dispatch.cycle(
-        //    &mut btb, &mut opq, 
-        //    &mut alu_sched, &mut agu_sched, 
-        //    &mut prf, &mut rob, &mut rat
-        //);
-        //// Execution units
-        //eu.cycle(&mut rob, &mut prf);
-        //// Instruction issue
-        //isu.cycle(&mut alu_sched, &mut eu);
-        //// Retire control unit
-        //rcu.cycle(&mut rob, &mut rat);
-        //rat.print(&prf);
-
-        rcu.cycle(&mut rob, &mut rat);
-        rat.print(&prf);
-        eu.cycle(&mut rob, &mut prf);
-        isu.cycle(&mut alu_sched, &mut eu);
-        dispatch.cycle(
-            &mut btb, &mut opq, 
-            &mut alu_sched, &mut agu_sched, 
-            &mut prf, &mut rob, &mut rat
-        );
-        idu.cycle(&mut ibq, &mut opq, &mut bpu);
-        ifu.cycle(&mut ftq, &mut ibq);
-        npc.cycle(&mut next_pc, &mut pq, &mut ftq);
-        bpu.cycle(&mut btb, &mut pq);
-
-
-        step();
+    // Programs are either ELF executables, or flat binaries which start
+    // executing at the load address
+    let bin = std::fs::read(&args.program)
+        .unwrap_or_else(|e| die(&args.program, e));
+    if Elf::is_elf(&bin) {
+        let elf = Elf::parse(&bin).unwrap_or_else(|e| die(&args.program, e));
+        core.load_elf(&elf).unwrap_or_else(|e| die(&args.program, e));
+    } else {
+        core.load(args.load_addr, &bin);
+        core.next_pc = args.load_addr;
+    }
+    if let Some(pc) = args.start_pc {
+        core.next_pc = pc;
     }
 
-}
+    // Address translation (all memory is identity-mapped with pages of
+    // the requested size, otherwise paging is disabled)
+    if let Some(size) = args.page_size {
+        core.map_memory(size);
+    }
 
+    if args.cosim {
+        core.enable_cosim();
+    }
 
+    let max_cycles = args.max_cycles.unwrap_or(usize::MAX);
+    let max_insts = args.max_insts.unwrap_or(usize::MAX);
+    while core.clk < max_cycles && core.rcu.stats.insts < max_insts 
+        && !core.halted() && !core.rcu.cosim_failed()
+    {
+        core.step();
+    }
+    core.tracer.finish().unwrap_or_else(|e| die("trace", e));
+
+    let mut out = match &args.stats_out {
+        Some(path) => create(path),
+        None => Box::new(std::io::stdout()),
+    };
+    core.print_stats(&mut out).and_then(|_| out.flush())
+        .unwrap_or_else(|e| die("stats", e));
+    if let Some(path) = &args.pmc_out {
+        let mut out = create(path);
+        let pmc = Pmc::collect(&core).to_json();
+        writeln!(out, "{:#}", pmc).and_then(|_| out.flush())
+            .unwrap_or_else(|e| die(path, e));
+    }
+    if let Some(err) = core.rcu.cosim.as_ref().and_then(|c| c.error.as_ref())
+    {
+        die("cosim", err);
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn args() {
+        Args::command().debug_assert();
+        let args = Args::try_parse_from([
+            "z2pl", "prog.bin", "--load-addr", "0x1000", "--max-cycles",
+            "500", "--trace-stage", "ifu=1", "--trace-stage", "rcu=0",
+        ]).unwrap();
+        assert_eq!(args.program, "prog.bin");
+        assert_eq!(args.load_addr, 0x1000);
+        assert_eq!(args.start_pc, None);
+        assert_eq!(args.max_cycles, Some(500));
+        assert_eq!(args.stage_levels, [
+            ("ifu".to_string(), 1), ("rcu".to_string(), 0),
+        ]);
+
+        for bad in [
+            ["--load-addr", "0xfoo"], ["--trace-stage", "ifu"],
+            ["--trace-stage", "ifu=x"], ["--page-size", "3m"],
+        ] {
+            let argv = ["z2pl", "prog.bin"].into_iter().chain(bad);
+            assert!(Args::try_parse_from(argv).is_err(), "{:?}", bad);
+        }
+    }
+}
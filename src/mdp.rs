@@ -0,0 +1,105 @@
+//! Memory dependence prediction.
+
+/// Number of entries in the store set ID table.
+pub const SSIT_SIZE: usize = 1024;
+/// Number of store sets.
+pub const NUM_STORE_SETS: usize = 128;
+
+/// A store-set memory dependence predictor.
+///
+/// Loads and stores which have caused a memory-ordering violation are
+/// placed into the same "store set". A load is predicted to depend on the
+/// most-recently dispatched store in its set.
+///
+/// NOTE: Stores are identified by their store queue sequence number.
+/// Since these are never reused, entries in the LFST don't need to be
+/// invalidated when a store leaves the store queue.
+pub struct StoreSetPredictor {
+    /// Store set ID table (indexed by program counter)
+    ssit: Vec<Option<usize>>,
+    /// Last fetched store table (indexed by store set ID)
+    lfst: Vec<Option<usize>>,
+    /// Next store set ID to allocate
+    next_ssid: usize,
+}
+impl StoreSetPredictor {
+    pub fn new() -> Self {
+        Self {
+            ssit: vec![None; SSIT_SIZE],
+            lfst: vec![None; NUM_STORE_SETS],
+            next_ssid: 0,
+        }
+    }
+
+    fn ssit_idx(pc: usize) -> usize { (pc ^ (pc >> 10)) % SSIT_SIZE }
+
+    /// Return the sequence number of the store that some load is predicted
+    /// to depend on (if any).
+    pub fn predict_load(&self, pc: usize) -> Option<usize> {
+        self.ssit[Self::ssit_idx(pc)].and_then(|ssid| self.lfst[ssid])
+    }
+
+    /// Record a dispatched store.
+    pub fn dispatch_store(&mut self, pc: usize, seq: usize) {
+        if let Some(ssid) = self.ssit[Self::ssit_idx(pc)] {
+            self.lfst[ssid] = Some(seq);
+        }
+    }
+
+    /// Train the predictor after a load was executed before an older store
+    /// to the same address.
+    pub fn violation(&mut self, load_pc: usize, store_pc: usize) {
+        let li = Self::ssit_idx(load_pc);
+        let si = Self::ssit_idx(store_pc);
+        let ssid = match (self.ssit[li], self.ssit[si]) {
+            (None, None) => {
+                let ssid = self.next_ssid;
+                self.next_ssid = (self.next_ssid + 1) % NUM_STORE_SETS;
+                ssid
+            },
+            (Some(x), None) | (None, Some(x)) => x,
+            // Merge both sets into the one with the smaller ID
+            (Some(x), Some(y)) => x.min(y),
+        };
+        self.ssit[li] = Some(ssid);
+        self.ssit[si] = Some(ssid);
+    }
+}
+impl Default for StoreSetPredictor {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predict_after_violation() {
+        let mut mdp = StoreSetPredictor::new();
+        mdp.dispatch_store(0x10, 0);
+        assert_eq!(mdp.predict_load(0x20), None);
+
+        // Loads depend on the most recent store in their set
+        mdp.violation(0x20, 0x10);
+        assert_eq!(mdp.predict_load(0x20), None);
+        mdp.dispatch_store(0x10, 1);
+        mdp.dispatch_store(0x18, 2);
+        assert_eq!(mdp.predict_load(0x20), Some(1));
+        mdp.dispatch_store(0x10, 3);
+        assert_eq!(mdp.predict_load(0x20), Some(3));
+    }
+
+    #[test]
+    fn merge_store_sets() {
+        let mut mdp = StoreSetPredictor::new();
+        mdp.violation(0x20, 0x10);
+        mdp.violation(0x40, 0x30);
+
+        // Both loads now share a set with both stores
+        mdp.violation(0x40, 0x10);
+        mdp.violation(0x20, 0x30);
+        mdp.dispatch_store(0x30, 7);
+        assert_eq!(mdp.predict_load(0x20), Some(7));
+        assert_eq!(mdp.predict_load(0x40), Some(7));
+    }
+}
@@ -1,65 +1,72 @@
 
-pub static mut CLOCK: usize = 0;
-pub fn clk() -> usize { unsafe { CLOCK } }
-pub fn step() { unsafe { CLOCK += 1 } }
-pub fn stepn(n: usize) { unsafe { CLOCK += n } }
-
+/// Size of simulated physical memory.
 pub const RAM_LEN: usize = 0x0200_0000;
-pub static mut RAM: [u8; RAM_LEN] = [0; RAM_LEN];
-pub fn read(addr: usize, len: usize) -> &'static [u8] { 
-    assert!(addr+len < RAM_LEN);
-    unsafe { &RAM[addr..addr+len] } 
-}
-pub fn read8(addr: usize) -> u8 { 
-    assert!(addr < RAM_LEN);
-    unsafe { RAM[addr] } 
-}
-pub fn read16(addr: usize) -> u16 { 
-    assert!(addr+2 < RAM_LEN);
-    unsafe { 
-        let (b, _) = RAM.split_at(std::mem::size_of::<u16>());
-        u16::from_le_bytes(b.try_into().unwrap()) 
-    } 
-}
-pub fn read32(addr: usize) -> u32 { 
-    assert!(addr+4 < RAM_LEN);
-    unsafe { 
-        let (b, _) = RAM.split_at(std::mem::size_of::<u32>());
-        u32::from_le_bytes(b.try_into().unwrap()) 
-    } 
-}
-pub fn write(addr: usize, data: &[u8]) {
-    assert!(addr+data.len() < RAM_LEN);
-    unsafe { RAM[addr..addr+data.len()].copy_from_slice(data) }
+
+/// Initial stack pointer for ELF executables. The last 2 MiB of memory are
+/// reserved for the stack and for page tables.
+pub const STACK_TOP: usize = RAM_LEN - 0x0010_0000;
+
+/// End of the memory which can be used for program segments.
+pub const LOAD_LIMIT: usize = RAM_LEN - 0x0020_0000;
+
+/// Returns true if some range of bytes is entirely within memory.
+///
+/// NOTE: Accesses outside of memory don't fault (we don't model exceptions
+/// for data accesses). Loads return zero and stores are dropped, both in 
+/// the pipeline and in the reference model.
+pub fn in_memory(addr: usize, size: usize) -> bool {
+    addr.checked_add(size).is_some_and(|end| end < RAM_LEN)
 }
-pub fn write8(addr: usize, data: u8) {
-    assert!(addr < RAM_LEN);
-    unsafe { RAM[addr] = data; }
+
+/// Simulated physical memory.
+#[derive(Clone)]
+pub struct Memory {
+    data: Vec<u8>,
 }
-pub fn write16(addr: usize, data: u16) {
-    assert!(addr+2 < RAM_LEN);
-    unsafe { 
-        let src = std::slice::from_raw_parts(
-            &data as *const u16 as *const u8,
-            std::mem::size_of::<u16>()
-        );
-        RAM[addr..addr+src.len()].copy_from_slice(src);
+impl Memory {
+    pub fn new() -> Self {
+        Self { data: vec![0; RAM_LEN] }
     }
-}
-pub fn write32(addr: usize, data: u32) {
-    assert!(addr+4 < RAM_LEN);
-    unsafe { 
-        let src = std::slice::from_raw_parts(
-            &data as *const u32 as *const u8,
-            std::mem::size_of::<u32>()
-        );
-        RAM[addr..addr+src.len()].copy_from_slice(src);
+
+    pub fn read(&self, addr: usize, len: usize) -> &[u8] {
+        assert!(addr+len < RAM_LEN);
+        &self.data[addr..addr+len]
+    }
+    pub fn read8(&self, addr: usize) -> u8 {
+        assert!(addr < RAM_LEN);
+        self.data[addr]
+    }
+    pub fn read16(&self, addr: usize) -> u16 {
+        assert!(addr+2 < RAM_LEN);
+        let b = self.read(addr, std::mem::size_of::<u16>());
+        u16::from_le_bytes(b.try_into().unwrap())
+    }
+    pub fn read32(&self, addr: usize) -> u32 {
+        assert!(addr+4 < RAM_LEN);
+        let b = self.read(addr, std::mem::size_of::<u32>());
+        u32::from_le_bytes(b.try_into().unwrap())
+    }
+    pub fn write(&mut self, addr: usize, data: &[u8]) {
+        assert!(addr+data.len() < RAM_LEN);
+        self.data[addr..addr+data.len()].copy_from_slice(data);
+    }
+    pub fn write8(&mut self, addr: usize, data: u8) {
+        assert!(addr < RAM_LEN);
+        self.data[addr] = data;
+    }
+    pub fn write16(&mut self, addr: usize, data: u16) {
+        self.write(addr, &data.to_le_bytes());
+    }
+    pub fn write32(&mut self, addr: usize, data: u32) {
+        self.write(addr, &data.to_le_bytes());
     }
-}
 
-pub fn cache_read(addr: usize) -> [u8; 32] {
-    assert!(addr & 0x1f == 0);
-    read(addr, 32).try_into().unwrap()
+    pub fn cache_read(&self, addr: usize) -> [u8; 32] {
+        assert!(addr & 0x1f == 0);
+        self.read(addr, 32).try_into().unwrap()
+    }
+}
+impl Default for Memory {
+    fn default() -> Self { Self::new() }
 }
-
 
@@ -0,0 +1,451 @@
+//! Address translation and page walks.
+
+use std::io::{ self, Write };
+use crate::mem::*;
+use crate::tlb::*;
+use crate::hier::AccessKind;
+use crate::config::TlbConfig;
+
+pub const PTE_PRESENT: u64 = 1 << 0;
+pub const PTE_WRITE: u64 = 1 << 1;
+/// Page size bit (in PDPTEs and PDEs).
+pub const PTE_PS: u64 = 1 << 7;
+pub const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Reasons why an address cannot be translated on this cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TranslateErr {
+    /// The translation is not available yet
+    Miss,
+    /// The address is not mapped
+    Fault,
+}
+
+/// A translation being filled into the L1 TLB (either from the L2 TLB,
+/// or from a page walk).
+///
+/// NOTE: A walk which faults is tracked with the 4 KiB page containing
+/// the address (and nothing is filled when it completes).
+#[derive(Clone, Copy, Debug)]
+struct TlbFill {
+    kind: AccessKind,
+    ent: TlbEntry,
+    done: usize,
+    /// This fill is the result of a page walk
+    walk: bool,
+    /// The page walk found that the address isn't mapped
+    fault: bool,
+}
+
+/// Caches the physical address of the next-level table for the upper
+/// levels of the page tables (PML4E, PDPTE, and PDE).
+pub struct PageWalkCache {
+    /// (level, virtual address prefix, next-level table, last used)
+    entries: Vec<(usize, usize, usize, usize)>,
+    /// Number of entries
+    size: usize,
+}
+impl PageWalkCache {
+    pub fn new(size: usize) -> Self {
+        Self { entries: Vec::new(), size }
+    }
+
+    /// Bits of the virtual address translated above some level.
+    fn prefix(level: usize, vaddr: usize) -> usize {
+        vaddr >> (12 + 9 * level)
+    }
+
+    fn lookup(&mut self, level: usize, vaddr: usize, clk: usize)
+        -> Option<usize>
+    {
+        let p = Self::prefix(level, vaddr);
+        let (_, _, table, used) = self.entries.iter_mut()
+            .find(|(l, v, _, _)| *l == level && *v == p)?;
+        *used = clk;
+        Some(*table)
+    }
+
+    fn insert(&mut self, level: usize, vaddr: usize, table: usize,
+              clk: usize)
+    {
+        let p = Self::prefix(level, vaddr);
+        if self.entries.iter().any(|(l, v, _, _)| *l == level && *v == p) {
+            return;
+        }
+        if self.entries.len() == self.size {
+            let lru = (0..self.entries.len())
+                .min_by_key(|i| self.entries[*i].3).unwrap();
+            self.entries.swap_remove(lru);
+        }
+        self.entries.push((level, p, table, clk));
+    }
+}
+
+/// Counters for the page walker.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WalkStats {
+    pub walks: usize,
+    /// Page table entries read from memory
+    pub steps: usize,
+    /// Levels skipped with the page walk cache
+    pub pwc_hits: usize,
+    pub faults: usize,
+    /// Translations which waited for a free walker
+    pub walker_busy: usize,
+}
+
+/// Translates virtual addresses for instruction fetch and for loads and
+/// stores, with a two-level TLB for each and a shared page walker.
+///
+/// NOTE: Walks read the page tables immediately, and only the latency of
+/// the walk is modeled. Accessed and dirty bits are never written. Page 
+/// table entries are assumed to hit in the L2.
+pub struct Mmu {
+    pub cfg: TlbConfig,
+    /// Physical address of the PML4 (or `None` if paging is disabled)
+    pub cr3: Option<usize>,
+    pub itlb: [Tlb; 2],
+    pub dtlb: [Tlb; 2],
+    pub pwc: PageWalkCache,
+    fills: Vec<TlbFill>,
+    /// Faulting walks which have completed, until the fault is reported
+    /// to the next translation for the same page
+    faults: Vec<TlbFill>,
+    /// The current cycle (updated by [Mmu::cycle])
+    clk: usize,
+    pub stats: WalkStats,
+}
+impl Mmu {
+    pub fn new(cr3: Option<usize>, cfg: &TlbConfig) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            cr3,
+            itlb: [
+                Tlb::new("L1ITLB", cfg.l1_itlb_size, 0), 
+                Tlb::new("L2ITLB", cfg.l2_itlb_size, cfg.l2_latency),
+            ],
+            dtlb: [
+                Tlb::new("L1DTLB", cfg.l1_dtlb_size, 0), 
+                Tlb::new("L2DTLB", cfg.l2_dtlb_size, cfg.l2_latency),
+            ],
+            pwc: PageWalkCache::new(cfg.pwc_size),
+            fills: Vec::new(),
+            faults: Vec::new(),
+            clk: 0,
+            stats: WalkStats::default(),
+        }
+    }
+
+    fn tlbs(&mut self, kind: AccessKind) -> &mut [Tlb; 2] {
+        match kind {
+            AccessKind::Inst => &mut self.itlb,
+            AccessKind::Data => &mut self.dtlb,
+        }
+    }
+
+    /// Install any translations which have finished on this cycle.
+    pub fn cycle(&mut self, clk: usize) {
+        self.clk = clk;
+        let (done, pending): (Vec<TlbFill>, Vec<TlbFill>) = self.fills
+            .iter().partition(|f| f.done <= clk);
+        self.fills = pending;
+        for f in done {
+            // NOTE: Only the most recent faults are kept (a translation
+            // for an older fault just walks again)
+            if f.fault {
+                trace!("[MMU] {:?} page fault for {:016x}", 
+                    f.kind, f.ent.vbase);
+                if self.faults.len() == self.cfg.max_walks {
+                    self.faults.remove(0);
+                }
+                self.faults.push(f);
+                continue;
+            }
+            trace!("[MMU] {:?} translation {:016x} => {:016x} ({:?})",
+                f.kind, f.ent.vbase, f.ent.pbase, f.ent.size);
+            let tlbs = self.tlbs(f.kind);
+            tlbs[0].insert(f.ent, clk);
+            if f.walk {
+                tlbs[1].insert(f.ent, clk);
+            }
+        }
+    }
+
+    /// Translate a virtual address, returning the physical address.
+    ///
+    /// Misses in the L1 TLB start a lookup in the L2 TLB (or a page walk)
+    /// and must be retried until the translation is filled. A page walk 
+    /// which faults takes just as long, and the fault is only returned when
+    /// the translation is retried after the walk has completed.
+    pub fn translate(&mut self, mem: &Memory, kind: AccessKind, 
+                     vaddr: usize) -> Result<usize, TranslateErr>
+    {
+        let Some(cr3) = self.cr3 else {
+            return Ok(vaddr);
+        };
+        let clk = self.clk;
+        let tlbs = self.tlbs(kind);
+        if let Some(e) = tlbs[0].lookup(vaddr, clk) {
+            return Ok(e.translate(vaddr));
+        }
+        let fault = self.faults.iter()
+            .position(|f| f.kind == kind && f.ent.contains(vaddr));
+        if let Some(idx) = fault {
+            self.faults.remove(idx);
+            return Err(TranslateErr::Fault);
+        }
+        let pending = self.fills.iter()
+            .any(|f| f.kind == kind && f.ent.contains(vaddr));
+        if pending {
+            return Err(TranslateErr::Miss);
+        }
+
+        let tlbs = self.tlbs(kind);
+        if let Some(ent) = tlbs[1].lookup(vaddr, clk) {
+            let done = clk + tlbs[1].latency;
+            self.fills.push(TlbFill { 
+                kind, ent, done, walk: false, fault: false 
+            });
+            return Err(TranslateErr::Miss);
+        }
+
+        let walks = self.fills.iter().filter(|f| f.walk).count();
+        if walks == self.cfg.max_walks {
+            self.stats.walker_busy += 1;
+            return Err(TranslateErr::Miss);
+        }
+        let (ent, steps) = self.walk(mem, cr3, vaddr);
+        let done = clk + self.tlbs(kind)[1].latency
+            + steps * self.cfg.walk_step_latency;
+        trace!("[MMU] Page walk for {:016x}, {} steps, done on cycle {}",
+            vaddr, steps, done);
+        let fault = ent.is_none();
+        let ent = ent.unwrap_or(TlbEntry {
+            vbase: vaddr & !PageSize::Size4K.mask(),
+            pbase: 0,
+            size: PageSize::Size4K,
+        });
+        self.fills.push(TlbFill { kind, ent, done, walk: true, fault });
+        Err(TranslateErr::Miss)
+    }
+
+    /// Walk the page tables for some virtual address, returning the
+    /// translation (or `None` if the address isn't mapped) and the number 
+    /// of page table entries read from memory.
+    fn walk(&mut self, mem: &Memory, cr3: usize, vaddr: usize)
+        -> (Option<TlbEntry>, usize)
+    {
+        self.stats.walks += 1;
+
+        // Start from the lowest level in the page walk cache
+        let mut level = 4;
+        let mut table = cr3;
+        for l in 1..=3 {
+            if let Some(t) = self.pwc.lookup(l, vaddr, self.clk) {
+                self.stats.pwc_hits += 4 - l;
+                level = l;
+                table = t;
+                break;
+            }
+        }
+
+        let mut steps = 0;
+        loop {
+            let idx = (vaddr >> (12 + 9 * (level - 1))) & 0x1ff;
+            let pte = read_pte(mem, table + idx * 8);
+            steps += 1;
+            self.stats.steps += 1;
+            if pte & PTE_PRESENT == 0 {
+                trace!("[MMU] Page fault for {:016x} (level {})",
+                    vaddr, level);
+                self.stats.faults += 1;
+                return (None, steps);
+            }
+
+            let addr = (pte & PTE_ADDR_MASK) as usize;
+            let size = match level {
+                1 => Some(PageSize::Size4K),
+                2 if pte & PTE_PS != 0 => Some(PageSize::Size2M),
+                3 if pte & PTE_PS != 0 => Some(PageSize::Size1G),
+                _ => None,
+            };
+            if let Some(size) = size {
+                let ent = TlbEntry {
+                    vbase: vaddr & !size.mask(),
+                    pbase: addr & !size.mask(),
+                    size,
+                };
+                return (Some(ent), steps);
+            }
+            level -= 1;
+            table = addr;
+            self.pwc.insert(level, vaddr, table, self.clk);
+        }
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        for tlb in self.itlb.iter().chain(self.dtlb.iter()) {
+            tlb.print_stats(out)?;
+        }
+        let s = &self.stats;
+        writeln!(out, "[MMU] walks={} steps={} pwc_hits={} faults={} \
+            walker_busy={}", s.walks, s.steps, s.pwc_hits, s.faults,
+            s.walker_busy)?;
+        Ok(())
+    }
+}
+
+/// Read a page table entry from physical memory. Entries outside of memory
+/// are treated as not present.
+fn read_pte(mem: &Memory, addr: usize) -> u64 {
+    if addr + 8 >= RAM_LEN {
+        return 0;
+    }
+    u64::from_le_bytes(mem.read(addr, 8).try_into().unwrap())
+}
+
+/// Build page tables identity-mapping all of memory with pages of some
+/// size, returning the physical address of the PML4.
+///
+/// The tables are placed at the end of memory.
+pub fn build_identity_map(mem: &mut Memory, size: PageSize) -> usize {
+    let mut next = RAM_LEN - (1 << 20);
+    let mut alloc = |mem: &mut Memory| {
+        let res = next;
+        mem.write(res, &[0; 0x1000]);
+        next += 0x1000;
+        res
+    };
+    let pml4 = alloc(mem);
+    let pdpt = alloc(mem);
+    write_pte(mem, pml4, pdpt);
+
+    let flags = PTE_PRESENT | PTE_WRITE;
+    let mut pds = Vec::new();
+    for addr in (0..RAM_LEN).step_by(size.bytes()) {
+        match size {
+            PageSize::Size1G => {
+                let pdpte = pdpt + (addr >> 30) * 8;
+                write_pte_flags(mem, pdpte, addr, flags | PTE_PS);
+            },
+            _ => {
+                let pdpt_idx = addr >> 30;
+                if pds.len() <= pdpt_idx {
+                    let pd = alloc(mem);
+                    write_pte(mem, pdpt + pdpt_idx * 8, pd);
+                    pds.push((pd, Vec::new()));
+                }
+                let (pd, pts) = &mut pds[pdpt_idx];
+                let pd_idx = (addr >> 21) & 0x1ff;
+                if size == PageSize::Size2M {
+                    let pde = *pd + pd_idx * 8;
+                    write_pte_flags(mem, pde, addr, flags | PTE_PS);
+                    continue;
+                }
+                if pts.len() <= pd_idx {
+                    let pt = alloc(mem);
+                    write_pte(mem, *pd + pd_idx * 8, pt);
+                    pts.push(pt);
+                }
+                let pt_idx = (addr >> 12) & 0x1ff;
+                write_pte_flags(mem, pts[pd_idx] + pt_idx * 8, addr, flags);
+            },
+        }
+    }
+    trace!("[MMU] Identity-mapped {:#x} bytes with {:?} pages, cr3={:08x}",
+        RAM_LEN, size, pml4);
+    pml4
+}
+
+fn write_pte(mem: &mut Memory, addr: usize, table: usize) {
+    write_pte_flags(mem, addr, table, PTE_PRESENT | PTE_WRITE);
+}
+
+fn write_pte_flags(mem: &mut Memory, addr: usize, paddr: usize, 
+                   flags: u64)
+{
+    let pte = (paddr as u64 & PTE_ADDR_MASK) | flags;
+    mem.write(addr, &pte.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+
+    /// An MMU with all of memory identity-mapped with some page size.
+    fn mmu(size: PageSize) -> (Mmu, Memory) {
+        let mut mem = Memory::new();
+        let cr3 = build_identity_map(&mut mem, size);
+        (Mmu::new(Some(cr3), &TlbConfig::default()), mem)
+    }
+
+    /// Translate an address, retrying until the translation is filled.
+    /// Returns the result and the number of cycles taken.
+    fn translate(mmu: &mut Mmu, mem: &Memory, vaddr: usize)
+        -> (Result<usize, TranslateErr>, usize)
+    {
+        let start = mmu.clk;
+        loop {
+            match mmu.translate(mem, AccessKind::Data, vaddr) {
+                Err(TranslateErr::Miss) => mmu.cycle(mmu.clk + 1),
+                res => return (res, mmu.clk - start),
+            }
+        }
+    }
+
+    #[test]
+    fn walk() {
+        let (mut mmu, mem) = mmu(PageSize::Size4K);
+        assert_eq!(translate(&mut mmu, &mem, 0x1234), (Ok(0x1234), 7 + 48));
+        assert_eq!((mmu.stats.walks, mmu.stats.steps), (1, 4));
+        assert_eq!(translate(&mut mmu, &mem, 0x1fff), (Ok(0x1fff), 0));
+
+        // The next page only needs the last level of the page tables
+        assert_eq!(translate(&mut mmu, &mem, 0x2000), (Ok(0x2000), 7 + 12));
+        assert_eq!((mmu.stats.steps, mmu.stats.pwc_hits), (5, 3));
+
+        // Translations are filled into the L2 DTLB too
+        mmu.dtlb[0].flush();
+        assert_eq!(translate(&mut mmu, &mem, 0x1000), (Ok(0x1000), 7));
+        assert_eq!(mmu.stats.walks, 2);
+    }
+
+    #[test]
+    fn large_pages() {
+        for (size, steps) in [(PageSize::Size2M, 3), (PageSize::Size1G, 2)] {
+            let (mut mmu, mem) = mmu(size);
+            let vaddr = 0x123_4567;
+            assert_eq!(translate(&mut mmu, &mem, vaddr), 
+                (Ok(vaddr), 7 + 12 * steps));
+            assert_eq!(mmu.dtlb[0].lookup(vaddr, 0).unwrap().size, size);
+        }
+    }
+
+    #[test]
+    fn fault() {
+        // Faults are only returned once the walk has completed (here, the
+        // PDPT entry isn't present)
+        let (mut mmu, mem) = mmu(PageSize::Size4K);
+        assert_eq!(translate(&mut mmu, &mem, 1 << 30), 
+            (Err(TranslateErr::Fault), 7 + 24));
+        assert_eq!(mmu.stats.faults, 1);
+
+        // Retrying walks again
+        assert_eq!(mmu.translate(&mem, AccessKind::Data, 1 << 30),
+            Err(TranslateErr::Miss));
+        assert_eq!(mmu.stats.walks, 2);
+    }
+
+    #[test]
+    fn walker_busy() {
+        let (mut mmu, mem) = mmu(PageSize::Size4K);
+        for n in 0..3 {
+            let res = mmu.translate(&mem, AccessKind::Data, n << 30);
+            assert_eq!(res, Err(TranslateErr::Miss));
+        }
+        assert_eq!((mmu.stats.walks, mmu.stats.walker_busy), (2, 1));
+        mmu.cycle(1000);
+        assert_eq!(mmu.translate(&mem, AccessKind::Data, 0), Ok(0));
+    }
+}
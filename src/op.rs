@@ -1,14 +1,38 @@
 
 use iced_x86::{
-    Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter,
-    ConditionCode, InstructionInfoFactory, OpKind, RflagsBits,
-    Mnemonic, Register, Code, MemorySize
+    ConditionCode, Instruction, OpKind, Register, Code, MemorySize
 };
 
 use crate::rf::*;
-use crate::retire::*;
-use crate::dispatch::*;
 use crate::front::DecodedInst;
+use crate::flags::*;
+
+/// A memory operand (base + index * scale + displacement).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemOperand {
+    pub base: Register,
+    pub idx: Register,
+    pub scale: usize,
+    pub disp: usize,
+}
+impl MemOperand {
+    /// Get the memory operand for some decoded instruction.
+    ///
+    /// NOTE: RIP-relative displacements are already resolved by the 
+    /// decoder, so these are treated as absolute addresses.
+    pub fn from_inst(inst: &Instruction) -> Self {
+        let base = match inst.memory_base() {
+            Register::RIP => Register::None,
+            r => r,
+        };
+        Self {
+            base,
+            idx: inst.memory_index(),
+            scale: inst.memory_index_scale() as usize,
+            disp: inst.memory_displacement64() as usize,
+        }
+    }
+}
 
 /// Representing a "macro-op".
 #[derive(Debug, Copy, Clone)]
@@ -16,45 +40,103 @@ pub enum MacroOp {
     Nop, Ud2,
     /// Mov (register <- immediate)
     MovRI(Register, i64),
-    /// Mov (memory <- register) (rd, ridx, disp, width, rs)
-    MovMR(Register, Register, usize, MemorySize, Register),
+    /// Mov (register <- memory)
+    MovRM(Register, MemOperand, MemorySize),
+    /// Mov (memory <- register)
+    MovMR(MemOperand, MemorySize, Register),
     /// Alu (register <- immediate)
     AluRI(ALUOp, Register, i64),
     /// Alu (register <- register)
     AluRR(ALUOp, Register, Register),
+    /// Increment/decrement (preserves CF)
+    IncDec(ALUOp, Register),
+    /// Conditional move (register <- register)
+    CmovRR(ConditionCode, Register, Register),
+    /// Compare (register, immediate) (flags only)
+    CmpRI(ALUOp, Register, i64),
+    /// Compare (register, register) (flags only)
+    CmpRR(ALUOp, Register, Register),
     /// Jump (immediate)
     JmpI(usize),
+    /// Conditional jump (immediate)
+    Jcc(ConditionCode, usize),
+    /// Jump (register)
+    JmpR(Register),
+    /// Jump (memory)
+    JmpM(MemOperand),
+    /// Call (immediate)
+    CallI(usize),
+    /// Call (register)
+    CallR(Register),
+    /// Call (memory)
+    CallM(MemOperand),
+    /// Return
+    Ret,
+    /// An instruction without a macro-op implementation.
+    ///
+    /// NOTE: These may be decoded on the wrong path, so we can't just
+    /// panic here. These are treated like UD2 in the back-end.
+    Unsupported(Code),
+    /// Fetch from an address outside memory (treated like UD2 in the 
+    /// back-end)
+    FetchFault,
 }
 
 /// Convert a decoded instruction into one [or more?] macro-ops.
 pub fn get_macro_ops(dec: &DecodedInst) -> MacroOp {
-    println!("[IDU] Found macro-op {:08x}: {:?} {:02x?}", dec.addr, 
+    detail!("[IDU] Found macro-op {:08x}: {:?} {:02x?}", dec.addr, 
         dec.inst.code(), &dec.bytes[..dec.inst.len()]);
-    let mut fac = InstructionInfoFactory::new();
-    let info = fac.info(&dec.inst);
     let opcd = dec.inst.mnemonic();
     use iced_x86::Mnemonic::*;
+
+    // NOTE: Only general-purpose registers are renamed (sub-registers are
+    // renamed as the full 64-bit register). The high byte registers (ie.
+    // AH) and 32-bit addresses are not supported.
+    let gpr_ok = |r: Register| r.is_gpr() && !matches!(r, 
+        Register::AH | Register::BH | Register::CH | Register::DH);
+    let gprs_ok = (0..dec.inst.op_count()).all(|i| {
+        dec.inst.op_kind(i) != OpKind::Register 
+            || gpr_ok(dec.inst.op_register(i))
+    });
+    let addr_ok = [dec.inst.memory_base(), dec.inst.memory_index()].iter()
+        .all(|r| matches!(r, Register::None | Register::RIP) || r.is_gpr64());
+    if !gprs_ok || !addr_ok {
+        return MacroOp::Unsupported(dec.inst.code());
+    }
+
     match opcd {
+        _ if dec.inst.is_jcc_short_or_near() => {
+            MacroOp::Jcc(dec.inst.condition_code(), 
+                dec.inst.near_branch64() as usize)
+        },
         Ud2 => MacroOp::Ud2, 
         Nop => MacroOp::Nop,
         Mov => {
             let dst = dec.inst.op0_kind();
             let src = dec.inst.op1_kind();
             match (dst, src) {
-                (OpKind::Register, OpKind::Immediate32to64) => {
+                (OpKind::Register, 
+                 OpKind::Immediate8 | OpKind::Immediate16 | 
+                 OpKind::Immediate32 | OpKind::Immediate32to64 | 
+                 OpKind::Immediate64) => {
                     MacroOp::MovRI(
-                        dec.inst.op0_register(), dec.inst.immediate32to64()
+                        dec.inst.op0_register(), dec.inst.immediate(1) as i64
                     )
                 },
+                // NOTE: 8/16-bit loads would need to be merged with the
+                // old value of the register, which isn't supported
+                (OpKind::Register, OpKind::Memory) 
+                    if dec.inst.op0_register().size() >= 4 => 
+                {
+                    MacroOp::MovRM(dec.inst.op0_register(),
+                        MemOperand::from_inst(&dec.inst), 
+                        dec.inst.memory_size())
+                },
                 (OpKind::Memory, OpKind::Register) => {
-                    let rbase = dec.inst.memory_base();
-                    let ridx  = dec.inst.memory_index();
-                    let disp  = dec.inst.memory_displacement64() as usize;
-                    let sz    = dec.inst.memory_size();
-                    MacroOp::MovMR(rbase, ridx, disp, sz, 
-                        dec.inst.op1_register())
+                    MacroOp::MovMR(MemOperand::from_inst(&dec.inst),
+                        dec.inst.memory_size(), dec.inst.op1_register())
                 },
-                _ => unimplemented!("{:?} {:?}", dst, src),
+                _ => MacroOp::Unsupported(dec.inst.code()),
             }
         },
         Add | Sub | And | Or | Xor => {
@@ -69,9 +151,13 @@ pub fn get_macro_ops(dec: &DecodedInst) -> MacroOp {
                 _ => unreachable!(),
             };
             match (dst, src) {
-                (OpKind::Register, OpKind::Immediate32to64) => {
+                (OpKind::Register, 
+                 OpKind::Immediate8 | OpKind::Immediate16 | 
+                 OpKind::Immediate32 | OpKind::Immediate8to16 | 
+                 OpKind::Immediate8to32 | OpKind::Immediate8to64 | 
+                 OpKind::Immediate32to64) => {
                     MacroOp::AluRI(aluop,
-                        dec.inst.op0_register(), dec.inst.immediate32to64()
+                        dec.inst.op0_register(), dec.inst.immediate(1) as i64
                     )
                 },
                 (OpKind::Register, OpKind::Register) => {
@@ -79,16 +165,86 @@ pub fn get_macro_ops(dec: &DecodedInst) -> MacroOp {
                         dec.inst.op0_register(), dec.inst.op1_register()
                     )
                 }
-                _ => unimplemented!("{:?} {:?}", dst, src),
+                _ => MacroOp::Unsupported(dec.inst.code()),
             }
 
         },
-        Jmp => {
-            assert!(dec.inst.op0_kind() == OpKind::NearBranch64);
-            let tgt = dec.inst.near_branch64();
-            MacroOp::JmpI(tgt as usize)
+        Shl | Sal | Shr => {
+            let aluop = if opcd == Shr { ALUOp::Shr } else { ALUOp::Shl };
+            let rd = dec.inst.op0_register();
+            match (dec.inst.op0_kind(), dec.inst.op1_kind()) {
+                (OpKind::Register, OpKind::Immediate8) => {
+                    MacroOp::AluRI(aluop, rd, dec.inst.immediate8() as i64)
+                },
+                (OpKind::Register, OpKind::Register) => {
+                    MacroOp::AluRR(aluop, rd, Register::RCX)
+                },
+                _ => MacroOp::Unsupported(dec.inst.code()),
+            }
+        },
+        Inc | Dec => {
+            let aluop = if opcd == Inc { ALUOp::Add } else { ALUOp::Sub };
+            match dec.inst.op0_kind() {
+                OpKind::Register => {
+                    MacroOp::IncDec(aluop, dec.inst.op0_register())
+                },
+                _ => MacroOp::Unsupported(dec.inst.code()),
+            }
+        },
+        Cmovo | Cmovno | Cmovb | Cmovae | Cmove | Cmovne | Cmovbe | Cmova |
+        Cmovs | Cmovns | Cmovp | Cmovnp | Cmovl | Cmovge | Cmovle | Cmovg => {
+            match (dec.inst.op0_kind(), dec.inst.op1_kind()) {
+                (OpKind::Register, OpKind::Register) => {
+                    MacroOp::CmovRR(dec.inst.condition_code(),
+                        dec.inst.op0_register(), dec.inst.op1_register())
+                },
+                _ => MacroOp::Unsupported(dec.inst.code()),
+            }
+        },
+        Cmp | Test => {
+            let dst = dec.inst.op0_kind();
+            let src = dec.inst.op1_kind();
+            let aluop = if opcd == Cmp { ALUOp::Sub } else { ALUOp::And };
+            match (dst, src) {
+                (OpKind::Register, 
+                 OpKind::Immediate8 | OpKind::Immediate16 | 
+                 OpKind::Immediate32 | OpKind::Immediate8to16 | 
+                 OpKind::Immediate8to32 | OpKind::Immediate8to64 | 
+                 OpKind::Immediate32to64) => {
+                    MacroOp::CmpRI(aluop,
+                        dec.inst.op0_register(), dec.inst.immediate(1) as i64
+                    )
+                },
+                (OpKind::Register, OpKind::Register) => {
+                    MacroOp::CmpRR(aluop,
+                        dec.inst.op0_register(), dec.inst.op1_register()
+                    )
+                }
+                _ => MacroOp::Unsupported(dec.inst.code()),
+            }
+        },
+        Jmp => match dec.inst.op0_kind() {
+            OpKind::NearBranch64 => {
+                MacroOp::JmpI(dec.inst.near_branch64() as usize)
+            },
+            OpKind::Register => MacroOp::JmpR(dec.inst.op0_register()),
+            OpKind::Memory => MacroOp::JmpM(MemOperand::from_inst(&dec.inst)),
+            _ => MacroOp::Unsupported(dec.inst.code()),
+        },
+        Call => match dec.inst.op0_kind() {
+            OpKind::NearBranch64 => {
+                MacroOp::CallI(dec.inst.near_branch64() as usize)
+            },
+            OpKind::Register => MacroOp::CallR(dec.inst.op0_register()),
+            OpKind::Memory => MacroOp::CallM(MemOperand::from_inst(&dec.inst)),
+            _ => MacroOp::Unsupported(dec.inst.code()),
+        },
+        // NOTE: 'ret imm16' is not supported
+        Ret => match dec.inst.op_count() {
+            0 => MacroOp::Ret,
+            _ => MacroOp::Unsupported(dec.inst.code()),
         },
-        _ => unimplemented!("{:?}", opcd),
+        _ => MacroOp::Unsupported(dec.inst.code()),
     }
 }
 
@@ -97,12 +253,14 @@ pub fn get_macro_ops(dec: &DecodedInst) -> MacroOp {
 pub enum Storage { 
     /// An architectural register (to-be-renamed).
     Arn(Register),
+    /// A temporary register (to-be-renamed).
+    Tmp(usize),
+    /// A group of flags (to-be-renamed).
+    Flags(FlagGroup),
     /// A physical register.
     Prn(Prn),
     /// A signed 64-bit immediate value
     Imm64(i64), 
-    /// Identifier for some bypass path
-    Bypass(usize), 
     /// A value of zero
     Zero,
     None,
@@ -120,10 +278,39 @@ pub enum ArchStorage {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Effect {
     RegWrite(Register, Prn),
-    MemWrite(Prn, Prn),
+    /// Write to a temporary register
+    TmpWrite(usize, Prn),
+    /// Write to a group of flags
+    FlagsWrite(FlagGroup, Prn),
+    /// Branch to an immediate target address
     BrnImm(usize),
+    /// Branch to an immediate target address if the condition holds
+    BrnCond(ConditionCode, usize),
+    /// Branch to the target address in the first operand
+    BrnInd,
     None,
 }
+impl Effect {
+    /// Return the renamed destination register for this effect (if any).
+    pub fn dest(&self) -> Option<(Arn, Prn)> {
+        match self {
+            Self::RegWrite(r, prn) => Some((Arn::from(*r), *prn)),
+            Self::TmpWrite(n, prn) => Some((Arn::tmp(*n), *prn)),
+            Self::FlagsWrite(g, prn) => Some((Arn::flags(*g), *prn)),
+            _ => None,
+        }
+    }
+
+    /// Bind the destination of this effect to some physical register.
+    pub fn with_prn(self, prn: Prn) -> Self {
+        match self {
+            Self::RegWrite(r, _) => Self::RegWrite(r, prn),
+            Self::TmpWrite(n, _) => Self::TmpWrite(n, prn),
+            Self::FlagsWrite(g, _) => Self::FlagsWrite(g, prn),
+            _ => unreachable!("{:?} has no destination", self),
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum UopKind {
@@ -137,28 +324,39 @@ pub enum UopKind {
 pub enum ALUOp { 
     Nop, Add, Sub, Or, And, Xor, Shl, Shr,
     Brn,
+    /// Select the second operand if the condition holds
+    Cmov(ConditionCode),
 }
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum AGUOp { Ld(MemorySize), St(MemorySize), LdSt }
+pub enum AGUOp { Ld(MemorySize), St(MemorySize) }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Uop {
     /// Address associated with this micro-op
     pub addr: usize,
+    /// Length of the associated instruction
+    pub len: usize,
     /// The actual operation
     pub kind: UopKind,
     /// Input operands
     pub arg: [Storage; 5],
     /// Output operands and architectural effects
-    pub eff: [Effect; 2],
+    pub eff: [Effect; 3],
+    /// Operand size (in bytes)
+    pub width: usize,
+    /// Sequence number (assigned at dispatch)
+    pub id: usize,
 }
 impl Uop {
-    pub fn empty(addr: usize) -> Self {
+    pub fn empty(addr: usize, len: usize) -> Self {
         Self { 
             addr,
+            len,
             kind: UopKind::None, 
             arg: [Storage::None; 5],
-            eff: [Effect::None; 2],
+            eff: [Effect::None; 3],
+            width: 8,
+            id: 0,
         }
     }
 
@@ -166,8 +364,17 @@ impl Uop {
         match self.kind {
             UopKind::Alu(ALUOp::Nop) => 1,
             UopKind::Alu(ALUOp::Add) => 1,
+            UopKind::Alu(ALUOp::Sub) => 1,
+            UopKind::Alu(ALUOp::Or)  => 1,
+            UopKind::Alu(ALUOp::And) => 1,
+            UopKind::Alu(ALUOp::Xor) => 1,
+            UopKind::Alu(ALUOp::Shl) => 1,
+            UopKind::Alu(ALUOp::Shr) => 1,
             UopKind::Alu(ALUOp::Brn) => 1,
-            _ => unimplemented!("{:?}", self.kind),
+            UopKind::Alu(ALUOp::Cmov(_)) => 1,
+            UopKind::None | UopKind::Illegal | UopKind::Agu(_) => {
+                unreachable!("{:?} doesn't execute on an ALU", self.kind)
+            },
         }
     }
 
@@ -179,43 +386,125 @@ impl Uop {
     }
     
     /// Determine if a micro-op is ready to be issued.
-    pub fn fire(&self) -> bool {
+    ///
+    /// A micro-op is ready when the ready bit is set for all of its physical
+    /// register dependencies (see [PhysicalRegisterFile::set_ready]).
+    pub fn fire(&self, prf: &PhysicalRegisterFile) -> bool {
         match self.kind {
             UopKind::Alu(ALUOp::Nop) => true,
-            UopKind::Alu(ALUOp::Brn) => true,
-            UopKind::Alu(alu_op) => {
-                if self.iter_prn_deps().count() == 0 {
-                    true
-                } else {
-                    unimplemented!("{:?}", self.arg)
-                }
+            UopKind::Alu(_) | UopKind::Agu(_) => {
+                self.iter_prn_deps().all(|prn| prf.is_ready(prn))
+            },
+            UopKind::None | UopKind::Illegal => {
+                unreachable!("{:?} is never scheduled", self.kind)
             },
-
-            _ => unimplemented!("Can't fire {:?}", self.kind),
         }
     }
 
     pub fn preg_allocs(&self) -> usize {
         self.eff.iter().filter(|e| 
-            if let Effect::RegWrite(_, prn) = e {
-                if prn == &Prn::alloc() { true } else { false }
-            } else { false }
+            e.dest().is_some_and(|(_, prn)| prn == Prn::alloc())
         ).count()
     }
     pub fn is_alu(&self) -> bool {
-        if let UopKind::Alu(_) = self.kind { true } else { false }
+        matches!(self.kind, UopKind::Alu(_))
     }
     pub fn is_agu(&self) -> bool {
-        if let UopKind::Agu(_) = self.kind { true } else { false }
+        matches!(self.kind, UopKind::Agu(_))
+    }
+    pub fn is_load(&self) -> bool {
+        matches!(self.kind, UopKind::Agu(AGUOp::Ld(_)))
+    }
+    pub fn is_store(&self) -> bool {
+        matches!(self.kind, UopKind::Agu(AGUOp::St(_)))
+    }
+    pub fn is_branch(&self) -> bool {
+        matches!(self.kind, UopKind::Alu(ALUOp::Brn))
+    }
+
+    /// Return the address of the next-sequential instruction.
+    pub fn next_addr(&self) -> usize {
+        self.addr + self.len
+    }
+
+    /// Read the flags needed to evaluate some condition code.
+    ///
+    /// NOTE: ALU micro-ops read flags from the third and fourth operands.
+    fn read_flags_for(&mut self, cc: ConditionCode) {
+        let (a, b) = FlagGroup::for_cond(cc);
+        self.arg[2] = a.map_or(Storage::None, Storage::Flags);
+        self.arg[3] = b.map_or(Storage::None, Storage::Flags);
+    }
+
+    /// Read all of the flags (for operations which may leave the flags 
+    /// unchanged).
+    fn read_flags(&mut self) {
+        self.arg[2] = Storage::Flags(FlagGroup::Arith);
+        self.arg[3] = Storage::Flags(FlagGroup::Carry);
+    }
+
+    /// Write the flags (optionally preserving CF).
+    fn write_flags(&mut self, carry: bool) {
+        self.eff[1] = Effect::FlagsWrite(FlagGroup::Arith, Prn::alloc());
+        if carry {
+            self.eff[2] = Effect::FlagsWrite(FlagGroup::Carry, Prn::alloc());
+        }
+    }
+
+    /// Write the result to a general-purpose register, and take the operand
+    /// size from the register.
+    ///
+    /// NOTE: 8/16-bit results are merged with the old value of the 
+    /// register, which is read from the fifth operand.
+    fn write_reg(&mut self, rd: Register) {
+        self.width = rd.size();
+        self.eff[0] = Effect::RegWrite(rd, Prn::alloc());
+        if self.width < 4 {
+            self.arg[4] = Storage::Arn(rd);
+        }
+    }
+
+    /// Set the address operands for an AGU micro-op.
+    ///
+    /// The base, index, and displacement are the first three operands,
+    /// and the scale is the last operand.
+    pub fn set_mem(&mut self, mem: MemOperand) {
+        let reg = |r: Register| {
+            if r == Register::None { Storage::None } else { Storage::Arn(r) }
+        };
+        self.arg[0] = reg(mem.base);
+        self.arg[1] = reg(mem.idx);
+        self.arg[2] = Storage::Imm64(mem.disp as i64);
+        self.arg[4] = Storage::Imm64(mem.scale as i64);
+    }
+
+    /// Lower a call into micro-ops. The return address is pushed onto the
+    /// stack before the branch micro-op (which is always the last).
+    fn lower_call(res: &mut Vec<Self>, addr: usize, len: usize, brn: Self) {
+        let mut sub = Uop::empty(addr, len);
+        sub.kind = UopKind::Alu(ALUOp::Sub);
+        sub.arg[0] = Storage::Arn(Register::RSP);
+        sub.arg[1] = Storage::Imm64(8);
+        sub.eff[0] = Effect::RegWrite(Register::RSP, Prn::alloc());
+        let mut st = Uop::empty(addr, len);
+        st.kind = UopKind::Agu(AGUOp::St(MemorySize::UInt64));
+        st.set_mem(MemOperand { 
+            base: Register::RSP, idx: Register::None, scale: 1, disp: 0,
+        });
+        st.arg[3] = Storage::Imm64(st.next_addr() as i64);
+        res.push(sub);
+        res.push(st);
+        res.push(brn);
     }
 
 
-    pub fn from_mop(mop: MacroOp, addr: usize) -> Vec<Self> {
+    pub fn from_mop(mop: MacroOp, addr: usize, len: usize) -> Vec<Self> {
         let mut res = Vec::new();
-        let mut op1 = Uop::empty(addr);
-        let mut op2 = Uop::empty(addr);
+        let mut op1 = Uop::empty(addr, len);
+        let mut op2 = Uop::empty(addr, len);
+        let mut op3 = Uop::empty(addr, len);
         match mop {
-            MacroOp::Ud2 => {
+            MacroOp::Ud2 | MacroOp::Unsupported(_) | MacroOp::FetchFault => {
                 op1.kind = UopKind::Illegal;
                 res.push(op1);
             },
@@ -227,26 +516,81 @@ impl Uop {
                 op1.kind = UopKind::Alu(ALUOp::Add);
                 op1.arg[0] = Storage::Imm64(imm);
                 op1.arg[1] = Storage::Zero;
+                op1.write_reg(rd);
+                res.push(op1);
+            },
+            MacroOp::MovRM(rd, mem, sz) => {
+                op1.kind = UopKind::Agu(AGUOp::Ld(sz));
+                op1.set_mem(mem);
                 op1.eff[0] = Effect::RegWrite(rd, Prn::alloc());
                 res.push(op1);
             },
-            MacroOp::MovMR(base, idx, disp, sz, src) => {
+            MacroOp::MovMR(mem, sz, src) => {
                 op1.kind = UopKind::Agu(AGUOp::St(sz));
-                op1.arg[0] = Storage::Arn(base);
-                op1.arg[1] = if idx == Register::None { 
-                    Storage::None
-                } else { 
-                    Storage::Arn(idx)
-                };
-                op1.arg[2] = Storage::Imm64(disp as i64);
+                op1.set_mem(mem);
                 op1.arg[3] = Storage::Arn(src);
                 res.push(op1);
             },
+            MacroOp::AluRI(opcd, rd, imm) => {
+                op1.kind = UopKind::Alu(opcd);
+                op1.arg[0] = Storage::Arn(rd);
+                op1.arg[1] = Storage::Imm64(imm);
+                op1.write_reg(rd);
+                op1.write_flags(true);
+                // Shifting by zero leaves the flags unchanged
+                let shift = matches!(opcd, ALUOp::Shl | ALUOp::Shr);
+                if shift && shift_count(imm as usize, op1.width) == 0 {
+                    op1.read_flags();
+                }
+                res.push(op1);
+            },
             MacroOp::AluRR(opcd, rd, rs) => {
                 op1.kind = UopKind::Alu(opcd);
                 op1.arg[0] = Storage::Arn(rd);
                 op1.arg[1] = Storage::Arn(rs);
-                op1.eff[0] = Effect::RegWrite(rd, Prn::alloc());
+                op1.write_reg(rd);
+                op1.write_flags(true);
+                if matches!(opcd, ALUOp::Shl | ALUOp::Shr) {
+                    op1.read_flags();
+                }
+                res.push(op1);
+            },
+            MacroOp::IncDec(opcd, rd) => {
+                op1.kind = UopKind::Alu(opcd);
+                op1.arg[0] = Storage::Arn(rd);
+                op1.arg[1] = Storage::Imm64(1);
+                op1.write_reg(rd);
+                op1.write_flags(false);
+                res.push(op1);
+            },
+            MacroOp::CmovRR(cc, rd, rs) => {
+                op1.kind = UopKind::Alu(ALUOp::Cmov(cc));
+                op1.arg[0] = Storage::Arn(rd);
+                op1.arg[1] = Storage::Arn(rs);
+                op1.read_flags_for(cc);
+                op1.write_reg(rd);
+                res.push(op1);
+            },
+            MacroOp::CmpRI(opcd, rd, imm) => {
+                op1.kind = UopKind::Alu(opcd);
+                op1.arg[0] = Storage::Arn(rd);
+                op1.arg[1] = Storage::Imm64(imm);
+                op1.width = rd.size();
+                op1.write_flags(true);
+                res.push(op1);
+            },
+            MacroOp::CmpRR(opcd, rd, rs) => {
+                op1.kind = UopKind::Alu(opcd);
+                op1.arg[0] = Storage::Arn(rd);
+                op1.arg[1] = Storage::Arn(rs);
+                op1.width = rd.size();
+                op1.write_flags(true);
+                res.push(op1);
+            },
+            MacroOp::Jcc(cc, tgt_imm) => {
+                op1.kind = UopKind::Alu(ALUOp::Brn);
+                op1.read_flags_for(cc);
+                op1.eff[0] = Effect::BrnCond(cc, tgt_imm);
                 res.push(op1);
             },
             MacroOp::JmpI(tgt_imm) => {
@@ -254,7 +598,68 @@ impl Uop {
                 op1.eff[0] = Effect::BrnImm(tgt_imm);
                 res.push(op1);
             },
-            _ => unimplemented!("no uop decomposition for {:?}", mop),
+            MacroOp::JmpR(rs) => {
+                op1.kind = UopKind::Alu(ALUOp::Brn);
+                op1.arg[0] = Storage::Arn(rs);
+                op1.eff[0] = Effect::BrnInd;
+                res.push(op1);
+            },
+            MacroOp::JmpM(mem) => {
+                op1.kind = UopKind::Agu(AGUOp::Ld(MemorySize::UInt64));
+                op1.set_mem(mem);
+                op1.eff[0] = Effect::TmpWrite(0, Prn::alloc());
+                op2.kind = UopKind::Alu(ALUOp::Brn);
+                op2.arg[0] = Storage::Tmp(0);
+                op2.eff[0] = Effect::BrnInd;
+                res.push(op1);
+                res.push(op2);
+            },
+            MacroOp::CallI(tgt_imm) => {
+                op1.kind = UopKind::Alu(ALUOp::Brn);
+                op1.eff[0] = Effect::BrnImm(tgt_imm);
+                Self::lower_call(&mut res, addr, len, op1);
+            },
+            // NOTE: The target is copied into a temporary register first, 
+            // since the target register may be RSP.
+            MacroOp::CallR(rs) => {
+                op1.kind = UopKind::Alu(ALUOp::Add);
+                op1.arg[0] = Storage::Arn(rs);
+                op1.arg[1] = Storage::Zero;
+                op1.eff[0] = Effect::TmpWrite(0, Prn::alloc());
+                op2.kind = UopKind::Alu(ALUOp::Brn);
+                op2.arg[0] = Storage::Tmp(0);
+                op2.eff[0] = Effect::BrnInd;
+                res.push(op1);
+                Self::lower_call(&mut res, addr, len, op2);
+            },
+            MacroOp::CallM(mem) => {
+                op1.kind = UopKind::Agu(AGUOp::Ld(MemorySize::UInt64));
+                op1.set_mem(mem);
+                op1.eff[0] = Effect::TmpWrite(0, Prn::alloc());
+                op2.kind = UopKind::Alu(ALUOp::Brn);
+                op2.arg[0] = Storage::Tmp(0);
+                op2.eff[0] = Effect::BrnInd;
+                res.push(op1);
+                Self::lower_call(&mut res, addr, len, op2);
+            },
+            MacroOp::Ret => {
+                // Pop the return address from the stack
+                op1.kind = UopKind::Agu(AGUOp::Ld(MemorySize::UInt64));
+                op1.set_mem(MemOperand { 
+                    base: Register::RSP, idx: Register::None, scale: 1, disp: 0,
+                });
+                op1.eff[0] = Effect::TmpWrite(0, Prn::alloc());
+                op2.kind = UopKind::Alu(ALUOp::Add);
+                op2.arg[0] = Storage::Arn(Register::RSP);
+                op2.arg[1] = Storage::Imm64(8);
+                op2.eff[0] = Effect::RegWrite(Register::RSP, Prn::alloc());
+                op3.kind = UopKind::Alu(ALUOp::Brn);
+                op3.arg[0] = Storage::Tmp(0);
+                op3.eff[0] = Effect::BrnInd;
+                res.push(op1);
+                res.push(op2);
+                res.push(op3);
+            },
         }
         res
     }
@@ -0,0 +1,459 @@
+//! Op cache.
+
+use std::io::{ self, Write };
+use crate::util::*;
+use crate::bp::*;
+use crate::front::*;
+use crate::dispatch::*;
+use crate::op::*;
+use crate::config::OpCacheConfig;
+use crate::event::*;
+
+/// Size of the aligned region covered by a single entry.
+pub const OC_LINE_SIZE: usize = 64;
+
+/// A decoded instruction stored in the op cache.
+#[derive(Clone, Copy, Debug)]
+pub struct OpCacheInst {
+    pub addr: usize,
+    pub len: usize,
+    pub op: MacroOp,
+}
+
+/// An entry in the op cache, holding the macro-ops for a sequential run of
+/// instructions.
+///
+/// An entry is terminated by any of the following:
+///
+/// - An instruction which ends beyond the 64-byte line containing the
+///   first instruction
+/// - A branch which was predicted taken, or the second branch in the entry
+/// - Running out of slots for macro-ops (instructions with a 64-bit
+///   immediate use two slots)
+///
+/// NOTE: Microcoded instructions are never stored in the op cache. For now,
+/// this is any instruction we can't lower into micro-ops.
+#[derive(Clone, Debug)]
+pub struct OpCacheEntry {
+    /// Address of the first instruction
+    pub addr: usize,
+    pub insts: Vec<OpCacheInst>,
+    /// Number of slots used
+    pub slots: usize,
+    /// Number of branches
+    pub branches: usize,
+}
+impl OpCacheEntry {
+    fn new(addr: usize) -> Self {
+        Self { addr, insts: Vec::new(), slots: 0, branches: 0 }
+    }
+
+    /// Address of the next-sequential instruction after this entry.
+    pub fn next_addr(&self) -> usize {
+        self.insts.last().map_or(self.addr, |i| i.addr + i.len)
+    }
+
+    fn line_end(&self) -> usize {
+        (self.addr & !(OC_LINE_SIZE - 1)) + OC_LINE_SIZE
+    }
+}
+
+/// Counters for the op cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpCacheStats {
+    /// Entries delivered to the OPQ
+    pub hits: usize,
+    /// Lookups which switched back to the decoders
+    pub misses: usize,
+    pub fills: usize,
+    pub evictions: usize,
+    /// Macro-ops delivered by the op cache
+    pub oc_ops: usize,
+    /// Macro-ops delivered by the decoders
+    pub decode_ops: usize,
+    /// Number of switches between the op cache and the decoders
+    pub switches: usize,
+    /// Number of switches from the decoders to the op cache
+    pub switches_to_oc: usize,
+    /// Cycles lost when switching
+    pub switch_cycles: usize,
+}
+
+/// The op cache (indexed by the address of the first instruction in each
+/// entry).
+pub struct OpCache {
+    pub cfg: OpCacheConfig,
+    sets: Vec<Vec<Option<(OpCacheEntry, usize)>>>,
+    /// Entry being built by the decoders
+    fill: Option<OpCacheEntry>,
+    /// The current cycle (updated by [OpCacheUnit::cycle])
+    clk: usize,
+    pub stats: OpCacheStats,
+}
+impl OpCache {
+    pub fn new(cfg: &OpCacheConfig) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            sets: vec![vec![None; cfg.ways]; cfg.sets],
+            fill: None,
+            clk: 0,
+            stats: OpCacheStats::default(),
+        }
+    }
+
+    fn set(&self, addr: usize) -> usize { 
+        (addr / OC_LINE_SIZE) % self.cfg.sets 
+    }
+
+    /// Return the entry containing an instruction at some address (if 
+    /// any), along with the index of the instruction in the entry. Entries
+    /// which start with the instruction are preferred.
+    ///
+    /// NOTE: All instructions in an entry start within the same 64-byte 
+    /// line, so they all map to the same set.
+    pub fn lookup(&mut self, addr: usize) -> Option<(&OpCacheEntry, usize)> {
+        let clk = self.clk;
+        let set = self.set(addr);
+        let set = &mut self.sets[set];
+        let pos = |e: &OpCacheEntry| {
+            e.insts.iter().position(|i| i.addr == addr)
+        };
+        let (ent, used) = set.iter_mut().flatten()
+            .filter(|(e, _)| pos(e).is_some())
+            .min_by_key(|(e, _)| pos(e))?;
+        *used = clk;
+        let idx = pos(ent).unwrap();
+        Some((ent, idx))
+    }
+
+    pub fn probe(&self, addr: usize) -> bool {
+        self.sets[self.set(addr)].iter().flatten()
+            .any(|(e, _)| e.addr == addr)
+    }
+
+    fn insert(&mut self, ent: OpCacheEntry) {
+        if ent.insts.is_empty() || self.probe(ent.addr) {
+            return;
+        }
+        trace!("[OC] Filled entry {:08x} ({} ops)", 
+            ent.addr, ent.insts.len());
+
+        // Replace the least-recently used entry in the set
+        let set = self.set(ent.addr);
+        let set = &mut self.sets[set];
+        let way = set.iter().position(|e| e.is_none()).unwrap_or_else(|| {
+            self.stats.evictions += 1;
+            (0..set.len()).min_by_key(|w| set[*w].as_ref().unwrap().1)
+                .unwrap()
+        });
+        set[way] = Some((ent, self.clk));
+        self.stats.fills += 1;
+    }
+
+    /// Terminate the entry being filled (if any) and install it.
+    pub fn finish_fill(&mut self) {
+        if let Some(ent) = self.fill.take() {
+            self.insert(ent);
+        }
+    }
+
+    /// Discard the entry being filled (ie. after a redirect).
+    pub fn cancel_fill(&mut self) {
+        self.fill = None;
+    }
+
+    /// Add an instruction from the decoders to the entry being filled.
+    pub fn fill_inst(&mut self, inst: OpCacheInst, slots: usize,
+                     pred: &Prediction)
+    {
+        // Microcoded instructions are never cached
+        if let MacroOp::Unsupported(_) = inst.op {
+            self.finish_fill();
+            return;
+        }
+
+        // Start a new entry if this isn't the next-sequential instruction,
+        // or if there's no room for it
+        let is_branch = BranchKind::from(inst.op) != BranchKind::None;
+        let (max_ops, max_branches) = 
+            (self.cfg.entry_ops, self.cfg.entry_branches);
+        let fits = self.fill.as_ref().is_some_and(|e| {
+            e.next_addr() == inst.addr
+                && e.slots + slots <= max_ops
+                && e.branches + is_branch as usize <= max_branches
+        });
+        if !fits {
+            self.finish_fill();
+            self.fill = Some(OpCacheEntry::new(inst.addr));
+        }
+
+        let ent = self.fill.as_mut().unwrap();
+        ent.insts.push(inst);
+        ent.slots += slots;
+        ent.branches += is_branch as usize;
+
+        let terminate = inst.addr + inst.len >= ent.line_end()
+            || ent.slots == max_ops
+            || ent.branches == max_branches
+            || pred.tgt.is_some();
+        if terminate {
+            self.finish_fill();
+        }
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        let s = &self.stats;
+        writeln!(out, "[OC] hits={} misses={} fills={} evictions={} oc_ops={} \
+            decode_ops={} switches={} switch_cycles={}", s.hits, s.misses,
+            s.fills, s.evictions, s.oc_ops, s.decode_ops, s.switches,
+            s.switch_cycles)?;
+        Ok(())
+    }
+}
+
+/// Which pipeline is delivering macro-ops to the OPQ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrontendMode {
+    /// Instruction bytes are fetched from the L1I and decoded
+    Decode,
+    /// Macro-ops are read from the op cache
+    OpCache,
+}
+
+/// Delivers macro-ops from the op cache, and decides when to switch
+/// between the op cache and the decoders.
+pub struct OpCacheUnit {
+    pub mode: FrontendMode,
+    /// Number of cycles remaining until the next pipeline can proceed
+    pub stall: usize,
+    /// Address of the next instruction to deliver (or `None` when starting
+    /// at the head of the FTQ)
+    pub pc: Option<usize>,
+    pub oc: OpCache,
+}
+impl OpCacheUnit {
+    pub fn new(cfg: &OpCacheConfig) -> Self {
+        Self { 
+            mode: FrontendMode::Decode, 
+            stall: 0, 
+            pc: None, 
+            oc: OpCache::new(cfg),
+        }
+    }
+
+    /// Returns true if the fetch unit and decoders are allowed to run.
+    pub fn decode_active(&self) -> bool {
+        self.mode == FrontendMode::Decode && self.stall == 0
+    }
+
+    /// Discard state after the front-end is redirected.
+    pub fn redirect(&mut self) {
+        self.pc = None;
+        self.oc.cancel_fill();
+    }
+
+    fn switch(&mut self, mode: FrontendMode) {
+        trace!("[OC] Switching to {:?} mode", mode);
+        self.mode  = mode;
+        self.stall = self.oc.cfg.switch_penalty;
+        self.pc    = None;
+        self.oc.stats.switches += 1;
+        if mode == FrontendMode::OpCache {
+            self.oc.stats.switches_to_oc += 1;
+        }
+    }
+
+    pub fn cycle(&mut self,
+        ftq: &mut Queue<FTQEntry>,
+        ibq: &Queue<IBQEntry>,
+        idu: &DecodeUnit,
+        opq: &mut Queue<OPQEntry>,
+        tr: &mut Tracer,
+        clk: usize,
+    ) {
+        self.oc.clk = clk;
+        if self.stall != 0 {
+            trace!("[OC] Stalled for mode switch ({} cycles left)",
+                self.stall);
+            self.stall -= 1;
+            self.oc.stats.switch_cycles += 1;
+            return;
+        }
+        let Ok(head) = ftq.peek(0).copied() else {
+            return;
+        };
+        let head_start = head.addr + head.start;
+        let head_end   = head.addr + head.end;
+
+        // Switch to the op cache once the decoders have drained, and the
+        // next fetch block starts with an op cache entry
+        if self.mode == FrontendMode::Decode {
+            let drained = ibq.is_empty() && idu.pick_offset == 0;
+            if drained && self.oc.probe(head_start) {
+                self.oc.finish_fill();
+                self.switch(FrontendMode::OpCache);
+            }
+            return;
+        }
+
+        // Find the op cache entry for the next instruction. On a miss,
+        // the decoders take over starting from this instruction.
+        //
+        // Delivery may continue from the middle of an entry (when it spans
+        // more than one fetch block), but otherwise must start at the 
+        // first instruction in an entry.
+        let (pc, cont) = match self.pc {
+            Some(pc) if head_start <= pc && pc < head_end => (pc, true),
+            _ => (head_start, false),
+        };
+        let hit = self.oc.lookup(pc)
+            .filter(|(_, idx)| cont || *idx == 0)
+            .map(|(ent, idx)| (ent.clone(), idx));
+        let Some((ent, idx)) = hit else {
+            trace!("[OC] Miss for {:08x}", pc);
+            self.oc.stats.misses += 1;
+            ftq.get_mut(0).start = pc - head.addr;
+            self.switch(FrontendMode::Decode);
+            return;
+        };
+        trace!("[OC] Hit for {:08x}", pc);
+
+        // Deliver macro-ops from this entry, up to the end of the head
+        // fetch block. Predictions are attached from the fetch block
+        // containing the last byte of each instruction.
+        let mut num = 0;
+        let mut pc = pc;
+        for inst in ent.insts[idx..].iter() {
+            if num == self.oc.cfg.width || opq.is_full() 
+                || inst.addr >= head_end 
+            {
+                break;
+            }
+            let last = inst.addr + inst.len - 1;
+            let blk = if last < head.addr + 0x20 {
+                head
+            } else if let Ok(next) = ftq.peek(1) {
+                *next
+            } else {
+                break;
+            };
+            let pred = Prediction::for_addr(inst.addr, &blk.preds, blk.cp);
+            opq.push(OPQEntry {
+                op: inst.op, addr: inst.addr, len: inst.len, pred
+            }).unwrap();
+            tr.inst(inst.addr, EventKind::Decode { op: inst.op, oc: true });
+            pc = inst.addr + inst.len;
+            num += 1;
+        }
+        self.oc.stats.oc_ops += num;
+        self.pc = Some(pc);
+
+        // An entry which is delivered over several cycles is only counted
+        // as a single hit
+        if idx == 0 && num != 0 {
+            self.oc.stats.hits += 1;
+        }
+
+        // Move to the next fetch block once this one has been delivered.
+        // Unless the block ends with a predicted-taken branch, the next
+        // instruction may start somewhere inside the next block.
+        if pc >= head_end {
+            ftq.pop().unwrap();
+            if head.end != 0x20 {
+                self.pc = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use iced_x86::{ Code, ConditionCode };
+
+    /// An op cache with some number of ways in each set.
+    fn op_cache(ways: usize) -> OpCache {
+        OpCache::new(&OpCacheConfig { ways, ..OpCacheConfig::default() })
+    }
+
+    /// Add an instruction to the entry being filled.
+    fn fill(oc: &mut OpCache, addr: usize, len: usize, op: MacroOp, 
+        taken: bool)
+    {
+        let pred = Prediction { 
+            tgt: taken.then_some(0x8000), ..Default::default() 
+        };
+        oc.fill_inst(OpCacheInst { addr, len, op }, 1, &pred);
+    }
+
+    fn entries(oc: &OpCache) -> Vec<(usize, usize)> {
+        let mut res: Vec<(usize, usize)> = oc.sets.iter().flatten()
+            .flatten().map(|(e, _)| (e.addr, e.insts.len())).collect();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn lookup() {
+        let mut oc = op_cache(8);
+        for addr in 0x100..0x103 {
+            fill(&mut oc, addr, 1, MacroOp::Nop, false);
+        }
+        assert!(!oc.probe(0x100));
+        fill(&mut oc, 0x103, 2, MacroOp::JmpI(0x8000), true);
+        assert_eq!(entries(&oc), vec![(0x100, 4)]);
+
+        let (ent, idx) = oc.lookup(0x102).unwrap();
+        assert_eq!((ent.addr, ent.next_addr(), idx), (0x100, 0x105, 2));
+        assert!(oc.lookup(0x105).is_none());
+        assert!(!oc.probe(0x102));
+    }
+
+    #[test]
+    fn terminate() {
+        let mut oc = op_cache(8);
+
+        // At the end of the line
+        fill(&mut oc, 0x13c, 2, MacroOp::Nop, false);
+        fill(&mut oc, 0x13e, 4, MacroOp::Nop, false);
+        // After the second branch
+        let jcc = MacroOp::Jcc(ConditionCode::ne, 0x100);
+        fill(&mut oc, 0x142, 2, jcc, false);
+        fill(&mut oc, 0x144, 2, jcc, false);
+        // When the next instruction isn't sequential
+        fill(&mut oc, 0x200, 2, MacroOp::Nop, false);
+        fill(&mut oc, 0x300, 2, MacroOp::Nop, false);
+        // Before a microcoded instruction (which isn't cached)
+        fill(&mut oc, 0x302, 2, MacroOp::Unsupported(Code::Lea_r64_m), 
+            false);
+        assert_eq!(entries(&oc), 
+            vec![(0x13c, 2), (0x142, 2), (0x200, 1), (0x300, 1)]);
+
+        // When all of the slots are used
+        let mut oc = op_cache(8);
+        for addr in 0x400..0x409 {
+            fill(&mut oc, addr, 1, MacroOp::Nop, false);
+        }
+        oc.finish_fill();
+        assert_eq!(entries(&oc), vec![(0x400, 8), (0x408, 1)]);
+    }
+
+    #[test]
+    fn evict() {
+        // Entries which are 4KiB apart map to the same set
+        let mut oc = op_cache(2);
+        for addr in [0x0000, 0x1000, 0x2000] {
+            oc.clk += 1;
+            fill(&mut oc, addr, 2, MacroOp::JmpI(0x8000), true);
+        }
+        assert_eq!(entries(&oc), vec![(0x1000, 1), (0x2000, 1)]);
+        assert_eq!(oc.stats.evictions, 1);
+
+        // Lookups update the replacement state
+        oc.clk += 1;
+        oc.lookup(0x1000);
+        fill(&mut oc, 0x3000, 2, MacroOp::JmpI(0x8000), true);
+        assert_eq!(entries(&oc), vec![(0x1000, 1), (0x3000, 1)]);
+    }
+}
@@ -1,3 +1,128 @@
 
+use crate::util::*;
+use crate::front::*;
+use crate::dispatch::*;
+use crate::issue::*;
+use crate::exec::*;
+use crate::retire::*;
+use crate::rf::*;
+use crate::bp::*;
+use crate::lsq::*;
+use crate::opcache::*;
+use crate::event::*;
 
+/// A request to squash all work younger than some reorder buffer entry
+/// and restart the front-end at a new address.
+#[derive(Clone, Copy, Debug)]
+pub struct Redirect {
+    /// Reorder buffer index of the youngest surviving entry
+    pub rob_idx: usize,
+    /// The correct next program counter
+    pub tgt: usize,
+    /// Also squash the entry at `rob_idx` (ie. when replaying a load after
+    /// a memory-ordering violation)
+    pub inclusive: bool,
+}
 
+/// Recover from a misprediction or a memory-ordering violation.
+///
+/// This entails (not necessarily in this order):
+///
+/// - Squashing all younger entries in the reorder buffer
+/// - Squashing all younger reservations and in-flight operations
+/// - Freeing physical registers allocated by squashed micro-ops
+/// - Restoring the speculative register alias table
+/// - Discarding everything in the front-end queues
+/// - Charging squashed instructions to bad speculation (see
+///   [crate::topdown::TopDown])
+/// - Redirecting the next-PC logic to the correct target address
+#[allow(clippy::too_many_arguments)]
+pub fn flush(r: Redirect,
+    rob: &mut ReorderBuffer,
+    prf: &mut PhysicalRegisterFile,
+    rat: &RegisterAliasTable,
+    frat: &mut RegisterAliasTable,
+    alu_sched: &mut [ALUScheduler],
+    agu_sched: &mut AGUScheduler,
+    eu: &mut ExecutionUnits,
+    lsq: &mut LoadStoreQueue,
+    opq: &mut Queue<OPQEntry>,
+    ibq: &mut Queue<IBQEntry>,
+    ftq: &mut Queue<FTQEntry>,
+    bpu: &mut BranchPredictionUnit,
+    idu: &mut DecodeUnit,
+    ocu: &mut OpCacheUnit,
+    dispatch: &mut DispatchUnit,
+    npc: &mut NextPCLogic,
+    next_pc: &mut usize,
+    tr: &mut Tracer,
+) {
+    tr.emit(Event {
+        clk: tr.clk, pc: Some(r.tgt), rob_idx: Some(r.rob_idx), id: None,
+        kind: EventKind::Redirect { inclusive: r.inclusive },
+    });
+
+    // Repair the speculative state of the branch predictor. When the entry
+    // itself is squashed, this is just the state before the instruction.
+    let ent = rob.get(r.rob_idx).unwrap();
+    if r.inclusive {
+        bpu.restore(ent.pred.cp);
+    } else {
+        let info = BranchInfo {
+            kind: BranchKind::from(ent.mop),
+            len: ent.uop.len,
+            addr: ent.uop.addr,
+        };
+        bpu.repair(info, ent.pred, r.tgt);
+    }
+
+    // Discard younger reservations and in-flight operations.
+    // This needs to happen before we modify the reorder buffer (which
+    // determines the age of an entry).
+    let squashed = |rob_idx: usize| {
+        rob.is_younger(rob_idx, r.rob_idx) 
+            || (r.inclusive && rob_idx == r.rob_idx)
+    };
+    let younger = |res: &Reservation| squashed(res.rob_idx);
+    for alq in alu_sched.iter_mut() {
+        alq.squash(younger);
+    }
+    agu_sched.squash(younger);
+    eu.squash(squashed);
+    lsq.squash(squashed);
+
+    // Discard younger entries in the reorder buffer and release any
+    // physical registers that were allocated for them
+    let (first, squashed) = if r.inclusive { 
+        (r.rob_idx, rob.squash_from(r.rob_idx))
+    } else { 
+        ((r.rob_idx + 1) % rob.size, rob.squash_younger(r.rob_idx))
+    };
+    for (i, ent) in squashed.iter().enumerate() {
+        let rob_idx = (first + i) % rob.size;
+        tr.uop(&ent.uop, rob_idx, EventKind::Flush);
+        for (_, prn) in ent.uop.eff.iter().filter_map(|e| e.dest()) {
+            prf.free_explicit(prn);
+        }
+    }
+    let num_insts = squashed.iter().filter(|e| e.last).count();
+    dispatch.topdown.squash(num_insts);
+
+    // Rebuild the speculative register alias table: start from the
+    // architectural state and replay the surviving in-flight entries
+    frat.data = rat.data;
+    for (_, ent) in rob.iter_inflight() {
+        for (arn, prn) in ent.uop.eff.iter().filter_map(|e| e.dest()) {
+            frat.update_arn(arn, prn);
+        }
+    }
+
+    // Everything in the front-end is on the wrong path
+    opq.clear();
+    ibq.clear();
+    ftq.clear();
+    idu.pick_offset = 0;
+    ocu.redirect();
+    let penalty = npc.redirect_penalty;
+    npc.redirect(next_pc, r.tgt, penalty);
+}
@@ -0,0 +1,461 @@
+//! Exporting pipeline traces for external viewers.
+//!
+//! [PipeViewSink] follows each micro-op through the pipeline and writes
+//! its lifetime in one of these formats:
+//!
+//! - Konata's log format (<https://github.com/shioyadan/Konata>)
+//! - gem5's O3PipeView format (for `util/o3-pipeview.py`)
+//!
+//! Instructions squashed by a redirect are shown as flushed, including
+//! instructions which were decoded but never dispatched.
+//!
+//! Each micro-op is written (and forgotten) once it retires or is squashed.
+//! Konata commands must be in cycle order, so they're held back until no
+//! other micro-op can start on an earlier cycle.
+//!
+//! NOTE: The fetch cycle for an instruction is the cycle that its fetch
+//! block was read from the L1I. If a fetch block is taken by a branch to
+//! a later address in the same block, the fetch cycle may be from the
+//! earlier read.
+
+use std::io::{ self, Write };
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, HashMap, VecDeque };
+
+use crate::event::*;
+use crate::elf::*;
+
+/// Number of gem5 ticks per cycle (the default for `o3-pipeview.py`).
+pub const O3_TICKS_PER_CYCLE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipeViewFormat {
+    Konata,
+    O3PipeView,
+}
+
+/// A decoded instruction which hasn't been dispatched yet.
+struct Pending {
+    pc: usize,
+    label: String,
+    fetch: usize,
+    decode: usize,
+    /// Number of micro-ops dispatched so far
+    uops: usize,
+}
+
+/// The lifetime of a single micro-op (or of an instruction which was
+/// squashed before being dispatched).
+struct Record {
+    /// Konata ID (or O3PipeView sequence number)
+    id: usize,
+    pc: usize,
+    label: String,
+    /// Index of the micro-op within its instruction
+    upc: usize,
+    fetch: usize,
+    decode: usize,
+    dispatch: Option<usize>,
+    issue: Option<usize>,
+    complete: Option<usize>,
+    /// Cycle and sequence number of retirement
+    retire: Option<(usize, usize)>,
+    flush: Option<usize>,
+}
+impl Record {
+    fn new(id: usize, p: &Pending) -> Self {
+        Self {
+            id, pc: p.pc, label: p.label.clone(), upc: p.uops,
+            fetch: p.fetch, decode: p.decode,
+            dispatch: None, issue: None, complete: None,
+            retire: None, flush: None,
+        }
+    }
+}
+
+pub struct PipeViewSink {
+    pub format: PipeViewFormat,
+    out: Box<dyn Write + Send>,
+    /// The first error from writing the output (nothing else is written
+    /// after an error)
+    err: Option<io::Error>,
+    /// Decoded instructions (in program order)
+    pending: VecDeque<Pending>,
+    /// Fetch blocks which were read from the L1I and may not have been
+    /// decoded yet: (block address, cycle)
+    fetched: VecDeque<(usize, usize)>,
+    /// Address of the last instruction from the decoders
+    last_decode: Option<usize>,
+    /// Records for in-flight micro-ops (keyed by sequence number)
+    inflight: HashMap<usize, Record>,
+    num_records: usize,
+    num_retired: usize,
+    /// Konata commands which haven't been written yet: (cycle, order
+    /// added, command)
+    cmds: BinaryHeap<Reverse<(usize, usize, String)>>,
+    num_cmds: usize,
+    /// The cycle of the last Konata command written (if any)
+    cur: Option<usize>,
+    /// The cycle of the most recent event
+    clk: usize,
+}
+impl PipeViewSink {
+    pub fn new(format: PipeViewFormat, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            format, out,
+            err: None,
+            pending: VecDeque::new(),
+            fetched: VecDeque::new(),
+            last_decode: None,
+            inflight: HashMap::new(),
+            num_records: 0,
+            num_retired: 0,
+            cmds: BinaryHeap::new(),
+            num_cmds: 0,
+            cur: None,
+            clk: 0,
+        }
+    }
+
+    fn new_record(&mut self, p: &Pending) -> Record {
+        self.num_records += 1;
+        Record::new(self.num_records - 1, p)
+    }
+
+    /// Return the cycle when the fetch block for a decoded instruction was
+    /// read from the L1I, discarding older fetch blocks.
+    fn fetch_cycle(&mut self, pc: usize, clk: usize) -> usize {
+        let blk = pc & !0x1f;
+
+        // Decoding a lower address in the same block moves on to the next
+        // read of the block (ie. in a loop)
+        let wrapped = self.last_decode
+            .is_some_and(|last| last & !0x1f == blk && pc <= last);
+        if wrapped {
+            self.fetched.pop_front();
+        }
+        self.last_decode = Some(pc);
+        while self.fetched.front().is_some_and(|(b, _)| *b != blk) {
+            self.fetched.pop_front();
+        }
+        self.fetched.front().map_or(clk, |(_, c)| *c)
+    }
+
+    /// Squash a decoded instruction which was never dispatched.
+    fn squash_pending(&mut self, p: Pending, clk: usize) -> io::Result<()> {
+        let mut rec = self.new_record(&p);
+        rec.flush = Some(clk);
+        self.write_record(&rec)
+    }
+
+    /// Write a record (once it has retired or been squashed).
+    fn write_record(&mut self, r: &Record) -> io::Result<()> {
+        match self.format {
+            PipeViewFormat::Konata => {
+                self.add_konata(r);
+                Ok(())
+            },
+            PipeViewFormat::O3PipeView => self.write_o3(r),
+        }
+    }
+
+    /// The earliest cycle that a command for any record which hasn't been 
+    /// written yet could be on.
+    fn watermark(&self) -> usize {
+        let fetched = self.fetched.front().map(|(_, clk)| *clk);
+        let pending = self.pending.iter().map(|p| p.fetch).min();
+        let inflight = self.inflight.values().map(|r| r.fetch).min();
+        [fetched, pending, inflight].into_iter().flatten()
+            .fold(self.clk, usize::min)
+    }
+
+    fn add_konata(&mut self, r: &Record) {
+        let id = r.id;
+        let mut cmds: Vec<(usize, String)> = Vec::new();
+        let stages = [
+            ("F", Some(r.fetch)), ("Dc", Some(r.decode)),
+            ("Ds", r.dispatch), ("Ex", r.issue), ("Cm", r.complete),
+        ];
+        let stages = stages.iter()
+            .filter_map(|(name, clk)| clk.map(|c| (*name, c)));
+        cmds.push((r.fetch, format!("I\t{}\t{}\t0", id, id)));
+        cmds.push((r.fetch, format!("L\t{}\t0\t{:08x}: {}",
+            id, r.pc, r.label)));
+        let mut prev: Option<&str> = None;
+        for (name, clk) in stages {
+            if let Some(p) = prev {
+                cmds.push((clk, format!("E\t{}\t0\t{}", id, p)));
+            }
+            cmds.push((clk, format!("S\t{}\t0\t{}", id, name)));
+            prev = Some(name);
+        }
+        let end = match (r.retire, r.flush) {
+            (Some((clk, seq)), _) => Some((clk, seq, 0)),
+            (_, Some(clk)) => Some((clk, id, 1)),
+            _ => None,
+        };
+        if let Some((clk, seq, kind)) = end {
+            cmds.push((clk, format!("E\t{}\t0\t{}", id, prev.unwrap())));
+            cmds.push((clk, format!("R\t{}\t{}\t{}", id, seq, kind)));
+        }
+        for (clk, cmd) in cmds {
+            self.cmds.push(Reverse((clk, self.num_cmds, cmd)));
+            self.num_cmds += 1;
+        }
+    }
+
+    /// Write the Konata commands before some cycle.
+    fn write_konata(&mut self, until: usize) -> io::Result<()> {
+        while self.cmds.peek().is_some_and(|Reverse(c)| c.0 < until) {
+            let Reverse((clk, _, cmd)) = self.cmds.pop().unwrap();
+            match self.cur {
+                None => {
+                    writeln!(self.out, "Kanata\t0004")?;
+                    writeln!(self.out, "C=\t{}", clk)?;
+                },
+                Some(cur) if clk != cur => {
+                    writeln!(self.out, "C\t{}", clk - cur)?;
+                },
+                _ => {},
+            }
+            self.cur = Some(clk);
+            writeln!(self.out, "{}", cmd)?;
+        }
+        Ok(())
+    }
+
+    /// NOTE: Rename and dispatch happen on the same cycle. Fields are 
+    /// separated by ':', so it's removed from labels.
+    fn write_o3(&mut self, r: &Record) -> io::Result<()> {
+        let tick = |clk: Option<usize>| {
+            clk.map_or(0, |c| c * O3_TICKS_PER_CYCLE)
+        };
+        writeln!(self.out, "O3PipeView:fetch:{}:0x{:016x}:{}:{}:{}",
+            tick(Some(r.fetch)), r.pc, r.upc, r.id + 1, 
+            r.label.replace(':', ""))?;
+        writeln!(self.out, "O3PipeView:decode:{}", tick(Some(r.decode)))?;
+        writeln!(self.out, "O3PipeView:rename:{}", tick(r.dispatch))?;
+        writeln!(self.out, "O3PipeView:dispatch:{}", tick(r.dispatch))?;
+        writeln!(self.out, "O3PipeView:issue:{}", tick(r.issue))?;
+        writeln!(self.out, "O3PipeView:complete:{}", tick(r.complete))?;
+        writeln!(self.out, "O3PipeView:retire:{}:store:0",
+            tick(r.retire.map(|(clk, _)| clk)))?;
+        Ok(())
+    }
+
+    fn handle(&mut self, ev: &Event, syms: &SymbolTable) -> io::Result<()> {
+        use EventKind::*;
+        let clk = ev.clk;
+        let pc = ev.pc.unwrap_or(0);
+
+        // Write any Konata commands which are ready on a new cycle
+        if clk != self.clk {
+            self.clk = clk;
+            if self.format == PipeViewFormat::Konata {
+                self.write_konata(self.watermark())?;
+            }
+        }
+
+        match ev.kind {
+            Fetch { .. } => {
+                self.fetched.push_back((pc & !0x1f, clk));
+            },
+            Decode { op, oc } => {
+                // NOTE: The op cache is only used after the decoders have
+                // drained, so every fetch block has been decoded
+                let fetch = if oc {
+                    self.fetched.clear();
+                    self.last_decode = None;
+                    clk
+                } else {
+                    self.fetch_cycle(pc, clk)
+                };
+                let label = match syms.lookup(pc) {
+                    Some(_) => format!("{:?} <{}>", op, syms.fmt(pc)),
+                    None => format!("{:?}", op),
+                };
+                self.pending.push_back(Pending {
+                    pc, label, fetch, decode: clk, uops: 0
+                });
+            },
+            Dispatch { kind, last } => {
+                // Anything older than this instruction must have been
+                // squashed in the front-end
+                while self.pending.front().is_some_and(|p| p.pc != pc) {
+                    let p = self.pending.pop_front().unwrap();
+                    self.squash_pending(p, clk)?;
+                }
+                if self.pending.is_empty() {
+                    self.pending.push_back(Pending {
+                        pc, label: String::new(), fetch: clk, decode: clk,
+                        uops: 0
+                    });
+                }
+                let p = self.pending.pop_front().unwrap();
+                let mut rec = self.new_record(&p);
+                rec.label = format!("{} {:?}", p.label, kind)
+                    .trim_start().to_string();
+                rec.dispatch = Some(clk);
+                if !last {
+                    self.pending.push_front(Pending { uops: p.uops + 1, ..p });
+                }
+                self.inflight.insert(ev.id.unwrap(), rec);
+            },
+            Issue { .. } => if let Some(r) = self.record(ev) {
+                r.issue.get_or_insert(clk);
+            },
+            Complete { .. } => if let Some(r) = self.record(ev) {
+                r.complete = Some(clk);
+            },
+            Retire => {
+                if let Some(mut r) = self.inflight.remove(&ev.id.unwrap()) {
+                    r.retire = Some((clk, self.num_retired));
+                    self.num_retired += 1;
+                    self.write_record(&r)?;
+                }
+            },
+            Flush => {
+                if let Some(mut r) = self.inflight.remove(&ev.id.unwrap()) {
+                    r.flush = Some(clk);
+                    self.write_record(&r)?;
+                }
+            },
+            Redirect { .. } => {
+                while let Some(p) = self.pending.pop_front() {
+                    self.squash_pending(p, clk)?;
+                }
+                self.fetched.clear();
+                self.last_decode = None;
+            },
+            Stall { .. } => {},
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, ev: &Event) -> Option<&mut Record> {
+        self.inflight.get_mut(&ev.id?)
+    }
+}
+impl EventSink for PipeViewSink {
+    fn event(&mut self, ev: &Event, syms: &SymbolTable) {
+        if self.err.is_some() {
+            return;
+        }
+        if let Err(e) = self.handle(ev, syms) {
+            self.err = Some(e);
+        }
+    }
+
+    /// NOTE: Micro-ops which are still in-flight are only shown by Konata.
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.err.take() {
+            return Err(e);
+        }
+        if self.format == PipeViewFormat::Konata {
+            let mut recs: Vec<Record> = self.inflight.drain()
+                .map(|(_, r)| r).collect();
+            recs.sort_by_key(|r| r.id);
+            for r in recs {
+                self.add_konata(&r);
+            }
+            self.write_konata(usize::MAX)?;
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+    use crate::op::*;
+
+    /// Output which can be read back after the run.
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    /// Run a short sequence through a sink: two instructions are decoded
+    /// from one fetch block, then the second one is squashed in the
+    /// front-end while the first one executes and retires.
+    fn run(format: PipeViewFormat) -> String {
+        use EventKind::*;
+        let buf = Buf::default();
+        let mut sink = PipeViewSink::new(format, Box::new(buf.clone()));
+        let alu = ExecUnit::Alu(0);
+        let dispatch = Dispatch { kind: UopKind::Alu(ALUOp::Nop), last: true };
+        for (clk, pc, id, kind) in [
+            (0, 0x1000, None, Fetch { paddr: 0x1000 }),
+            (1, 0x1000, None, Decode { op: MacroOp::Nop, oc: false }),
+            (1, 0x1004, None, Decode { op: MacroOp::Ud2, oc: false }),
+            (2, 0x1000, Some(7), dispatch),
+            (2, 0x1000, Some(7), Issue { unit: alu }),
+            (3, 0x1000, Some(7), Complete { unit: alu }),
+            (3, 0x1000, None, Redirect { inclusive: false }),
+            (5, 0x1000, Some(7), Retire),
+        ] {
+            let ev = Event { clk, pc: Some(pc), rob_idx: id, id, kind };
+            sink.event(&ev, &SymbolTable::default());
+        }
+        sink.finish().unwrap();
+        let data = buf.0.lock().unwrap();
+        String::from_utf8(data.clone()).unwrap()
+    }
+
+    #[test]
+    fn o3pipeview() {
+        assert_eq!(run(PipeViewFormat::O3PipeView), "\
+            O3PipeView:fetch:0:0x0000000000001004:0:2:Ud2\n\
+            O3PipeView:decode:1000\n\
+            O3PipeView:rename:0\n\
+            O3PipeView:dispatch:0\n\
+            O3PipeView:issue:0\n\
+            O3PipeView:complete:0\n\
+            O3PipeView:retire:0:store:0\n\
+            O3PipeView:fetch:0:0x0000000000001000:0:1:Nop Alu(Nop)\n\
+            O3PipeView:decode:1000\n\
+            O3PipeView:rename:2000\n\
+            O3PipeView:dispatch:2000\n\
+            O3PipeView:issue:2000\n\
+            O3PipeView:complete:3000\n\
+            O3PipeView:retire:5000:store:0\n");
+    }
+
+    #[test]
+    fn konata() {
+        // Commands on the same cycle are in the order that the records
+        // were written (so the squashed instruction comes first)
+        assert_eq!(run(PipeViewFormat::Konata), "\
+            Kanata\t0004\n\
+            C=\t0\n\
+            I\t1\t1\t0\n\
+            L\t1\t0\t00001004: Ud2\n\
+            S\t1\t0\tF\n\
+            I\t0\t0\t0\n\
+            L\t0\t0\t00001000: Nop Alu(Nop)\n\
+            S\t0\t0\tF\n\
+            C\t1\n\
+            E\t1\t0\tF\n\
+            S\t1\t0\tDc\n\
+            E\t0\t0\tF\n\
+            S\t0\t0\tDc\n\
+            C\t1\n\
+            E\t0\t0\tDc\n\
+            S\t0\t0\tDs\n\
+            E\t0\t0\tDs\n\
+            S\t0\t0\tEx\n\
+            C\t1\n\
+            E\t1\t0\tDc\n\
+            R\t1\t1\t1\n\
+            E\t0\t0\tEx\n\
+            S\t0\t0\tCm\n\
+            C\t2\n\
+            E\t0\t0\tCm\n\
+            R\t0\t0\t0\n");
+    }
+}
@@ -0,0 +1,228 @@
+//! Performance counters.
+//!
+//! Counters are named after the Zen 2 PMC events that they model (see the
+//! AMD PPR for Family 17h Model 31h, or `perf list` on a Zen 2 machine), so
+//! that a run can be compared directly against `perf stat` numbers from
+//! real hardware. The values are collected from the statistics kept by
+//! each unit at the end of a run.
+//!
+//! NOTE: Some events only approximate the hardware definitions:
+//!
+//! - Dispatch stalls count cycles where the oldest macro-op which couldn't
+//!   be dispatched was blocked by some resource (only the first missing
+//!   resource is counted)
+//! - The op cache and decoder events count macro-ops, not micro-ops
+//! - TLB misses count every lookup that missed, including retries while
+//!   a page walk is outstanding
+//! - L2 and L3 events only count demand requests (prefetches are kept 
+//!   separately, see [crate::cache::CacheStats])
+
+use std::collections::BTreeMap;
+use std::io::{ self, Write };
+use serde_json::json;
+
+use crate::Core;
+
+/// Names and descriptions of all counters (in the order they are
+/// reported).
+pub const PMC_EVENTS: &[(&str, &str)] = &[
+    ("ls_not_halted_cyc",
+        "Cycles not in halt"),
+    ("ex_ret_instr",
+        "Retired instructions"),
+    ("ex_ret_cops",
+        "Retired micro-ops"),
+    ("ex_ret_brn",
+        "Retired branch instructions"),
+    ("ex_ret_brn_misp",
+        "Retired branch instructions mispredicted"),
+    ("ex_ret_brn_tkn",
+        "Retired taken branch instructions"),
+    ("ex_ret_brn_tkn_misp",
+        "Retired taken branch instructions mispredicted"),
+    ("ex_ret_brn_ind_misp",
+        "Retired indirect branch instructions mispredicted"),
+    ("ex_ret_near_ret",
+        "Retired near returns"),
+    ("ex_ret_near_ret_mispred",
+        "Retired near returns mispredicted"),
+    ("de_dis_uop_queue_empty_di0",
+        "Cycles where the op queue is empty"),
+    ("de_dis_uops_from_decoder.decoder_dispatched",
+        "Ops delivered by the decoders"),
+    ("de_dis_uops_from_decoder.opcache_dispatched",
+        "Ops delivered by the op cache"),
+    ("de_dis_dispatch_token_stalls0.retire_token_stall",
+        "Dispatch stall cycles (ROB full)"),
+    ("de_dis_dispatch_token_stalls0.alu_token_stall",
+        "Dispatch stall cycles (ALU schedulers full)"),
+    ("de_dis_dispatch_token_stalls0.agsq_token_stall",
+        "Dispatch stall cycles (AGU scheduler full)"),
+    ("de_dis_dispatch_token_stalls1.int_phy_reg_file_rsrc_stall",
+        "Dispatch stall cycles (integer PRF full)"),
+    ("de_dis_dispatch_token_stalls1.load_queue_rsrc_stall",
+        "Dispatch stall cycles (load queue full)"),
+    ("de_dis_dispatch_token_stalls1.store_queue_rsrc_stall",
+        "Dispatch stall cycles (store queue full)"),
+    ("ic_fw32",
+        "32-byte instruction fetches"),
+    ("ic_fw32_miss",
+        "32-byte instruction fetches which missed in the L1I"),
+    ("ic_fetch_stall.ic_stall_back_pressure",
+        "Fetch stall cycles (IBQ full)"),
+    ("ic_fetch_stall.ic_stall_dq_empty",
+        "Fetch stall cycles (FTQ empty)"),
+    ("ic_fetch_stall.ic_stall_any",
+        "Fetch stall cycles (any reason)"),
+    ("ic_oc_mode_switch.ic_oc_mode_switch",
+        "Switches from the decoders to the op cache"),
+    ("ic_oc_mode_switch.oc_ic_mode_switch",
+        "Switches from the op cache to the decoders"),
+    ("op_cache_hit_miss.op_cache_hit",
+        "Op cache hits"),
+    ("op_cache_hit_miss.op_cache_miss",
+        "Op cache misses"),
+    ("ls_dc_accesses",
+        "L1D accesses"),
+    ("l2_request_g1.rd_blk_l",
+        "L2 requests for L1D misses"),
+    ("l2_cache_req_stat.ic_dc_hit_in_l2",
+        "L1I and L1D misses which hit in the L2"),
+    ("l2_cache_req_stat.ic_dc_miss_in_l2",
+        "L1I and L1D misses which missed in the L2"),
+    ("l3_request_g1.caching_l3_cache_accesses",
+        "L3 accesses"),
+    ("l3_comb_clstr_state.request_miss",
+        "L3 misses"),
+    ("bp_l1_tlb_miss_l2_hit",
+        "L1 ITLB misses which hit in the L2 ITLB"),
+    ("bp_l1_tlb_miss_l2_tlb_miss",
+        "L1 ITLB misses which missed in the L2 ITLB"),
+    ("ls_l1_d_tlb_miss.all",
+        "L1 DTLB misses"),
+];
+
+/// A set of counters, keyed by event name.
+#[derive(Clone, Debug, Default)]
+pub struct Pmc {
+    pub counts: BTreeMap<&'static str, usize>,
+}
+impl Pmc {
+    /// Collect the counters from the current state of a core.
+    pub fn collect(core: &Core) -> Self {
+        let fetch = &core.ifu.stats;
+        let oc    = &core.ocu.oc.stats;
+        let dis   = &core.dispatch.stats;
+        let ret   = &core.rcu.stats;
+        let c     = &core.caches;
+        let mmu   = &core.mmu;
+        let fetch_stalls = fetch.icache_miss + fetch.itlb_miss
+            + fetch.ftq_empty + fetch.ibq_full;
+        let values = [
+            core.clk,
+            ret.insts,
+            ret.uops,
+            ret.branches,
+            ret.branch_misp,
+            ret.taken,
+            ret.taken_misp,
+            ret.indirect_misp,
+            ret.returns,
+            ret.return_misp,
+            dis.opq_empty,
+            oc.decode_ops,
+            oc.oc_ops,
+            dis.rob_full,
+            dis.alu_sched_full,
+            dis.agu_sched_full,
+            dis.prf_full,
+            dis.lq_full,
+            dis.sq_full,
+            fetch.fetches,
+            fetch.block_misses,
+            fetch.ibq_full,
+            fetch.ftq_empty,
+            fetch_stalls,
+            oc.switches_to_oc,
+            oc.switches - oc.switches_to_oc,
+            oc.hits,
+            oc.misses,
+            c.l1d.stats.hits + c.l1d.stats.misses,
+            c.l1d.stats.misses,
+            c.l2.stats.hits,
+            c.l2.stats.misses,
+            c.l3.stats.hits + c.l3.stats.misses,
+            c.l3.stats.misses,
+            mmu.itlb[1].stats.hits,
+            mmu.itlb[1].stats.misses,
+            mmu.dtlb[0].stats.misses,
+        ];
+        assert_eq!(values.len(), PMC_EVENTS.len());
+        let counts = PMC_EVENTS.iter().map(|(name, _)| *name)
+            .zip(values).collect();
+        Self { counts }
+    }
+
+    pub fn get(&self, name: &str) -> usize {
+        self.counts.get(name).copied().unwrap_or(0)
+    }
+
+    /// Write the counters in a format similar to `perf stat`.
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (name, desc) in PMC_EVENTS {
+            writeln!(out, "[PMC] {:>12} {:<58} # {}",
+                self.get(name), name, desc)?;
+        }
+        let cyc = self.get("ls_not_halted_cyc");
+        if cyc != 0 {
+            writeln!(out, "[PMC] {:>12.3} IPC",
+                self.get("ex_ret_instr") as f64 / cyc as f64)?;
+        }
+        Ok(())
+    }
+
+    /// Return the counters as a JSON object (keyed by event name).
+    pub fn to_json(&self) -> serde_json::Value {
+        json!(self.counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn collect() {
+        let mut core = Core::new(&Config::default());
+        core.load(0, &[
+            0xb9, 0x0a, 0x00, 0x00, 0x00,   // mov ecx, 10
+            0xff, 0xc9,                     // 1: dec ecx
+            0x75, 0xfc,                     // jnz 1b
+            0x0f, 0x0b,                     // ud2
+        ]);
+        core.run_until(10_000);
+        assert!(core.halted());
+
+        let pmc = Pmc::collect(&core);
+        assert_eq!(pmc.counts.len(), PMC_EVENTS.len());
+        assert_eq!(pmc.get("ls_not_halted_cyc"), core.clk);
+        assert_eq!(pmc.get("ex_ret_instr"), 21);
+        assert_eq!(pmc.get("ex_ret_brn"), 10);
+        assert_eq!(pmc.get("ex_ret_brn_tkn"), 9);
+        assert!(pmc.get("ex_ret_brn_misp") >= 1);
+
+        // All of the code is in one fetch block, which misses once (plus
+        // possibly the next block on the wrong path)
+        let (fw, miss) = (pmc.get("ic_fw32"), pmc.get("ic_fw32_miss"));
+        assert!((1..=2).contains(&miss) && fw >= miss, "{} {}", fw, miss);
+        assert_eq!(pmc.get("ls_dc_accesses"), 0);
+
+        let mut out = Vec::new();
+        pmc.print(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), PMC_EVENTS.len() + 1);
+        assert!(out.lines().last().unwrap().ends_with(" IPC"));
+        assert_eq!(pmc.to_json()["ex_ret_instr"], 21);
+    }
+}
@@ -0,0 +1,215 @@
+//! Hardware data prefetchers.
+
+/// A prefetcher which observes the stream of accesses to some cache and
+/// generates addresses to be prefetched into that cache.
+pub trait Prefetcher: Send {
+    fn name(&self) -> &'static str;
+
+    /// Observe an access to some address by the instruction at 'pc',
+    /// returning a list of addresses to prefetch.
+    fn access(&mut self, pc: usize, addr: usize, hit: bool) -> Vec<usize>;
+}
+
+/// Prefetches the next line after each miss.
+pub struct NextLinePrefetcher {
+    line_size: usize,
+}
+impl NextLinePrefetcher {
+    pub fn new(line_size: usize) -> Self {
+        Self { line_size }
+    }
+}
+impl Prefetcher for NextLinePrefetcher {
+    fn name(&self) -> &'static str { "next-line" }
+
+    fn access(&mut self, _pc: usize, addr: usize, hit: bool) -> Vec<usize> {
+        if hit {
+            return Vec::new();
+        }
+        vec![(addr & !(self.line_size - 1)) + self.line_size]
+    }
+}
+
+/// Number of entries in the table used by [StridePrefetcher].
+pub const STRIDE_TABLE_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct StrideEntry {
+    /// Program counter of the associated instruction
+    pc: usize,
+    /// The last address accessed by this instruction
+    last: usize,
+    stride: isize,
+    /// Saturating confidence counter
+    conf: u8,
+}
+
+/// Detects a constant stride between accesses made by the same instruction.
+pub struct StridePrefetcher {
+    table: [Option<StrideEntry>; STRIDE_TABLE_SIZE],
+    /// Number of strides to prefetch ahead
+    degree: usize,
+}
+impl StridePrefetcher {
+    pub fn new(degree: usize) -> Self {
+        Self { table: [None; STRIDE_TABLE_SIZE], degree }
+    }
+}
+impl Prefetcher for StridePrefetcher {
+    fn name(&self) -> &'static str { "stride" }
+
+    fn access(&mut self, pc: usize, addr: usize, _hit: bool) -> Vec<usize> {
+        let slot = &mut self.table[pc % STRIDE_TABLE_SIZE];
+        let ent = match slot {
+            Some(e) if e.pc == pc => e,
+            _ => {
+                *slot = Some(StrideEntry { 
+                    pc, last: addr, ..Default::default() 
+                });
+                return Vec::new();
+            },
+        };
+
+        let stride = addr.wrapping_sub(ent.last) as isize;
+        if stride != 0 && stride == ent.stride {
+            ent.conf = (ent.conf + 1).min(3);
+        } else {
+            ent.conf = 0;
+            ent.stride = stride;
+        }
+        ent.last = addr;
+        if ent.conf < 2 {
+            return Vec::new();
+        }
+        (1..=self.degree as isize)
+            .map(|n| addr.wrapping_add_signed(ent.stride * n))
+            .collect()
+    }
+}
+
+/// Number of regions tracked by [RegionPrefetcher].
+pub const NUM_REGIONS: usize = 16;
+/// Size of a region in bytes.
+pub const REGION_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    /// Base address of the region
+    base: usize,
+    /// The last line accessed in this region
+    last: usize,
+    /// Direction of the stream (in lines)
+    dir: isize,
+    /// Saturating confidence counter
+    conf: u8,
+    /// Timestamp of the last access (for replacement)
+    used: usize,
+}
+
+/// Detects streams of accesses (in either direction) within a region,
+/// and prefetches the following lines in the region.
+pub struct RegionPrefetcher {
+    regions: Vec<Region>,
+    line_size: usize,
+    /// Number of lines to prefetch ahead
+    degree: usize,
+    /// Number of accesses observed (for replacement)
+    tick: usize,
+}
+impl RegionPrefetcher {
+    pub fn new(line_size: usize, degree: usize) -> Self {
+        Self { regions: Vec::new(), line_size, degree, tick: 0 }
+    }
+}
+impl Prefetcher for RegionPrefetcher {
+    fn name(&self) -> &'static str { "region" }
+
+    fn access(&mut self, _pc: usize, addr: usize, _hit: bool) -> Vec<usize> {
+        self.tick += 1;
+        let base = addr & !(REGION_SIZE - 1);
+        let line = addr & !(self.line_size - 1);
+
+        let Some(r) = self.regions.iter_mut().find(|r| r.base == base) else {
+            let r = Region { 
+                base, last: line, dir: 0, conf: 0, used: self.tick 
+            };
+            if self.regions.len() < NUM_REGIONS {
+                self.regions.push(r);
+            } else {
+                let lru = self.regions.iter_mut().min_by_key(|r| r.used);
+                *lru.unwrap() = r;
+            }
+            return Vec::new();
+        };
+        r.used = self.tick;
+        if line == r.last {
+            return Vec::new();
+        }
+
+        let dir = if line > r.last { 1 } else { -1 };
+        if dir == r.dir {
+            r.conf = (r.conf + 1).min(3);
+        } else {
+            r.conf = 0;
+            r.dir = dir;
+        }
+        r.last = line;
+        if r.conf < 1 {
+            return Vec::new();
+        }
+
+        // Prefetches never cross the region boundary
+        let step = r.dir * self.line_size as isize;
+        (1..=self.degree as isize)
+            .map(|n| line.wrapping_add_signed(step * n))
+            .filter(|a| a & !(REGION_SIZE - 1) == base)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_line() {
+        let mut pf = NextLinePrefetcher::new(64);
+        assert_eq!(pf.access(0, 0x1010, false), vec![0x1040]);
+        assert!(pf.access(0, 0x1010, true).is_empty());
+    }
+
+    #[test]
+    fn stride() {
+        let mut pf = StridePrefetcher::new(2);
+        let pc = 0x400;
+        let res: Vec<Vec<usize>> = (0..4)
+            .map(|i| pf.access(pc, 0x1000 - i * 0x100, true))
+            .collect();
+        assert!(res[..3].iter().all(|r| r.is_empty()));
+        assert_eq!(res[3], vec![0xc00, 0xb00]);
+
+        // Other instructions are tracked separately
+        assert!(pf.access(pc + 1, 0x5000, true).is_empty());
+        assert_eq!(pf.access(pc, 0xc00, true), vec![0xb00, 0xa00]);
+
+        // A different stride resets the confidence
+        assert!(pf.access(pc, 0xc08, true).is_empty());
+    }
+
+    #[test]
+    fn region() {
+        let mut pf = RegionPrefetcher::new(64, 4);
+        assert!(pf.access(0, 0x1f00, true).is_empty());
+        assert!(pf.access(0, 0x1f40, true).is_empty());
+        assert!(pf.access(0, 0x1f48, true).is_empty());
+
+        // Prefetches stop at the end of the region
+        assert_eq!(pf.access(0, 0x1f80, true), vec![0x1fc0]);
+
+        // Descending streams
+        pf.access(0, 0x3800, true);
+        pf.access(0, 0x37c0, true);
+        assert_eq!(pf.access(0, 0x3780, true), 
+            vec![0x3740, 0x3700, 0x36c0, 0x3680]);
+    }
+}
@@ -1,58 +1,186 @@
-use iced_x86::{
-    Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter,
-    ConditionCode, InstructionInfoFactory, OpKind, RflagsBits,
-    Mnemonic, Register, Code, MemorySize
-};
+use std::io::{ self, Write };
 
 use crate::op::*;
 use crate::rf::*;
-use crate::dispatch::*;
-use crate::util::*;
+use crate::bp::*;
+use crate::lsq::*;
+use crate::hier::*;
+use crate::mem::*;
+use crate::event::*;
+use crate::cosim::*;
 
-pub struct RetireControlUnit;
+/// Counters for retirement.
+#[derive(Clone, Debug, Default)]
+pub struct RetireStats {
+    pub insts: usize,
+    pub uops: usize,
+    pub branches: usize,
+    pub branch_misp: usize,
+    pub taken: usize,
+    pub taken_misp: usize,
+    /// Mispredicted indirect jumps and calls
+    pub indirect_misp: usize,
+    pub returns: usize,
+    pub return_misp: usize,
+    /// Micro-ops retired in each function (when symbols are available)
+    pub funcs: std::collections::BTreeMap<String, usize>,
+}
+
+pub struct RetireControlUnit {
+    /// Number of micro-ops retired per cycle
+    pub width: usize,
+    /// Address and macro-op of the faulting instruction which stopped
+    /// retirement (if any)
+    pub halted: Option<(usize, MacroOp)>,
+    pub stats: RetireStats,
+    /// Checks retired instructions against the reference model (if
+    /// enabled)
+    pub cosim: Option<CoSim>,
+}
 impl RetireControlUnit {
+    pub fn new(width: usize) -> Self {
+        Self { 
+            width, halted: None, stats: RetireStats::default(), cosim: None 
+        }
+    }
+
+    /// Returns true if co-simulation found a mismatch (after which nothing
+    /// else is retired).
+    pub fn cosim_failed(&self) -> bool {
+        self.cosim.as_ref().is_some_and(|c| c.error.is_some())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn cycle(&mut self, 
         rob: &mut ReorderBuffer, 
-        rat: &mut RegisterAliasTable
+        rat: &mut RegisterAliasTable,
+        prf: &mut PhysicalRegisterFile,
+        bpu: &mut BranchPredictionUnit,
+        btb: &mut BranchTargetBuffer,
+        lsq: &mut LoadStoreQueue,
+        caches: &mut CacheHierarchy,
+        mem: &mut Memory,
+        tr: &mut Tracer,
+        clk: usize,
     ) {
-        println!("[RCU] Reorder buffer status:");
-        println!("[RCU]   In-flight:    {}", rob.num_used());
-        println!("[RCU]   Free entries: {}", rob.num_free());
-        println!("[RCU]   Retire ptr:   {}", rob.retire_ptr);
-        println!("[RCU]   Dispatch ptr: {}", rob.dispatch_ptr);
+        detail!("[RCU] Reorder buffer status:");
+        detail!("[RCU]   In-flight:    {}", rob.num_used());
+        detail!("[RCU]   Free entries: {}", rob.num_free());
+        detail!("[RCU]   Retire ptr:   {}", rob.retire_ptr);
+        detail!("[RCU]   Dispatch ptr: {}", rob.dispatch_ptr);
 
-        for i in 0..8 {
+        for _ in 0..self.width {
+            if self.halted.is_some() || self.cosim_failed() {
+                break;
+            }
             match rob.pop() {
+                // NOTE: Exceptions aren't modeled, so a fault just stops
+                // the core (without retiring the instruction)
+                Ok((_, ent)) if ent.fault => {
+                    trace!("[RCU] Fault at {:08x} {:?}, halting", 
+                        ent.uop.addr, ent.mop);
+                    self.halted = Some((ent.uop.addr, ent.mop));
+                    break;
+                },
                 Ok((idx, ent)) => {
-                    println!("[RCU] Retiring entry {} ({}/8): {:08x} {:?}",
-                             idx, i, ent.uop.addr, ent.uop.kind);
+                    tr.uop(&ent.uop, idx, EventKind::Retire);
+                    self.stats.uops += 1;
+                    self.stats.insts += ent.last as usize;
+                    if let Some((sym, _)) = tr.syms.lookup(ent.uop.addr) {
+                        *self.stats.funcs.entry(sym.name.clone())
+                            .or_default() += 1;
+                    }
+
+                    // Train the branch predictor
+                    if let Some(npc) = ent.npc {
+                        let info = BranchInfo {
+                            kind: BranchKind::from(ent.mop),
+                            len: ent.uop.len,
+                            addr: ent.uop.addr,
+                        };
+                        self.count_branch(&ent, &info, npc);
+                        bpu.update(btb, info, ent.pred, npc);
+                    }
+
+                    // Release load queue entries, and write the data for
+                    // stores back to memory
+                    if ent.uop.is_load() {
+                        lsq.retire_load(idx);
+                    }
+                    let mut store = None;
+                    if ent.uop.is_store() {
+                        store = lsq.retire_store(idx, caches, mem, clk);
+                    }
 
                     // Commit architectural effects
                     for eff in ent.uop.eff {
                         match eff {
-                            Effect::RegWrite(arn, prn) => {
-                                rat.update(arn, prn);
-                                println!("[RCU] {:?} commit to {:?}", prn, arn);
+                            Effect::RegWrite(..) | Effect::TmpWrite(..) |
+                            Effect::FlagsWrite(..) => {
+                                // The previously-committed mapping for this
+                                // register is no longer reachable
+                                let (arn, prn) = eff.dest().unwrap();
+                                let prev = rat.resolve_arn(arn);
+                                rat.update_arn(arn, prn);
+                                prf.free_explicit(prev);
+                                trace!("[RCU] {:?} commit to {:?}", prn, arn);
+                                trace!("[RCU] Freed {:?}", prev);
                             },
+                            // Branches are resolved during execution
+                            Effect::BrnImm(_) | Effect::BrnCond(..) |
+                            Effect::BrnInd => {},
                             Effect::None => {},
-                            _ => unimplemented!("{:x?}", eff),
                         }
                     }
+
+                    if let Some(cosim) = self.cosim.as_mut() {
+                        cosim.retire(&ent, store, rat, prf, &tr.syms);
+                    }
                 },
                 Err(ROBErr::Incomplete) => {
                     let front = rob.get_front().unwrap();
-                    println!("[RCU] Commit stalled for {:08x} {:?}",
-                             front.uop.addr, front.uop.kind);
+                    tr.uop(&front.uop, rob.retire_ptr, EventKind::Stall {
+                        stage: Stage::Retire, reason: StallReason::Incomplete
+                    });
                     break;
                 }
                 Err(ROBErr::Empty) => {
-                    println!("[RCU] Reorder buffer is empty");
+                    detail!("[RCU] Reorder buffer is empty");
                     break;
                 },
                 Err(e) => unreachable!("{:?}", e),
             }
         }
     }
+
+    fn count_branch(&mut self, ent: &ROBEntry, info: &BranchInfo, 
+                    npc: usize)
+    {
+        let s = &mut self.stats;
+        let next = ent.uop.next_addr();
+        let misp = ent.pred.tgt.unwrap_or(next) != npc;
+        let taken = npc != next;
+        s.branches += 1;
+        s.branch_misp += misp as usize;
+        s.taken += taken as usize;
+        s.taken_misp += (taken && misp) as usize;
+        s.indirect_misp += (info.kind.is_indirect() && misp) as usize;
+        if info.kind == BranchKind::Return {
+            s.returns += 1;
+            s.return_misp += misp as usize;
+        }
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "[RCU] insts={} uops={}", 
+            self.stats.insts, self.stats.uops)?;
+        let mut funcs: Vec<_> = self.stats.funcs.iter().collect();
+        funcs.sort_by_key(|(_, n)| std::cmp::Reverse(**n));
+        for (name, n) in funcs {
+            writeln!(out, "[RCU]   {:>8} {}", n, name)?;
+        }
+        Ok(())
+    }
 }
 
 
@@ -68,11 +196,23 @@ pub enum ROBErr {
 pub struct ROBEntry {
     pub mop: MacroOp,
     pub uop: Uop,
+    /// Front-end prediction made for this instruction
+    pub pred: Prediction,
+    /// The resolved next program counter (for branches)
+    pub npc: Option<usize>,
     pub complete: bool,
+    /// The instruction raises an exception at retirement (ie. UD2, or a
+    /// fetch from outside memory)
+    pub fault: bool,
+    /// This is the last micro-op for the instruction
+    pub last: bool,
 }
 impl ROBEntry {
-    pub fn new(mop: MacroOp, uop: Uop) -> Self {
-        Self { mop, uop, complete: false }
+    pub fn new(mop: MacroOp, uop: Uop, pred: Prediction, last: bool) 
+        -> Self 
+    {
+        Self { mop, uop, pred, npc: None, complete: false, fault: false,
+            last }
     }
 }
 
@@ -176,7 +316,99 @@ impl ReorderBuffer {
         self.data[idx].as_mut()
     }
 
+    /// Return the age of an entry relative to the retire pointer 
+    /// (the oldest in-flight entry has age 0).
+    pub fn age(&self, idx: usize) -> usize {
+        assert!(idx < self.size);
+        (idx + self.size - self.retire_ptr) % self.size
+    }
+
+    /// Returns true if entry 'a' is younger than entry 'b'.
+    pub fn is_younger(&self, a: usize, b: usize) -> bool {
+        self.age(a) > self.age(b)
+    }
+
+    /// Return an iterator over all in-flight entries (from oldest to
+    /// youngest) along with their indexes.
+    pub fn iter_inflight(&self) -> impl Iterator<Item=(usize, &ROBEntry)> {
+        let num_used = self.num_used();
+        (0..num_used).map(move |off| {
+            let idx = (self.retire_ptr + off) % self.size;
+            (idx, self.data[idx].as_ref().unwrap())
+        })
+    }
+
+    /// Remove all entries younger than the entry at 'idx', returning the 
+    /// squashed entries (from oldest to youngest).
+    pub fn squash_younger(&mut self, idx: usize) -> Vec<ROBEntry> {
+        assert!(self.data[idx].is_some());
+        let num_squashed = self.num_used() - (self.age(idx) + 1);
+        let mut res = Vec::new();
+        for off in 1..=num_squashed {
+            let ptr = (idx + off) % self.size;
+            res.push(self.data[ptr].take().unwrap());
+        }
+        self.dispatch_ptr = (idx + 1) % self.size;
+        res
+    }
+
+    /// Squash the entry at some index along with all younger entries,
+    /// returning the squashed entries.
+    pub fn squash_from(&mut self, idx: usize) -> Vec<ROBEntry> {
+        let younger = self.squash_younger(idx);
+        let mut res = vec![self.data[idx].take().unwrap()];
+        res.extend(younger);
+        self.dispatch_ptr = idx;
+        res
+    }
+
 }
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use iced_x86::Register;
+
+    #[test]
+    fn halt_on_fault() {
+        let mut rob = ReorderBuffer::new(4);
+        let mut rat = RegisterAliasTable::new();
+        let mut prf = PhysicalRegisterFile::new(180);
+        let kind = DirectionPredictorKind::Perceptron;
+        let mut bpu = BranchPredictionUnit::new(kind);
+        let mut btb = BranchTargetBuffer::new();
+        let mut lsq = LoadStoreQueue::new(&LsqConfig::default());
+        let mut caches = CacheHierarchy::new(&CachesConfig::default());
+        let mut mem = Memory::new();
+        let mut tr = Tracer::new();
+        let mut rcu = RetireControlUnit::new(8);
+        let pred = Prediction::default();
+
+        // A faulting instruction followed by an instruction which would
+        // otherwise be able to retire
+        let mop = MacroOp::FetchFault;
+        let uop = Uop::from_mop(mop, 0x20, 0)[0];
+        let mut ent = ROBEntry::new(mop, uop, pred, true);
+        ent.complete = true;
+        ent.fault = true;
+        rob.push(ent).unwrap();
+        let mop = MacroOp::MovRI(Register::RAX, 1);
+        let mut uop = Uop::from_mop(mop, 0x30, 5)[0];
+        uop.eff[0] = Effect::RegWrite(Register::RAX, prf.alloc().unwrap());
+        let mut ent = ROBEntry::new(mop, uop, pred, true);
+        ent.complete = true;
+        rob.push(ent).unwrap();
+
+        rcu.cycle(&mut rob, &mut rat, &mut prf, &mut bpu, &mut btb, 
+            &mut lsq, &mut caches, &mut mem, &mut tr, 0);
+        assert!(matches!(rcu.halted, Some((0x20, MacroOp::FetchFault))));
+        assert_eq!(rob.num_used(), 1);
+        rcu.cycle(&mut rob, &mut rat, &mut prf, &mut bpu, &mut btb, 
+            &mut lsq, &mut caches, &mut mem, &mut tr, 0);
+        assert_eq!(rob.num_used(), 1);
+    }
+}
@@ -1,7 +1,8 @@
 
-use std::collections::HashMap;
 use iced_x86::Register;
 
+use crate::flags::*;
+
 /// A tag for a physical register.
 #[repr(transparent)]
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -19,81 +20,138 @@ impl std::fmt::Debug for Prn {
     }
 }
 
+/// Number of general-purpose registers.
+pub const NUM_GPR: usize = 16;
+/// Number of temporary registers (used internally by micro-ops).
+pub const NUM_TMP: usize = 2;
+/// Total number of renamed architectural registers (including the flags).
+pub const NUM_ARN: usize = NUM_GPR + NUM_TMP + NUM_FLAG_GROUPS;
+
 /// A tag for an architectural register.
+///
+/// NOTE: Temporary registers are only visible to the micro-ops within a
+/// single macro-op. They're renamed like any other architectural register,
+/// so the physical registers backing them are freed in the same way.
 #[repr(transparent)]
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Arn(pub usize);
+impl Arn {
+    /// Return the tag for a temporary register.
+    pub fn tmp(n: usize) -> Self {
+        assert!(n < NUM_TMP);
+        Self(NUM_GPR + n)
+    }
+
+    /// Return the tag for a group of flags.
+    pub fn flags(g: FlagGroup) -> Self {
+        Self(NUM_GPR + NUM_TMP + g as usize)
+    }
+}
 impl std::fmt::Debug for Arn {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", Register::from(*self))
+        if *self == Self::flags(FlagGroup::Arith) {
+            write!(f, "FLAGS.ARITH")
+        } else if *self == Self::flags(FlagGroup::Carry) {
+            write!(f, "FLAGS.CF")
+        } else if self.0 >= NUM_GPR {
+            write!(f, "TMP{}", self.0 - NUM_GPR)
+        } else {
+            write!(f, "{:?}", Register::from(*self))
+        }
     }
 }
 
 impl From<Arn> for Register {
     fn from(x: Arn) -> Self {
         match x.0 {
-            00 => Register::RAX,
-            01 => Register::RBX,
-            02 => Register::RCX,
-            03 => Register::RDX,
-            04 => Register::RSI,
-            05 => Register::RDI,
-            06 => Register::RBP,
-            07 => Register::RSP,
-            08 => Register::R8,
-            09 => Register::R9,
+             0 => Register::RAX,
+             1 => Register::RBX,
+             2 => Register::RCX,
+             3 => Register::RDX,
+             4 => Register::RSI,
+             5 => Register::RDI,
+             6 => Register::RBP,
+             7 => Register::RSP,
+             8 => Register::R8,
+             9 => Register::R9,
             10 => Register::R10,
             11 => Register::R11,
             12 => Register::R12,
             13 => Register::R13,
             14 => Register::R14,
             15 => Register::R15,
-            _ => unimplemented!(),
+            _ => unreachable!("{:?} is not a general-purpose register", x),
 
         }
     }
 }
+/// NOTE: Sub-registers (ie. EAX) are renamed as the full 64-bit register.
 impl From<Register> for Arn {
     fn from(x: Register) -> Self {
-        let num = match x {
-            Register::RAX => 00,
-            Register::RBX => 01,
-            Register::RCX => 02,
-            Register::RDX => 03,
-            Register::RSI => 04,
-            Register::RDI => 05,
-            Register::RBP => 06,
-            Register::RSP => 07,
-            Register::R8  => 08,
-            Register::R9  => 09,
+        let num = match x.full_register() {
+            Register::RAX =>  0,
+            Register::RBX =>  1,
+            Register::RCX =>  2,
+            Register::RDX =>  3,
+            Register::RSI =>  4,
+            Register::RDI =>  5,
+            Register::RBP =>  6,
+            Register::RSP =>  7,
+            Register::R8  =>  8,
+            Register::R9  =>  9,
             Register::R10 => 10,
             Register::R11 => 11,
             Register::R12 => 12,
             Register::R13 => 13,
             Register::R14 => 14,
             Register::R15 => 15,
-            _ => unimplemented!(),
+            _ => unreachable!("{:?} is not renamed", x),
         };
         Self(num)
     }
 }
 
+/// Return a mask for the low 'width' bytes of a register.
+pub fn width_mask(width: usize) -> usize {
+    if width >= 8 { usize::MAX } else { (1 << (8 * width)) - 1 }
+}
+
+/// Write a 'width'-byte result to a 64-bit register with some old value.
+///
+/// NOTE: 32-bit results are zero-extended, and 8/16-bit results are 
+/// merged with the old value.
+pub fn merge_gpr(width: usize, old: usize, val: usize) -> usize {
+    let mask = width_mask(width);
+    match width {
+        1 | 2 => (old & !mask) | (val & mask),
+        _ => val & mask,
+    }
+}
+
 pub struct RegisterAliasTable {
     //pub data: HashMap<Register, Prn>
-    pub data: [Prn; 16],
+    pub data: [Prn; NUM_ARN],
 }
 impl RegisterAliasTable {
     pub fn new() -> Self {
-        let mut data: [Prn; 16] = [Prn(0); 16];
+        // NOTE: The initial RAT maps each register to the physical register 
+        // with the same index (see [PhysicalRegisterFile::new]).
+        let mut data: [Prn; NUM_ARN] = [Prn(0); NUM_ARN];
+        for (idx, prn) in data.iter_mut().enumerate() {
+            *prn = Prn(idx);
+        }
         Self { data }
     }
     pub fn print(&self, prf: &PhysicalRegisterFile) {
-        println!("[RAT] Register Alias Table state:");
-        for (arn, prn) in self.data.iter().enumerate() {
+        detail!("[RAT] Register Alias Table state:");
+        for (arn, prn) in self.data[..NUM_GPR].iter().enumerate() {
             let areg = format!("{:?}", Register::from(Arn(arn)));
-            println!("[RAT]   {:3} => {:03} => {:016x}", 
+            detail!("[RAT]   {:3} => {:03} => {:016x}", 
                      areg, prn.0, prf.read(*prn));
         }
+        let flags = prf.read(self.resolve_arn(Arn::flags(FlagGroup::Arith))) 
+            | prf.read(self.resolve_arn(Arn::flags(FlagGroup::Carry)));
+        detail!("[RAT]   FLAGS => {:016x}", flags);
     }
     pub fn resolve(&self, r: Register) -> Prn {
         let idx = Arn::from(r).0;
@@ -104,27 +162,49 @@ impl RegisterAliasTable {
         let idx = Arn::from(r).0;
         self.data[idx] = prn;
     }
+
+    pub fn resolve_arn(&self, arn: Arn) -> Prn {
+        self.data[arn.0]
+    }
+
+    pub fn update_arn(&mut self, arn: Arn, prn: Prn) {
+        self.data[arn.0] = prn;
+    }
+}
+impl Default for RegisterAliasTable {
+    fn default() -> Self { Self::new() }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct PRFEntry {
     pub free: bool,
+    /// Set when the value has been produced (the scoreboard "ready" bit)
+    pub ready: bool,
     pub data: usize,
 }
 impl PRFEntry {
     pub fn new() -> Self {
-        Self { free: true, data: 0 }
+        Self { free: true, ready: false, data: 0 }
     }
 }
+impl Default for PRFEntry {
+    fn default() -> Self { Self::new() }
+}
 
 pub struct PhysicalRegisterFile {
-    pub data: [PRFEntry; 180],
+    pub data: Vec<PRFEntry>,
 }
 impl PhysicalRegisterFile {
-    pub fn new() -> Self {
-        let mut res = Self { data: [PRFEntry::new(); 180] };
-        // NOTE: The initial RAT maps all registers to Prn(0)
-        res.alloc_explicit(Prn(0)).unwrap();
+    pub fn new(size: usize) -> Self {
+        assert!(size > NUM_ARN);
+        let mut res = Self { data: vec![PRFEntry::new(); size] };
+        // NOTE: The initial RAT maps each architectural register to the 
+        // physical register with the same index. These values are available
+        // from the very first cycle.
+        for idx in 0..NUM_ARN {
+            res.alloc_explicit(Prn(idx));
+            res.set_ready(Prn(idx));
+        }
         res
     }
     pub fn can_alloc(&self) -> bool {
@@ -138,26 +218,37 @@ impl PhysicalRegisterFile {
     }
 
     pub fn find(&mut self) -> Option<Prn> {
-        if let Some((i, e)) = self.data.iter_mut().enumerate()
-            .find(|(i, e)| e.free) 
-        {
-            Some(Prn(i))
-        } else { None }
+        self.data.iter().position(|e| e.free).map(Prn)
     }
 
     pub fn read(&self, prn: Prn) -> usize {
-        assert!(self.data[prn.0].free == false);
+        assert!(!self.data[prn.0].free);
         self.data[prn.0].data
     }
     pub fn write(&mut self, prn: Prn, val: usize) {
-        assert!(self.data[prn.0].free == false);
+        assert!(!self.data[prn.0].free);
         self.data[prn.0].data = val;
     }
 
+    /// Returns true if the value for a physical register has been produced.
+    pub fn is_ready(&self, prn: Prn) -> bool {
+        assert!(!self.data[prn.0].free);
+        self.data[prn.0].ready
+    }
+
+    /// Mark a physical register as ready. 
+    ///
+    /// This is the target of the tag broadcast from the execution units: 
+    /// any micro-op waiting on this register can be issued afterwards.
+    pub fn set_ready(&mut self, prn: Prn) {
+        assert!(!self.data[prn.0].free);
+        self.data[prn.0].ready = true;
+    }
+
 
     pub fn alloc(&mut self) -> Option<Prn> {
         if let Some(prn) = self.find() { 
-            self.alloc_explicit(prn).unwrap();
+            self.alloc_explicit(prn);
             Some(prn)
         } else {
             None
@@ -165,16 +256,17 @@ impl PhysicalRegisterFile {
     }
 
     /// Explicitly allocate a particular physical register.
-    pub fn alloc_explicit(&mut self, prn: Prn) -> Result<(), ()> {
-        assert!(self.data[prn.0].free == true);
+    pub fn alloc_explicit(&mut self, prn: Prn) {
+        assert!(self.data[prn.0].free);
         self.data[prn.0].free = false;
-        Ok(())
+        self.data[prn.0].ready = false;
     }
 
     /// Explicitly clear and free a particular physical register.
     pub fn free_explicit(&mut self, prn: Prn) {
-        assert!(self.data[prn.0].free == false);
+        assert!(!self.data[prn.0].free);
         self.data[prn.0].free = true;
+        self.data[prn.0].ready = false;
         self.data[prn.0].data = 0;
     }
 
@@ -194,3 +286,45 @@ impl std::ops::IndexMut<usize> for PhysicalRegisterFile {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_widths() {
+        let old = 0x1122_3344_5566_7788;
+        assert_eq!(merge_gpr(1, old, 0x1ff), 0x1122_3344_5566_77ff);
+        assert_eq!(merge_gpr(2, old, 0xabcd), 0x1122_3344_5566_abcd);
+        assert_eq!(merge_gpr(4, old, 0xffff_ffff), 0xffff_ffff);
+        assert_eq!(merge_gpr(8, old, 5), 5);
+    }
+
+    #[test]
+    fn sub_registers() {
+        for r in [Register::AL, Register::AX, Register::EAX, Register::RAX] {
+            assert_eq!(Arn::from(r), Arn(0));
+        }
+        assert_eq!(Arn::from(Register::R15L), Arn::from(Register::R15));
+        assert_eq!(Register::from(Arn::from(Register::SIL)), Register::RSI);
+    }
+
+    #[test]
+    fn rename_flags() {
+        // Each group of flags is renamed separately
+        let mut prf = PhysicalRegisterFile::new(180);
+        let free = prf.free_regs();
+        let mut rat = RegisterAliasTable::new();
+        let (arith, carry) = 
+            (Arn::flags(FlagGroup::Arith), Arn::flags(FlagGroup::Carry));
+        assert_ne!(rat.resolve_arn(arith), rat.resolve_arn(carry));
+
+        let prn = prf.alloc().unwrap();
+        prf.write(prn, FLAG_CF);
+        rat.update_arn(carry, prn);
+        assert_eq!(prf.read(rat.resolve_arn(carry)), FLAG_CF);
+        assert_eq!(prf.read(rat.resolve_arn(arith)), 0);
+        assert!(!prf.is_ready(prn));
+        assert_eq!(prf.free_regs(), free - 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Translation lookaside buffers.
+
+use std::io::{ self, Write };
+
+/// Size of a page mapped by a page table entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+impl PageSize {
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Size4K => 1 << 12,
+            Self::Size2M => 1 << 21,
+            Self::Size1G => 1 << 30,
+        }
+    }
+    pub fn mask(&self) -> usize { self.bytes() - 1 }
+}
+impl std::str::FromStr for PageSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4k" => Ok(Self::Size4K),
+            "2m" => Ok(Self::Size2M),
+            "1g" => Ok(Self::Size1G),
+            _ => Err(format!("unknown page size '{}'", s)),
+        }
+    }
+}
+
+/// A cached translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TlbEntry {
+    /// Base virtual address of the page
+    pub vbase: usize,
+    /// Base physical address of the page
+    pub pbase: usize,
+    pub size: PageSize,
+}
+impl TlbEntry {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        vaddr & !self.size.mask() == self.vbase
+    }
+    pub fn translate(&self, vaddr: usize) -> usize {
+        self.pbase | (vaddr & self.size.mask())
+    }
+}
+
+/// Counters for a TLB.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TlbStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// A fully-associative TLB with LRU replacement.
+pub struct Tlb {
+    pub name: &'static str,
+    /// Each entry, along with the cycle it was last used
+    entries: Vec<(TlbEntry, usize)>,
+    /// Number of entries
+    pub size: usize,
+    /// Number of cycles for a hit
+    pub latency: usize,
+    pub stats: TlbStats,
+}
+impl Tlb {
+    pub fn new(name: &'static str, size: usize, latency: usize) -> Self {
+        Self { name, entries: Vec::new(), size, latency,
+            stats: TlbStats::default()
+        }
+    }
+
+    pub fn lookup(&mut self, vaddr: usize, clk: usize) -> Option<TlbEntry> {
+        match self.entries.iter_mut().find(|(e, _)| e.contains(vaddr)) {
+            Some((e, used)) => {
+                *used = clk;
+                self.stats.hits += 1;
+                Some(*e)
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    pub fn insert(&mut self, ent: TlbEntry, clk: usize) {
+        if self.entries.iter().any(|(e, _)| *e == ent) {
+            return;
+        }
+        if self.entries.len() == self.size {
+            let lru = (0..self.entries.len())
+                .min_by_key(|i| self.entries[*i].1).unwrap();
+            self.entries.swap_remove(lru);
+        }
+        self.entries.push((ent, clk));
+    }
+
+    /// Remove all entries.
+    pub fn flush(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn print_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "[{}] hits={} misses={}",
+            self.name, self.stats.hits, self.stats.misses)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(n: usize) -> TlbEntry {
+        TlbEntry { vbase: n << 12, pbase: (n + 0x100) << 12, 
+            size: PageSize::Size4K }
+    }
+
+    #[test]
+    fn translate() {
+        let e = TlbEntry { vbase: 0x20_0000, pbase: 0x60_0000, 
+            size: PageSize::Size2M };
+        assert!(e.contains(0x3f_ffff) && !e.contains(0x40_0000));
+        assert_eq!(e.translate(0x21_2345), 0x61_2345);
+    }
+
+    #[test]
+    fn lru() {
+        let mut tlb = Tlb::new("TEST", 2, 0);
+        tlb.insert(page(1), 1);
+        tlb.insert(page(2), 2);
+        assert_eq!(tlb.lookup(0x1abc, 3), Some(page(1)));
+        tlb.insert(page(3), 4);
+        assert_eq!(tlb.lookup(0x2abc, 5), None);
+        assert_eq!(tlb.lookup(0x1abc, 6), Some(page(1)));
+        assert_eq!((tlb.stats.hits, tlb.stats.misses), (2, 1));
+    }
+}
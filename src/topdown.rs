@@ -0,0 +1,157 @@
+//! Top-down cycle accounting.
+//!
+//! Every cycle, the dispatch unit has one slot for each macro-op it can
+//! dispatch (see `BackendConfig::dispatch_width`). Each slot is attributed
+//! to one of:
+//!
+//! - Retiring: used by an instruction which retired
+//! - Bad speculation: used by an instruction which was squashed, or left
+//!   empty while the front-end recovers from a redirect
+//! - Frontend bound: left empty because the op queue ran dry
+//! - Backend bound: left empty because some back-end resource was full
+//!
+//! NOTE: Slots used by instructions which are still in-flight at the end
+//! of a run can't be attributed yet, so they're left out of the total
+//! (and reported separately).
+
+use std::collections::BTreeMap;
+use std::io::{ self, Write };
+
+use crate::event::*;
+
+/// Dispatch slot counters.
+#[derive(Clone, Debug)]
+pub struct TopDown {
+    /// Number of slots per cycle
+    pub width: usize,
+    /// Total number of slots
+    pub slots: usize,
+    /// Slots used by dispatched instructions
+    pub dispatched: usize,
+    /// Slots used by instructions which were later squashed
+    pub squashed: usize,
+    /// Empty slots after a redirect, until dispatch resumes
+    pub recovery: usize,
+    /// Empty slots in cycles where nothing was dispatched
+    pub fe_latency: usize,
+    /// Empty slots in cycles where only some macro-ops were dispatched
+    pub fe_bandwidth: usize,
+    /// Empty slots for each back-end resource
+    pub backend: BTreeMap<StallReason, usize>,
+    /// Set after a redirect until the next macro-op is dispatched
+    recovering: bool,
+}
+impl TopDown {
+    pub fn new(width: usize) -> Self {
+        Self {
+            width,
+            slots: 0,
+            dispatched: 0,
+            squashed: 0,
+            recovery: 0,
+            fe_latency: 0,
+            fe_bandwidth: 0,
+            backend: BTreeMap::new(),
+            recovering: false,
+        }
+    }
+
+    /// Account for one cycle of dispatch, where 'num' macro-ops were
+    /// dispatched before dispatch was stopped by 'stall' (or by an empty
+    /// op queue).
+    pub fn cycle(&mut self, num: usize, stall: Option<StallReason>) {
+        let empty = self.width - num;
+        self.slots += self.width;
+        self.dispatched += num;
+        if num != 0 {
+            self.recovering = false;
+        }
+        match stall {
+            Some(reason) => {
+                *self.backend.entry(reason).or_default() += empty;
+            },
+            None if self.recovering => self.recovery += empty,
+            None if num == 0 => self.fe_latency += empty,
+            None => self.fe_bandwidth += empty,
+        }
+    }
+
+    /// Account for instructions squashed after a redirect.
+    pub fn squash(&mut self, num_insts: usize) {
+        self.squashed += num_insts;
+        self.recovering = true;
+    }
+
+    pub fn bad_speculation(&self) -> usize { self.squashed + self.recovery }
+    pub fn frontend_bound(&self) -> usize {
+        self.fe_latency + self.fe_bandwidth
+    }
+    pub fn backend_bound(&self) -> usize { self.backend.values().sum() }
+
+    /// Write the breakdown, given the number of instructions retired.
+    pub fn print_stats(&self, retired: usize, out: &mut dyn Write) 
+        -> io::Result<()> 
+    {
+        let inflight = self.dispatched
+            .saturating_sub(self.squashed + retired);
+        let total = self.slots - inflight;
+        let mut line = |depth: usize, name: &str, n: usize| {
+            let pct = 100.0 * n as f64 / total.max(1) as f64;
+            let name = format!("{}{}", "  ".repeat(depth), name);
+            writeln!(out, "[TD] {:<32} {:>5.1}% {:>10}", name, pct, n)
+        };
+        line(0, "Retiring", retired)?;
+        line(0, "Bad speculation", self.bad_speculation())?;
+        line(1, "Squashed", self.squashed)?;
+        line(1, "Recovery", self.recovery)?;
+        line(0, "Frontend bound", self.frontend_bound())?;
+        line(1, "Latency", self.fe_latency)?;
+        line(1, "Bandwidth", self.fe_bandwidth)?;
+        line(0, "Backend bound", self.backend_bound())?;
+        for (reason, n) in self.backend.iter() {
+            line(1, reason.desc(), *n)?;
+        }
+        writeln!(out, "[TD] slots={} ({} per cycle)",
+            self.slots, self.width)?;
+        if inflight != 0 {
+            writeln!(out, "[TD] excluded {} slots used by instructions \
+                still in flight", inflight)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots() {
+        let mut td = TopDown::new(4);
+        td.cycle(4, None);
+        td.cycle(2, None);
+        td.cycle(0, None);
+        td.cycle(1, Some(StallReason::RobFull));
+        td.squash(2);
+        td.cycle(0, Some(StallReason::LqFull));
+        td.cycle(0, None);
+        td.cycle(3, None);
+        assert_eq!(td.slots, 28);
+        assert_eq!(td.dispatched, 10);
+        assert_eq!(td.squashed, 2);
+        assert_eq!(td.recovery, 4);
+        assert_eq!((td.fe_latency, td.fe_bandwidth), (4, 3));
+        assert_eq!(td.backend[&StallReason::RobFull], 3);
+        assert_eq!(td.backend_bound(), 7);
+
+        // 7 instructions retired, so one is still in flight
+        let mut out = Vec::new();
+        td.print_stats(7, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("[TD] Retiring"));
+        assert!(lines[0].ends_with("25.9%          7"), "{}", lines[0]);
+        assert_eq!(*lines.last().unwrap(),
+            "[TD] excluded 1 slots used by instructions still in flight");
+    }
+}
@@ -3,6 +3,12 @@ use std::collections::VecDeque;
 
 pub type PipelinePacket<T, E> = Result<T, E>;
 
+/// Errors from operations on a [Queue].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueErr {
+    Full,
+    Empty,
+}
 
 pub struct Queue<T: Sized + Clone> {
     pub data: VecDeque<T>,
@@ -20,6 +26,7 @@ impl <T: Sized + Clone> Queue<T> {
     pub fn len(&self) -> usize { self.data.len() }
     pub fn num_free(&self) -> usize { self.cap - self.data.len() }
     pub fn front(&self) -> Option<&T> { self.data.front() }
+    pub fn clear(&mut self) { self.data.clear() }
 
     pub fn get_mut(&mut self, n: usize) -> &mut T {
         assert!(n < self.data.len());
@@ -27,26 +34,26 @@ impl <T: Sized + Clone> Queue<T> {
     }
 
 
-    pub fn peek(&self, n: usize) -> Result<&T, ()> {
-        if let Some(x) = self.data.get(n) { Ok(x) } else { Err(()) }
+    pub fn peek(&self, n: usize) -> Result<&T, QueueErr> {
+        self.data.get(n).ok_or(QueueErr::Empty)
     }
 
-    pub fn push(&mut self, e: T) -> Result<usize, ()> {
+    pub fn push(&mut self, e: T) -> Result<usize, QueueErr> {
         if self.len() + 1 > self.data.capacity() {
-            Err(())
+            Err(QueueErr::Full)
         } else {
             self.data.push_back(e);
             Ok(self.data.len() - 1)
         }
     }
 
-    pub fn pop(&mut self) -> Result<T, ()> {
-        if let Some(res) = self.data.pop_front() { Ok(res) } else { Err(()) }
+    pub fn pop(&mut self) -> Result<T, QueueErr> {
+        self.data.pop_front().ok_or(QueueErr::Empty)
     }
 
-    pub fn popn_exact(&mut self, num: usize) -> Result<Vec<T>, ()> {
+    pub fn popn_exact(&mut self, num: usize) -> Result<Vec<T>, QueueErr> {
         if self.is_empty() || self.len() < num {
-            Err(())
+            Err(QueueErr::Empty)
         } else {
             let mut res = Vec::new();
             for _ in 0..num {
@@ -55,9 +62,9 @@ impl <T: Sized + Clone> Queue<T> {
             Ok(res)
         }
     }
-    pub fn popn_upto(&mut self, num: usize) -> Result<Vec<T>, ()> {
+    pub fn popn_upto(&mut self, num: usize) -> Result<Vec<T>, QueueErr> {
         if self.is_empty() {
-            Err(())
+            Err(QueueErr::Empty)
         } else {
             let mut res = Vec::new();
             for _ in 0..num {